@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use crate::config::ConfigBackoff;
+
+/// Tracks the delay between consecutive attempts, e.g. to reconnect
+pub struct Backoff {
+    config: ConfigBackoff,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(config: ConfigBackoff) -> Self {
+        Self {
+            current: Duration::from_secs(config.min_secs),
+            config,
+        }
+    }
+    /// Returns the delay to wait now, and increases the following delay
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(Duration::from_secs(self.config.max_secs));
+        delay
+    }
+    pub fn reset(&mut self) {
+        self.current = Duration::from_secs(self.config.min_secs);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Backoff;
+    use crate::config::ConfigBackoff;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(ConfigBackoff {
+            min_secs: 1,
+            max_secs: 5,
+        });
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,49 @@
+//! Simulates a Hikvision camera, for testing HikSink without one
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use structopt::StructOpt;
+
+#[path = "../sim.rs"]
+mod sim;
+
+const SAMPLE_SCRIPT: &str = include_str!("../../samples/sim_script.toml");
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "hik_sim",
+    about = "Simulated Hikvision camera for testing HikSink."
+)]
+struct CliArgs {
+    #[structopt(
+        parse(from_os_str),
+        help = "Script of alerts to send. See samples/sim_script.toml for the format, which is used if not given."
+    )]
+    script: Option<PathBuf>,
+    #[structopt(
+        long = "listen",
+        default_value = "127.0.0.1:8081",
+        help = "Address and port to listen on."
+    )]
+    listen: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = CliArgs::from_args();
+    let script = match &args.script {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))
+            .and_then(|s| sim::Script::parse(&s)),
+        None => sim::Script::parse(SAMPLE_SCRIPT),
+    };
+    let addr = match script.and_then(|script| sim::serve(script, args.listen)) {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Unable to start simulator: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Simulated camera listening on {}", addr);
+    let () = futures::future::pending().await;
+}
@@ -1,100 +1,1943 @@
-use std::{collections::HashSet, path::Path};
+//! The HikSink config, loaded from a TOML file and environment variables
 
-use figment::{providers::Format, Figment};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use figment::{
+    providers::{Format, Serialized},
+    value::{Dict, Value},
+    Figment,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::hikapi::{EventIdentifier, EventType};
+
+/// The HikSink config, see `sample_config.toml` for what each option does
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Config {
+    /// Options for HikSink itself
+    #[serde(default)]
     pub system: ConfigSystem,
+    /// The cameras to connect to
     pub camera: Vec<ConfigCamera>,
-    pub mqtt: ConfigMqtt,
+    /// Where camera events are delivered
+    #[serde(default)]
+    pub sink: ConfigSink,
+    /// How to connect to the MQTT broker and what to publish. Required when the sink is MQTT.
+    #[serde(default)]
+    pub mqtt: Option<ConfigMqtt>,
+    /// Overrides of how event types are shown in home assistant, keyed by the event type string sent by the camera
+    #[serde(default)]
+    pub event_types: HashMap<String, ConfigEventType>,
+    /// Serve health checks over HTTP. Disabled unless configured.
+    #[serde(default)]
+    pub health: Option<ConfigHealth>,
+    /// POST alerts to an HTTP endpoint, alongside the sink. Disabled unless configured.
+    #[serde(default)]
+    pub webhook: Option<ConfigWebhook>,
+    /// Write alerts to InfluxDB, alongside the sink. Disabled unless configured.
+    #[serde(default)]
+    pub influx: Option<ConfigInflux>,
+    /// Accept alerts pushed by cameras in `push` mode. Disabled unless configured.
+    #[serde(default)]
+    pub listener: Option<ConfigListener>,
+    /// Problems that didn't stop the config from loading, e.g. misspelled keys. Logged once logging is set up.
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+/// Shown in place of passwords when printing the config
+pub const REDACTED: &str = "********";
+
+impl Config {
+    /// A copy of the config with passwords masked, safe to print
+    pub fn redacted(&self) -> Config {
+        let mut cfg = self.clone();
+        for cam in &mut cfg.camera {
+            cam.password = REDACTED.to_string();
+        }
+        if let Some(mqtt) = &mut cfg.mqtt {
+            if mqtt.password.is_some() {
+                mqtt.password = Some(REDACTED.to_string());
+            }
+            for broker in &mut mqtt.brokers {
+                if broker.password.is_some() {
+                    broker.password = Some(REDACTED.to_string());
+                }
+            }
+        }
+        // Usually hold a token
+        if let Some(webhook) = &mut cfg.webhook {
+            for value in webhook.headers.values_mut() {
+                *value = REDACTED.to_string();
+            }
+        }
+        if let Some(influx) = &mut cfg.influx {
+            if influx.token.is_some() {
+                influx.token = Some(REDACTED.to_string());
+            }
+        }
+        cfg
+    }
+    /// The tracing filter for `log_level`, with each camera's own `log_level` applied to the logs of its connection
+    pub fn log_directives(&self) -> String {
+        let mut directives = vec![self.system.log_level.clone()];
+        for cam in &self.camera {
+            if let Some(level) = &cam.log_level {
+                // Matches the `id` field of the camera's span
+                directives.push(format!("[{{id={}}}]={}", cam.identifier(), level));
+            }
+        }
+        directives.join(",")
+    }
+}
+
+/// Where camera events are delivered
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct ConfigSink {
+    /// The kind of sink
+    #[serde(default, rename = "type")]
+    pub kind: SinkType,
+}
+
+/// The kinds of sink HikSink can deliver events to
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkType {
+    /// Publish to an MQTT broker, with Home Assistant discovery
+    #[default]
+    Mqtt,
+    /// Print each event to stdout as a line of JSON
+    Stdout,
+}
+
+/// Options for POSTing alerts to an HTTP endpoint
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ConfigWebhook {
+    /// The endpoint to POST to
+    pub url: String,
+    /// Extra headers sent with each request, e.g. `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Only POST these event types, optionally qualified by channel. Everything is sent if empty.
+    #[serde(default)]
+    pub events_include: Vec<String>,
+    /// Never POST these event types, optionally qualified by channel
+    #[serde(default)]
+    pub events_exclude: Vec<String>,
+    /// Give up on a request after this long
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+    /// How many times a failed request is retried before the alert is dropped
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// The delay between retries
+    #[serde(default)]
+    pub retry_backoff: ConfigBackoff,
+    /// How many alerts can wait for a slow endpoint before the oldest are dropped
+    #[serde(default = "default_webhook_queue_size")]
+    pub queue_size: usize,
+}
+
+impl ConfigWebhook {
+    /// Whether the trigger passes the webhook's `events_include` and `events_exclude` filters
+    pub fn includes_trigger(&self, identifier: &EventIdentifier) -> bool {
+        passes_event_filters(&self.events_include, &self.events_exclude, identifier)
+    }
+}
+
+/// Options for writing alerts to InfluxDB
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ConfigInflux {
+    /// The server, e.g. `http://localhost:8086`
+    pub url: String,
+    /// Sent as `Authorization: Token ...`. An API token for InfluxDB 2, or `username:password` for InfluxDB 1.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The InfluxDB 2 organization the bucket belongs to
+    #[serde(default)]
+    pub org: Option<String>,
+    /// The InfluxDB 2 bucket to write to
+    #[serde(default)]
+    pub bucket: Option<String>,
+    /// The InfluxDB 1 database to write to, instead of a bucket
+    #[serde(default)]
+    pub database: Option<String>,
+    /// How often the points collected since the last write are sent
+    #[serde(default = "default_influx_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+    /// Give up on a write after this long
+    #[serde(default = "default_influx_timeout_secs")]
+    pub timeout_secs: u64,
+    /// How many points are kept while writes fail before the oldest are dropped
+    #[serde(default = "default_influx_max_points")]
+    pub max_points: usize,
+    /// How many alerts can wait for a slow server before the oldest are dropped
+    #[serde(default = "default_influx_queue_size")]
+    pub queue_size: usize,
+}
+
+/// Options for the server cameras push alerts to
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ConfigListener {
+    /// The address and port to listen on, e.g. `0.0.0.0:8123`
+    pub bind: SocketAddr,
+}
+
+/// Options for the health check server
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ConfigHealth {
+    /// The address and port to listen on, e.g. `0.0.0.0:8080`
+    pub listen: SocketAddr,
 }
 
+/// Options for HikSink itself
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct ConfigSystem {
+    /// A tracing filter, e.g. `info` or `hik_sink=debug`
+    #[serde(default = "default_log_level")]
     pub log_level: String,
+    /// How HikSink's own logs are written
+    #[serde(default)]
+    pub log_format: SystemLogFormat,
+    /// Colour text logs with ANSI escape codes
+    #[serde(default = "default_true")]
+    pub log_ansi: bool,
+    /// Clear alerts that haven't been posted for this many seconds. Needed for events that never send an inactive state.
+    #[serde(default)]
+    pub alert_auto_clear_secs: Option<u64>,
+    /// Per event type overrides of `alert_auto_clear_secs`, keyed by the event name used in MQTT topics (e.g. `FaceSnap`)
+    #[serde(default)]
+    pub alert_auto_clear_event_secs: HashMap<String, u64>,
+    /// Delete retained MQTT topics of triggers and cameras that no longer exist
+    #[serde(default = "default_true")]
+    pub cleanup_stale_topics: bool,
+    /// File used to remember state (e.g. published topics) between restarts
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    /// Don't create entities for video loss triggers or publish their alerts
+    #[serde(default)]
+    pub ignore_videoloss: bool,
+    /// Report a problem with a connected camera that hasn't sent any alert for this many seconds
+    #[serde(default)]
+    pub stale_after_secs: Option<u64>,
+    /// Drop an alert when the same event of the same camera was just received from another
+    /// device, e.g. from both the camera and its NVR
+    #[serde(default)]
+    pub dedup: bool,
+    /// Debounce settings of event types, keyed by the event name used in MQTT topics (e.g. `FieldDetection`)
+    #[serde(default)]
+    pub debounce: HashMap<String, ConfigDebounce>,
+    /// Keep a disconnected camera available for this many seconds, so a reconnect in time isn't shown as unavailable
+    #[serde(default)]
+    pub availability_grace_secs: u64,
+    /// Save the raw alert parts that fail to parse under this directory, in a subdirectory per camera
+    #[serde(default)]
+    pub capture_dir: Option<PathBuf>,
+    /// Save every alert part under `capture_dir`, not only those that fail to parse
+    #[serde(default)]
+    pub capture_all: bool,
+    /// Delete the oldest captures once they take up more than this many megabytes
+    #[serde(default = "default_capture_max_mb")]
+    pub capture_max_mb: u64,
+    /// How many cameras may be connecting at the same time. Unlimited if not set.
+    #[serde(default)]
+    pub max_concurrent_connects: Option<usize>,
+    /// Wait this many milliseconds between starting each camera
+    #[serde(default)]
+    pub connect_stagger_ms: u64,
+    /// Report a problem with a camera whose clock is more than this many seconds from HikSink's
+    #[serde(default = "default_clock_drift_threshold_secs")]
+    pub clock_drift_threshold_secs: u64,
+    /// Exit when the MQTT broker rejects the credentials instead of retrying, so a supervisor notices
+    #[serde(default)]
+    pub exit_on_mqtt_auth_failure: bool,
+}
+
+impl Default for ConfigSystem {
+    fn default() -> Self {
+        Self {
+            log_level: default_log_level(),
+            log_format: SystemLogFormat::default(),
+            log_ansi: true,
+            alert_auto_clear_secs: None,
+            alert_auto_clear_event_secs: HashMap::new(),
+            cleanup_stale_topics: true,
+            state_file: None,
+            ignore_videoloss: false,
+            stale_after_secs: None,
+            dedup: false,
+            debounce: HashMap::new(),
+            availability_grace_secs: 0,
+            capture_dir: None,
+            capture_all: false,
+            capture_max_mb: default_capture_max_mb(),
+            max_concurrent_connects: None,
+            connect_stagger_ms: 0,
+            clock_drift_threshold_secs: default_clock_drift_threshold_secs(),
+            exit_on_mqtt_auth_failure: false,
+        }
+    }
 }
 
+/// A camera or recorder to connect to
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct ConfigCamera {
+    /// The ID used for the camera, see [`ConfigCamera::identifier`]. Set when the config is loaded.
     #[serde(skip_deserializing)]
     pub generated_id: String,
+    /// Used in MQTT topics and home assistant unique IDs instead of an ID generated from the name,
+    /// so the camera can be renamed without creating new entities
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Publishes the camera's topics under this instead of the `[mqtt]` base topic. Discovery is unaffected.
+    #[serde(default)]
+    pub base_topic: Option<String>,
+    /// The name shown in home assistant
     pub name: String,
+    /// The hostname or IP address of the camera. A scheme or port written into it (e.g. `http://camera.local:8080`)
+    /// is moved into `scheme` and `port` when the config is loaded.
     pub address: String,
+    /// The HTTP port, if not the default of the scheme
     pub port: Option<u16>,
+    /// Whether to connect over HTTP or HTTPS. HTTP if not set.
+    #[serde(default)]
+    pub scheme: Option<CameraScheme>,
+    /// Another hostname or IP address of the camera, tried when it can't be reached at `address`
+    #[serde(default)]
+    pub fallback_address: Option<String>,
+    /// The port at `fallback_address`, if not the same as `port`
+    #[serde(default)]
+    pub fallback_port: Option<u16>,
+    /// Set exactly one of this, `username_file` or `username_env`
+    #[serde(default)]
     pub username: String,
+    /// A file containing the username, e.g. a docker secret
+    #[serde(default)]
+    pub username_file: Option<PathBuf>,
+    /// An environment variable containing the username
+    #[serde(default)]
+    pub username_env: Option<String>,
+    /// Set exactly one of this, `password_file` or `password_env`
+    #[serde(default)]
     pub password: String,
+    /// A file containing the password, e.g. a docker secret
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// An environment variable containing the password
+    #[serde(default)]
+    pub password_env: Option<String>,
+    /// Whether to keep retrying (slowly) after the camera rejects the credentials
+    #[serde(default = "default_true")]
+    pub retry_on_auth_failure: bool,
+    /// The format of alerts requested from the camera's alert stream
+    #[serde(default)]
+    pub alert_format: AlertFormat,
+    /// Fetch a picture from the camera and publish it when a trigger alerts
+    #[serde(default)]
+    pub snapshot_on_alert: bool,
+    /// Minimum time between snapshots of the same trigger
+    #[serde(default = "default_snapshot_min_interval_secs")]
+    pub snapshot_min_interval_secs: u64,
+    /// Publish separate human and vehicle entities for smart (AcuSense) triggers
+    #[serde(default)]
+    pub split_smart_targets: bool,
+    /// Overrides the system `alert_auto_clear_secs` for this camera
+    #[serde(default)]
+    pub alert_auto_clear_secs: Option<u64>,
+    /// Overrides the system `stale_after_secs` for this camera
+    #[serde(default)]
+    pub stale_after_secs: Option<u64>,
+    /// The home assistant area the camera is suggested to be placed in
+    #[serde(default)]
+    pub area: Option<String>,
+    /// Display names of triggers, keyed by `<channel>/<event>` (e.g. `1/linedetection`), or just `<event>` for triggers without a channel
+    #[serde(default)]
+    pub trigger_names: HashMap<String, String>,
+    /// Display names of channels (e.g. the cameras connected to an NVR), keyed by the channel number.
+    /// Takes precedence over the names configured on the NVR.
+    #[serde(default)]
+    pub channels: HashMap<String, String>,
+    /// Display names of alarm inputs, keyed by the port number.
+    /// Takes precedence over the names configured on the camera.
+    #[serde(default)]
+    pub io_ports: HashMap<String, String>,
+    /// Show each channel (e.g. each camera connected to an NVR) as its own device in home assistant,
+    /// connected through this one
+    #[serde(default)]
+    pub device_per_channel: bool,
+    /// Subtracted from alert channel ids above it, for NVRs that number IP channels from 33 in alerts but from 1
+    /// in the trigger list. Recorders get an offset of 32 when it's needed to match an alert to a trigger.
+    #[serde(default)]
+    pub channel_offset: Option<u32>,
+    /// Only publish these event types, optionally qualified by channel (e.g. `Motion` or `1/Motion`). Empty means all.
+    #[serde(default)]
+    pub events_include: Vec<String>,
+    /// Never publish these event types, optionally qualified by channel
+    #[serde(default)]
+    pub events_exclude: Vec<String>,
+    /// Overrides the system `ignore_videoloss` for this camera
+    #[serde(default)]
+    pub ignore_videoloss: Option<bool>,
+    /// Triggers to add to those scanned from the camera, e.g. `1/Motion` or `Io/1`. Needed for cameras with a broken trigger list.
+    #[serde(default)]
+    pub triggers: Vec<String>,
+    /// Scan the camera's triggers again this often, to pick up events enabled after connecting
+    #[serde(default)]
+    pub trigger_rescan_secs: Option<u64>,
+    /// Check the camera's clock again this often, not only when connecting
+    #[serde(default)]
+    pub clock_check_secs: Option<u64>,
+    /// Read the camera's device info again this often, so firmware upgrades show up without reconnecting
+    #[serde(default)]
+    pub info_refresh_secs: Option<u64>,
+    /// Create triggers for alerts the camera didn't list in its triggers, instead of ignoring them
+    #[serde(default)]
+    pub auto_register_triggers: bool,
+    /// Skip triggers that aren't set to notify the surveillance center, as they can never send alerts
+    #[serde(default)]
+    pub only_notifying_triggers: bool,
+    /// How alerts are received from the camera
+    #[serde(default)]
+    pub mode: CameraMode,
+    /// Publish switches for settings of the camera, e.g. motion detection, that can be changed over MQTT
+    #[serde(default)]
+    pub expose_controls: bool,
+    /// Count how many are in each channel's regions from region entrance and exiting alerts
+    #[serde(default)]
+    pub derive_occupancy: bool,
+    /// The IDs of the cameras this device also reports the events of, by the channel they're on, for
+    /// NVRs that don't send the camera's serial number. Only used when `dedup` is enabled.
+    #[serde(default)]
+    pub duplicates_of: HashMap<String, String>,
+    /// Overrides the system `debounce` settings of event types for this camera, one option at a time
+    #[serde(default)]
+    pub debounce: HashMap<String, ConfigDebounce>,
+    /// The level of the logs about this camera's connection, e.g. `trace`, overriding the system `log_level`
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// Connect to the camera. Disabled cameras keep their entities, shown as unavailable.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+/// How alerts are received from a camera
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CameraMode {
+    /// Connect to the camera's alert stream
+    #[default]
+    Stream,
+    /// The camera POSTs alerts to the `[listener]`, for cameras without an alert stream
+    Push,
+}
+
+/// The protocol used to talk to a camera
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CameraScheme {
+    /// Plain HTTP
+    Http,
+    /// HTTP over TLS. The camera's certificate must be trusted.
+    Https,
+}
+
+impl CameraScheme {
+    fn as_str(self) -> &'static str {
+        match self {
+            CameraScheme::Http => "http",
+            CameraScheme::Https => "https",
+        }
+    }
+}
+
+/// The format to request alerts in
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertFormat {
+    /// Request XML alerts
+    Xml,
+    /// Request JSON alerts. Only supported on newer firmware.
+    Json,
+    /// Let the camera choose, and detect the format of each alert
+    #[default]
+    Auto,
 }
 
 impl ConfigCamera {
+    /// The ID of the camera, used in MQTT topics. Either the configured `id` or one generated from the name.
     pub fn identifier(&self) -> &str {
         self.generated_id.as_ref()
     }
+    /// Whether the trigger passes the camera's `events_include` and `events_exclude` filters
+    pub fn includes_trigger(&self, identifier: &EventIdentifier) -> bool {
+        passes_event_filters(&self.events_include, &self.events_exclude, identifier)
+    }
+    /// The configured `triggers`. Invalid entries are rejected when the config is loaded.
+    pub fn manual_triggers(&self) -> Vec<EventIdentifier> {
+        self.triggers
+            .iter()
+            .filter_map(|entry| parse_trigger_entry(entry).ok())
+            .collect()
+    }
+    /// The display name configured for a trigger, if any
+    pub fn trigger_name(&self, identifier: &EventIdentifier) -> Option<&str> {
+        self.trigger_names
+            .iter()
+            .find(|(key, _)| trigger_key_matches(key, identifier))
+            .map(|(_, name)| name.as_str())
+    }
+    /// The config for connecting to the camera at its `fallback_address`, if it has one
+    pub fn fallback(&self) -> Option<ConfigCamera> {
+        let address = self.fallback_address.clone()?;
+        Some(ConfigCamera {
+            address,
+            port: self.fallback_port.or(self.port),
+            ..self.clone()
+        })
+    }
+    /// The root URL of the camera's web interface, e.g. `http://192.168.1.200:8080`
+    pub fn base_url(&self) -> String {
+        let host = if self.address.contains(':') {
+            // IPv6 addresses need brackets to be told apart from the port
+            format!("[{}]", self.address)
+        } else {
+            self.address.clone()
+        };
+        format!(
+            "{}://{}{}",
+            self.scheme.unwrap_or(CameraScheme::Http).as_str(),
+            host,
+            self.port.map(|p| format!(":{}", p)).unwrap_or_default()
+        )
+    }
 }
 
+/// How to connect to the MQTT broker and what to publish
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct ConfigMqtt {
+    /// The hostname or IP address of the broker
     pub address: String,
+    /// The port of the broker
+    #[serde(default = "default_mqtt_port")]
     pub port: u16,
-    pub username: String,
-    pub password: String,
+    /// Leave out the username and password for brokers that allow anonymous access
+    #[serde(default)]
+    pub username: Option<String>,
+    /// A file containing the username, instead of `username`
+    #[serde(default)]
+    pub username_file: Option<PathBuf>,
+    /// An environment variable containing the username, instead of `username`
+    #[serde(default)]
+    pub username_env: Option<String>,
+    /// The password for `username`
+    #[serde(default)]
+    pub password: Option<String>,
+    /// A file containing the password, instead of `password`
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// An environment variable containing the password, instead of `password`
+    #[serde(default)]
+    pub password_env: Option<String>,
+    /// The topic everything except discovery is published under
+    #[serde(default = "default_base_topic")]
     pub base_topic: String,
+    /// The discovery prefix configured in home assistant
+    #[serde(default = "default_home_assistant_topic")]
     pub home_assistant_topic: String,
+    /// The topic everything about a camera is published under
+    #[serde(default = "TopicTemplate::camera_default")]
+    pub camera_topic: TopicTemplate,
+    /// The topic of a trigger's state, which its other topics are published under
+    #[serde(default = "TopicTemplate::trigger_default")]
+    pub trigger_topic: TopicTemplate,
+    /// Key home assistant discovery on each camera's serial number instead of its ID, so renaming a camera keeps its entities
+    #[serde(default)]
+    pub stable_ids: bool,
+    /// The version of MQTT to speak to the broker
+    #[serde(default)]
+    pub protocol: MqttProtocol,
+    /// With MQTT 5, have the broker discard trigger states after this many seconds, so stale alerts age out
+    /// if HikSink stops without clearing them
+    #[serde(default)]
+    pub message_expiry_secs: Option<u32>,
+    /// The topic of HikSink's own availability and last will. Defaults to `<base_topic>/availability`.
+    #[serde(default)]
+    pub availability_topic: Option<String>,
+    /// Published to availability topics while HikSink or a camera is available
+    #[serde(default = "default_payload_available")]
+    pub payload_available: String,
+    /// Published to availability topics while HikSink or a camera isn't available
+    #[serde(default = "default_payload_not_available")]
+    pub payload_not_available: String,
+    /// The client ID, which has to be unique on the broker
     #[serde(default = "default_client_id")]
     pub client_id: String,
+    /// How often to ping the broker. Increase this on slow or unreliable connections.
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// How many camera events can be queued before alerts are dropped and connection changes wait for MQTT
+    #[serde(default = "default_event_channel_capacity")]
+    pub event_channel_capacity: usize,
+    /// How many messages to keep for publishing later while the broker is unreachable
+    #[serde(default = "default_offline_buffer_size")]
+    pub offline_buffer_size: usize,
+    /// How long to wait before reconnecting after a broker connection error
+    #[serde(default)]
+    pub reconnect_backoff: ConfigBackoff,
+    /// Other brokers that everything is also published to, each with its own connection
+    #[serde(default)]
+    pub brokers: Vec<ConfigMqttBroker>,
+    /// Retain trigger states on the broker. Momentary events (e.g. `FaceSnap`) are never retained unless overridden.
+    #[serde(default = "default_true")]
+    pub retain_state: bool,
+    /// Per event type overrides of `retain_state`, keyed by the event name used in MQTT topics (e.g. `FaceSnap`)
+    #[serde(default)]
+    pub retain_state_events: HashMap<String, bool>,
+    /// The QoS used for each class of message
+    #[serde(default)]
+    pub qos: ConfigMqttQoS,
+    /// Also publish each trigger's state as a plain on/off payload, for consumers that don't parse JSON
+    #[serde(default)]
+    pub plain_state_topics: bool,
+    /// The plain state payload of active triggers
+    #[serde(default = "default_plain_state_payload_on")]
+    pub plain_state_payload_on: String,
+    /// The plain state payload of inactive triggers
+    #[serde(default = "default_plain_state_payload_off")]
+    pub plain_state_payload_off: String,
+    /// Publish detection regions to their own topic instead of inside the trigger state
+    #[serde(default)]
+    pub regions_topic: bool,
+    /// Publish the contents of alerts that couldn't be parsed, to make reporting them easier
+    #[serde(default)]
+    pub publish_parse_failures: bool,
+    /// Alerts longer than this are truncated before being published as a parse failure
+    #[serde(default = "default_parse_failure_max_bytes")]
+    pub parse_failure_max_bytes: usize,
+    /// The format of each camera's connection log topic
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Name triggers of unnamed channels after the `channelName` sent in their alerts, updating their discovery
+    #[serde(default)]
+    pub names_from_alerts: bool,
+}
+
+/// A broker that mirrors everything published to the main one
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ConfigMqttBroker {
+    /// The hostname or IP address of the broker
+    pub address: String,
+    /// The port of the broker
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    /// Leave out the username and password for brokers that allow anonymous access
+    #[serde(default)]
+    pub username: Option<String>,
+    /// A file containing the username, instead of `username`
+    #[serde(default)]
+    pub username_file: Option<PathBuf>,
+    /// An environment variable containing the username, instead of `username`
+    #[serde(default)]
+    pub username_env: Option<String>,
+    /// The password for `username`
+    #[serde(default)]
+    pub password: Option<String>,
+    /// A file containing the password, instead of `password`
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// An environment variable containing the password, instead of `password`
+    #[serde(default)]
+    pub password_env: Option<String>,
+    /// The client ID, which has to be unique on this broker
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+}
+
+impl ConfigMqtt {
+    /// The settings to connect to a mirror broker. Everything but the connection is shared with the main broker.
+    pub fn mirror(&self, broker: &ConfigMqttBroker) -> ConfigMqtt {
+        ConfigMqtt {
+            address: broker.address.clone(),
+            port: broker.port,
+            username: broker.username.clone(),
+            username_file: None,
+            username_env: None,
+            password: broker.password.clone(),
+            password_file: None,
+            password_env: None,
+            client_id: broker.client_id.clone(),
+            brokers: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// The version of MQTT used
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttProtocol {
+    /// MQTT 3.1.1
+    #[default]
+    V4,
+    /// MQTT 5, which supports message expiry
+    V5,
+}
+
+/// The format of each camera's connection log topic
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// A plain human readable message
+    Text,
+    /// The connection state, when it last changed and a human readable message
+    #[default]
+    Json,
+}
+
+/// The format of HikSink's own logs
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemLogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// A JSON object per line, for log collectors
+    Json,
+}
+
+/// Overrides of how an event type is shown in home assistant
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct ConfigEventType {
+    /// Replaces the friendly name, e.g. `I/O Port`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// A home assistant binary sensor device class
+    #[serde(default)]
+    pub device_class: Option<String>,
+    /// A material design icon, e.g. `mdi:motion-sensor`
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// The kind of home assistant entity triggers of this event type get. Binary sensor if not set.
+    #[serde(default)]
+    pub ha_entity: Option<HaEntity>,
+}
+
+/// How the triggers of an event type appear in home assistant
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HaEntity {
+    /// On while the trigger is alerting
+    #[default]
+    BinarySensor,
+    /// An event entity, fired each time the trigger starts alerting. Needs home assistant 2023.8 or later.
+    Event,
+    /// A device trigger for automations, fired each time the trigger starts alerting
+    DeviceTrigger,
+}
+
+/// Limits how often the state of a flapping trigger is published
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Default)]
+pub struct ConfigDebounce {
+    /// Keep the trigger alerting until this long after its last active alert, so a quick off and on again isn't published
+    #[serde(default)]
+    pub min_off_delay_secs: Option<u64>,
+    /// Publish changed regions of an ongoing alert at most this often
+    #[serde(default)]
+    pub min_publish_interval_secs: Option<u64>,
+}
+
+/// Device classes home assistant supports for binary sensors
+const BINARY_SENSOR_DEVICE_CLASSES: &[&str] = &[
+    "battery",
+    "battery_charging",
+    "carbon_monoxide",
+    "cold",
+    "connectivity",
+    "door",
+    "garage_door",
+    "gas",
+    "heat",
+    "light",
+    "lock",
+    "moisture",
+    "motion",
+    "moving",
+    "occupancy",
+    "opening",
+    "plug",
+    "power",
+    "presence",
+    "problem",
+    "running",
+    "safety",
+    "smoke",
+    "sound",
+    "tamper",
+    "update",
+    "vibration",
+    "window",
+];
+
+/// The QoS used for each class of message
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct ConfigMqttQoS {
+    /// Trigger states
+    #[serde(default)]
+    pub state: QoSLevel,
+    /// Online/offline messages of the bridge and cameras
+    #[serde(default)]
+    pub availability: QoSLevel,
+    /// Home assistant discovery
+    #[serde(default)]
+    pub discovery: QoSLevel,
+    /// Statistics and logs
+    #[serde(default)]
+    pub stats: QoSLevel,
+}
+
+/// An MQTT QoS level (0, 1 or 2)
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct QoSLevel(pub u8);
+
+impl Default for QoSLevel {
+    fn default() -> Self {
+        QoSLevel(1)
+    }
+}
+
+impl TryFrom<u8> for QoSLevel {
+    type Error = String;
+
+    fn try_from(level: u8) -> Result<Self, Self::Error> {
+        if level <= 2 {
+            Ok(QoSLevel(level))
+        } else {
+            Err(format!("invalid QoS level {}, expected 0, 1 or 2", level))
+        }
+    }
+}
+
+impl From<QoSLevel> for u8 {
+    fn from(level: QoSLevel) -> Self {
+        level.0
+    }
+}
+
+/// An MQTT topic with `{placeholder}`s filled in when publishing, e.g. `{base}/device_{camera_id}`
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct TopicTemplate {
+    template: String,
+    /// The levels of the topic, split by `/`
+    levels: Vec<Vec<TemplatePart>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum TemplatePart {
+    Text(String),
+    Placeholder(String),
+}
+
+/// Placeholders that can be used in topic templates
+const TOPIC_PLACEHOLDERS: &[&str] = &["base", "camera_id", "camera", "channel", "event_type"];
+
+impl TopicTemplate {
+    /// Today's layout of camera topics
+    pub fn camera_default() -> Self {
+        Self::try_from("{base}/device_{camera_id}".to_string()).expect("valid camera topic")
+    }
+
+    /// Today's layout of trigger topics
+    pub fn trigger_default() -> Self {
+        Self::try_from("{camera}/{channel}/{event_type}".to_string()).expect("valid trigger topic")
+    }
+
+    /// Whether `{placeholder}` is used anywhere in the template
+    pub fn uses(&self, placeholder: &str) -> bool {
+        self.levels
+            .iter()
+            .flatten()
+            .any(|part| matches!(part, TemplatePart::Placeholder(p) if p == placeholder))
+    }
+
+    /// Fills in the placeholders. Levels that are left empty, e.g. by the `{channel}` of a trigger
+    /// without one, are left out.
+    pub fn render(&self, values: &[(&str, &str)]) -> String {
+        self.render_levels(values, None)
+    }
+
+    /// Fills in the placeholders, replacing each level that uses `wildcard` with `+`
+    /// so the topic can be subscribed to for every value of it
+    pub fn render_wildcard(&self, wildcard: &str, values: &[(&str, &str)]) -> String {
+        self.render_levels(values, Some(wildcard))
+    }
+
+    fn render_levels(&self, values: &[(&str, &str)], wildcard: Option<&str>) -> String {
+        let mut topic = Vec::new();
+        for level in &self.levels {
+            let mut rendered = String::new();
+            for part in level {
+                match part {
+                    TemplatePart::Text(text) => rendered.push_str(text),
+                    TemplatePart::Placeholder(p) if Some(p.as_str()) == wildcard => {
+                        rendered = "+".to_string();
+                        break;
+                    }
+                    TemplatePart::Placeholder(p) => {
+                        let value = values.iter().find(|(name, _)| name == p);
+                        rendered.push_str(value.map(|(_, value)| *value).unwrap_or_default());
+                    }
+                }
+            }
+            if !rendered.is_empty() || level.is_empty() {
+                topic.push(rendered);
+            }
+        }
+        topic.join("/")
+    }
+}
+
+impl TryFrom<String> for TopicTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        if template.is_empty() {
+            return Err("topic template is empty".to_string());
+        }
+        if template.contains(['+', '#']) {
+            return Err(format!(
+                "topic template `{}` can't contain the MQTT wildcards + and #",
+                template
+            ));
+        }
+        let mut levels = Vec::new();
+        for level in template.split('/') {
+            let mut parts = Vec::new();
+            let mut rest = level;
+            while let Some(start) = rest.find(['{', '}']) {
+                if rest[start..].starts_with('}') {
+                    return Err(format!(
+                        "topic template `{}` has a `}}` without a `{{`",
+                        template
+                    ));
+                }
+                let end = rest[start..]
+                    .find('}')
+                    .map(|end| start + end)
+                    .ok_or_else(|| {
+                        format!("topic template `{}` has a `{{` without a `}}`", template)
+                    })?;
+                let name = &rest[start + 1..end];
+                if !TOPIC_PLACEHOLDERS.contains(&name) {
+                    return Err(format!(
+                        "topic template `{}` has an unknown placeholder `{{{}}}`. Expected one of {{{}}}",
+                        template,
+                        name,
+                        TOPIC_PLACEHOLDERS.join("}, {")
+                    ));
+                }
+                if start > 0 {
+                    parts.push(TemplatePart::Text(rest[..start].to_string()));
+                }
+                parts.push(TemplatePart::Placeholder(name.to_string()));
+                rest = &rest[end + 1..];
+            }
+            if !rest.is_empty() {
+                parts.push(TemplatePart::Text(rest.to_string()));
+            }
+            levels.push(parts);
+        }
+        Ok(TopicTemplate { template, levels })
+    }
+}
+
+impl From<TopicTemplate> for String {
+    fn from(template: TopicTemplate) -> Self {
+        template.template
+    }
+}
+
+impl std::fmt::Display for TopicTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+/// An exponential backoff, doubling the delay after each consecutive failure
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ConfigBackoff {
+    /// The delay after the first failure
+    pub min_secs: u64,
+    /// The longest delay
+    pub max_secs: u64,
+}
+
+impl Default for ConfigBackoff {
+    fn default() -> Self {
+        Self {
+            min_secs: 1,
+            max_secs: 30,
+        }
+    }
+}
+
+/// The config sections which can be set from the environment
+const ENV_SECTIONS: &[&str] = &[
+    "system",
+    "sink",
+    "mqtt",
+    "health",
+    "webhook",
+    "influx",
+    "listener",
+    "event_types",
+];
+/// Fields which are always strings from the environment, since e.g. a numeric password would otherwise be a number
+const ENV_STRING_FIELDS: &[&str] = &["name", "id", "username", "password", "client_id"];
+
+/// Applies `HIKSINK_` environment variables to the config. The section is separated from the field by a single `_`,
+/// e.g. `HIKSINK_MQTT_BASE_TOPIC`, and cameras are selected by their index, e.g. `HIKSINK_CAMERA_0_NAME`.
+/// Values are parsed like TOML, so deeper tables can be set inline, e.g. `HIKSINK_MQTT_QOS="{state=0}"`.
+fn apply_env_overrides(root: &mut Dict, env: impl Iterator<Item = (String, String)>) {
+    // Sorted so whole sections are set before the fields overriding them
+    let env: BTreeMap<String, String> = env.collect();
+    for (key, value) in env {
+        let key = match key.strip_prefix("HIKSINK_") {
+            Some(key) => key.to_ascii_lowercase(),
+            None => continue,
+        };
+        let value: Value = if ENV_STRING_FIELDS
+            .iter()
+            .any(|f| key.ends_with(&format!("_{}", f)))
+        {
+            Value::from(value)
+        } else {
+            value.parse().expect("infallible")
+        };
+        if let Some(camera) = key.strip_prefix("camera_") {
+            let (index, field) = match camera.split_once('_') {
+                Some((index, field)) if !field.is_empty() => (index, field),
+                _ => continue,
+            };
+            let index: usize = match index.parse() {
+                Ok(index) => index,
+                Err(_) => continue,
+            };
+            let cameras = root
+                .entry("camera".to_string())
+                .or_insert_with(|| Value::from(Vec::<Value>::new()));
+            if !matches!(cameras, Value::Array(..)) {
+                *cameras = Value::from(Vec::<Value>::new());
+            }
+            if let Value::Array(_, cameras) = cameras {
+                if cameras.len() <= index {
+                    cameras.resize(index + 1, Value::from(Dict::new()));
+                }
+                insert_field(&mut cameras[index], field, value);
+            }
+        } else if key == "camera" || ENV_SECTIONS.contains(&key.as_str()) {
+            // A whole section, e.g. `HIKSINK_SYSTEM="{log_level=\"DEBUG\"}"`
+            root.insert(key, value);
+        } else if let Some(section) = ENV_SECTIONS
+            .iter()
+            .find(|section| key.starts_with(&format!("{}_", section)))
+        {
+            let field = &key[section.len() + 1..];
+            let table = root
+                .entry(section.to_string())
+                .or_insert_with(|| Value::from(Dict::new()));
+            insert_field(table, field, value);
+        }
+    }
+}
+
+/// Sets a field of a table, replacing the table if it isn't one
+fn insert_field(table: &mut Value, field: &str, value: Value) {
+    if !matches!(table, Value::Dict(..)) {
+        *table = Value::from(Dict::new());
+    }
+    if let Value::Dict(_, dict) = table {
+        dict.insert(field.to_string(), value);
+    }
+}
+
+/// Resolves a credential set either directly, as `<name>_file` (trailing newlines are removed) or as `<name>_env`.
+/// Returns `None` if none of them are set, and an error if more than one is.
+fn resolve_secret(
+    name: &str,
+    value: Option<&str>,
+    file: &Option<PathBuf>,
+    env: &Option<String>,
+) -> Result<Option<String>, String> {
+    let value = value.filter(|v| !v.is_empty());
+    match (value, file, env) {
+        (value, None, None) => Ok(value.map(String::from)),
+        (None, Some(file), None) => std::fs::read_to_string(file)
+            .map(|s| Some(s.trim_end_matches(&['\r', '\n'][..]).to_string()))
+            .map_err(|e| format!("Unable to read {}_file {:?}: {}", name, file, e)),
+        (None, None, Some(env)) => std::env::var(env)
+            .map(Some)
+            .map_err(|e| format!("Unable to read {}_env `{}`: {}", name, env, e)),
+        _ => Err(format!(
+            "Only one of {0}, {0}_file and {0}_env can be set",
+            name
+        )),
+    }
+}
+
+/// Parses a `triggers` entry. Either `<channel>/<event>`, `<event>/<port>` (for I/O ports), or `<event>`.
+pub fn parse_trigger_entry(entry: &str) -> Result<EventIdentifier, String> {
+    let (channel, event) = match entry.split_once('/') {
+        Some((first, second)) if first.chars().all(|c| c.is_ascii_digit()) => (Some(first), second),
+        Some((first, second)) => (Some(second), first),
+        None => (None, entry),
+    };
+    if channel == Some("") {
+        return Err(format!("trigger `{}` has an empty channel", entry));
+    }
+    let event_type =
+        EventType::from_name(event).map_err(|e| format!("trigger `{}`: {}", entry, e))?;
+    Ok(EventIdentifier::new(channel.map(String::from), event_type))
+}
+
+/// Whether a `trigger_names` key refers to the trigger. Events can be given by their camera name (e.g. `vmd`) or the name used in MQTT topics (e.g. `Motion`).
+pub fn trigger_key_matches(key: &str, identifier: &EventIdentifier) -> bool {
+    let (channel, event) = match key.split_once('/') {
+        Some((channel, event)) => (Some(channel), event),
+        None => (None, key),
+    };
+    channel == identifier.channel.as_deref() && event_name_matches(event, &identifier.event_type)
+}
+
+/// Whether the trigger is matched by an `events_include` entry, or there are none, and no `events_exclude` entry
+fn passes_event_filters(
+    include: &[String],
+    exclude: &[String],
+    identifier: &EventIdentifier,
+) -> bool {
+    (include.is_empty() || include.iter().any(|e| event_filter_matches(e, identifier)))
+        && !exclude.iter().any(|e| event_filter_matches(e, identifier))
+}
+
+/// Whether an `events_include`/`events_exclude` entry refers to the trigger. Entries without a channel match all channels.
+pub fn event_filter_matches(entry: &str, identifier: &EventIdentifier) -> bool {
+    match entry.split_once('/') {
+        Some((channel, event)) => {
+            Some(channel) == identifier.channel.as_deref()
+                && event_name_matches(event, &identifier.event_type)
+        }
+        None => event_name_matches(entry, &identifier.event_type),
+    }
+}
+
+/// Finds the `event_types` entry for an event type
+pub fn event_type_override<'a>(
+    event_types: &'a HashMap<String, ConfigEventType>,
+    event_type: &EventType,
+) -> Option<&'a ConfigEventType> {
+    event_types
+        .iter()
+        .find(|(key, _)| event_name_matches(key, event_type))
+        .map(|(_, event)| event)
+}
+
+fn event_name_matches(event: &str, event_type: &EventType) -> bool {
+    event.parse().as_ref() == Ok(event_type) || event_type.to_string().eq_ignore_ascii_case(event)
+}
+
+fn default_snapshot_min_interval_secs() -> u64 {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_capture_max_mb() -> u64 {
+    100
+}
+
+fn default_clock_drift_threshold_secs() -> u64 {
+    60
+}
+
+fn default_log_level() -> String {
+    String::from("info")
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_base_topic() -> String {
+    String::from("hikvision_cameras")
+}
+
+fn default_home_assistant_topic() -> String {
+    String::from("homeassistant")
 }
 
 fn default_client_id() -> String {
     String::from("hik-sink")
 }
 
+fn default_payload_available() -> String {
+    String::from("online")
+}
+
+fn default_payload_not_available() -> String {
+    String::from("offline")
+}
+
+fn default_plain_state_payload_on() -> String {
+    String::from("ON")
+}
+
+fn default_plain_state_payload_off() -> String {
+    String::from("OFF")
+}
+
+fn default_keep_alive_secs() -> u64 {
+    5
+}
+
+fn default_event_channel_capacity() -> usize {
+    20
+}
+
+fn default_offline_buffer_size() -> usize {
+    1000
+}
+
+fn default_parse_failure_max_bytes() -> usize {
+    4096
+}
+
+fn default_webhook_timeout_secs() -> u64 {
+    10
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+fn default_webhook_queue_size() -> usize {
+    100
+}
+
+fn default_influx_flush_interval_secs() -> u64 {
+    10
+}
+
+fn default_influx_timeout_secs() -> u64 {
+    10
+}
+
+fn default_influx_max_points() -> usize {
+    10000
+}
+
+fn default_influx_queue_size() -> usize {
+    100
+}
+
+/// Loads the config from a TOML file, with `HIKSINK_` environment variables overriding it
 pub fn load_config_from_path(path: impl AsRef<Path>) -> Result<Config, String> {
     load_config(figment::providers::Toml::file(path))
 }
 
+/// Loads the config from any figment provider, with `HIKSINK_` environment variables overriding it
 pub fn load_config(data: impl figment::Provider) -> Result<Config, String> {
-    let mut cfg: Config = Figment::new()
-        .merge(figment::providers::Env::prefixed("HIKSINK_"))
-        .merge(data)
+    load_config_with_env(data, std::env::vars())
+}
+
+/// Loads the config, with `HIKSINK_` environment variables overriding the provided data
+fn load_config_with_env(
+    data: impl figment::Provider,
+    env: impl Iterator<Item = (String, String)>,
+) -> Result<Config, String> {
+    let mut root: Dict = Figment::from(data).extract().map_err(|e| e.to_string())?;
+    apply_env_overrides(&mut root, env);
+    let mut cfg: Config = Figment::from(Serialized::defaults(&root))
         .extract()
-        .map_err(|e| e.to_string())?;
-
-    // Generate the camera ids
-    for camera in &mut cfg.camera {
-        // Only lowercase characters and _ allowed
-        camera.generated_id = camera
-            .name
-            .chars()
-            .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '_')
-            .map(|c| {
-                if c == ' ' {
-                    '_'
-                } else {
-                    c.to_ascii_lowercase()
+        .map_err(|e| explain_error(e, &root))?;
+    // Everything the config understood is in it, so any other key was ignored
+    if let Ok(serde_json::Value::Object(known)) = serde_json::to_value(&cfg) {
+        find_unknown_keys(&root, &known, None, &mut cfg.warnings);
+    }
+
+    generate_camera_ids(&mut cfg.camera)?;
+    for cam in &mut cfg.camera {
+        normalize_address(cam)?;
+    }
+    resolve_credentials(&mut cfg)?;
+    validate_config(&cfg)?;
+    Ok(cfg)
+}
+
+/// Adds a warning for each key in a table of the loaded config that isn't in the same table of the parsed config.
+/// `table` names the table for the warnings, `None` for the top level.
+fn find_unknown_keys(
+    loaded: &Dict,
+    known: &serde_json::Map<String, serde_json::Value>,
+    table: Option<&str>,
+    warnings: &mut Vec<String>,
+) {
+    let nested = |key: &str| match table {
+        Some(table) => format!("{}.{}", table, key),
+        None => key.to_string(),
+    };
+    for (key, value) in loaded {
+        match (value, known.get(key)) {
+            (_, None) => {
+                let location = match table {
+                    Some(table) => format!("in [{}]", table),
+                    None => "at the top level".to_string(),
+                };
+                let suggestion = closest_key(key, known.keys())
+                    .map(|k| format!(", did you mean `{}`?", k))
+                    .unwrap_or_else(|| ". It is ignored.".to_string());
+                warnings.push(format!("Unknown key `{}` {}{}", key, location, suggestion));
+            }
+            (Value::Dict(_, loaded), Some(serde_json::Value::Object(known))) => {
+                find_unknown_keys(loaded, known, Some(&nested(key)), warnings);
+            }
+            (Value::Array(_, loaded), Some(serde_json::Value::Array(known))) => {
+                for (i, (loaded, known)) in loaded.iter().zip(known).enumerate() {
+                    if let (Value::Dict(_, loaded), serde_json::Value::Object(known)) =
+                        (loaded, known)
+                    {
+                        // Cameras are easier to find by their name than by their position
+                        let table = match known.get("name") {
+                            Some(serde_json::Value::String(name)) => {
+                                format!("{} \"{}\"", nested(key), name)
+                            }
+                            _ => format!("{} #{}", nested(key), i + 1),
+                        };
+                        find_unknown_keys(loaded, known, Some(&table), warnings);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Describes an error extracting the config. A missing field is often only misspelled, so that's pointed out.
+fn explain_error(error: figment::Error, root: &Dict) -> String {
+    let field = match &error.kind {
+        figment::error::Kind::MissingField(field) => field.to_string(),
+        _ => return error.to_string(),
+    };
+    let mut table = root;
+    let mut parent: Option<&Vec<Value>> = None;
+    for segment in &error.path {
+        // Tables in an array, e.g. `[[camera]]`, are found by their index
+        let next = match parent {
+            Some(array) => segment.parse::<usize>().ok().and_then(|i| array.get(i)),
+            None => table.get(segment),
+        };
+        parent = None;
+        match next {
+            Some(Value::Dict(_, next)) => table = next,
+            Some(Value::Array(_, array)) => parent = Some(array),
+            _ => return error.to_string(),
+        }
+    }
+    match table
+        .keys()
+        .find(|key| closest_key(key, std::iter::once(&field)).is_some())
+    {
+        Some(key) => format!("{}. Found `{}`, did you mean `{}`?", error, key, field),
+        None => error.to_string(),
+    }
+}
+
+/// The valid key closest to a misspelled one, if any is close enough to be what was meant
+fn closest_key<'a>(key: &str, valid: impl Iterator<Item = &'a String>) -> Option<&'a String> {
+    let key = key.to_lowercase().replace('-', "_");
+    valid
+        .map(|v| (edit_distance(&key, v), v))
+        .filter(|(distance, v)| *distance <= (v.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, v)| v)
+}
+
+/// The number of characters to insert, remove or replace to turn one string into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(a != *b);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Config for a camera that isn't in the config file, e.g. one given on the command line
+pub fn adhoc_camera(
+    address: &str,
+    port: Option<u16>,
+    username: &str,
+    password: &str,
+) -> Result<ConfigCamera, String> {
+    let mut camera: ConfigCamera = Figment::from(Serialized::defaults(serde_json::json!({
+        "name": address,
+        "address": address,
+        "port": port,
+        "username": username,
+        "password": password,
+    })))
+    .extract()
+    .map_err(|e| e.to_string())?;
+    camera.id = Some("probe".to_string());
+    generate_camera_ids(std::slice::from_mut(&mut camera))?;
+    normalize_address(&mut camera)?;
+    Ok(camera)
+}
+
+/// Moves a scheme or port written into a camera's address into `scheme` and `port`, leaving only the host,
+/// and rejects addresses that can't be a host
+fn normalize_address(cam: &mut ConfigCamera) -> Result<(), String> {
+    cam.address = split_address(
+        &cam.name,
+        ("address", "port"),
+        &cam.address,
+        &mut cam.port,
+        &mut cam.scheme,
+    )?;
+    if let Some(fallback) = &cam.fallback_address {
+        cam.fallback_address = Some(split_address(
+            &cam.name,
+            ("fallback_address", "fallback_port"),
+            fallback,
+            &mut cam.fallback_port,
+            &mut cam.scheme,
+        )?);
+    } else if cam.fallback_port.is_some() {
+        return Err(format!(
+            "Camera {} has a fallback_port without a fallback_address",
+            cam.name
+        ));
+    }
+    Ok(())
+}
+
+/// Splits an address into the host, and the scheme and port written into it, checking them against the
+/// configured ones. `fields` are the names of the address and port settings, for errors.
+fn split_address(
+    name: &str,
+    fields: (&str, &str),
+    address: &str,
+    port: &mut Option<u16>,
+    scheme: &mut Option<CameraScheme>,
+) -> Result<String, String> {
+    let (field, port_field) = fields;
+    let invalid = |reason: &str| {
+        format!(
+            "Camera {} has an invalid {} `{}`: {}",
+            name, field, address, reason
+        )
+    };
+    let mut host = address.trim();
+    if let Some((prefix, rest)) = host.split_once("://") {
+        let prefix = match prefix.to_ascii_lowercase().as_str() {
+            "http" => CameraScheme::Http,
+            "https" => CameraScheme::Https,
+            _ => return Err(invalid("only http:// and https:// are supported")),
+        };
+        if let Some(configured) = scheme.filter(|s| *s != prefix) {
+            return Err(invalid(&format!(
+                "it starts with {}:// but scheme is set to {}",
+                prefix.as_str(),
+                configured.as_str()
+            )));
+        }
+        *scheme = Some(prefix);
+        host = rest;
+    }
+    // A trailing slash is harmless, anything after it isn't
+    let host = host.strip_suffix('/').unwrap_or(host);
+    if host.is_empty() {
+        return Err(invalid("it needs the camera's hostname or IP address"));
+    }
+    if host.contains(char::is_whitespace) {
+        return Err(invalid("it can't contain spaces"));
+    }
+    if host.contains(['/', '?', '#']) {
+        return Err(invalid(
+            "it can't contain a path, only the hostname or IP address. HikSink adds the /ISAPI paths itself",
+        ));
+    }
+    if host.contains('@') {
+        return Err(invalid(
+            "it can't contain credentials, use username and password instead",
+        ));
+    }
+    let (host, written_port) = match host.strip_prefix('[') {
+        // An IPv6 address, maybe with a port, e.g. `[fe80::1]:8080`
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, rest)) => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(invalid("unexpected text after the IPv6 address")),
+            },
+            None => return Err(invalid("the IPv6 address is missing its closing ]")),
+        },
+        None => match host.split_once(':') {
+            // More than one colon is an IPv6 address without a port
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (host, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(invalid("it needs the camera's hostname or IP address"));
+    }
+    if let Some(written_port) = written_port {
+        let written_port = match written_port.parse::<u16>() {
+            Ok(written_port) if written_port != 0 => written_port,
+            _ => {
+                return Err(invalid(&format!(
+                    "`{}` isn't a valid port, expected a number from 1 to 65535",
+                    written_port
+                )))
+            }
+        };
+        if let Some(configured) = port.filter(|p| *p != written_port) {
+            return Err(invalid(&format!(
+                "it includes port {} but {} is set to {}. Remove one of them",
+                written_port, port_field, configured
+            )));
+        }
+        *port = Some(written_port);
+    }
+    Ok(host.to_string())
+}
+
+/// Sets the ID of each camera, either the configured `id` or one generated from the name
+fn generate_camera_ids(cameras: &mut [ConfigCamera]) -> Result<(), String> {
+    for camera in cameras {
+        camera.generated_id = match &camera.id {
+            Some(id) => {
+                if id.is_empty()
+                    || !id
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+                {
+                    return Err(format!(
+                        "Camera {} has an invalid id `{}`. Only lowercase letters, numbers and _ are allowed",
+                        camera.name, id
+                    ));
                 }
-            })
-            .collect();
+                id.clone()
+            }
+            // Only lowercase characters and _ allowed
+            None => camera
+                .name
+                .chars()
+                .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '_')
+                .map(|c| {
+                    if c == ' ' {
+                        '_'
+                    } else {
+                        c.to_ascii_lowercase()
+                    }
+                })
+                .collect(),
+        };
+    }
+    Ok(())
+}
+
+/// Reads credentials kept outside of the config
+fn resolve_credentials(cfg: &mut Config) -> Result<(), String> {
+    for cam in &mut cfg.camera {
+        let username = resolve_secret(
+            "username",
+            Some(&cam.username),
+            &cam.username_file,
+            &cam.username_env,
+        );
+        let password = resolve_secret(
+            "password",
+            Some(&cam.password),
+            &cam.password_file,
+            &cam.password_env,
+        );
+        match (username, password) {
+            (Ok(Some(username)), Ok(Some(password))) => {
+                cam.username = username;
+                cam.password = password;
+            }
+            (Ok(None), _) => {
+                return Err(format!(
+                    "Camera {} needs one of username, username_file or username_env",
+                    cam.name
+                ))
+            }
+            (_, Ok(None)) => {
+                return Err(format!(
+                    "Camera {} needs one of password, password_file or password_env",
+                    cam.name
+                ))
+            }
+            (Err(e), _) | (_, Err(e)) => return Err(format!("Camera {}: {}", cam.name, e)),
+        }
+    }
+    let mqtt = match &mut cfg.mqtt {
+        Some(mqtt) => mqtt,
+        None => return Ok(()),
+    };
+    mqtt.username = resolve_secret(
+        "username",
+        mqtt.username.as_deref(),
+        &mqtt.username_file,
+        &mqtt.username_env,
+    )
+    .map_err(|e| format!("MQTT: {}", e))?;
+    mqtt.password = resolve_secret(
+        "password",
+        mqtt.password.as_deref(),
+        &mqtt.password_file,
+        &mqtt.password_env,
+    )
+    .map_err(|e| format!("MQTT: {}", e))?;
+    for broker in &mut mqtt.brokers {
+        let error = |e| format!("MQTT broker {}: {}", broker.address, e);
+        let username = resolve_secret(
+            "username",
+            broker.username.as_deref(),
+            &broker.username_file,
+            &broker.username_env,
+        )
+        .map_err(error)?;
+        let password = resolve_secret(
+            "password",
+            broker.password.as_deref(),
+            &broker.password_file,
+            &broker.password_env,
+        )
+        .map_err(error)?;
+        broker.username = username;
+        broker.password = password;
+    }
+
+    Ok(())
+}
+
+/// Checks the topic templates have the placeholders that keep every camera and trigger on its own topic
+fn validate_topic_templates(mqtt: &ConfigMqtt, cameras: &[ConfigCamera]) -> Result<(), String> {
+    let templates = [
+        (
+            "camera_topic",
+            &mqtt.camera_topic,
+            &["camera_id"][..],
+            &["base", "camera_id"][..],
+        ),
+        (
+            "trigger_topic",
+            &mqtt.trigger_topic,
+            &["camera", "channel", "event_type"][..],
+            TOPIC_PLACEHOLDERS,
+        ),
+    ];
+    for (name, template, required, allowed) in templates {
+        if let Some(missing) = required.iter().find(|p| !template.uses(p)) {
+            return Err(format!(
+                "{} `{}` is missing the {{{}}} placeholder",
+                name, template, missing
+            ));
+        }
+        if let Some(other) = TOPIC_PLACEHOLDERS
+            .iter()
+            .find(|p| template.uses(p) && !allowed.contains(p))
+        {
+            return Err(format!(
+                "{} `{}` can't use {{{}}}, only {{{}}}",
+                name,
+                template,
+                other,
+                allowed.join("}, {")
+            ));
+        }
+    }
+    // With their own base topics, cameras could still end up on the same topic, or under another camera's
+    let topics: Vec<(String, &ConfigCamera)> = cameras
+        .iter()
+        .map(|cam| {
+            let base = cam.base_topic.as_deref().unwrap_or(&mqtt.base_topic);
+            let topic = mqtt
+                .camera_topic
+                .render(&[("base", base), ("camera_id", cam.identifier())]);
+            (topic, cam)
+        })
+        .collect();
+    for (i, (topic, cam)) in topics.iter().enumerate() {
+        for (other_topic, other) in &topics[i + 1..] {
+            let clash = topic == other_topic
+                || topic.starts_with(&format!("{}/", other_topic))
+                || other_topic.starts_with(&format!("{}/", topic));
+            if clash {
+                return Err(format!(
+                    "Cameras {} and {} would publish to overlapping topics `{}` and `{}`. Change the base_topic of one of them.",
+                    cam.name, other.name, topic, other_topic
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the config for anything that would only fail once HikSink is running
+pub fn validate_config(cfg: &Config) -> Result<(), String> {
+    if cfg.sink.kind == SinkType::Mqtt && cfg.mqtt.is_none() {
+        return Err("The [mqtt] section is required when the sink is MQTT".to_string());
+    }
+    tracing_subscriber::EnvFilter::try_new(&cfg.system.log_level)
+        .map_err(|e| format!("log_level `{}` is invalid: {}", cfg.system.log_level, e))?;
+    validate_camera_ids(&cfg.camera)?;
+    validate_duplicates(&cfg.camera)?;
+    validate_event_types(&cfg.event_types)?;
+    validate_debounce(&cfg.system.debounce).map_err(|e| format!("System has {}", e))?;
+    if let Some(mqtt) = &cfg.mqtt {
+        validate_topic_templates(mqtt, &cfg.camera)?;
+    }
+    if cfg.system.max_concurrent_connects == Some(0) {
+        return Err(
+            "max_concurrent_connects is set to 0, so no camera could connect. Remove it to allow any number."
+                .to_string(),
+        );
+    }
+    if cfg.system.stale_after_secs == Some(0) {
+        return Err(
+            "stale_after_secs is set to 0. Remove it to stop watching for stale events."
+                .to_string(),
+        );
     }
-    // Check that IDs are unique
-    let mut ids = HashSet::new();
     for cam in &cfg.camera {
-        let id: &str = cam.generated_id.as_ref();
-        if ids.contains(&id) {
+        validate_camera(cam)?;
+    }
+    if let Some(webhook) = &cfg.webhook {
+        validate_webhook(webhook)?;
+    }
+    if let Some(influx) = &cfg.influx {
+        validate_influx(influx)?;
+    }
+    if cfg.listener.is_none() {
+        if let Some(cam) = cfg.camera.iter().find(|c| c.mode == CameraMode::Push) {
             return Err(format!(
-                "Camera {} has duplicate ID: {}",
+                "Camera {} is in push mode, which needs a [listener] section",
+                cam.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that camera IDs are unique
+fn validate_camera_ids(cameras: &[ConfigCamera]) -> Result<(), String> {
+    let mut ids: HashMap<&str, &ConfigCamera> = HashMap::new();
+    for cam in cameras {
+        if let Some(other) = ids.insert(cam.identifier(), cam) {
+            let hint = if cam.id.is_none() && other.id.is_none() {
+                "Give one of them a unique `id`"
+            } else {
+                // Probably an `id` was added to keep the topics of a renamed camera, which now clashes with the ID generated for another
+                "Give the other camera its own `id`, or pick a different one"
+            };
+            return Err(format!(
+                "Camera {} has duplicate ID: {} (also used by camera {}). {}",
                 cam.name,
-                cam.identifier()
+                cam.identifier(),
+                other.name,
+                hint
             ));
         }
-        ids.insert(id);
     }
-    Ok(cfg)
+    Ok(())
+}
+
+/// Checks that `duplicates_of` names another camera
+fn validate_duplicates(cameras: &[ConfigCamera]) -> Result<(), String> {
+    for cam in cameras {
+        for (channel, id) in &cam.duplicates_of {
+            if id == cam.identifier() || !cameras.iter().any(|c| c.identifier() == id) {
+                return Err(format!(
+                    "Camera {} has duplicates_of channel {} set to `{}`, which is not the ID of another camera",
+                    cam.name, channel, id
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that event type overrides are usable in home assistant
+fn validate_event_types(event_types: &HashMap<String, ConfigEventType>) -> Result<(), String> {
+    for (key, event) in event_types {
+        if let Err(e) = EventType::from_name(key) {
+            return Err(format!("Event type `{}` is invalid: {}", key, e));
+        }
+        if let Some(device_class) = &event.device_class {
+            if !BINARY_SENSOR_DEVICE_CLASSES.contains(&device_class.as_str()) {
+                return Err(format!(
+                    "Event type `{}` has an unknown device_class `{}`. Expected one of: {}",
+                    key,
+                    device_class,
+                    BINARY_SENSOR_DEVICE_CLASSES.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that event filters and manual triggers are usable
+fn validate_camera(cam: &ConfigCamera) -> Result<(), String> {
+    for entry in &cam.triggers {
+        parse_trigger_entry(entry)
+            .map_err(|e| format!("Camera {} has an invalid {}", cam.name, e))?;
+    }
+    validate_event_filters(cam.events_include.iter().chain(&cam.events_exclude))
+        .map_err(|e| format!("Camera {} has {}", cam.name, e))?;
+    validate_debounce(&cam.debounce).map_err(|e| format!("Camera {} has {}", cam.name, e))?;
+    if let Some(level) = &cam.log_level {
+        level
+            .parse::<tracing_subscriber::filter::LevelFilter>()
+            .map_err(|_| {
+                format!(
+                    "Camera {} has an invalid log_level `{}`. Expected one of trace, debug, info, warn, error or off.",
+                    cam.name, level
+                )
+            })?;
+    }
+    if cam.trigger_rescan_secs == Some(0) {
+        return Err(format!(
+            "Camera {} has trigger_rescan_secs set to 0. Remove it to disable re-scanning.",
+            cam.name
+        ));
+    }
+    if cam.info_refresh_secs == Some(0) {
+        return Err(format!(
+            "Camera {} has info_refresh_secs set to 0. Remove it to only read the device info when connecting.",
+            cam.name
+        ));
+    }
+    if cam.clock_check_secs == Some(0) {
+        return Err(format!(
+            "Camera {} has clock_check_secs set to 0. Remove it to only check the clock when connecting.",
+            cam.name
+        ));
+    }
+    if cam.stale_after_secs == Some(0) {
+        return Err(format!(
+            "Camera {} has stale_after_secs set to 0. Remove it to use the system setting.",
+            cam.name
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that debounce settings are keyed by an event type
+fn validate_debounce(debounce: &HashMap<String, ConfigDebounce>) -> Result<(), String> {
+    for key in debounce.keys() {
+        if let Err(e) = EventType::from_name(key) {
+            return Err(format!(
+                "debounce settings for an invalid event type `{}`: {}",
+                key, e
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `events_include`/`events_exclude` entries name an event, and a channel if qualified
+fn validate_event_filters<'a>(entries: impl Iterator<Item = &'a String>) -> Result<(), String> {
+    for entry in entries {
+        let (channel, event) = entry.split_once('/').unwrap_or(("1", entry));
+        if channel.trim().is_empty() || event.trim().is_empty() {
+            return Err(format!("an invalid event filter: `{}`", entry));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the webhook can be sent
+fn validate_webhook(webhook: &ConfigWebhook) -> Result<(), String> {
+    reqwest::Url::parse(&webhook.url)
+        .map_err(|e| format!("Webhook has an invalid url `{}`: {}", webhook.url, e))?;
+    for (name, value) in &webhook.headers {
+        reqwest::header::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Webhook has an invalid header name: `{}`", name))?;
+        reqwest::header::HeaderValue::from_str(value)
+            .map_err(|_| format!("Webhook has an invalid value for header `{}`", name))?;
+    }
+    validate_event_filters(webhook.events_include.iter().chain(&webhook.events_exclude))
+        .map_err(|e| format!("Webhook has {}", e))?;
+    if webhook.queue_size == 0 {
+        return Err("Webhook queue_size must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+/// Checks that the InfluxDB writes have somewhere to go
+fn validate_influx(influx: &ConfigInflux) -> Result<(), String> {
+    reqwest::Url::parse(&influx.url)
+        .map_err(|e| format!("Influx has an invalid url `{}`: {}", influx.url, e))?;
+    match (&influx.org, &influx.bucket, &influx.database) {
+        (Some(_), Some(_), None) | (None, None, Some(_)) => {}
+        (_, _, Some(_)) => return Err(
+            "Influx needs either org and bucket (InfluxDB 2) or database (InfluxDB 1), not both"
+                .to_string(),
+        ),
+        _ => {
+            return Err(
+                "Influx needs org and bucket (InfluxDB 2) or database (InfluxDB 1)".to_string(),
+            )
+        }
+    }
+    if influx.flush_interval_secs == 0 {
+        return Err("Influx flush_interval_secs must be at least 1".to_string());
+    }
+    if influx.queue_size == 0 || influx.max_points == 0 {
+        return Err("Influx queue_size and max_points must be at least 1".to_string());
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use figment::providers::Format;
 
+    use super::{event_filter_matches, parse_trigger_entry, trigger_key_matches};
+    use crate::hikapi::{EventIdentifier, EventType};
+
+    #[test]
+    fn test_trigger_key_matches() {
+        let line = EventIdentifier::new(Some("1".into()), EventType::LineDetection);
+        assert!(trigger_key_matches("1/linedetection", &line));
+        assert!(trigger_key_matches("1/LineDetection", &line));
+        assert!(!trigger_key_matches("2/linedetection", &line));
+        assert!(!trigger_key_matches("linedetection", &line));
+
+        let motion = EventIdentifier::new(Some("1".into()), EventType::Motion);
+        assert!(trigger_key_matches("1/vmd", &motion));
+        assert!(trigger_key_matches("1/motion", &motion));
+
+        let io = EventIdentifier::new(None, EventType::Io);
+        assert!(trigger_key_matches("io", &io));
+        assert!(!trigger_key_matches("1/io", &io));
+    }
+
     #[test]
     fn test_sample_config_valid() {
         const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
@@ -102,4 +1945,901 @@ mod test {
             SAMPLE_CONFIG
         )));
     }
+
+    #[test]
+    fn test_event_filter_matches() {
+        let motion = EventIdentifier::new(Some("1".into()), EventType::Motion);
+        assert!(event_filter_matches("Motion", &motion));
+        assert!(event_filter_matches("motion", &motion));
+        assert!(event_filter_matches("VMD", &motion));
+        assert!(event_filter_matches("1/Motion", &motion));
+        assert!(!event_filter_matches("2/Motion", &motion));
+        assert!(!event_filter_matches("LineDetection", &motion));
+    }
+
+    #[test]
+    fn test_parse_trigger_entry() {
+        assert_eq!(
+            parse_trigger_entry("1/Motion"),
+            Ok(EventIdentifier::new(Some("1".into()), EventType::Motion))
+        );
+        assert_eq!(
+            parse_trigger_entry("1/linedetection"),
+            Ok(EventIdentifier::new(
+                Some("1".into()),
+                EventType::LineDetection
+            ))
+        );
+        assert_eq!(
+            parse_trigger_entry("Io/1"),
+            Ok(EventIdentifier::new(Some("1".into()), EventType::Io))
+        );
+        assert_eq!(
+            parse_trigger_entry("diskfull"),
+            Ok(EventIdentifier::new(None, EventType::DiskFull))
+        );
+        assert!(parse_trigger_entry("1/").is_err());
+        assert!(parse_trigger_entry("Io/").is_err());
+        assert!(parse_trigger_entry("1/Line Detection").is_err());
+    }
+
+    #[test]
+    fn test_invalid_event_filter() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        for filter in ["", "1/", "/Motion"] {
+            let config = SAMPLE_CONFIG.replace(
+                "password = \"camera_password\"",
+                &format!(
+                    "password = \"camera_password\"\nevents_exclude = [\"{}\"]",
+                    filter
+                ),
+            );
+            let error = super::load_config(figment::providers::Toml::string(&config)).unwrap_err();
+            assert!(error.contains("invalid event filter"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_camera_ids() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let camera = |name: &str, id: Option<&str>| {
+            let id = id
+                .map(|id| format!("id = \"{}\"\n", id))
+                .unwrap_or_default();
+            format!(
+                "\n[[camera]]\n{}name = \"{}\"\naddress = \"192.168.1.201\"\nusername = \"admin\"\npassword = \"pass\"\n",
+                id, name
+            )
+        };
+        let load = |cameras: &[String]| {
+            super::load_config(figment::providers::Toml::string(&format!(
+                "{}{}",
+                SAMPLE_CONFIG,
+                cameras.concat()
+            )))
+        };
+
+        // Explicit ids are used as is, so renaming the camera keeps its topics
+        let config = load(&[camera("Front Door Cam", Some("front_door"))]).unwrap();
+        assert_eq!(config.camera[0].identifier(), "front_porch");
+        assert_eq!(config.camera[1].identifier(), "front_door");
+
+        for invalid in ["", "Front_Door", "front door", "front-door"] {
+            let error = load(&[camera("Front Door", Some(invalid))]).unwrap_err();
+            assert!(error.contains("invalid id"), "{}", error);
+        }
+
+        let error = load(&[camera("Front Porch", None)]).unwrap_err();
+        assert!(error.contains("duplicate ID: front_porch"), "{}", error);
+
+        let mut cameras = config.camera.clone();
+        cameras[0].duplicates_of = [("1".to_string(), "front_porch".to_string())].into();
+        let error = super::validate_duplicates(&cameras).unwrap_err();
+        assert!(error.contains("not the ID of another camera"), "{}", error);
+        let mut nvr = config.camera[0].clone();
+        nvr.generated_id = "nvr".to_string();
+        nvr.duplicates_of = [("4".to_string(), "front_porch".to_string())].into();
+        cameras = vec![config.camera[0].clone(), nvr];
+        assert_eq!(super::validate_duplicates(&cameras), Ok(()));
+        // An explicit id clashing with a generated one
+        let error = load(&[camera("Back Porch", Some("front_porch"))]).unwrap_err();
+        assert!(
+            error.contains("duplicate ID: front_porch (also used by camera Front Porch)"),
+            "{}",
+            error
+        );
+        let error =
+            load(&[camera("Side", Some("side")), camera("Garage", Some("side"))]).unwrap_err();
+        assert!(error.contains("duplicate ID: side"), "{}", error);
+    }
+
+    #[test]
+    fn test_credentials_from_file_and_env() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let dir = std::env::temp_dir().join(format!("hiksink_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let password_file = dir.join("camera_password");
+        std::fs::write(&password_file, "from_file\n").unwrap();
+        std::env::set_var("HIK_SINK_TEST_CAMERA_USERNAME", "from_env");
+        std::env::set_var("HIK_SINK_TEST_MQTT_PASSWORD", "mqtt_from_env");
+        let load = |camera: &str, mqtt: &str| {
+            let config = SAMPLE_CONFIG
+                .replace(
+                    "username = \"steven\"\npassword = \"camera_password\"",
+                    camera,
+                )
+                .replace("password = \"pass\"", mqtt);
+            super::load_config(figment::providers::Toml::string(&config))
+        };
+
+        let config = load(
+            &format!(
+                "username_env = \"HIK_SINK_TEST_CAMERA_USERNAME\"\npassword_file = {:?}",
+                password_file
+            ),
+            "password_env = \"HIK_SINK_TEST_MQTT_PASSWORD\"",
+        )
+        .unwrap();
+        assert_eq!(config.camera[0].username, "from_env");
+        // Trailing newlines are removed
+        assert_eq!(config.camera[0].password, "from_file");
+        assert_eq!(
+            config.mqtt.as_ref().unwrap().password.as_deref(),
+            Some("mqtt_from_env")
+        );
+
+        // Only one way of setting each credential is allowed
+        let error = load(
+            &format!(
+                "username = \"steven\"\npassword = \"camera_password\"\npassword_file = {:?}",
+                password_file
+            ),
+            "password = \"pass\"",
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "Camera Front Porch: Only one of password, password_file and password_env can be set"
+        );
+        let error = load("username = \"steven\"", "password = \"pass\"").unwrap_err();
+        assert!(
+            error.contains("Camera Front Porch needs one of password"),
+            "{}",
+            error
+        );
+
+        let error = load(
+            "username = \"steven\"\npassword_file = \"/does/not/exist\"",
+            "password = \"pass\"",
+        )
+        .unwrap_err();
+        assert!(error.contains("Unable to read password_file"), "{}", error);
+        let error = load(
+            "username = \"steven\"\npassword = \"camera_password\"",
+            "password_env = \"HIK_SINK_TEST_NOT_SET\"",
+        )
+        .unwrap_err();
+        assert!(
+            error.starts_with("MQTT: Unable to read password_env"),
+            "{}",
+            error
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_only_config() {
+        let env = [
+            ("HIKSINK_SYSTEM_LOG_LEVEL", "DEBUG"),
+            ("HIKSINK_MQTT_ADDRESS", "broker"),
+            ("HIKSINK_MQTT_PORT", "1883"),
+            ("HIKSINK_MQTT_BASE_TOPIC", "cameras"),
+            ("HIKSINK_MQTT_HOME_ASSISTANT_TOPIC", "homeassistant"),
+            ("HIKSINK_MQTT_QOS", "{state = 0}"),
+            ("HIKSINK_CAMERA_1_NAME", "Garage"),
+            ("HIKSINK_CAMERA_1_ADDRESS", "192.168.1.201"),
+            ("HIKSINK_CAMERA_1_USERNAME", "admin"),
+            ("HIKSINK_CAMERA_1_PASSWORD", "1234"),
+            ("HIKSINK_CAMERA_0_NAME", "Front Porch"),
+            ("HIKSINK_CAMERA_0_ADDRESS", "192.168.1.200"),
+            ("HIKSINK_CAMERA_0_PORT", "8000"),
+            ("HIKSINK_CAMERA_0_USERNAME", "admin"),
+            ("HIKSINK_CAMERA_0_PASSWORD", "secret"),
+            ("HIKSINK_CAMERA_0_EVENTS_EXCLUDE", "[\"VideoLoss\"]"),
+            ("HIKSINK_CONFIG", "/app/config.toml"),
+            ("PATH", "/usr/bin"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let config =
+            super::load_config_with_env(figment::providers::Toml::string(""), env).unwrap();
+        assert_eq!(config.system.log_level, "DEBUG");
+        assert_eq!(config.mqtt.as_ref().unwrap().address, "broker");
+        assert_eq!(config.mqtt.as_ref().unwrap().port, 1883);
+        assert_eq!(config.mqtt.as_ref().unwrap().base_topic, "cameras");
+        assert_eq!(config.mqtt.as_ref().unwrap().qos.state, super::QoSLevel(0));
+        assert_eq!(config.camera.len(), 2);
+        assert_eq!(config.camera[0].identifier(), "front_porch");
+        assert_eq!(config.camera[0].port, Some(8000));
+        assert_eq!(
+            config.camera[0].events_exclude,
+            vec!["VideoLoss".to_string()]
+        );
+        assert_eq!(config.camera[1].name, "Garage");
+        // Numbers can still be used as strings
+        assert_eq!(config.camera[1].password, "1234");
+    }
+
+    #[test]
+    fn test_topic_templates() {
+        use super::TopicTemplate;
+        let template = |t: &str| TopicTemplate::try_from(t.to_string());
+        let trigger = template("site/{camera}/{channel}/{event_type}").unwrap();
+        assert_eq!(
+            trigger.render(&[
+                ("camera", "cams/front"),
+                ("channel", "ch1"),
+                ("event_type", "Motion")
+            ]),
+            "site/cams/front/ch1/Motion"
+        );
+        // Levels left empty are dropped
+        assert_eq!(
+            trigger.render(&[("camera", "cams/front"), ("event_type", "Io")]),
+            "site/cams/front/Io"
+        );
+        let camera = template("{base}/cam_{camera_id}/x").unwrap();
+        assert_eq!(
+            camera.render_wildcard("camera_id", &[("base", "hik")]),
+            "hik/+/x"
+        );
+        assert_eq!(camera.to_string(), "{base}/cam_{camera_id}/x");
+
+        assert!(template("").is_err());
+        assert!(template("{base}/+/{camera_id}").is_err());
+        assert!(template("{base}/{camera_id").is_err());
+        assert!(template("{base}/camera_id}").is_err());
+        let error = template("{base}/{camera}/{id}").unwrap_err();
+        assert!(error.contains("unknown placeholder `{id}`"), "{}", error);
+
+        // Invalid templates are rejected when loading
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let config = |extra: &str| {
+            super::load_config(figment::providers::Toml::string(
+                &SAMPLE_CONFIG.replace("[mqtt]\n", &format!("[mqtt]\n{}\n", extra)),
+            ))
+        };
+        let error = config("camera_topic = \"{base}/{name}\"").unwrap_err();
+        assert!(error.contains("unknown placeholder `{name}`"), "{}", error);
+
+        let mut cfg = config("camera_topic = \"site/cameras/{camera_id}\"").unwrap();
+        super::validate_config(&cfg).unwrap();
+        cfg.mqtt.as_mut().unwrap().camera_topic = template("{base}/cameras").unwrap();
+        let error = super::validate_config(&cfg).unwrap_err();
+        assert!(
+            error.contains("missing the {camera_id} placeholder"),
+            "{}",
+            error
+        );
+        cfg.mqtt.as_mut().unwrap().camera_topic = template("{base}/{camera_id}/{channel}").unwrap();
+        let error = super::validate_config(&cfg).unwrap_err();
+        assert!(error.contains("can't use {channel}"), "{}", error);
+        cfg.mqtt.as_mut().unwrap().camera_topic = TopicTemplate::camera_default();
+        cfg.mqtt.as_mut().unwrap().trigger_topic = template("{camera}/{event_type}").unwrap();
+        let error = super::validate_config(&cfg).unwrap_err();
+        assert!(
+            error.contains("missing the {channel} placeholder"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_camera_base_topic() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let mut config =
+            super::load_config(figment::providers::Toml::string(SAMPLE_CONFIG)).unwrap();
+        let mut other = config.camera[0].clone();
+        other.generated_id = "garage".to_string();
+        other.base_topic = Some("shared/cameras".to_string());
+        config.camera.push(other);
+        super::validate_config(&config).unwrap();
+
+        // Nested under another camera's topic
+        config.camera[1].base_topic = Some("hikvision_cameras/device_front_porch".to_string());
+        let error = super::validate_config(&config).unwrap_err();
+        assert!(
+            error.contains("overlapping topics `hikvision_cameras/device_front_porch` and `hikvision_cameras/device_front_porch/device_garage`"),
+            "{}",
+            error
+        );
+
+        // The same topic from different base topics and IDs
+        config.mqtt.as_mut().unwrap().camera_topic =
+            super::TopicTemplate::try_from("{base}_{camera_id}".to_string()).unwrap();
+        config.camera[1].base_topic = Some("hikvision_cameras_front".to_string());
+        config.camera[1].generated_id = "porch".to_string();
+        let error = super::validate_config(&config).unwrap_err();
+        assert!(
+            error.contains("overlapping topics `hikvision_cameras_front_porch` and `hikvision_cameras_front_porch`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_minimal_config() {
+        let config = super::load_config_with_env(
+            figment::providers::Toml::string(indoc::indoc! {r#"
+                mqtt = { address = "broker" }
+                [[camera]]
+                name = "Garage"
+                address = "192.168.1.201"
+                username = "admin"
+                password = "pass"
+            "#}),
+            std::iter::empty(),
+        )
+        .unwrap();
+        assert_eq!(config.warnings, Vec::<String>::new());
+        assert_eq!(config.system, super::ConfigSystem::default());
+        assert_eq!(config.system.log_level, "info");
+        let mqtt = config.mqtt.as_ref().unwrap();
+        assert_eq!(mqtt.address, "broker");
+        assert_eq!(mqtt.port, 1883);
+        assert_eq!(mqtt.username, None);
+        assert_eq!(mqtt.password, None);
+        assert_eq!(mqtt.base_topic, "hikvision_cameras");
+        assert_eq!(mqtt.home_assistant_topic, "homeassistant");
+        assert_eq!(config.camera[0].identifier(), "garage");
+        super::validate_config(&config).unwrap();
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let env = [
+            ("HIKSINK_CAMERA_0_PASSWORD", "from_env"),
+            ("HIKSINK_MQTT_BASE_TOPIC", "from_env"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let config =
+            super::load_config_with_env(figment::providers::Toml::string(SAMPLE_CONFIG), env)
+                .unwrap();
+        assert_eq!(config.mqtt.as_ref().unwrap().base_topic, "from_env");
+        assert_eq!(config.mqtt.as_ref().unwrap().address, "localhost");
+        assert_eq!(config.camera.len(), 1);
+        assert_eq!(config.camera[0].password, "from_env");
+        assert_eq!(config.camera[0].name, "Front Porch");
+    }
+
+    #[test]
+    fn test_invalid_qos() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let config = SAMPLE_CONFIG.replace(
+            "home_assistant_topic = \"homeassistant\"",
+            "home_assistant_topic = \"homeassistant\"\nqos = { state = 3 }",
+        );
+        let error = super::load_config(figment::providers::Toml::string(&config)).unwrap_err();
+        assert!(error.contains("invalid QoS level 3"), "{}", error);
+
+        let config = SAMPLE_CONFIG.replace(
+            "home_assistant_topic = \"homeassistant\"",
+            "home_assistant_topic = \"homeassistant\"\nqos = { state = 0 }",
+        );
+        let config = super::load_config(figment::providers::Toml::string(&config)).unwrap();
+        assert_eq!(config.mqtt.as_ref().unwrap().qos.state, super::QoSLevel(0));
+        assert_eq!(
+            config.mqtt.as_ref().unwrap().qos.discovery,
+            super::QoSLevel(1)
+        );
+    }
+
+    #[test]
+    fn test_event_type_overrides() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let config = format!(
+            "{}\n[event_types.IO]\nname = \"Doorbell\"\n[event_types.mixedTargetDetection]\ndevice_class = \"occupancy\"\n",
+            SAMPLE_CONFIG
+        );
+        let config = super::load_config(figment::providers::Toml::string(&config)).unwrap();
+        let io = super::event_type_override(&config.event_types, &EventType::Io).unwrap();
+        assert_eq!(io.name.as_deref(), Some("Doorbell"));
+        let unknown = EventType::Unknown("mixedtargetdetection".into());
+        let unknown = super::event_type_override(&config.event_types, &unknown).unwrap();
+        assert_eq!(unknown.device_class.as_deref(), Some("occupancy"));
+        assert_eq!(
+            super::event_type_override(&config.event_types, &EventType::Motion),
+            None
+        );
+
+        let config = format!(
+            "{}\n[event_types.IO]\ndevice_class = \"doorbell\"\n",
+            SAMPLE_CONFIG
+        );
+        let error = super::load_config(figment::providers::Toml::string(&config)).unwrap_err();
+        assert!(
+            error.contains("Event type `IO` has an unknown device_class `doorbell`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_validate_config() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let config = super::load_config(figment::providers::Toml::string(SAMPLE_CONFIG)).unwrap();
+        assert_eq!(super::validate_config(&config), Ok(()));
+
+        // MQTT is only needed when publishing to it
+        let mut stdout = config.clone();
+        stdout.mqtt = None;
+        assert!(super::validate_config(&stdout).is_err());
+        stdout.sink.kind = super::SinkType::Stdout;
+        assert_eq!(super::validate_config(&stdout), Ok(()));
+
+        let mut push = config.clone();
+        push.camera[0].mode = super::CameraMode::Push;
+        let error = super::validate_config(&push).unwrap_err();
+        assert!(error.contains("needs a [listener] section"), "{}", error);
+        push.listener = Some(super::ConfigListener {
+            bind: "0.0.0.0:8123".parse().unwrap(),
+        });
+        assert_eq!(super::validate_config(&push), Ok(()));
+
+        let mut no_connects = config.clone();
+        no_connects.system.max_concurrent_connects = Some(0);
+        let error = super::validate_config(&no_connects).unwrap_err();
+        assert!(
+            error.contains("max_concurrent_connects is set to 0"),
+            "{}",
+            error
+        );
+
+        let mut webhook = super::ConfigWebhook {
+            url: "http://localhost:1880/hiksink".to_string(),
+            headers: [("Authorization".to_string(), "Bearer token".to_string())].into(),
+            events_include: vec!["motion".to_string()],
+            events_exclude: vec![],
+            timeout_secs: 10,
+            retries: 3,
+            retry_backoff: super::ConfigBackoff::default(),
+            queue_size: 100,
+        };
+        assert_eq!(super::validate_webhook(&webhook), Ok(()));
+        webhook
+            .headers
+            .insert("Bad Header".to_string(), String::new());
+        let error = super::validate_webhook(&webhook).unwrap_err();
+        assert!(error.contains("invalid header name"), "{}", error);
+        webhook.headers.clear();
+        webhook.url = "localhost".to_string();
+        let error = super::validate_webhook(&webhook).unwrap_err();
+        assert!(error.contains("invalid url"), "{}", error);
+
+        let mut influx = super::ConfigInflux {
+            url: "http://localhost:8086".to_string(),
+            token: Some("token".to_string()),
+            org: Some("home".to_string()),
+            bucket: Some("cameras".to_string()),
+            database: None,
+            flush_interval_secs: 10,
+            timeout_secs: 10,
+            max_points: 10000,
+            queue_size: 100,
+        };
+        assert_eq!(super::validate_influx(&influx), Ok(()));
+        influx.database = Some("cameras".to_string());
+        let error = super::validate_influx(&influx).unwrap_err();
+        assert!(error.contains("not both"), "{}", error);
+        influx.org = None;
+        influx.bucket = None;
+        assert_eq!(super::validate_influx(&influx), Ok(()));
+        influx.database = None;
+        let error = super::validate_influx(&influx).unwrap_err();
+        assert!(error.contains("needs org and bucket"), "{}", error);
+
+        let mut cameras = config.camera.clone();
+        cameras.push(cameras[0].clone());
+        let error = super::validate_camera_ids(&cameras).unwrap_err();
+        assert!(error.contains("duplicate ID: front_porch"), "{}", error);
+
+        let mut event_types = std::collections::HashMap::new();
+        event_types.insert(
+            "motion".to_string(),
+            super::ConfigEventType {
+                name: None,
+                device_class: Some("nonsense".to_string()),
+                icon: None,
+                ha_entity: None,
+            },
+        );
+        let error = super::validate_event_types(&event_types).unwrap_err();
+        assert!(
+            error.contains("unknown device_class `nonsense`"),
+            "{}",
+            error
+        );
+
+        let mut camera = config.camera[0].clone();
+        camera.events_exclude = vec!["1/".to_string()];
+        let error = super::validate_camera(&camera).unwrap_err();
+        assert!(error.contains("invalid event filter"), "{}", error);
+        camera.events_exclude.clear();
+        camera.trigger_rescan_secs = Some(0);
+        let error = super::validate_camera(&camera).unwrap_err();
+        assert!(error.contains("trigger_rescan_secs set to 0"), "{}", error);
+        camera.trigger_rescan_secs = None;
+        camera.stale_after_secs = Some(0);
+        let error = super::validate_camera(&camera).unwrap_err();
+        assert!(error.contains("stale_after_secs set to 0"), "{}", error);
+        camera.stale_after_secs = None;
+        camera.debounce.insert(
+            "FieldDetection".to_string(),
+            super::ConfigDebounce::default(),
+        );
+        assert_eq!(super::validate_camera(&camera), Ok(()));
+        camera
+            .debounce
+            .insert(String::new(), super::ConfigDebounce::default());
+        let error = super::validate_camera(&camera).unwrap_err();
+        assert!(
+            error.contains("debounce settings for an invalid event type"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_log_directives() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let mut config =
+            super::load_config(figment::providers::Toml::string(SAMPLE_CONFIG)).unwrap();
+        assert_eq!(config.log_directives(), "INFO");
+
+        // Full filter directives are accepted
+        config.system.log_level = "info,hik_sink=debug,rumqttc=warn".to_string();
+        assert_eq!(super::validate_config(&config), Ok(()));
+        config.system.log_level = "hik_sink=loud".to_string();
+        let error = super::validate_config(&config).unwrap_err();
+        assert!(
+            error.contains("log_level `hik_sink=loud` is invalid"),
+            "{}",
+            error
+        );
+        config.system.log_level = "warn".to_string();
+
+        config.camera[0].log_level = Some("loud".to_string());
+        let error = super::validate_config(&config).unwrap_err();
+        assert!(error.contains("invalid log_level `loud`"), "{}", error);
+        config.camera[0].log_level = Some("trace".to_string());
+        assert_eq!(super::validate_config(&config), Ok(()));
+        assert_eq!(config.log_directives(), "warn,[{id=front_porch}]=trace");
+
+        // Only the camera's span gets the override
+        #[derive(Clone, Default)]
+        struct Output(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for Output {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::new(config.log_directives()))
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("Camera coms", camera = %"Front Porch", id = %"front_porch")
+                .in_scope(|| tracing::trace!("front porch payload"));
+            tracing::info_span!("Camera coms", camera = %"Garage", id = %"garage")
+                .in_scope(|| tracing::trace!("garage payload"));
+            tracing::trace!("bridge detail");
+            tracing::warn!("bridge warning");
+        });
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("front porch payload"), "{}", output);
+        assert!(!output.contains("garage payload"), "{}", output);
+        assert!(!output.contains("bridge detail"), "{}", output);
+        assert!(output.contains("bridge warning"), "{}", output);
+    }
+
+    #[test]
+    fn test_unresolvable_secret_file() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let config = SAMPLE_CONFIG.replace(
+            "password = \"camera_password\"",
+            "password_file = \"/nonexistent/hiksink_password\"",
+        );
+        let error = super::load_config(figment::providers::Toml::string(&config)).unwrap_err();
+        assert!(error.contains("/nonexistent/hiksink_password"), "{}", error);
+    }
+
+    #[test]
+    fn test_redacted() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let config = super::load_config(figment::providers::Toml::string(SAMPLE_CONFIG)).unwrap();
+        let redacted = config.redacted();
+        assert!(redacted
+            .camera
+            .iter()
+            .all(|cam| cam.password == super::REDACTED));
+        assert_eq!(
+            redacted.mqtt.unwrap().password,
+            config
+                .mqtt
+                .clone()
+                .unwrap()
+                .password
+                .map(|_| super::REDACTED.to_string())
+        );
+        assert_eq!(redacted.camera[0].username, config.camera[0].username);
+    }
+
+    #[test]
+    fn test_mqtt_brokers() {
+        let config = super::load_config(figment::providers::Toml::string(indoc::indoc! {r#"
+            [mqtt]
+            address = "localhost"
+            port = 1883
+            username = "user"
+            password = "pass"
+            base_topic = "cams"
+            home_assistant_topic = "homeassistant"
+            [[mqtt.brokers]]
+            address = "backup.local"
+            username = "mirror"
+            password = "secret"
+            client_id = "hiksink-mirror"
+            [[camera]]
+            name = "Front Porch"
+            address = "192.168.1.200"
+            username = "admin"
+            password = "pass"
+        "#}))
+        .unwrap();
+        assert_eq!(config.warnings, Vec::<String>::new());
+        let mqtt = config.mqtt.clone().unwrap();
+        let mirror = mqtt.mirror(&mqtt.brokers[0]);
+        assert_eq!(mirror.address, "backup.local");
+        assert_eq!(mirror.port, 1883);
+        assert_eq!(mirror.username.as_deref(), Some("mirror"));
+        assert_eq!(mirror.password.as_deref(), Some("secret"));
+        assert_eq!(mirror.client_id, "hiksink-mirror");
+        assert_eq!(mirror.base_topic, mqtt.base_topic);
+        assert!(mirror.brokers.is_empty());
+
+        let redacted = config.redacted().mqtt.unwrap();
+        assert_eq!(
+            redacted.brokers[0].password.as_deref(),
+            Some(super::REDACTED)
+        );
+    }
+
+    #[test]
+    fn test_adhoc_camera() {
+        let camera = super::adhoc_camera("192.168.1.64", Some(8000), "admin", "pass").unwrap();
+        assert_eq!(camera.identifier(), "probe");
+        assert_eq!(camera.base_url(), "http://192.168.1.64:8000");
+        assert_eq!(camera.username, "admin");
+        assert_eq!(camera.password, "pass");
+        assert_eq!(camera.alert_format, super::AlertFormat::Auto);
+
+        let camera = super::adhoc_camera("192.168.1.64", None, "admin", "pass").unwrap();
+        assert_eq!(camera.base_url(), "http://192.168.1.64");
+
+        let camera =
+            super::adhoc_camera("https://camera.local:8443", None, "admin", "pass").unwrap();
+        assert_eq!(camera.base_url(), "https://camera.local:8443");
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let config = super::load_config(figment::providers::Toml::string(SAMPLE_CONFIG)).unwrap();
+        assert_eq!(config.warnings, Vec::<String>::new());
+
+        let config = super::load_config(figment::providers::Toml::string(indoc::indoc! {r#"
+            webhok = { url = "http://localhost" }
+            [system]
+            log_level = "INFO"
+            log_levl = "DEBUG"
+            [mqtt]
+            address = "localhost"
+            port = 1883
+            base_topic = "cams"
+            home_assistant_topic = "homeassistant"
+            [mqtt.qos]
+            stat = 1
+            [[camera]]
+            name = "Front Porch"
+            address = "192.168.1.200"
+            username = "admin"
+            password = "pass"
+            retry_on_auth_fail = false
+            colour = "red"
+            [[camera]]
+            name = "Back"
+            address = "192.168.1.201"
+            username = "admin"
+            password = "pass"
+            [camera.debounce.Motion]
+            min_off_delay = 3
+        "#}))
+        .unwrap();
+        assert_eq!(
+            config.warnings,
+            vec![
+                "Unknown key `colour` in [camera \"Front Porch\"]. It is ignored.",
+                "Unknown key `retry_on_auth_fail` in [camera \"Front Porch\"], did you mean `retry_on_auth_failure`?",
+                "Unknown key `min_off_delay` in [camera \"Back\".debounce.Motion], did you mean `min_off_delay_secs`?",
+                "Unknown key `stat` in [mqtt.qos], did you mean `state`?",
+                "Unknown key `log_levl` in [system], did you mean `log_level`?",
+                "Unknown key `webhok` at the top level, did you mean `webhook`?",
+            ]
+        );
+
+        // A misspelled required key fails, pointing out the likely typo
+        let error = super::load_config(figment::providers::Toml::string(
+            &SAMPLE_CONFIG.replace("address = \"localhost\"", "adress = \"localhost\""),
+        ))
+        .unwrap_err();
+        assert!(
+            error.contains("missing field `address`")
+                && error.ends_with("Found `adress`, did you mean `address`?"),
+            "{}",
+            error
+        );
+        let error = super::load_config(figment::providers::Toml::string(&format!(
+            "{}\n[[camera]]\nname = \"Back\"\nadress = \"192.168.1.201\"\nusername = \"admin\"\npassword = \"pass\"\n",
+            SAMPLE_CONFIG
+        )))
+        .unwrap_err();
+        assert!(
+            error.ends_with("Found `adress`, did you mean `address`?"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_camera_address() {
+        const SAMPLE_CONFIG: &str = include_str!("../sample_config.toml");
+        let load = |settings: &str| {
+            super::load_config(figment::providers::Toml::string(&format!(
+                "{}\n[[camera]]\nname = \"Garage\"\n{}\nusername = \"admin\"\npassword = \"pass\"\n",
+                SAMPLE_CONFIG, settings
+            )))
+            .map(|cfg| cfg.camera[1].clone())
+        };
+        let valid = [
+            (
+                "address = \"192.168.1.10\"",
+                "192.168.1.10",
+                "http://192.168.1.10",
+            ),
+            (
+                "address = \" http://192.168.1.10:8080/ \"",
+                "192.168.1.10",
+                "http://192.168.1.10:8080",
+            ),
+            (
+                "address = \"HTTPS://camera.local\"",
+                "camera.local",
+                "https://camera.local",
+            ),
+            (
+                "address = \"camera.local:65001\"\nport = 65001",
+                "camera.local",
+                "http://camera.local:65001",
+            ),
+            (
+                "address = \"https://nvr:8443\"\nscheme = \"https\"",
+                "nvr",
+                "https://nvr:8443",
+            ),
+            ("address = \"fe80::1\"", "fe80::1", "http://[fe80::1]"),
+            (
+                "address = \"[fe80::1]:8080\"",
+                "fe80::1",
+                "http://[fe80::1]:8080",
+            ),
+        ];
+        for (settings, address, url) in valid {
+            let camera = load(settings).unwrap();
+            assert_eq!(camera.address, address, "{}", settings);
+            assert_eq!(camera.base_url(), url, "{}", settings);
+        }
+
+        let invalid = [
+            ("address = \"\"", "needs the camera's hostname"),
+            ("address = \"http://\"", "needs the camera's hostname"),
+            ("address = \":8080\"", "needs the camera's hostname"),
+            ("address = \"front porch\"", "can't contain spaces"),
+            (
+                "address = \"rtsp://192.168.1.10\"",
+                "only http:// and https://",
+            ),
+            (
+                "address = \"http://192.168.1.10/ISAPI/System/deviceInfo\"",
+                "can't contain a path",
+            ),
+            ("address = \"192.168.1.10?a=b\"", "can't contain a path"),
+            (
+                "address = \"admin:pass@192.168.1.10\"",
+                "can't contain credentials",
+            ),
+            (
+                "address = \"192.168.1.10:http\"",
+                "`http` isn't a valid port",
+            ),
+            ("address = \"192.168.1.10:0\"", "`0` isn't a valid port"),
+            (
+                "address = \"192.168.1.10:70000\"",
+                "`70000` isn't a valid port",
+            ),
+            (
+                "address = \"192.168.1.10:8080\"\nport = 80",
+                "includes port 8080 but port is set to 80",
+            ),
+            (
+                "address = \"http://192.168.1.10\"\nscheme = \"https\"",
+                "starts with http:// but scheme is set to https",
+            ),
+            ("address = \"[fe80::1\"", "missing its closing ]"),
+            ("address = \"[fe80::1]8080\"", "unexpected text"),
+        ];
+        for (settings, expected) in invalid {
+            let error = load(settings).unwrap_err();
+            assert!(
+                error.starts_with("Camera Garage has an invalid address"),
+                "{}",
+                error
+            );
+            assert!(error.contains(expected), "{}: {}", settings, error);
+        }
+
+        // The fallback is normalized the same way, keeping the port of the address unless it has its own
+        let camera =
+            load("address = \"192.168.1.10:8080\"\nfallback_address = \"10.0.0.10\"").unwrap();
+        assert_eq!(camera.base_url(), "http://192.168.1.10:8080");
+        assert_eq!(
+            camera.fallback().unwrap().base_url(),
+            "http://10.0.0.10:8080"
+        );
+        let camera =
+            load("address = \"192.168.1.10\"\nfallback_address = \"http://10.0.0.10:81/\"")
+                .unwrap();
+        assert_eq!(camera.fallback_address.as_deref(), Some("10.0.0.10"));
+        assert_eq!(camera.fallback().unwrap().base_url(), "http://10.0.0.10:81");
+        assert_eq!(load("address = \"192.168.1.10\"").unwrap().fallback(), None);
+
+        let error = load(
+            "address = \"192.168.1.10\"\nfallback_address = \"10.0.0.10:81\"\nfallback_port = 82",
+        )
+        .unwrap_err();
+        assert!(
+            error.contains("invalid fallback_address `10.0.0.10:81`: it includes port 81 but fallback_port is set to 82"),
+            "{}",
+            error
+        );
+        let error =
+            load("address = \"http://192.168.1.10\"\nfallback_address = \"https://10.0.0.10\"")
+                .unwrap_err();
+        assert!(error.contains("but scheme is set to http"), "{}", error);
+        let error = load("address = \"192.168.1.10\"\nfallback_port = 81").unwrap_err();
+        assert!(
+            error.contains("fallback_port without a fallback_address"),
+            "{}",
+            error
+        );
+    }
 }
@@ -0,0 +1,130 @@
+use std::convert::Infallible;
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::{config::ConfigHealth, sink::status::BridgeStatus};
+
+/// Serves health checks for container orchestration
+pub fn serve(config: &ConfigHealth, status: watch::Receiver<BridgeStatus>) {
+    let server = match Server::try_bind(&config.listen) {
+        Ok(server) => server,
+        Err(e) => {
+            error!(listen=%config.listen, "Unable to start health check server: {}", e);
+            return;
+        }
+    };
+    info!(listen=%config.listen, "Serving health checks");
+    let make_service = make_service_fn(move |_| {
+        let status = status.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = respond(&req, &status.borrow());
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = server.serve(make_service).await {
+            error!("Health check server stopped: {}", e);
+        }
+    });
+}
+
+fn respond(req: &Request<Body>, status: &BridgeStatus) -> Response<Body> {
+    if req.method() != Method::GET {
+        return plain(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+    match req.uri().path() {
+        "/healthz" => check(status.is_healthy()),
+        "/readyz" => check(status.is_ready()),
+        "/status" => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(status).unwrap_or_default()))
+            .unwrap(),
+        _ => plain(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+fn check(ok: bool) -> Response<Body> {
+    if ok {
+        plain(StatusCode::OK, "OK")
+    } else {
+        plain(StatusCode::SERVICE_UNAVAILABLE, "Unavailable")
+    }
+}
+
+fn plain(code: StatusCode, text: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(text))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::respond;
+    use crate::sink::status::{BridgeStatus, CameraStatus};
+    use chrono::Utc;
+    use hyper::{Body, Method, Request, StatusCode};
+
+    fn camera(connected: bool, running: bool) -> CameraStatus {
+        CameraStatus {
+            id: "cam1".into(),
+            name: "Camera 1".into(),
+            connected,
+            running,
+            error: None,
+            since: Utc::now(),
+            last_event: None,
+            triggers: 2,
+        }
+    }
+
+    fn get(path: &str, status: &BridgeStatus) -> StatusCode {
+        let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+        respond(&req, status).status()
+    }
+
+    #[test]
+    fn test_health_checks() {
+        let mut status = BridgeStatus {
+            mqtt_connected: true,
+            mqtt_error: None,
+            cameras: vec![camera(false, true)],
+        };
+        assert_eq!(get("/healthz", &status), StatusCode::OK);
+        assert_eq!(get("/readyz", &status), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(get("/status", &status), StatusCode::OK);
+        assert_eq!(get("/other", &status), StatusCode::NOT_FOUND);
+
+        status.cameras.push(camera(true, true));
+        assert_eq!(get("/readyz", &status), StatusCode::OK);
+
+        // Nothing gets through without MQTT
+        status.mqtt_connected = false;
+        assert_eq!(get("/healthz", &status), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(get("/readyz", &status), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Every camera has given up
+        status.mqtt_connected = true;
+        status.cameras = vec![camera(false, false)];
+        assert_eq!(get("/healthz", &status), StatusCode::SERVICE_UNAVAILABLE);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/healthz")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            respond(&req, &status).status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
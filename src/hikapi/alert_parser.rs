@@ -1,32 +1,117 @@
 use super::EventIdentifier;
+use chrono::{DateTime, FixedOffset};
 use minidom::Element;
 use serde::{Deserialize, Serialize};
 
+/// A corner of a detection region, in the camera's normalised coordinates (0-1000)
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct RegionCoordinates {
+    /// Distance from the left of the image
     pub x: u32,
+    /// Distance from the bottom of the image
     pub y: u32,
 }
 
+/// A region of a smart event (e.g. line crossing) that triggered the alert
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct DetectionRegion {
+    /// The ID of the region as configured on the camera
     pub id: String,
+    /// The sensitivity of the region, from 0 to 100
     pub sensitivity: u8,
+    /// The corners of the region
     pub coordinates: Vec<RegionCoordinates>,
+    /// What was detected in the region by smart (AcuSense) cameras. e.g. `human` or `vehicle`
+    pub target: Option<String>,
 }
+/// A license plate read by an ANPR camera
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
+pub struct AnprDetails {
+    /// The license plate number
+    pub plate: String,
+    /// Which way the vehicle was travelling. e.g. `forward` or `reverse`
+    pub direction: Option<String>,
+    /// The lane the vehicle was in, numbered by the camera
+    pub lane: Option<String>,
+}
+
+/// Totals reported by people counting cameras
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
+pub struct PeopleCount {
+    /// People that entered
+    pub enter: Option<u64>,
+    /// People that left
+    pub exit: Option<u64>,
+    /// People that passed by without entering or leaving
+    pub pass: Option<u64>,
+}
+
+/// An alert from the camera's alert stream
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct AlertItem {
+    /// The trigger that alerted
     pub identifier: EventIdentifier,
+    /// Whether the event is in progress. Cameras repeat active alerts while it lasts.
     pub active: bool,
+    /// The regions that were triggered, for smart events
     pub regions: Vec<DetectionRegion>,
+    /// How many times the camera has sent this alert since the event started
     pub post_count: u64,
+    /// The camera's description of the event
     pub description: String,
+    /// The alert time as sent by the camera
     pub date: String,
+    /// The alert time, if the camera sent it in a recognised format
+    pub time: Option<DateTime<FixedOffset>>,
+    /// What was detected by smart (AcuSense) cameras. e.g. `human` or `vehicle`
+    pub target: Option<String>,
+    /// The license plate read by ANPR cameras
+    #[serde(default)]
+    pub anpr: Option<AnprDetails>,
+    /// The counts sent by people counting cameras
+    #[serde(default)]
+    pub people_counting: Option<PeopleCount>,
+    /// The serial number of the device that raised the event, from `Extensions/eventPush`.
+    /// NVRs send the serial of the camera on the channel.
+    #[serde(default)]
+    pub device_serial: Option<String>,
+    /// The name of the channel as configured on an NVR, e.g. `Driveway`
+    #[serde(default)]
+    pub channel_name: Option<String>,
+}
+
+/// Whitespace, and the byte order mark some firmwares put before each document
+fn is_padding(c: char) -> bool {
+    c.is_whitespace() || c == '\u{feff}'
+}
+
+/// Whether a part of the alert stream holds nothing but padding, as sent by some NVRs to keep the stream alive
+pub(super) fn is_keep_alive(s: &str) -> bool {
+    s.trim_matches(is_padding).is_empty()
 }
 
 impl AlertItem {
+    /// Parses every alert in a part of the alert stream. Some NVRs send several documents back to
+    /// back in a single part.
+    pub fn parse_all(s: &str) -> Result<Vec<AlertItem>, AlertParseError> {
+        const END_TAG: &str = "</EventNotificationAlert>";
+        let mut alerts = Vec::new();
+        let mut rest = s;
+        while let Some(end) = rest.find(END_TAG) {
+            let (document, remaining) = rest.split_at(end + END_TAG.len());
+            alerts.push(AlertItem::parse(document)?);
+            rest = remaining;
+        }
+        // Anything left over isn't a complete alert, so let the parser report why
+        if !is_keep_alive(rest) || alerts.is_empty() {
+            alerts.push(AlertItem::parse(rest)?);
+        }
+        Ok(alerts)
+    }
+
+    /// Parses a single XML alert
     pub fn parse(s: &str) -> Result<AlertItem, AlertParseError> {
-        let root: Element = s.parse()?;
+        let root: Element = s.trim_matches(is_padding).parse()?;
         if root.name() != "EventNotificationAlert" {
             return Err(AlertParseError::FieldMissing(
                 "EventNotificationAlert".into(),
@@ -40,34 +125,78 @@ impl AlertItem {
             let event_state = root
                 .get_child("eventState", minidom::NSChoice::Any)
                 .ok_or_else(|| AlertParseError::FieldMissing("eventState".to_string()))?;
-            let event_active = event_state.text();
-            match event_active.as_ref() {
-                "active" => true,
-                "inactive" => false,
-                _ => return Err(AlertParseError::EventStateInvalid(event_active)),
-            }
+            parse_event_state(event_state.text())?
         };
+        // Some firmwares leave these out (e.g. DVR video loss heartbeats), so they aren't required
         let event_description = root
             .get_child("eventDescription", minidom::NSChoice::Any)
-            .ok_or_else(|| AlertParseError::FieldMissing("eventDescription".to_string()))?
-            .text();
+            .map(|e| e.text())
+            .unwrap_or_default();
         let event_date = root
             .get_child("dateTime", minidom::NSChoice::Any)
-            .ok_or_else(|| AlertParseError::FieldMissing("dateTime".to_string()))?
-            .text();
-        let active_post_count = {
-            let pc = root
-                .get_child("activePostCount", minidom::NSChoice::Any)
-                .ok_or_else(|| AlertParseError::FieldMissing("activePostCount".to_string()))?;
-            pc.text().parse::<u64>().map_err(|e| {
+            .map(|e| e.text())
+            .unwrap_or_default();
+        let active_post_count = match root
+            .get_child("activePostCount", minidom::NSChoice::Any)
+            .map(|pc| pc.text())
+        {
+            Some(pc) if !pc.trim().is_empty() => pc.trim().parse::<u64>().map_err(|e| {
                 AlertParseError::NumberExpected("activePostCount".into(), e.to_string())
-            })?
+            })?,
+            _ => 0,
         };
         let channel = root
             .get_child("channelID", minidom::NSChoice::Any)
             .or_else(|| root.get_child("dynChannelID", minidom::NSChoice::Any))
             .map(|c| c.text());
+        let channel_name = root
+            .get_child("channelName", minidom::NSChoice::Any)
+            .map(|c| c.text().trim().to_string())
+            .filter(|c| !c.is_empty());
         let regions = pull_region_list(&root)?;
+        let target = root
+            .get_child("detectionTarget", minidom::NSChoice::Any)
+            .map(|t| t.text())
+            .or_else(|| regions.iter().find_map(|r| r.target.clone()));
+        let anpr = root
+            .get_child("ANPR", minidom::NSChoice::Any)
+            .and_then(|anpr| {
+                let text = |field: &str| {
+                    anpr.get_child(field, minidom::NSChoice::Any)
+                        .map(|e| e.text())
+                        .filter(|t| !t.is_empty())
+                };
+                Some(AnprDetails {
+                    plate: text("licensePlate")?,
+                    direction: text("direction"),
+                    lane: text("laneNo").or_else(|| text("line")),
+                })
+            });
+        let people_counting = root
+            .get_child("peopleCounting", minidom::NSChoice::Any)
+            .or_else(|| root.get_child("PeopleCounting", minidom::NSChoice::Any))
+            .map(|counting| -> Result<PeopleCount, AlertParseError> {
+                let count = |field: &str| {
+                    counting
+                        .get_child(field, minidom::NSChoice::Any)
+                        .map(|e| {
+                            e.text().trim().parse::<u64>().map_err(|e| {
+                                AlertParseError::NumberExpected(field.into(), e.to_string())
+                            })
+                        })
+                        .transpose()
+                };
+                Ok(PeopleCount {
+                    enter: count("enter")?,
+                    exit: count("exit")?,
+                    pass: count("pass")?,
+                })
+            })
+            .transpose()?;
+        let device_serial = root
+            .get_child("Extensions", minidom::NSChoice::Any)
+            .and_then(|e| e.get_child("eventPush", minidom::NSChoice::Any))
+            .and_then(|e| parse_event_push_serial(&e.text()));
 
         let event_type = event_type
             .parse()
@@ -80,11 +209,113 @@ impl AlertItem {
             regions,
             post_count: active_post_count,
             description: event_description,
+            time: parse_alert_time(&event_date),
             date: event_date,
+            target,
+            anpr,
+            people_counting,
+            device_serial,
+            channel_name,
         })
     }
 }
 
+impl AlertItem {
+    /// Parses an alert from the JSON variant of the alert stream (`Accept: application/json`)
+    pub fn parse_json(s: &str) -> Result<AlertItem, AlertParseError> {
+        let root: serde_json::Value = serde_json::from_str(s)?;
+        let event_type = json_text(&root, "eventType")?;
+        let event_active = parse_event_state(json_text(&root, "eventState")?)?;
+        // Some firmwares leave these out (e.g. DVR video loss heartbeats), so they aren't required
+        let event_description = json_text(&root, "eventDescription").unwrap_or_default();
+        let event_date = json_text(&root, "dateTime").unwrap_or_default();
+        let active_post_count = match json_number(&root, "activePostCount") {
+            Ok(pc) => pc,
+            Err(AlertParseError::FieldMissing(_)) => 0,
+            Err(e) => return Err(e),
+        };
+        let channel = json_text(&root, "channelID")
+            .or_else(|_| json_text(&root, "dynChannelID"))
+            .ok();
+        let channel_name = json_text(&root, "channelName")
+            .ok()
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty());
+        let regions = pull_region_list_json(&root)?;
+        let target = json_text(&root, "detectionTarget")
+            .ok()
+            .or_else(|| regions.iter().find_map(|r| r.target.clone()));
+        let anpr = root.get("ANPR").and_then(|anpr| {
+            Some(AnprDetails {
+                plate: json_text(anpr, "licensePlate")
+                    .ok()
+                    .filter(|t| !t.is_empty())?,
+                direction: json_text(anpr, "direction").ok(),
+                lane: json_text(anpr, "laneNo")
+                    .or_else(|_| json_text(anpr, "line"))
+                    .ok(),
+            })
+        });
+        let people_counting = root
+            .get("peopleCounting")
+            .or_else(|| root.get("PeopleCounting"))
+            .map(|counting| -> Result<PeopleCount, AlertParseError> {
+                let count = |field: &str| match json_number(counting, field) {
+                    Ok(n) => Ok(Some(n)),
+                    Err(AlertParseError::FieldMissing(_)) => Ok(None),
+                    Err(e) => Err(e),
+                };
+                Ok(PeopleCount {
+                    enter: count("enter")?,
+                    exit: count("exit")?,
+                    pass: count("pass")?,
+                })
+            })
+            .transpose()?;
+
+        let event_type = event_type
+            .parse()
+            .map_err(|e| AlertParseError::EventTypeInvalid(event_type, e))?;
+        let identifier = EventIdentifier::new(channel, event_type);
+
+        Ok(AlertItem {
+            identifier,
+            active: event_active,
+            regions,
+            post_count: active_post_count,
+            description: event_description,
+            time: parse_alert_time(&event_date),
+            date: event_date,
+            target,
+            anpr,
+            people_counting,
+            device_serial: None,
+            channel_name,
+        })
+    }
+}
+
+/// Parses the serial from an `eventPush` extension, e.g. `IO&&DS-2CD2185FWD-I20180101AAWR111111111,2021-07-02T14:25:36+08:00,1.0`
+fn parse_event_push_serial(push: &str) -> Option<String> {
+    let (_, rest) = push.split_once("&&")?;
+    let serial = rest.split(',').next()?.trim();
+    (!serial.is_empty()).then(|| serial.to_string())
+}
+
+/// Parses whether an alert is active. Firmwares differ in the case and spelling of the state.
+fn parse_event_state(state: String) -> Result<bool, AlertParseError> {
+    match state.trim().to_ascii_lowercase().as_str() {
+        "active" | "true" => Ok(true),
+        "inactive" | "false" => Ok(false),
+        _ => Err(AlertParseError::EventStateInvalid(state)),
+    }
+}
+
+/// Parses the camera's time of an alert. Cameras send the time in their local timezone, e.g. `2021-07-02T14:25:36+08:00`
+fn parse_alert_time(date: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(date.trim()).ok()
+}
+
 fn pull_region_list(el: &minidom::Element) -> Result<Vec<DetectionRegion>, AlertParseError> {
     let mut rl = Vec::new();
 
@@ -132,34 +363,123 @@ fn pull_region_list(el: &minidom::Element) -> Result<Vec<DetectionRegion>, Alert
                     region_coordinates.push(RegionCoordinates { x, y });
                 }
             }
+            let target = child
+                .get_child("detectionTarget", minidom::NSChoice::Any)
+                .map(|t| t.text());
             rl.push(DetectionRegion {
                 id,
                 sensitivity,
                 coordinates: region_coordinates,
+                target,
             });
         }
     }
     Ok(rl)
 }
 
+/// Region lists in the JSON stream are either a list of entries, or a list of objects wrapping a `DetectionRegionEntry`
+fn pull_region_list_json(
+    root: &serde_json::Value,
+) -> Result<Vec<DetectionRegion>, AlertParseError> {
+    let mut rl = Vec::new();
+
+    let container = match root.get("DetectionRegionList") {
+        Some(serde_json::Value::Array(entries)) => entries.as_slice(),
+        Some(_) => {
+            return Err(AlertParseError::InvalidChild(
+                "DetectionRegionList array".to_string(),
+                "non-array value".to_string(),
+            ))
+        }
+        None => &[],
+    };
+    for entry in container {
+        let entry = entry.get("DetectionRegionEntry").unwrap_or(entry);
+        let id = json_text(entry, "regionID")?;
+        let sensitivity = json_number(entry, "sensitivityLevel")?;
+        let sensitivity = u8::try_from(sensitivity).map_err(|e| {
+            AlertParseError::NumberExpected("sensitivityLevel".into(), e.to_string())
+        })?;
+
+        let mut region_coordinates = Vec::new();
+        if let Some(serde_json::Value::Array(coords)) = entry.get("RegionCoordinatesList") {
+            for coord in coords {
+                let coord = coord.get("RegionCoordinates").unwrap_or(coord);
+                let x = json_number(coord, "positionX")?;
+                let y = json_number(coord, "positionY")?;
+                let x = u32::try_from(x).map_err(|e| {
+                    AlertParseError::NumberExpected("positionX".into(), e.to_string())
+                })?;
+                let y = u32::try_from(y).map_err(|e| {
+                    AlertParseError::NumberExpected("positionY".into(), e.to_string())
+                })?;
+                region_coordinates.push(RegionCoordinates { x, y });
+            }
+        }
+        rl.push(DetectionRegion {
+            id,
+            sensitivity,
+            coordinates: region_coordinates,
+            target: json_text(entry, "detectionTarget").ok(),
+        });
+    }
+    Ok(rl)
+}
+
+/// Pulls a field from a JSON object as text. Numbers are converted since firmwares differ on which fields are quoted.
+fn json_text(obj: &serde_json::Value, field: &str) -> Result<String, AlertParseError> {
+    match obj.get(field) {
+        Some(serde_json::Value::String(s)) => Ok(s.clone()),
+        Some(serde_json::Value::Number(n)) => Ok(n.to_string()),
+        _ => Err(AlertParseError::FieldMissing(field.to_string())),
+    }
+}
+
+/// Pulls a field from a JSON object as a number, accepting numbers encoded as strings
+fn json_number(obj: &serde_json::Value, field: &str) -> Result<u64, AlertParseError> {
+    match obj.get(field) {
+        Some(serde_json::Value::Number(n)) => n.as_u64().ok_or_else(|| {
+            AlertParseError::NumberExpected(
+                field.into(),
+                format!("{} is not an unsigned integer", n),
+            )
+        }),
+        Some(serde_json::Value::String(s)) => s
+            .parse::<u64>()
+            .map_err(|e| AlertParseError::NumberExpected(field.into(), e.to_string())),
+        _ => Err(AlertParseError::FieldMissing(field.to_string())),
+    }
+}
+
 quick_error! {
+    /// Why an alert couldn't be parsed
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub enum AlertParseError {
+        /// The body isn't well formed XML
         XmlInvalid(error: String) {
             from(e: minidom::Error) -> (e.to_string())
         }
+        /// The body isn't well formed JSON
+        JsonInvalid(error: String) {
+            from(e: serde_json::Error) -> (e.to_string())
+        }
+        /// A required field wasn't found
         FieldMissing(field: String) {
             display("Field was expected but missing: {}", field)
         }
+        /// A field that should hold a number doesn't
         NumberExpected(field: String, error: String) {
             display("{} should be a number: {}", field, error)
         }
+        /// The event type couldn't be read
         EventTypeInvalid(provided: String, error: String) {
             display("Event type `{}` was incorrectly formatted: {}", provided, error)
         }
+        /// The event state is neither active nor inactive
         EventStateInvalid(found: String) {
             display("Event state should be active / inactive. Got: {}", found)
         }
+        /// An element holds a child of the wrong kind
         InvalidChild(expected: String, found: String) {
             display("Child node in XML invalid. Expected {}, found {}", expected, found)
         }
@@ -168,11 +488,14 @@ quick_error! {
 
 #[cfg(test)]
 mod test {
-    use super::AlertItem;
+    use super::{AlertItem, PeopleCount};
     use serde::Deserialize;
     const SAMPLES_CAM: &str = include_str!("../../samples/samples_cam.txt");
     const SAMPLES_NVR: &str = include_str!("../../samples/samples_nvr.txt");
     const SAMPLES_PTZ: &str = include_str!("../../samples/samples_ptz.txt");
+    const SAMPLES_CAM_JSON: &str = include_str!("../../samples/samples_cam_json.txt");
+    const SAMPLES_DVR_INCOMPLETE: &str = include_str!("../../samples/samples_dvr_incomplete.txt");
+    const ALERT_CONCATENATED: &str = include_str!("../../samples/alert_concatenated.xml");
 
     #[test]
     fn test_parse_all_samples() {
@@ -197,6 +520,389 @@ mod test {
         insta::assert_yaml_snapshot!(all_parsed);
     }
 
+    #[test]
+    fn test_parse_concatenated() {
+        let parsed = AlertItem::parse_all(ALERT_CONCATENATED).unwrap();
+        insta::assert_yaml_snapshot!(parsed);
+
+        // A truncated second document is still an error
+        let truncated = &ALERT_CONCATENATED[..ALERT_CONCATENATED.len() - 20];
+        assert!(AlertItem::parse_all(truncated).is_err());
+        assert!(AlertItem::parse_all("").is_err());
+    }
+
+    #[test]
+    fn test_parse_incomplete_samples() {
+        let mut all_parsed = Vec::new();
+        for sample in SAMPLES_DVR_INCOMPLETE.lines() {
+            #[derive(Deserialize)]
+            struct Line {
+                pub content: String,
+            }
+
+            let sample: Line = serde_json::from_str(sample).unwrap();
+            let parsed = AlertItem::parse(&sample.content).unwrap();
+            all_parsed.push(parsed);
+        }
+
+        insta::assert_yaml_snapshot!(all_parsed);
+    }
+
+    #[test]
+    fn test_parse_alert_time() {
+        let mut text = SAMPLES_CAM.to_string();
+        text.push_str(SAMPLES_NVR);
+        text.push_str(SAMPLES_PTZ);
+
+        for sample in text.lines() {
+            #[derive(Deserialize)]
+            struct Line {
+                pub content: String,
+            }
+
+            let sample: Line = serde_json::from_str(sample).unwrap();
+            let parsed = AlertItem::parse(&sample.content).unwrap();
+            // The camera's offset is kept
+            let time = parsed.time.expect("Sample time should parse");
+            assert_eq!(time.to_rfc3339(), parsed.date);
+        }
+
+        assert_eq!(
+            super::parse_alert_time("2021-07-02T14:25:36Z").map(|t| t.to_rfc3339()),
+            Some("2021-07-02T14:25:36+00:00".to_string())
+        );
+        assert_eq!(super::parse_alert_time("2021-07-02 14:25:36"), None);
+        assert_eq!(super::parse_alert_time(""), None);
+    }
+
+    #[test]
+    fn test_parse_json_samples() {
+        let mut all_parsed = Vec::new();
+        for sample in SAMPLES_CAM_JSON.lines() {
+            #[derive(Deserialize)]
+            struct Line {
+                pub content: String,
+            }
+
+            let sample: Line = serde_json::from_str(sample).unwrap();
+            let parsed = AlertItem::parse_json(&sample.content).unwrap();
+            all_parsed.push(parsed);
+        }
+
+        insta::assert_yaml_snapshot!(all_parsed);
+    }
+
+    #[test]
+    fn test_ignores_invalid_json() {
+        insta::assert_yaml_snapshot!(AlertItem::parse_json(""), @r###"
+        ---
+        Err:
+          JsonInvalid: EOF while parsing a value at line 1 column 0
+        "###);
+
+        // Missing event type
+        insta::assert_yaml_snapshot!(AlertItem::parse_json(indoc::indoc!{r#"
+            {
+                "ipAddress": "128.100.0.5",
+                "portNo": 80,
+                "protocol": "HTTP",
+                "macAddress": "ff:ff:ff:ff:ff:ff",
+                "channelID": 1,
+                "dateTime": "2021-07-02T14:25:36+08:00",
+                "activePostCount": 0,
+                "eventState": "inactive",
+                "eventDescription": "videoloss alarm",
+                "channelName": ""
+            }
+        "#}), @r###"
+        ---
+        Err:
+          FieldMissing: eventType
+        "###);
+
+        // Region list is not a list
+        insta::assert_yaml_snapshot!(AlertItem::parse_json(indoc::indoc!{r#"
+            {
+                "ipAddress": "128.100.0.5",
+                "channelID": 1,
+                "dateTime": "2021-07-02T14:25:36+08:00",
+                "activePostCount": 1,
+                "eventType": "linedetection",
+                "eventState": "active",
+                "eventDescription": "linedetection alarm",
+                "DetectionRegionList": {}
+            }
+        "#}), @r###"
+        ---
+        Err:
+          InvalidChild:
+            - DetectionRegionList array
+            - non-array value
+        "###);
+    }
+
+    #[test]
+    fn test_parse_detection_target() {
+        insta::assert_yaml_snapshot!(AlertItem::parse(indoc::indoc! {r#"
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <ipAddress>128.100.0.5</ipAddress>
+            <portNo>80</portNo>
+            <protocol>HTTP</protocol>
+            <macAddress>ff:ff:ff:ff:ff:ff</macAddress>
+            <channelID>1</channelID>
+            <dateTime>2022-05-12T20:41:08+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>linedetection</eventType>
+            <eventState>active</eventState>
+            <eventDescription>linedetection alarm</eventDescription>
+            <channelName>Driveway</channelName>
+            <DetectionRegionList>
+            <DetectionRegionEntry>
+            <regionID>1</regionID>
+            <sensitivityLevel>50</sensitivityLevel>
+            <RegionCoordinatesList>
+            <RegionCoordinates>
+            <positionX>424</positionX>
+            <positionY>642</positionY>
+            </RegionCoordinates>
+            <RegionCoordinates>
+            <positionX>161</positionX>
+            <positionY>610</positionY>
+            </RegionCoordinates>
+            </RegionCoordinatesList>
+            <detectionTarget>human</detectionTarget>
+            <TargetRect>
+            <X>0.541</X>
+            <Y>0.321</Y>
+            <width>0.043</width>
+            <height>0.161</height>
+            </TargetRect>
+            </DetectionRegionEntry>
+            </DetectionRegionList>
+            </EventNotificationAlert>
+        "#})
+        .unwrap());
+
+        // Top level target takes precedence
+        let parsed = AlertItem::parse(indoc::indoc! {r#"
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <channelID>1</channelID>
+            <dateTime>2022-05-12T20:41:08+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>fielddetection</eventType>
+            <eventState>active</eventState>
+            <eventDescription>fielddetection alarm</eventDescription>
+            <detectionTarget>vehicle</detectionTarget>
+            <DetectionRegionList>
+            <DetectionRegionEntry>
+            <regionID>1</regionID>
+            <sensitivityLevel>50</sensitivityLevel>
+            <detectionTarget>human</detectionTarget>
+            </DetectionRegionEntry>
+            </DetectionRegionList>
+            </EventNotificationAlert>
+        "#})
+        .unwrap();
+        assert_eq!(parsed.target.as_deref(), Some("vehicle"));
+        assert_eq!(parsed.regions[0].target.as_deref(), Some("human"));
+    }
+
+    #[test]
+    fn test_parse_anpr() {
+        insta::assert_yaml_snapshot!(AlertItem::parse(indoc::indoc! {r#"
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <ipAddress>128.100.0.5</ipAddress>
+            <channelID>1</channelID>
+            <dateTime>2022-05-12T20:41:08+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>ANPR</eventType>
+            <eventState>active</eventState>
+            <eventDescription>ANPR</eventDescription>
+            <ANPR>
+            <country>3</country>
+            <licensePlate>AB12CDE</licensePlate>
+            <line>2</line>
+            <direction>forward</direction>
+            <confidenceLevel>98</confidenceLevel>
+            <plateType>unknown</plateType>
+            <plateColor>yellow</plateColor>
+            <vehicleType>vehicle</vehicleType>
+            </ANPR>
+            </EventNotificationAlert>
+        "#})
+        .unwrap());
+
+        let parsed = AlertItem::parse_json(indoc::indoc! {r#"
+            {
+                "channelID": 1,
+                "dateTime": "2022-05-12T20:41:08+08:00",
+                "activePostCount": 1,
+                "eventType": "vehicledetection",
+                "eventState": "active",
+                "eventDescription": "vehicledetection alarm",
+                "ANPR": {
+                    "licensePlate": "AB12CDE",
+                    "direction": "reverse"
+                }
+            }
+        "#})
+        .unwrap();
+        assert_eq!(
+            parsed.anpr.as_ref().map(|a| a.plate.as_str()),
+            Some("AB12CDE")
+        );
+        assert_eq!(
+            parsed.anpr.and_then(|a| a.direction).as_deref(),
+            Some("reverse")
+        );
+
+        // No plate was read
+        let parsed = AlertItem::parse(indoc::indoc! {r#"
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <channelID>1</channelID>
+            <dateTime>2022-05-12T20:41:08+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>ANPR</eventType>
+            <eventState>active</eventState>
+            <eventDescription>ANPR</eventDescription>
+            <ANPR>
+            <licensePlate></licensePlate>
+            </ANPR>
+            </EventNotificationAlert>
+        "#})
+        .unwrap();
+        assert_eq!(parsed.anpr, None);
+    }
+
+    #[test]
+    fn test_parse_people_counting() {
+        let parsed = AlertItem::parse(indoc::indoc! {r#"
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <channelID>1</channelID>
+            <dateTime>2022-05-12T20:41:08+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>PeopleCounting</eventType>
+            <eventState>active</eventState>
+            <eventDescription>peopleCounting alarm</eventDescription>
+            <peopleCounting>
+            <statisticalMethods>realTime</statisticalMethods>
+            <enter>12</enter>
+            <exit>9</exit>
+            </peopleCounting>
+            </EventNotificationAlert>
+        "#})
+        .unwrap();
+        assert_eq!(
+            parsed.people_counting,
+            Some(PeopleCount {
+                enter: Some(12),
+                exit: Some(9),
+                pass: None,
+            })
+        );
+
+        let parsed = AlertItem::parse_json(indoc::indoc! {r#"
+            {
+                "channelID": 1,
+                "dateTime": "2022-05-12T20:41:08+08:00",
+                "activePostCount": 1,
+                "eventType": "peopleCounting",
+                "eventState": "active",
+                "eventDescription": "peopleCounting alarm",
+                "peopleCounting": {
+                    "enter": 12,
+                    "exit": "9",
+                    "pass": 30
+                }
+            }
+        "#})
+        .unwrap();
+        assert_eq!(
+            parsed.people_counting,
+            Some(PeopleCount {
+                enter: Some(12),
+                exit: Some(9),
+                pass: Some(30),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_event_push_serial() {
+        assert_eq!(
+            super::parse_event_push_serial(
+                "IO&&DS-2CD2185FWD-I20180101AAWR111111111,2021-07-02T14:25:36+08:00,1.0"
+            ),
+            Some("DS-2CD2185FWD-I20180101AAWR111111111".to_string())
+        );
+        assert_eq!(super::parse_event_push_serial("IO&&,2021-07-02"), None);
+        assert_eq!(super::parse_event_push_serial("1.0"), None);
+    }
+
+    #[test]
+    fn test_parse_event_state() {
+        for (state, active) in [
+            ("active", true),
+            ("Active", true),
+            ("ACTIVE", true),
+            (" active\n", true),
+            ("true", true),
+            ("inactive", false),
+            ("Inactive", false),
+            ("INACTIVE", false),
+            ("false", false),
+        ] {
+            assert_eq!(
+                super::parse_event_state(state.to_string()).unwrap(),
+                active,
+                "{:?}",
+                state
+            );
+        }
+        let parsed = AlertItem::parse(indoc::indoc! {r#"
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <dynChannelID>4</dynChannelID>
+            <dateTime>2021-07-02T15:30:56+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>VMD</eventType>
+            <eventState>Active</eventState>
+            <eventDescription>Motion alarm</eventDescription>
+            </EventNotificationAlert>
+        "#})
+        .unwrap();
+        assert!(parsed.active);
+
+        assert_eq!(
+            super::parse_event_state(" Unknown ".to_string())
+                .unwrap_err()
+                .to_string(),
+            "Event state should be active / inactive. Got:  Unknown "
+        );
+    }
+
+    #[test]
+    fn test_parse_padding() {
+        assert!(super::is_keep_alive(""));
+        assert!(super::is_keep_alive("\r\n"));
+        assert!(super::is_keep_alive(" \u{feff}\r\n "));
+        assert!(!super::is_keep_alive("<a/>"));
+
+        // Some firmwares start each document with a byte order mark
+        let alert = indoc::indoc! {r#"
+            <?xml version="1.0" encoding="UTF-8"?>
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <channelID>1</channelID>
+            <dateTime>2021-07-02T15:30:56+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>VMD</eventType>
+            <eventState>active</eventState>
+            <eventDescription>Motion alarm</eventDescription>
+            </EventNotificationAlert>
+        "#};
+        let parsed = AlertItem::parse(&format!("\u{feff}\r\n{}", alert)).unwrap();
+        assert_eq!(parsed, AlertItem::parse(alert).unwrap());
+    }
+
     #[test]
     fn test_ignores_invalid_xml() {
         insta::assert_yaml_snapshot!(AlertItem::parse(""), @r###"
@@ -1,218 +1,1498 @@
-use std::{pin::Pin, time::Duration};
+use std::{collections::HashMap, io::Write, pin::Pin, time::Duration};
 
 use super::{
-    alert_parser::{AlertItem, AlertParseError},
+    alert_parser::{is_keep_alive, AlertItem, AlertParseError},
+    capture::Capture,
+    channels_parser::{ChannelParseError, InputChannel},
+    connect_limit::ConnectLimit,
+    controls_parser::{parse_inputs, parse_outputs, ControlParseError, ControlState, ControlType},
     device_info::{DeviceInfo, DeviceInfoParseError},
+    event_queue::EventQueue,
+    ptz_parser::{PtzParseError, PtzPreset},
+    time_parser::{parse_time, TimeParseError},
     triggers_parser::{TriggerItem, TriggerParseError},
+    EventIdentifier, EventType,
 };
-use crate::config::ConfigCamera;
+use crate::config::{AlertFormat, CameraMode, ConfigCamera};
 use digest_auth::AuthContext;
 use futures::StreamExt;
 use reqwest::{header, Response};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use strum::Display;
+use tokio::{
+    sync::{mpsc, watch},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
+/// Something that happened to a camera started with [`run_camera`]
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct CameraEvent {
+    /// The [identifier](ConfigCamera::identifier) of the camera
     pub id: String,
+    /// What happened
     pub event: CameraEventType,
 }
 
+/// What happened to a camera
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub enum CameraEventType {
+    /// Connected, or reconnected, to the camera
     Connected {
+        /// Details of the camera
         info: DeviceInfo,
+        /// The triggers that can send alerts
         triggers: Vec<TriggerItem>,
+        /// The root URL the camera was reached at, which is on its fallback address if the first failed
+        address: String,
     },
+    /// The connection failed, or couldn't be established
     Disconnected {
+        /// Why the connection failed
         error: String,
+        /// What kind of failure it was, for telling failures apart without reading `error`
+        #[serde(default)]
+        kind: DisconnectKind,
+        /// How long until the connection is retried. `None` if it won't be.
+        retry_in_secs: Option<u64>,
     },
+    /// An alert from the camera
     Alert(AlertItem),
+    /// A periodic re-scan found triggers that were enabled or disabled on the camera since connecting
+    TriggersUpdated {
+        /// Triggers that are new
+        added: Vec<TriggerItem>,
+        /// Triggers that are gone
+        removed: Vec<EventIdentifier>,
+    },
+    /// A picture taken when a trigger alerted
+    Snapshot {
+        /// The trigger that alerted
+        identifier: EventIdentifier,
+        /// The picture, usually a JPEG
+        image: Vec<u8>,
+    },
+    /// An alert the camera sent couldn't be parsed. The stream is still usable.
+    ParseFailure {
+        /// Why it couldn't be parsed
+        error: String,
+        /// What the camera sent
+        raw: String,
+    },
+    /// The unparsed contents of a part of the alert stream, sent before its alerts. Only sent by cameras
+    /// started with `forward_raw`.
+    RawPart(String),
+    /// Disconnected by a [pause command](CameraCommand::Pause), and won't connect again until resumed
+    Paused,
+    /// The state of a control, read after connecting or changing it. Only sent by cameras with
+    /// `expose_controls`.
+    Control(ControlState),
+    /// The camera accepted a [reboot command](CameraCommand::Reboot) and is disconnected until it's back
+    Rebooting {
+        /// How long until reconnecting is attempted
+        retry_in_secs: u64,
+    },
+    /// The camera couldn't be rebooted. It's still connected.
+    RebootFailed {
+        /// Why it couldn't be rebooted
+        error: String,
+    },
+    /// A control couldn't be changed
+    ControlFailed {
+        /// The setting that was being changed
+        control: ControlType,
+        /// The channel of the setting
+        channel: String,
+        /// Why it couldn't be changed
+        error: String,
+    },
+    /// The PTZ presets of the camera, read after connecting. Only sent by PTZ cameras with
+    /// `expose_controls`.
+    PtzPresets(Vec<PtzPreset>),
+    /// The device info changed while connected, e.g. after a firmware upgrade. Read every `info_refresh_secs`.
+    InfoUpdated(DeviceInfo),
+    /// How far the camera's clock is from HikSink's, read after connecting and every `clock_check_secs`
+    ClockDrift {
+        /// The camera's time minus HikSink's, in seconds
+        drift_secs: i64,
+    },
+    /// The camera couldn't be moved to a preset
+    PresetFailed {
+        /// The preset number or name that was asked for
+        preset: String,
+        /// Why it couldn't be moved
+        error: String,
+    },
+}
+
+/// Commands for a camera started with [`run_camera`], sent through its [`CameraHandle`]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CameraCommand {
+    /// Drops the connection and connects again straight away, skipping any retry delay
+    Reconnect,
+    /// Disconnects from the camera until it's resumed
+    Pause,
+    /// Connects to a paused camera again
+    Resume,
+    /// Reboots the camera, then reconnects once it's had time to go down
+    Reboot,
+    /// Switches a control on or off, then reads it back so its actual state is reported
+    SetControl {
+        /// The setting to change
+        control: ControlType,
+        /// The channel of the setting
+        channel: String,
+        /// Whether to switch it on
+        enabled: bool,
+    },
+    /// Moves a PTZ camera to one of its presets, by number or name
+    GotoPreset(String),
 }
 
-/// The camera manager handles reconnecting to a camera if it errors out and forwards all camera events to a shared queue
-pub fn run_camera(cam: ConfigCamera, queue: mpsc::Sender<CameraEvent>) {
+/// Controls a camera started with [`run_camera`]
+#[derive(Debug, Clone)]
+pub struct CameraHandle {
+    cancel: CancellationToken,
+    commands: mpsc::UnboundedSender<CameraCommand>,
+    info: watch::Receiver<Option<DeviceInfo>>,
+}
+
+impl CameraHandle {
+    /// Disconnects from the camera and stops its manager
+    pub fn stop(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Sends a command to the camera's manager. Returns `false` if the manager has stopped.
+    pub fn send(&self, command: CameraCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    /// The device info from the last time the camera was connected to, if it has been
+    pub fn device_info(&self) -> watch::Receiver<Option<DeviceInfo>> {
+        self.info.clone()
+    }
+}
+
+/// Delay between reconnection attempts after a connection or stream failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+/// Delay between reconnection attempts after the camera rejected our credentials.
+/// Hikvision cameras lock the account after a handful of failed logins, so this needs to be slow.
+const AUTH_FAILURE_RECONNECT_DELAY: Duration = Duration::from_secs(10 * 60);
+/// Delay before reconnecting to a rebooting camera. It keeps answering for a few seconds after accepting
+/// the reboot, so reconnecting straight away would find it about to go down.
+const REBOOT_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// The camera manager handles reconnecting to a camera if it errors out and forwards all camera events to a shared queue.
+/// The returned handle stops the manager, or sends it [commands](CameraCommand).
+/// With `forward_raw`, the unparsed contents of the alert stream are forwarded too.
+/// With a `capture`, the raw parts of the alert stream are saved to it.
+/// Each attempt to connect waits for a permit from `connects`.
+pub fn run_camera(
+    cam: ConfigCamera,
+    queue: EventQueue,
+    forward_raw: bool,
+    capture: Option<Capture>,
+    connects: ConnectLimit,
+) -> CameraHandle {
     let logging_span = info_span!("Camera coms", camera=%cam.name, id=%cam.identifier());
+    let cancel = CancellationToken::new();
+    let cancelled = cancel.clone();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let (info_tx, info_rx) = watch::channel(None);
     tokio::spawn(
         async move {
-            info!("Initiating camera connection...");
-            let mut cam = reconnect_cam(cam, &queue).await;
-            loop {
-                let next = cam.next_event().await;
-                match next {
-                    Ok(alert) => {
+            tokio::select! {
+                _ = cancelled.cancelled() => info!("Camera stopped, disconnecting"),
+                _ = manage_camera(cam, queue, forward_raw, capture, connects, commands_rx, info_tx) => {}
+            }
+        }
+        .instrument(logging_span),
+    );
+    CameraHandle {
+        cancel,
+        commands: commands_tx,
+        info: info_rx,
+    }
+}
+
+/// What the camera manager does next
+enum NextStep {
+    /// Connect once the delay has passed
+    Connect(Duration),
+    Pause,
+    Stop,
+}
+
+async fn manage_camera(
+    config: ConfigCamera,
+    queue: EventQueue,
+    forward_raw: bool,
+    capture: Option<Capture>,
+    connects: ConnectLimit,
+    mut commands: mpsc::UnboundedReceiver<CameraCommand>,
+    info: watch::Sender<Option<DeviceInfo>>,
+) {
+    info!("Initiating camera connection...");
+    let mut next = NextStep::Connect(Duration::ZERO);
+    loop {
+        next = match next {
+            NextStep::Connect(delay) => {
+                match connect(&config, &queue, &connects, &mut commands, delay).await {
+                    Ok(mut cam) => {
+                        info.send_replace(Some(cam.info.clone()));
+                        if let Some(capture) = &capture {
+                            cam.capture_to(capture.clone());
+                        }
+                        if cam.config.expose_controls && !send_controls(&mut cam, &queue).await {
+                            return;
+                        }
+                        stream_alerts(cam, &queue, forward_raw, &mut commands).await
+                    }
+                    Err(next) => next,
+                }
+            }
+            NextStep::Pause => pause(&config, &queue, &mut commands).await,
+            NextStep::Stop => return,
+        };
+    }
+}
+
+/// Connects to the camera after the delay, unless a command interrupts it. Reconnect commands start over
+/// without waiting out the delay or the retry delay.
+async fn connect(
+    config: &ConfigCamera,
+    queue: &EventQueue,
+    connects: &ConnectLimit,
+    commands: &mut mpsc::UnboundedReceiver<CameraCommand>,
+    mut delay: Duration,
+) -> Result<Camera, NextStep> {
+    loop {
+        let reconnect = reconnect_cam(config.clone(), queue, connects, delay);
+        delay = Duration::ZERO;
+        tokio::pin!(reconnect);
+        loop {
+            tokio::select! {
+                cam = &mut reconnect => return cam.ok_or(NextStep::Stop),
+                command = commands.recv() => match command {
+                    Some(CameraCommand::Reconnect) => {
+                        info!("Reconnect requested");
+                        break;
+                    }
+                    Some(CameraCommand::Pause) => return Err(NextStep::Pause),
+                    Some(CameraCommand::Resume) => continue,
+                    Some(CameraCommand::SetControl { control, .. }) => {
+                        warn!(%control, "Camera isn't connected, unable to change control");
+                    }
+                    Some(CameraCommand::Reboot) => warn!("Camera isn't connected, unable to reboot"),
+                    Some(CameraCommand::GotoPreset(preset)) => {
+                        warn!(preset = preset.as_str(), "Camera isn't connected, unable to go to preset");
+                    }
+                    None => return Err(NextStep::Stop),
+                },
+            }
+        }
+    }
+}
+
+/// Stays disconnected until resumed
+async fn pause(
+    config: &ConfigCamera,
+    queue: &EventQueue,
+    commands: &mut mpsc::UnboundedReceiver<CameraCommand>,
+) -> NextStep {
+    info!("Camera paused");
+    let sent = queue
+        .send(CameraEvent {
+            id: config.identifier().to_string(),
+            event: CameraEventType::Paused,
+        })
+        .await;
+    if sent.is_err() {
+        debug!("Camera shutting down...");
+        return NextStep::Stop;
+    }
+    loop {
+        match commands.recv().await {
+            Some(CameraCommand::Resume) | Some(CameraCommand::Reconnect) => {
+                info!("Camera resumed");
+                return NextStep::Connect(Duration::ZERO);
+            }
+            Some(CameraCommand::Pause) => continue,
+            Some(CameraCommand::SetControl { control, .. }) => {
+                warn!(%control, "Camera is paused, unable to change control");
+            }
+            Some(CameraCommand::Reboot) => warn!("Camera is paused, unable to reboot"),
+            Some(CameraCommand::GotoPreset(preset)) => {
+                warn!(
+                    preset = preset.as_str(),
+                    "Camera is paused, unable to go to preset"
+                );
+            }
+            None => return NextStep::Stop,
+        }
+    }
+}
+
+/// Forwards the alerts from a connected camera until the connection fails or a command drops it
+async fn stream_alerts(
+    mut cam: Camera,
+    queue: &EventQueue,
+    forward_raw: bool,
+    commands: &mut mpsc::UnboundedReceiver<CameraCommand>,
+) -> NextStep {
+    let mut last_snapshots: HashMap<EventIdentifier, Instant> = HashMap::new();
+    let mut rescan = cam.config.trigger_rescan_secs.map(|secs| {
+        let period = Duration::from_secs(secs);
+        tokio::time::interval_at(Instant::now() + period, period)
+    });
+    let mut clock_check = cam.config.clock_check_secs.map(|secs| {
+        let period = Duration::from_secs(secs);
+        tokio::time::interval_at(Instant::now() + period, period)
+    });
+    let mut info_refresh = cam.config.info_refresh_secs.map(|secs| {
+        let period = Duration::from_secs(secs);
+        tokio::time::interval_at(Instant::now() + period, period)
+    });
+    loop {
+        let next = tokio::select! {
+            next = next_event(&mut cam) => next,
+            command = commands.recv() => match command {
+                Some(CameraCommand::Reconnect) => {
+                    info!("Reconnect requested, dropping the connection");
+                    let _ = queue
+                        .send(CameraEvent {
+                            id: cam.config.identifier().to_string(),
+                            event: CameraEventType::Disconnected {
+                                error: "Reconnect requested".to_string(),
+                                kind: DisconnectKind::Requested,
+                                retry_in_secs: Some(0),
+                            },
+                        })
+                        .await;
+                    return NextStep::Connect(Duration::ZERO);
+                }
+                Some(CameraCommand::Pause) => return NextStep::Pause,
+                Some(CameraCommand::Resume) => continue,
+                Some(CameraCommand::SetControl { control, channel, enabled }) => {
+                    let event = match cam.set_control(control, &channel, enabled).await {
+                        Ok(enabled) => {
+                            info!(%control, channel = channel.as_str(), enabled, "Camera control changed");
+                            CameraEventType::Control(ControlState { control, channel, enabled })
+                        }
+                        Err(e) => {
+                            warn!(%control, channel = channel.as_str(), "Unable to change camera control: {}", e);
+                            CameraEventType::ControlFailed { control, channel, error: e.to_string() }
+                        }
+                    };
+                    let sent = queue
+                        .send(CameraEvent {
+                            id: cam.config.identifier().to_string(),
+                            event,
+                        })
+                        .await;
+                    if sent.is_err() {
+                        debug!("Camera shutting down...");
+                        return NextStep::Stop;
+                    }
+                    continue;
+                }
+                Some(CameraCommand::GotoPreset(preset)) => {
+                    match cam.goto_preset(&preset).await {
+                        Ok(()) => {
+                            info!(preset = preset.as_str(), "Camera moved to preset");
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!(preset = preset.as_str(), "Unable to go to preset: {}", e);
+                            let sent = queue
+                                .send(CameraEvent {
+                                    id: cam.config.identifier().to_string(),
+                                    event: CameraEventType::PresetFailed { preset, error: e.to_string() },
+                                })
+                                .await;
+                            if sent.is_err() {
+                                debug!("Camera shutting down...");
+                                return NextStep::Stop;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                Some(CameraCommand::Reboot) => {
+                    let event = match cam.reboot().await {
+                        Ok(()) => {
+                            info!("Camera rebooting");
+                            CameraEventType::Rebooting { retry_in_secs: REBOOT_RECONNECT_DELAY.as_secs() }
+                        }
+                        Err(e) => {
+                            warn!("Unable to reboot camera: {}", e);
+                            CameraEventType::RebootFailed { error: e.to_string() }
+                        }
+                    };
+                    let rebooting = matches!(event, CameraEventType::Rebooting { .. });
+                    let sent = queue
+                        .send(CameraEvent {
+                            id: cam.config.identifier().to_string(),
+                            event,
+                        })
+                        .await;
+                    if sent.is_err() {
+                        debug!("Camera shutting down...");
+                        return NextStep::Stop;
+                    }
+                    if rebooting {
+                        return NextStep::Connect(REBOOT_RECONNECT_DELAY);
+                    }
+                    continue;
+                }
+                None => return NextStep::Stop,
+            },
+            _ = next_tick(&mut info_refresh) => {
+                match cam.refresh_info().await {
+                    Ok(Some(info)) => {
+                        info!(firmware = info.firmware_version.as_str(), "Camera device info changed");
                         let sent = queue
                             .send(CameraEvent {
                                 id: cam.config.identifier().to_string(),
-                                event: CameraEventType::Alert(alert),
+                                event: CameraEventType::InfoUpdated(info),
                             })
                             .await;
                         if sent.is_err() {
                             debug!("Camera shutting down...");
-                            return;
+                            return NextStep::Stop;
                         }
                     }
-                    Err(e) => {
-                        warn!("Camera errored: {}. Attempting reconnection...", e);
-                        let _ = queue
+                    Ok(None) => debug!("Camera device info unchanged"),
+                    Err(e) => warn!("Unable to refresh camera device info: {}", e),
+                }
+                continue;
+            }
+            _ = next_tick(&mut clock_check) => {
+                match cam.clock_drift().await {
+                    Ok(drift_secs) => {
+                        let sent = queue
                             .send(CameraEvent {
                                 id: cam.config.identifier().to_string(),
-                                event: CameraEventType::Disconnected {
-                                    error: e.to_string(),
-                                },
+                                event: CameraEventType::ClockDrift { drift_secs },
+                            })
+                            .await;
+                        if sent.is_err() {
+                            debug!("Camera shutting down...");
+                            return NextStep::Stop;
+                        }
+                    }
+                    Err(e) => warn!("Unable to check the camera's clock: {}", e),
+                }
+                continue;
+            }
+            _ = next_tick(&mut rescan) => {
+                match cam.rescan_triggers().await {
+                    Ok((added, removed)) if !added.is_empty() || !removed.is_empty() => {
+                        info!(added = added.len(), removed = removed.len(), "Camera triggers changed");
+                        let sent = queue
+                            .send(CameraEvent {
+                                id: cam.config.identifier().to_string(),
+                                event: CameraEventType::TriggersUpdated { added, removed },
                             })
                             .await;
-                        cam = reconnect_cam(cam.config, &queue).await;
+                        if sent.is_err() {
+                            debug!("Camera shutting down...");
+                            return NextStep::Stop;
+                        }
+                    }
+                    Ok(_) => debug!("Camera triggers unchanged"),
+                    // The alert stream is still fine, so try again next time instead of reconnecting
+                    Err(e) => warn!("Unable to re-scan camera triggers: {}", e),
+                }
+                continue;
+            }
+        };
+        match next {
+            Ok((raw, alerts)) => {
+                if forward_raw {
+                    let sent = queue
+                        .send(CameraEvent {
+                            id: cam.config.identifier().to_string(),
+                            event: CameraEventType::RawPart(raw),
+                        })
+                        .await;
+                    if sent.is_err() {
+                        debug!("Camera shutting down...");
+                        return NextStep::Stop;
+                    }
+                }
+                for alert in alerts {
+                    if alert.active && cam.config.snapshot_on_alert {
+                        let min_interval =
+                            Duration::from_secs(cam.config.snapshot_min_interval_secs);
+                        let due = last_snapshots
+                            .get(&alert.identifier)
+                            .map(|last| last.elapsed() >= min_interval)
+                            .unwrap_or(true);
+                        if due {
+                            last_snapshots.insert(alert.identifier.clone(), Instant::now());
+                            cam.send_snapshot(alert.identifier.clone(), queue.clone());
+                        }
+                    }
+                    let sent = queue
+                        .send(CameraEvent {
+                            id: cam.config.identifier().to_string(),
+                            event: CameraEventType::Alert(alert),
+                        })
+                        .await;
+                    if sent.is_err() {
+                        debug!("Camera shutting down...");
+                        return NextStep::Stop;
                     }
                 }
             }
+            Err(CameraError::AlertUnparseable(error, raw)) => {
+                // Only this alert is lost, the stream itself is still fine
+                warn!("Unable to parse alert: {}", error);
+                let sent = queue
+                    .send(CameraEvent {
+                        id: cam.config.identifier().to_string(),
+                        event: CameraEventType::ParseFailure {
+                            error: error.to_string(),
+                            raw,
+                        },
+                    })
+                    .await;
+                if sent.is_err() {
+                    debug!("Camera shutting down...");
+                    return NextStep::Stop;
+                }
+            }
+            Err(e) => {
+                warn!("Camera errored: {}. Attempting reconnection...", e);
+                let _ = queue
+                    .send(CameraEvent {
+                        id: cam.config.identifier().to_string(),
+                        event: CameraEventType::Disconnected {
+                            error: e.to_string(),
+                            kind: e.kind(),
+                            retry_in_secs: Some(0),
+                        },
+                    })
+                    .await;
+                return NextStep::Connect(Duration::ZERO);
+            }
         }
-        .instrument(logging_span),
-    );
+    }
+}
+
+/// Reads the state of each control the camera has, and sends its PTZ presets. Controls that can't be
+/// read are left out, as not every camera supports them. Returns `false` if the queue has closed.
+async fn send_controls(cam: &mut Camera, queue: &EventQueue) -> bool {
+    for (control, channel) in cam.controls() {
+        let enabled = match cam.control(control, &channel).await {
+            Ok(enabled) => enabled,
+            Err(e) => {
+                debug!(%control, channel = channel.as_str(), "Camera control unavailable: {}", e);
+                continue;
+            }
+        };
+        let sent = queue
+            .send(CameraEvent {
+                id: cam.config.identifier().to_string(),
+                event: CameraEventType::Control(ControlState {
+                    control,
+                    channel,
+                    enabled,
+                }),
+            })
+            .await;
+        if sent.is_err() {
+            debug!("Camera shutting down...");
+            return false;
+        }
+    }
+    if !cam.presets.is_empty() {
+        let sent = queue
+            .send(CameraEvent {
+                id: cam.config.identifier().to_string(),
+                event: CameraEventType::PtzPresets(cam.presets.clone()),
+            })
+            .await;
+        if sent.is_err() {
+            debug!("Camera shutting down...");
+            return false;
+        }
+    }
+    true
+}
+
+/// Waits for the next alerts from the camera's stream, forever for cameras pushing their alerts instead
+async fn next_event(cam: &mut Camera) -> Result<(String, Vec<AlertItem>), CameraError> {
+    match cam.config.mode {
+        CameraMode::Stream => cam.next_event().await,
+        CameraMode::Push => futures::future::pending().await,
+    }
 }
 
-async fn reconnect_cam(cam: ConfigCamera, queue: &mpsc::Sender<CameraEvent>) -> Camera {
+/// Waits for the next tick of a periodic task (e.g. the trigger re-scan), forever if the task is disabled
+async fn next_tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Connects to the camera after the delay, retrying until successful. Returns `None` if retrying has been
+/// disabled for the failure.
+async fn reconnect_cam(
+    cam: ConfigCamera,
+    queue: &EventQueue,
+    connects: &ConnectLimit,
+    delay: Duration,
+) -> Option<Camera> {
+    tokio::time::sleep(delay).await;
+    let endpoints: Vec<ConfigCamera> = std::iter::once(cam.clone()).chain(cam.fallback()).collect();
     loop {
-        match Camera::load(cam.clone()).await {
+        let permit = connects.acquire().await;
+        // Cameras pushing their alerts are only connected to for their device info and triggers
+        let loaded = load_any(&endpoints, |endpoint| async move {
+            match endpoint.mode {
+                CameraMode::Stream => Camera::load(endpoint).await,
+                CameraMode::Push => Camera::probe(endpoint).await,
+            }
+        })
+        .await;
+        // Retries wait without holding up other cameras
+        drop(permit);
+        match loaded {
             Ok(c) => {
-                info!("Camera connection established");
+                let address = c.config.base_url();
+                info!(address = address.as_str(), "Camera connection established");
                 let _ = queue
                     .send(CameraEvent {
                         id: c.config.identifier().to_string(),
                         event: CameraEventType::Connected {
                             triggers: c.triggers.clone(),
                             info: c.info.clone(),
+                            address,
                         },
                     })
                     .await;
-                return c;
+                if let Some(drift_secs) = c.clock_drift {
+                    let _ = queue
+                        .send(CameraEvent {
+                            id: c.config.identifier().to_string(),
+                            event: CameraEventType::ClockDrift { drift_secs },
+                        })
+                        .await;
+                }
+                return Some(c);
+            }
+            Err(e) if e.is_auth_failure() => {
+                let action = if cam.retry_on_auth_failure {
+                    format!(
+                        "Retrying in {} minutes",
+                        AUTH_FAILURE_RECONNECT_DELAY.as_secs() / 60
+                    )
+                } else {
+                    "Retries are disabled for this camera".to_string()
+                };
+                error!(
+                    "Camera rejected the configured credentials: {}. Fix the username and password in the config. {}.",
+                    e, action
+                );
+                let _ = queue
+                    .send(CameraEvent {
+                        id: cam.identifier().to_string(),
+                        event: CameraEventType::Disconnected {
+                            error: format!(
+                                "Reconnection failure: {}. Check the camera's username and password in the config. {}.",
+                                e, action
+                            ),
+                            kind: e.kind(),
+                            retry_in_secs: cam
+                                .retry_on_auth_failure
+                                .then(|| AUTH_FAILURE_RECONNECT_DELAY.as_secs()),
+                        },
+                    })
+                    .await;
+                if !cam.retry_on_auth_failure {
+                    return None;
+                }
+                tokio::time::sleep(AUTH_FAILURE_RECONNECT_DELAY).await;
             }
             Err(e) => {
                 error!("Error reconnecting to camera {}", e);
+                let error = if endpoints.len() > 1 {
+                    format!(
+                        "Reconnection failure at both the address and the fallback address: {}",
+                        e
+                    )
+                } else {
+                    format!("Reconnection failure: {}", e)
+                };
                 let _ = queue
                     .send(CameraEvent {
                         id: cam.identifier().to_string(),
                         event: CameraEventType::Disconnected {
-                            error: format!("Reconnection failure: {}", e),
+                            error,
+                            kind: e.kind(),
+                            retry_in_secs: Some(RECONNECT_DELAY.as_secs()),
                         },
                     })
                     .await;
-                tokio::time::sleep(tokio::time::Duration::from_millis(3000)).await;
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Tries each address of a camera in turn, the configured one first, returning the first that connects.
+/// Gives up on a rejected login, as the other addresses lead to the same camera and would only lock the account.
+async fn load_any<T, F, Fut>(endpoints: &[ConfigCamera], mut load: F) -> Result<T, CameraError>
+where
+    F: FnMut(ConfigCamera) -> Fut,
+    Fut: std::future::Future<Output = Result<T, CameraError>>,
+{
+    let mut error = CameraError::ConnectionClosed;
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        match load(endpoint.clone()).await {
+            Ok(loaded) => return Ok(loaded),
+            Err(e) if e.is_auth_failure() => return Err(e),
+            Err(e) => {
+                if i + 1 < endpoints.len() {
+                    warn!(
+                        address = endpoint.base_url().as_str(),
+                        "Unable to connect, trying the next address: {}", e
+                    );
+                }
+                error = e;
             }
         }
     }
+    Err(error)
 }
 
+type AlertStream = Pin<
+    Box<
+        dyn futures::Stream<Item = Result<multipart_stream::Part, multipart_stream::parser::Error>>
+            + Send,
+    >,
+>;
+
+/// A connection to a camera's ISAPI, authenticated with digest auth
 pub struct Camera {
+    /// The config the camera was loaded with. When connected through the fallback address, that's
+    /// the address in here, so everything sent to the camera goes the same way.
     pub config: ConfigCamera,
+    /// Details of the camera, read when connecting
     pub info: DeviceInfo,
+    /// The triggers that can send alerts, including manually configured ones
     pub triggers: Vec<TriggerItem>,
-    stream: Pin<
-        Box<
-            dyn futures::Stream<
-                    Item = Result<multipart_stream::Part, multipart_stream::parser::Error>,
-                > + Send,
-        >,
-    >,
+    /// The ids of the alarm output ports. Only loaded for cameras with `expose_controls`.
+    pub outputs: Vec<String>,
+    /// The presets of PTZ cameras. Only loaded for cameras with `expose_controls`.
+    pub presets: Vec<PtzPreset>,
+    /// How far the camera's clock was from HikSink's when connecting, in seconds. `None` if it couldn't be read.
+    pub clock_drift: Option<i64>,
+    /// The alert stream, once subscribed
+    stream: Option<AlertStream>,
+    /// Number of parts in the stream that weren't alerts (e.g. pictures)
+    skipped_parts: u64,
+    /// Where the raw parts of the alert stream are saved, if anywhere
+    capture: Option<Capture>,
+    client: reqwest::Client,
 }
 
 impl Camera {
+    /// Connects to the camera and subscribes to its alerts
     pub async fn load(config: ConfigCamera) -> Result<Camera, CameraError> {
+        let mut camera = Self::probe(config).await?;
+        camera.subscribe().await?;
+        Ok(camera)
+    }
+
+    /// Loads the camera's device info and triggers without subscribing to alerts
+    pub async fn probe(config: ConfigCamera) -> Result<Camera, CameraError> {
         let client = reqwest::Client::builder()
             .tcp_keepalive(Duration::from_secs(60))
             .build()
             .map_err(CameraError::ConnectionError)?;
-        let info = {
-            let info_text = Self::camera_get_url("/ISAPI/System/deviceInfo", &client, &config)
-                .await?
-                .text()
-                .await
-                .map_err(CameraError::CameraInvalidResponseBody)?;
-            DeviceInfo::parse(&info_text)?
-        };
+        let info = Self::load_info(&client, &config).await?;
 
-        let triggers = {
-            let triggers_text = Self::camera_get_url("/ISAPI/Event/triggers", &client, &config)
-                .await?
-                .text()
+        let triggers = Self::scan_triggers(&client, &config, &info).await?;
+        let outputs = if config.expose_controls {
+            // Plenty of cameras have no outputs at all
+            Self::load_outputs(&client, &config)
                 .await
-                .map_err(CameraError::CameraInvalidResponseBody)?;
-            TriggerItem::parse(&triggers_text)?
+                .unwrap_or_else(|e| {
+                    debug!("Unable to load alarm outputs: {}", e);
+                    Vec::new()
+                })
+        } else {
+            Vec::new()
         };
-
-        let stream = {
-            let res =
-                Self::camera_get_url("/ISAPI/Event/notification/alertStream", &client, &config)
-                    .await?;
-            let content_type: mime::Mime = res
-                .headers()
-                .get(header::CONTENT_TYPE)
-                .ok_or_else(|| {
-                    CameraError::StreamInvalid("Content type header missing on stream".into())
-                })?
-                .to_str()
-                .map_err(|e| {
-                    CameraError::StreamInvalid(format!("Content type header invalid string: {}", e))
-                })?
-                .parse()
-                .map_err(|e| {
-                    CameraError::StreamInvalid(format!("Content type invalid format: {}", e))
-                })?;
-            if content_type.type_() != "multipart" {
-                return Err(CameraError::StreamInvalid(format!(
-                    "Content type on stream should have been multipart. Instead it was {}",
-                    content_type.type_()
-                )));
-            }
-            let boundary = content_type.get_param(mime::BOUNDARY).ok_or_else(|| {
-                CameraError::StreamInvalid("Multipart stream has no boundary set".to_string())
-            })?;
-
-            Box::pin(multipart_stream::parse(
-                res.bytes_stream(),
-                boundary.as_str(),
-            ))
+        let presets = if config.expose_controls {
+            // Only PTZ cameras have presets, the rest don't know the path
+            Self::load_presets(&client, &config)
+                .await
+                .unwrap_or_else(|e| {
+                    debug!("Unable to load PTZ presets: {}", e);
+                    Vec::new()
+                })
+        } else {
+            Vec::new()
         };
+        let clock_drift = Self::load_clock_drift(&client, &config)
+            .await
+            .map_err(|e| debug!("Unable to read the camera's clock: {}", e))
+            .ok();
 
         Ok(Camera {
             info,
             config,
             triggers,
-            stream,
+            outputs,
+            presets,
+            clock_drift,
+            stream: None,
+            skipped_parts: 0,
+            capture: None,
+            client,
         })
     }
 
+    /// Opens the alert stream, so alerts can be read with [`Camera::next_event`]
+    pub async fn subscribe(&mut self) -> Result<(), CameraError> {
+        self.subscribe_recording(None).await
+    }
+
+    /// Same as [`Camera::subscribe`], but also writes the raw alert stream to `recording`, starting
+    /// with its content type header, so it can be replayed later
+    pub async fn subscribe_recording(
+        &mut self,
+        mut recording: Option<std::fs::File>,
+    ) -> Result<(), CameraError> {
+        let mut headers = header::HeaderMap::new();
+        match self.config.alert_format {
+            AlertFormat::Xml => {
+                headers.insert(header::ACCEPT, "application/xml".parse().unwrap());
+            }
+            AlertFormat::Json => {
+                headers.insert(header::ACCEPT, "application/json".parse().unwrap());
+            }
+            AlertFormat::Auto => {}
+        }
+        let res = Self::camera_get_url_with_headers(
+            "/ISAPI/Event/notification/alertStream",
+            &self.client,
+            &self.config,
+            headers,
+        )
+        .await?;
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .ok_or_else(|| {
+                CameraError::StreamInvalid("Content type header missing on stream".into())
+            })?
+            .to_str()
+            .map_err(|e| {
+                CameraError::StreamInvalid(format!("Content type header invalid string: {}", e))
+            })?
+            .to_string();
+        let boundary = stream_boundary(&content_type)?;
+
+        if let Some(file) = &mut recording {
+            write!(file, "Content-Type: {}\r\n\r\n", content_type)
+                .map_err(|e| CameraError::RecordingFailed(e.to_string()))?;
+        }
+        let bytes = res.bytes_stream().inspect(move |chunk| {
+            if let (Some(file), Ok(chunk)) = (&mut recording, chunk) {
+                if let Err(e) = file.write_all(chunk) {
+                    error!("Unable to write recording: {}", e);
+                }
+            }
+        });
+        self.stream = Some(Box::pin(multipart_stream::parse(bytes, &boundary)));
+        Ok(())
+    }
+
+    /// Scans the camera's triggers, adding the manually configured ones and channel names
+    async fn scan_triggers(
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+        info: &DeviceInfo,
+    ) -> Result<Vec<TriggerItem>, CameraError> {
+        let mut triggers = match Self::load_triggers(client, config).await {
+            Ok(triggers) => triggers,
+            // Configured triggers are a workaround for cameras with a broken trigger list, so carry on without it
+            Err(e) if !config.triggers.is_empty() => {
+                warn!(
+                    "Unable to scan triggers, only using the configured triggers: {}",
+                    e
+                );
+                Vec::new()
+            }
+            Err(e) => return Err(e),
+        };
+        for identifier in config.manual_triggers() {
+            if !triggers.iter().any(|t| t.identifier == identifier) {
+                triggers.push(identifier.into());
+            }
+        }
+
+        // Recorders know the names of the cameras connected to each channel
+        if info.is_recorder() {
+            match Self::load_channel_names(client, config).await {
+                Ok(names) => {
+                    for trigger in &mut triggers {
+                        trigger.channel_name = trigger
+                            .identifier
+                            .channel
+                            .as_ref()
+                            .and_then(|c| names.get(c).cloned());
+                    }
+                }
+                Err(e) => warn!(
+                    "Unable to fetch channel names, using channel numbers: {}",
+                    e
+                ),
+            }
+        }
+
+        if triggers
+            .iter()
+            .any(|t| t.identifier.event_type == EventType::Io)
+        {
+            match Self::load_inputs(client, config).await {
+                Ok(names) => {
+                    for trigger in &mut triggers {
+                        if trigger.identifier.event_type == EventType::Io {
+                            trigger.port_name = trigger
+                                .identifier
+                                .channel
+                                .as_ref()
+                                .and_then(|c| names.get(c).cloned());
+                        }
+                    }
+                }
+                Err(e) => warn!(
+                    "Unable to fetch alarm input names, using port numbers: {}",
+                    e
+                ),
+            }
+        }
+        Ok(triggers)
+    }
+
+    async fn load_info(
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+    ) -> Result<DeviceInfo, CameraError> {
+        let info_text = Self::camera_get_url("/ISAPI/System/deviceInfo", client, config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)?;
+        Ok(DeviceInfo::parse(&info_text)?)
+    }
+
+    /// Reads the device info again, returning it if it changed since it was last read
+    pub async fn refresh_info(&mut self) -> Result<Option<DeviceInfo>, CameraError> {
+        let info = Self::load_info(&self.client, &self.config).await?;
+        if info == self.info {
+            return Ok(None);
+        }
+        self.info = info.clone();
+        Ok(Some(info))
+    }
+
+    /// Reads the camera's clock, returning how far ahead of HikSink's it is in seconds
+    pub async fn clock_drift(&mut self) -> Result<i64, CameraError> {
+        Self::load_clock_drift(&self.client, &self.config).await
+    }
+
+    async fn load_clock_drift(
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+    ) -> Result<i64, CameraError> {
+        let time_text = Self::camera_get_url("/ISAPI/System/time", client, config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)?;
+        let camera_time = parse_time(&time_text)?;
+        Ok((camera_time.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_seconds())
+    }
+
+    /// Scans the camera's triggers again, returning the ones that were added and removed since the last scan
+    pub async fn rescan_triggers(
+        &mut self,
+    ) -> Result<(Vec<TriggerItem>, Vec<EventIdentifier>), CameraError> {
+        let triggers = Self::scan_triggers(&self.client, &self.config, &self.info).await?;
+        let changes = diff_triggers(&self.triggers, &triggers);
+        self.triggers = triggers;
+        Ok(changes)
+    }
+
+    async fn load_triggers(
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+    ) -> Result<Vec<TriggerItem>, CameraError> {
+        let triggers_text = Self::camera_get_url("/ISAPI/Event/triggers", client, config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)?;
+        Ok(TriggerItem::parse(&triggers_text)?)
+    }
+
+    /// Fetches the names of a recorder's IP and analog channels, keyed by the channel id.
+    /// Recorders usually only support one of the two lists, so this only fails if neither could be loaded.
+    async fn load_channel_names(
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+    ) -> Result<HashMap<String, String>, CameraError> {
+        let mut names = HashMap::new();
+        let mut error = None;
+        for path in [
+            "/ISAPI/System/Video/inputs/channels",
+            "/ISAPI/ContentMgmt/InputProxy/channels",
+        ] {
+            match Self::load_channels(path, client, config).await {
+                Ok(channels) => names.extend(channels.into_iter().map(|c| (c.id, c.name))),
+                Err(e) => {
+                    debug!("Unable to fetch channels from {}: {}", path, e);
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if names.is_empty() => Err(e),
+            _ => Ok(names),
+        }
+    }
+
+    async fn load_channels(
+        path: &str,
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+    ) -> Result<Vec<InputChannel>, CameraError> {
+        let channels_text = Self::camera_get_url(path, client, config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)?;
+        Ok(InputChannel::parse(&channels_text)?)
+    }
+
+    /// The controls the camera might have: motion detection on each channel with a motion trigger,
+    /// each alarm output, and the supplement light of ColorVu cameras. Only some cameras have a
+    /// supplement light, the rest fail to read it.
+    pub fn controls(&self) -> Vec<(ControlType, String)> {
+        let mut controls: Vec<_> = self
+            .triggers
+            .iter()
+            .filter(|t| t.identifier.event_type == EventType::Motion)
+            .filter_map(|t| t.identifier.channel.clone())
+            .map(|channel| (ControlType::MotionDetection, channel))
+            .collect();
+        controls.dedup();
+        controls.extend(
+            self.outputs
+                .iter()
+                .map(|id| (ControlType::AlarmOutput, id.clone())),
+        );
+        controls.push((ControlType::SupplementLight, "1".to_string()));
+        controls
+    }
+
+    /// Reads whether a control is switched on
+    pub async fn control(
+        &mut self,
+        control: ControlType,
+        channel: &str,
+    ) -> Result<bool, CameraError> {
+        let text = Self::camera_get_url(&control.path(channel), &self.client, &self.config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)?;
+        Ok(control.parse_enabled(&text)?)
+    }
+
+    /// Switches a control on or off, returning whether it's on according to the camera afterwards.
+    /// Alarm outputs return the state that was set.
+    pub async fn set_control(
+        &mut self,
+        control: ControlType,
+        channel: &str,
+        enabled: bool,
+    ) -> Result<bool, CameraError> {
+        let current = Self::camera_get_url(&control.path(channel), &self.client, &self.config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)?;
+        let body = control.set_enabled(&current, enabled)?;
+        let url = format!("{}{}", self.config.base_url(), control.set_path(channel));
+        request_url(
+            &self.client,
+            reqwest::Method::PUT,
+            &url,
+            &self.config.username,
+            &self.config.password,
+            header::HeaderMap::new(),
+            Some(body),
+        )
+        .await?;
+        match control {
+            // Cameras can accept a change without applying it, so report what they actually did
+            ControlType::MotionDetection | ControlType::SupplementLight => {
+                self.control(control, channel).await
+            }
+            // Pulsed outputs turn themselves off again, so the status says little straight away
+            ControlType::AlarmOutput => Ok(enabled),
+        }
+    }
+
+    async fn load_outputs(
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+    ) -> Result<Vec<String>, CameraError> {
+        let outputs_text = Self::camera_get_url("/ISAPI/System/IO/outputs", client, config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)?;
+        Ok(parse_outputs(&outputs_text)?)
+    }
+
+    async fn load_inputs(
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+    ) -> Result<HashMap<String, String>, CameraError> {
+        let inputs_text = Self::camera_get_url("/ISAPI/System/IO/inputs", client, config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)?;
+        Ok(parse_inputs(&inputs_text)?)
+    }
+
+    async fn load_presets(
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+    ) -> Result<Vec<PtzPreset>, CameraError> {
+        let presets_text =
+            Self::camera_get_url("/ISAPI/PTZCtrl/channels/1/presets", client, config)
+                .await?
+                .text()
+                .await
+                .map_err(CameraError::CameraInvalidResponseBody)?;
+        Ok(PtzPreset::parse(&presets_text)?)
+    }
+
+    /// Moves the camera to one of its [presets](Camera::presets), by number or name
+    pub async fn goto_preset(&mut self, preset: &str) -> Result<(), CameraError> {
+        let id = match PtzPreset::find(&self.presets, preset) {
+            Some(preset) => preset.id.clone(),
+            None => return Err(CameraError::PresetUnknown(preset.to_string())),
+        };
+        let url = format!(
+            "{}/ISAPI/PTZCtrl/channels/1/presets/{}/goto",
+            self.config.base_url(),
+            id
+        );
+        request_url(
+            &self.client,
+            reqwest::Method::PUT,
+            &url,
+            &self.config.username,
+            &self.config.password,
+            header::HeaderMap::new(),
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Tells the camera to reboot. The alert stream will end shortly after.
+    pub async fn reboot(&mut self) -> Result<(), CameraError> {
+        let url = format!("{}/ISAPI/System/reboot", self.config.base_url());
+        request_url(
+            &self.client,
+            reqwest::Method::PUT,
+            &url,
+            &self.config.username,
+            &self.config.password,
+            header::HeaderMap::new(),
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Fetches the body of a page of the camera's API unparsed, e.g. to show users what the camera sent
+    pub async fn get_text(config: &ConfigCamera, path: &str) -> Result<String, CameraError> {
+        let client = reqwest::Client::new();
+        Self::camera_get_url(path, &client, config)
+            .await?
+            .text()
+            .await
+            .map_err(CameraError::CameraInvalidResponseBody)
+    }
+
     /// Get a full http://<url></path>. e.g. path should be `/ISAPI/Event/triggers`
     async fn camera_get_url(
         path: &str,
         client: &reqwest::Client,
         config: &ConfigCamera,
     ) -> Result<Response, CameraError> {
-        let url = format!(
-            "http://{}{}{}",
-            config.address,
-            config.port.map(|p| format!(":{}", p)).unwrap_or_default(),
-            path
+        Self::camera_get_url_with_headers(path, client, config, header::HeaderMap::new()).await
+    }
+
+    /// Same as [`Camera::camera_get_url`], but sends additional headers with the request
+    async fn camera_get_url_with_headers(
+        path: &str,
+        client: &reqwest::Client,
+        config: &ConfigCamera,
+        headers: header::HeaderMap,
+    ) -> Result<Response, CameraError> {
+        let url = format!("{}{}", config.base_url(), path);
+        get_url(client, &url, &config.username, &config.password, headers).await
+    }
+
+    /// Fetches a picture for the alerting trigger in the background and forwards it to the queue.
+    /// Failures are only logged so they can't interfere with the alert itself.
+    pub fn send_snapshot(&self, identifier: EventIdentifier, queue: EventQueue) {
+        let client = self.client.clone();
+        let config = self.config.clone();
+        tokio::spawn(
+            async move {
+                // Video channels are numbered from 1, with the stream number (01 being the main stream) appended
+                let path = format!(
+                    "/ISAPI/Streaming/channels/{}01/picture",
+                    identifier.channel.as_deref().unwrap_or("1")
+                );
+                let image = match Self::camera_get_url(&path, &client, &config).await {
+                    Ok(res) => res
+                        .bytes()
+                        .await
+                        .map_err(CameraError::CameraInvalidResponseBody),
+                    Err(e) => Err(e),
+                };
+                match image {
+                    Ok(image) => {
+                        let _ = queue
+                            .send(CameraEvent {
+                                id: config.identifier().to_string(),
+                                event: CameraEventType::Snapshot {
+                                    identifier,
+                                    image: image.to_vec(),
+                                },
+                            })
+                            .await;
+                    }
+                    Err(e) => {
+                        let trigger = config
+                            .trigger_name(&identifier)
+                            .map(String::from)
+                            .unwrap_or_else(|| identifier.to_string());
+                        warn!(%trigger, "Unable to fetch snapshot: {}", e)
+                    }
+                }
+            }
+            .in_current_span(),
         );
-        get_url(client, &url, &config.username, &config.password).await
     }
 
-    pub async fn next_event(&mut self) -> Result<AlertItem, CameraError> {
-        let next = self
-            .stream
-            .next()
-            .await
-            .ok_or(CameraError::ConnectionClosed)?
-            .map_err(|e| {
-                CameraError::StreamInvalid(format!("Couldn't get next part of stream: {}", e))
-            })?;
-        let part_str = String::from_utf8(next.body.to_vec()).map_err(|e| {
-            CameraError::StreamInvalid(format!("Stream returned non-UTF-8 text: {}", e))
-        })?;
-        trace!(cam=?self.config.identifier(), contents=?part_str, "Camera Alert");
-        Ok(AlertItem::parse(&part_str)?)
+    /// Saves the raw parts of the alert stream read by [`Camera::next_event`]
+    pub fn capture_to(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
+    /// Waits for the next part of the alert stream, returning its unparsed contents and the alerts in it
+    pub async fn next_event(&mut self) -> Result<(String, Vec<AlertItem>), CameraError> {
+        loop {
+            let next = self
+                .stream
+                .as_mut()
+                .ok_or(CameraError::ConnectionClosed)?
+                .next()
+                .await
+                .ok_or(CameraError::ConnectionClosed)?
+                .map_err(|e| {
+                    CameraError::StreamInvalid(format!("Couldn't get next part of stream: {}", e))
+                })?;
+            match parse_part(&next.headers, &next.body, self.config.alert_format) {
+                Some(parsed) => {
+                    if let Some(capture) = &self.capture {
+                        capture.part(self.config.identifier(), &next.body, parsed.is_err());
+                    }
+                    return parsed;
+                }
+                None => {
+                    // Cameras with pictures attached to notifications interleave them with the alerts
+                    self.skipped_parts += 1;
+                    trace!(
+                        cam=?self.config.identifier(),
+                        content_type=?next.headers.get(header::CONTENT_TYPE),
+                        skipped=self.skipped_parts,
+                        "Skipping non-alert part",
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Reads the multipart boundary from the content type of the alert stream
+pub fn stream_boundary(content_type: &str) -> Result<String, CameraError> {
+    let content_type: mime::Mime = content_type
+        .parse()
+        .map_err(|e| CameraError::StreamInvalid(format!("Content type invalid format: {}", e)))?;
+    if content_type.type_() != "multipart" {
+        return Err(CameraError::StreamInvalid(format!(
+            "Content type on stream should have been multipart. Instead it was {}",
+            content_type.type_()
+        )));
+    }
+    content_type
+        .get_param(mime::BOUNDARY)
+        .map(|boundary| boundary.to_string())
+        .ok_or_else(|| {
+            CameraError::StreamInvalid("Multipart stream has no boundary set".to_string())
+        })
+}
+
+/// Parses a part of the alert stream, given its headers and body, returning its decoded contents
+/// and the alerts in it. Returns `None` for parts that aren't alerts, e.g. pictures or empty keep-alives.
+pub fn parse_part(
+    headers: &header::HeaderMap,
+    body: &[u8],
+    format: AlertFormat,
+) -> Option<Result<(String, Vec<AlertItem>), CameraError>> {
+    let format = part_format(headers, body, format);
+    if format == PartFormat::Unsupported {
+        return None;
+    }
+    let (part_str, had_errors) = decode_part(headers, body);
+    if is_keep_alive(&part_str) {
+        return None;
+    }
+    if had_errors {
+        trace!(raw=?body, "Alert contained invalid characters, which were replaced");
+    }
+    trace!(contents=?part_str, "Camera Alert");
+    let parsed = match format {
+        PartFormat::Json => AlertItem::parse_json(&part_str).map(|alert| vec![alert]),
+        _ => AlertItem::parse_all(&part_str),
+    };
+    Some(match parsed {
+        Ok(alerts) => Ok((part_str, alerts)),
+        Err(e) => Err(CameraError::AlertUnparseable(e, part_str)),
+    })
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum PartFormat {
+    Xml,
+    Json,
+    /// Not an alert, e.g. an attached picture
+    Unsupported,
+}
+
+/// Determines the format of a part of the alert stream. The part's content type is preferred since
+/// some cameras send JSON even when XML was requested.
+fn part_format(headers: &header::HeaderMap, body: &[u8], format: AlertFormat) -> PartFormat {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .and_then(|c| c.parse::<mime::Mime>().ok());
+    match content_type {
+        Some(content_type) => {
+            if content_type.subtype() == mime::JSON || content_type.suffix() == Some(mime::JSON) {
+                PartFormat::Json
+            } else if content_type.subtype() == mime::XML
+                || content_type.suffix() == Some(mime::XML)
+                || content_type.type_() == mime::TEXT
+            {
+                PartFormat::Xml
+            } else {
+                PartFormat::Unsupported
+            }
+        }
+        None => match format {
+            AlertFormat::Xml => PartFormat::Xml,
+            AlertFormat::Json => PartFormat::Json,
+            AlertFormat::Auto => {
+                let first = body.iter().find(|b| !b.is_ascii_whitespace());
+                if first == Some(&b'{') {
+                    PartFormat::Json
+                } else {
+                    PartFormat::Xml
+                }
+            }
+        },
+    }
+}
+
+/// Decodes the text of a part using the charset declared in its content type or XML prolog, defaulting to UTF-8.
+/// Invalid characters are replaced rather than failing, in which case `true` is returned alongside the text.
+fn decode_part(headers: &header::HeaderMap, body: &[u8]) -> (String, bool) {
+    let header_charset = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .and_then(|c| c.parse::<mime::Mime>().ok())
+        .and_then(|c| c.get_param(mime::CHARSET).map(|c| c.to_string()));
+    let encoding = header_charset
+        .or_else(|| xml_prolog_encoding(body))
+        .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+    let (text, _, had_errors) = encoding.decode(body);
+    (text.into_owned(), had_errors)
+}
+
+/// Finds the encoding declared in an XML prolog, e.g. `<?xml version="1.0" encoding="GB2312"?>`
+fn xml_prolog_encoding(body: &[u8]) -> Option<String> {
+    let start = body.iter().position(|b| !b.is_ascii_whitespace())?;
+    let body = &body[start..];
+    if !body.starts_with(b"<?xml") {
+        return None;
     }
+    let end = body.windows(2).position(|w| w == b"?>")?;
+    // The prolog is always ASCII, whatever the encoding of the rest of the document
+    let prolog = std::str::from_utf8(&body[..end]).ok()?;
+    let rest = &prolog[prolog.find("encoding")? + "encoding".len()..];
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &rest[1..];
+    Some(value[..value.find(quote)?].to_string())
+}
+
+/// Checks whether a 401 response body indicates the account has been locked after too many failed logins
+fn is_lockout_response(body: &str) -> bool {
+    body.contains("userLocked") || body.contains("<lockStatus>lock</lockStatus>")
+}
+
+/// Compares two trigger scans, returning the triggers that were added and the identifiers of those that were removed
+fn diff_triggers(
+    previous: &[TriggerItem],
+    current: &[TriggerItem],
+) -> (Vec<TriggerItem>, Vec<EventIdentifier>) {
+    let added = current
+        .iter()
+        .filter(|t| !previous.iter().any(|p| p.identifier == t.identifier))
+        .cloned()
+        .collect();
+    let removed = previous
+        .iter()
+        .filter(|p| !current.iter().any(|t| t.identifier == p.identifier))
+        .map(|p| p.identifier.clone())
+        .collect();
+    (added, removed)
 }
 
 async fn get_url(
@@ -220,18 +1500,50 @@ async fn get_url(
     url: &str,
     username: &str,
     password: &str,
+    headers: header::HeaderMap,
+) -> Result<Response, CameraError> {
+    request_url(
+        client,
+        reqwest::Method::GET,
+        url,
+        username,
+        password,
+        headers,
+        None,
+    )
+    .await
+}
+
+/// Sends a request with digest auth. The body, if any, is sent with both the challenge and the
+/// authenticated request.
+async fn request_url(
+    client: &reqwest::Client,
+    method: reqwest::Method,
+    url: &str,
+    username: &str,
+    password: &str,
+    headers: header::HeaderMap,
+    body: Option<String>,
 ) -> Result<Response, CameraError> {
     let url = reqwest::Url::parse(url).map_err(|e| CameraError::UrlError(e.to_string()))?;
-    let res = client
-        .get(url.clone())
+    let request = |auth: Option<String>| {
+        let mut request = client
+            .request(method.clone(), url.clone())
+            .headers(headers.clone());
+        if let Some(body) = &body {
+            request = request.body(body.clone());
+        }
+        if let Some(auth) = auth {
+            request = request.header("Authorization", auth);
+        }
+        request
+    };
+    let res = request(None)
         .send()
         .await
         .map_err(CameraError::ConnectionError)?;
     if res.status() != 401 {
-        return Err(CameraError::AuthenticationFailed(format!(
-            "Could not get digest from server. Status code: {}",
-            res.status()
-        )));
+        return Err(CameraError::UnexpectedStatus(res.status()));
     }
 
     let auth = {
@@ -244,7 +1556,8 @@ async fn get_url(
             .ok_or_else(|| {
                 CameraError::AuthenticationFailed("Digest not supported by camera.".into())
             })?;
-        let context = AuthContext::new(username, password, url.path());
+        let mut context = AuthContext::new(username, password, url.path());
+        context.method = method.as_str().into();
         let mut promt = digest_auth::parse(resp_auth).map_err(|e| {
             CameraError::AuthenticationFailed(format!(
                 "Digest from camera could not be parsed: {}",
@@ -256,13 +1569,16 @@ async fn get_url(
         })?
     };
 
-    let res = client
-        .get(url)
-        .header("Authorization", auth.to_header_string())
+    let res = request(Some(auth.to_header_string()))
         .send()
         .await
         .map_err(CameraError::ConnectionError)?;
     if res.status() == 401 {
+        // Hikvision reports a locked account in the body of the rejection
+        let body = res.text().await.unwrap_or_default();
+        if is_lockout_response(&body) {
+            return Err(CameraError::AccountLocked);
+        }
         return Err(CameraError::AuthenticationFailed(
             "Username or password incorrect".into(),
         ));
@@ -273,48 +1589,680 @@ async fn get_url(
         ));
     }
     if res.status() != 200 {
-        return Err(CameraError::AuthenticationFailed(format!(
-            "Invalid status code after auth token sent: {:?}",
-            res.status()
-        )));
+        return Err(CameraError::UnexpectedStatus(res.status()));
     }
     Ok(res)
 }
 
 quick_error! {
+    /// Why talking to a camera failed
     #[derive(Debug)]
     pub enum CameraError {
+        /// Configured address doesn't form a valid URL
         UrlError(error: String) {
             display("Unable to parse URL: {}", error)
         }
+        /// The request couldn't be sent or the connection dropped
         ConnectionError(error: reqwest::Error) {
             display("Unable to connect to camera: {}", error)
             source(error)
         }
+        /// The response body couldn't be read
         CameraInvalidResponseBody(error: reqwest::Error) {
             display("Camera returned mangled response body: {}", error)
             source(error)
         }
+        /// The credentials were rejected
         AuthenticationFailed (error: String) {
             display("Could not authenticate with camera: {}", error)
         }
+        /// Too many failed logins, the camera refuses to try more for a while
+        AccountLocked {
+            display("Camera has locked the account after too many failed logins")
+        }
+        /// Any other unsuccessful response
+        UnexpectedStatus(status: reqwest::StatusCode) {
+            display("Camera returned an unexpected status code: {}", status)
+        }
+        /// The alert stream isn't a multipart stream, or broke mid-stream
         StreamInvalid(error: String) {
             display("Stream could not be resolved to a multipart form: {}", error)
         }
+        /// The alert stream ended
         ConnectionClosed {
             display("Camera closed connection")
         }
+        /// `/ISAPI/System/deviceInfo` couldn't be parsed
         DeviceInfoInvalid(error: DeviceInfoParseError) {
             from()
             source(error)
         }
+        /// `/ISAPI/Event/triggers` couldn't be parsed
         TriggersInvalid(error: TriggerParseError) {
             from()
             source(error)
         }
+        /// The channel list couldn't be parsed
+        ChannelsInvalid(error: ChannelParseError) {
+            from()
+            source(error)
+        }
+        /// A control's settings couldn't be parsed
+        ControlInvalid(error: ControlParseError) {
+            from()
+            source(error)
+        }
+        /// The PTZ preset list couldn't be parsed
+        PresetsInvalid(error: PtzParseError) {
+            from()
+            source(error)
+        }
+        /// `/ISAPI/System/time` couldn't be parsed
+        TimeInvalid(error: TimeParseError) {
+            from()
+            source(error)
+        }
+        /// The camera has no preset with the number or name asked for
+        PresetUnknown(preset: String) {
+            display("Camera has no preset {:?}", preset)
+        }
+        /// A part of the alert stream couldn't be parsed
         AlertInvalid(error: AlertParseError) {
             from()
             source(error)
         }
+        /// An alert couldn't be parsed, along with what was received
+        AlertUnparseable(error: AlertParseError, raw: String) {
+            display("Unable to parse alert: {}", error)
+            source(error)
+        }
+        /// The recording file couldn't be written
+        RecordingFailed(error: String) {
+            display("Unable to write recording: {}", error)
+        }
+    }
+}
+
+/// Why a camera disconnected, kept alongside the error text so it can be acted on without parsing it
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    Default,
+    Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum DisconnectKind {
+    /// The camera rejected the credentials or locked the account
+    Auth,
+    /// The camera couldn't be reached, e.g. it's offline or its name didn't resolve
+    Network,
+    /// The camera didn't answer in time
+    Timeout,
+    /// The camera answered, but not the way the API should
+    Protocol,
+    /// A part of the alert stream couldn't be parsed
+    ParseAlert,
+    /// What the camera reported about itself while connecting couldn't be parsed
+    ParseTriggers,
+    /// The camera ended the alert stream
+    Closed,
+    /// Dropped on purpose, e.g. by a reconnect command
+    Requested,
+    /// Anything else
+    #[default]
+    Other,
+}
+
+impl CameraError {
+    /// The kind of disconnect this error causes
+    pub fn kind(&self) -> DisconnectKind {
+        match self {
+            CameraError::ConnectionError(e) if e.is_timeout() => DisconnectKind::Timeout,
+            CameraError::UrlError(_) | CameraError::ConnectionError(_) => DisconnectKind::Network,
+            CameraError::AuthenticationFailed(_) | CameraError::AccountLocked => {
+                DisconnectKind::Auth
+            }
+            CameraError::UnexpectedStatus(_)
+            | CameraError::StreamInvalid(_)
+            | CameraError::CameraInvalidResponseBody(_) => DisconnectKind::Protocol,
+            CameraError::ConnectionClosed => DisconnectKind::Closed,
+            CameraError::AlertInvalid(_) | CameraError::AlertUnparseable(_, _) => {
+                DisconnectKind::ParseAlert
+            }
+            CameraError::DeviceInfoInvalid(_)
+            | CameraError::TriggersInvalid(_)
+            | CameraError::ChannelsInvalid(_)
+            | CameraError::ControlInvalid(_)
+            | CameraError::PresetsInvalid(_)
+            | CameraError::TimeInvalid(_) => DisconnectKind::ParseTriggers,
+            CameraError::PresetUnknown(_) | CameraError::RecordingFailed(_) => {
+                DisconnectKind::Other
+            }
+        }
+    }
+
+    /// Returns `true` if the camera rejected the configured credentials.
+    /// Retrying quickly on these will lock the account on the camera.
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self,
+            CameraError::AuthenticationFailed(_) | CameraError::AccountLocked
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_part, diff_triggers, is_lockout_response, part_format, PartFormat};
+    use crate::{
+        config::AlertFormat,
+        hikapi::{triggers_parser::TriggerItem, AlertItem, EventIdentifier, EventType},
+    };
+    use reqwest::header;
+
+    #[test]
+    fn test_part_format_detection() {
+        let headers = |content_type: &str| {
+            let mut headers = header::HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            headers
+        };
+        let no_headers = header::HeaderMap::new();
+
+        // The content type always wins
+        assert_eq!(
+            part_format(&headers("application/json"), b"{}", AlertFormat::Xml),
+            PartFormat::Json
+        );
+        assert_eq!(
+            part_format(
+                &headers("application/xml; charset=\"UTF-8\""),
+                b"<a/>",
+                AlertFormat::Json
+            ),
+            PartFormat::Xml
+        );
+        assert_eq!(
+            part_format(&headers("text/plain"), b"<a/>", AlertFormat::Auto),
+            PartFormat::Xml
+        );
+        // Attached pictures are skipped
+        assert_eq!(
+            part_format(&headers("image/jpeg"), b"\xff\xd8\xff", AlertFormat::Auto),
+            PartFormat::Unsupported
+        );
+        // Otherwise fall back to the configured format, or sniff the body
+        assert_eq!(
+            part_format(&no_headers, b"{}", AlertFormat::Xml),
+            PartFormat::Xml
+        );
+        assert_eq!(
+            part_format(&no_headers, b"<a/>", AlertFormat::Json),
+            PartFormat::Json
+        );
+        assert_eq!(
+            part_format(&no_headers, b"\r\n{}", AlertFormat::Auto),
+            PartFormat::Json
+        );
+        assert_eq!(
+            part_format(&no_headers, b"<a/>", AlertFormat::Auto),
+            PartFormat::Xml
+        );
+    }
+
+    #[test]
+    fn test_lockout_detection() {
+        assert!(is_lockout_response(indoc::indoc! {r#"
+            <?xml version="1.0" encoding="UTF-8"?>
+            <userCheck>
+            <statusValue>401</statusValue>
+            <statusString>Unauthorized</statusString>
+            <lockStatus>lock</lockStatus>
+            <unlockTime>1800</unlockTime>
+            </userCheck>
+        "#}));
+        assert!(is_lockout_response(indoc::indoc! {r#"
+            <?xml version="1.0" encoding="UTF-8"?>
+            <ResponseStatus version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <statusCode>4</statusCode>
+            <statusString>Invalid Operation</statusString>
+            <subStatusCode>userLocked</subStatusCode>
+            </ResponseStatus>
+        "#}));
+        assert!(!is_lockout_response(indoc::indoc! {r#"
+            <?xml version="1.0" encoding="UTF-8"?>
+            <userCheck>
+            <statusValue>401</statusValue>
+            <statusString>Unauthorized</statusString>
+            <lockStatus>unlock</lockStatus>
+            <retryLoginTime>5</retryLoginTime>
+            </userCheck>
+        "#}));
+        assert!(!is_lockout_response(""));
+    }
+
+    #[test]
+    fn test_diff_triggers() {
+        let trigger = |channel: &str, event_type: EventType| -> TriggerItem {
+            EventIdentifier::new(Some(channel.into()), event_type).into()
+        };
+        let previous = vec![
+            trigger("1", EventType::Motion),
+            trigger("1", EventType::Tamper),
+        ];
+        let current = vec![
+            trigger("1", EventType::Motion),
+            trigger("1", EventType::LineDetection),
+        ];
+        let (added, removed) = diff_triggers(&previous, &current);
+        assert_eq!(added, vec![trigger("1", EventType::LineDetection)]);
+        assert_eq!(
+            removed,
+            vec![EventIdentifier::new(Some("1".into()), EventType::Tamper)]
+        );
+
+        let (added, removed) = diff_triggers(&current, &current);
+        assert!(added.is_empty());
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn test_decode_part() {
+        // GB2312 isn't valid UTF-8, so the charset in the prolog has to be used
+        let body = include_bytes!("../../samples/alert_gb2312.xml");
+        let (text, had_errors) = decode_part(&header::HeaderMap::new(), body);
+        assert!(!had_errors);
+        let alert = AlertItem::parse(&text).unwrap();
+        assert_eq!(alert.identifier.event_type, EventType::Motion);
+        assert!(alert.active);
+        assert!(text.contains("<channelName>前门</channelName>"));
+
+        // The content type takes precedence
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/xml; charset=GB2312".parse().unwrap(),
+        );
+        let (text, had_errors) = decode_part(
+            &headers,
+            &body[body.iter().position(|b| *b == b'\n').unwrap()..],
+        );
+        assert!(!had_errors);
+        assert!(text.contains("前门"));
+
+        // Undeclared encodings are assumed to be UTF-8, replacing anything invalid
+        let (text, had_errors) = decode_part(&header::HeaderMap::new(), b"<a>\xff</a>");
+        assert!(had_errors);
+        assert_eq!(text, "<a>\u{fffd}</a>");
+        let (text, had_errors) = decode_part(&header::HeaderMap::new(), "<a>前门</a>".as_bytes());
+        assert!(!had_errors);
+        assert_eq!(text, "<a>前门</a>");
+    }
+
+    /// Starts a simulated camera running the sample script without its delays
+    fn simulated_camera(password: &str) -> crate::config::ConfigCamera {
+        let mut script =
+            crate::sim::Script::parse(include_str!("../../samples/sim_script.toml")).unwrap();
+        for step in &mut script.steps {
+            step.delay_ms = 0;
+        }
+        let addr = crate::sim::serve(script, "127.0.0.1:0".parse().unwrap()).unwrap();
+        crate::config::adhoc_camera("127.0.0.1", Some(addr.port()), "admin", password).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_load_any() {
+        use super::{load_any, CameraError};
+        let primary = crate::config::adhoc_camera("192.168.1.10", None, "admin", "pass").unwrap();
+        let mut fallback = primary.clone();
+        fallback.address = "10.0.0.10".to_string();
+        let endpoints = vec![primary, fallback];
+        // Each address answers with the next result, recording the order they were tried in
+        let attempt = |results: Vec<Result<&'static str, CameraError>>| {
+            let results = std::sync::Mutex::new(results.into_iter());
+            let tried = std::sync::Mutex::new(Vec::new());
+            let endpoints = &endpoints;
+            async move {
+                let loaded = load_any(endpoints, |endpoint| {
+                    tried.lock().unwrap().push(endpoint.address);
+                    let result = results.lock().unwrap().next().unwrap();
+                    async move { result }
+                })
+                .await;
+                (loaded, tried.into_inner().unwrap())
+            }
+        };
+
+        let (loaded, tried) = attempt(vec![Ok("primary")]).await;
+        assert_eq!(loaded.unwrap(), "primary");
+        assert_eq!(tried, vec!["192.168.1.10"]);
+
+        let (loaded, tried) =
+            attempt(vec![Err(CameraError::ConnectionClosed), Ok("fallback")]).await;
+        assert_eq!(loaded.unwrap(), "fallback");
+        assert_eq!(tried, vec!["192.168.1.10", "10.0.0.10"]);
+
+        let (loaded, _) = attempt(vec![
+            Err(CameraError::ConnectionClosed),
+            Err(CameraError::StreamInvalid("bad".into())),
+        ])
+        .await;
+        assert!(matches!(loaded, Err(CameraError::StreamInvalid(_))));
+
+        // A rejected login would be rejected at every address
+        let (loaded, tried) = attempt(vec![Err(CameraError::AccountLocked)]).await;
+        assert!(matches!(loaded, Err(CameraError::AccountLocked)));
+        assert_eq!(tried, vec!["192.168.1.10"]);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_address() {
+        let mut config = simulated_camera("password");
+        config.fallback_address = Some(config.address.clone());
+        config.fallback_port = config.port;
+        // Nothing listens on the discard port
+        config.port = Some(9);
+        let fallback_url = config.fallback().unwrap().base_url();
+        let (tx, mut rx) = super::EventQueue::channel(10);
+        let camera = super::run_camera(config, tx, false, None, super::ConnectLimit::default());
+        let address = loop {
+            if let super::CameraEventType::Connected { address, .. } =
+                rx.recv().await.unwrap().event
+            {
+                break address;
+            }
+        };
+        assert_eq!(address, fallback_url);
+        camera.stop();
+    }
+
+    #[tokio::test]
+    async fn test_simulated_camera() {
+        let mut camera = super::Camera::load(simulated_camera("password"))
+            .await
+            .unwrap();
+        assert_eq!(camera.info.device_name, "Simulated Camera");
+        assert_eq!(camera.triggers.len(), 17);
+        // Nothing changed since connecting
+        assert!(camera.refresh_info().await.unwrap().is_none());
+        camera.info.firmware_version = "V1.0.0".into();
+        assert!(camera.refresh_info().await.unwrap().is_some());
+
+        let mut alerts = Vec::new();
+        for _ in 0..3 {
+            let (_, parsed) = camera.next_event().await.unwrap();
+            alerts.extend(
+                parsed
+                    .into_iter()
+                    .map(|alert| (alert.identifier, alert.active)),
+            );
+        }
+        let motion = EventIdentifier::new(Some("1".into()), EventType::Motion);
+        let line = EventIdentifier::new(Some("1".into()), EventType::LineDetection);
+        assert_eq!(
+            alerts,
+            vec![(motion.clone(), true), (motion, false), (line, true)]
+        );
+        assert!(matches!(
+            camera.next_event().await,
+            Err(super::CameraError::AlertUnparseable(_, _))
+        ));
+        assert!(matches!(
+            camera.next_event().await,
+            Err(super::CameraError::StreamInvalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_simulated_camera_wrong_password() {
+        let error = match super::Camera::load(simulated_camera("wrong")).await {
+            Ok(_) => panic!("Connected with the wrong password"),
+            Err(e) => e,
+        };
+        assert!(error.is_auth_failure(), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_simulated_camera_events() {
+        let (tx, mut rx) = super::EventQueue::channel(100);
+        let camera = super::run_camera(
+            simulated_camera("password"),
+            tx,
+            false,
+            None,
+            super::ConnectLimit::default(),
+        );
+        let mut events = Vec::new();
+        // Until it has reconnected after the malformed part broke the stream
+        while events.iter().filter(|e| *e == "connected").count() < 2 {
+            let event = tokio::time::timeout(std::time::Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            events.push(match event.event {
+                super::CameraEventType::Connected { .. } => "connected".to_string(),
+                super::CameraEventType::Disconnected { retry_in_secs, .. } => {
+                    format!("disconnected, retry in {:?}", retry_in_secs)
+                }
+                super::CameraEventType::Alert(alert) => {
+                    format!("{} {}", alert.identifier, alert.active)
+                }
+                super::CameraEventType::ParseFailure { .. } => "parse failure".to_string(),
+                // The simulator's clock is HikSink's, give or take the second it's read in
+                super::CameraEventType::ClockDrift { drift_secs } if drift_secs.abs() <= 1 => {
+                    "clock right".to_string()
+                }
+                other => format!("{:?}", other),
+            });
+        }
+        camera.stop();
+        assert_eq!(
+            events,
+            vec![
+                "connected",
+                "clock right",
+                "CH1 Motion true",
+                "CH1 Motion false",
+                "CH1 Line Crossing true",
+                "parse failure",
+                "disconnected, retry in Some(0)",
+                "connected",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_simulated_camera_stalled_sink() {
+        // Only room for the events sent after connecting
+        let (tx, mut rx) = super::EventQueue::channel(2);
+        let camera = super::run_camera(
+            simulated_camera("password"),
+            tx.clone(),
+            false,
+            None,
+            super::ConnectLimit::default(),
+        );
+        // Nothing is read from the queue, but the camera keeps reading its alerts to the end of the stream.
+        // Only the end of the motion is kept.
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while tx.dropped() < 3 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The disconnect waited for room, and the camera reconnects once there is some
+        let mut events = Vec::new();
+        while events.last() != Some(&"connected") || events.len() < 2 {
+            let event = tokio::time::timeout(std::time::Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            events.push(match event.event {
+                super::CameraEventType::Connected { .. } => "connected",
+                super::CameraEventType::Disconnected { .. } => "disconnected",
+                _ => "other",
+            });
+        }
+        camera.stop();
+        assert_eq!(
+            events,
+            vec!["connected", "other", "other", "disconnected", "connected"]
+        );
+        assert_eq!(tx.dropped(), 3);
+    }
+
+    /// Waits for an event from a camera, skipping the others
+    async fn wait_for(
+        rx: &mut crate::hikapi::EventReceiver,
+        matches: fn(&super::CameraEventType) -> bool,
+    ) {
+        loop {
+            let event = tokio::time::timeout(std::time::Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if matches(&event.event) {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulated_camera_commands() {
+        use super::{CameraCommand, CameraEventType};
+
+        let (tx, mut rx) = super::EventQueue::channel(100);
+        let camera = super::run_camera(
+            simulated_camera("password"),
+            tx,
+            false,
+            None,
+            super::ConnectLimit::default(),
+        );
+        wait_for(&mut rx, |e| matches!(e, CameraEventType::Connected { .. })).await;
+
+        assert!(camera.send(CameraCommand::Pause));
+        wait_for(&mut rx, |e| matches!(e, CameraEventType::Paused)).await;
+        // Nothing happens while paused, other than resuming
+        assert!(camera.send(CameraCommand::Pause));
+        assert!(camera.send(CameraCommand::Resume));
+        wait_for(&mut rx, |e| matches!(e, CameraEventType::Connected { .. })).await;
+
+        assert!(camera.send(CameraCommand::Reconnect));
+        wait_for(&mut rx, |e| {
+            matches!(e, CameraEventType::Disconnected { error, retry_in_secs: Some(0), .. } if error == "Reconnect requested")
+        })
+        .await;
+        wait_for(&mut rx, |e| matches!(e, CameraEventType::Connected { .. })).await;
+
+        camera.stop();
+    }
+
+    #[tokio::test]
+    async fn test_simulated_camera_controls() {
+        use super::{CameraCommand, CameraEventType, ControlType};
+
+        let config = crate::config::ConfigCamera {
+            expose_controls: true,
+            ..simulated_camera("password")
+        };
+        let mut camera = super::Camera::probe(config).await.unwrap();
+        let motion = ControlType::MotionDetection;
+        let output = ControlType::AlarmOutput;
+        let light = ControlType::SupplementLight;
+        assert_eq!(
+            camera.controls(),
+            vec![
+                (motion, "1".to_string()),
+                (output, "1".to_string()),
+                (output, "2".to_string()),
+                (light, "1".to_string())
+            ]
+        );
+        assert!(!camera.control(output, "1").await.unwrap());
+        assert!(camera.set_control(output, "1", true).await.unwrap());
+        assert!(camera.control(output, "1").await.unwrap());
+        assert!(camera.control(motion, "1").await.unwrap());
+        assert!(!camera.set_control(motion, "1", false).await.unwrap());
+        assert!(!camera.control(motion, "1").await.unwrap());
+        assert!(camera.control(motion, "2").await.is_err());
+        assert!(!camera.control(light, "1").await.unwrap());
+        assert!(camera.set_control(light, "1", true).await.unwrap());
+        assert!(camera.control(light, "1").await.unwrap());
+
+        assert_eq!(camera.presets.len(), 3);
+        camera.goto_preset("Gate").await.unwrap();
+        camera.goto_preset("4").await.unwrap();
+        assert!(matches!(
+            camera.goto_preset("3").await,
+            Err(super::CameraError::PresetUnknown(_))
+        ));
+
+        let (tx, mut rx) = super::EventQueue::channel(100);
+        let config = crate::config::ConfigCamera {
+            expose_controls: true,
+            ..simulated_camera("password")
+        };
+        let camera = super::run_camera(config, tx, false, None, super::ConnectLimit::default());
+        wait_for(
+            &mut rx,
+            |e| matches!(e, CameraEventType::Control(state) if state.control == ControlType::MotionDetection && state.enabled),
+        )
+        .await;
+        wait_for(
+            &mut rx,
+            |e| matches!(e, CameraEventType::PtzPresets(presets) if presets.len() == 3),
+        )
+        .await;
+        assert!(camera.send(CameraCommand::GotoPreset("Garden".into())));
+        wait_for(
+            &mut rx,
+            |e| matches!(e, CameraEventType::PresetFailed { preset, .. } if preset == "Garden"),
+        )
+        .await;
+        assert!(camera.send(CameraCommand::SetControl {
+            control: motion,
+            channel: "1".into(),
+            enabled: false,
+        }));
+        wait_for(
+            &mut rx,
+            |e| matches!(e, CameraEventType::Control(state) if state.control == ControlType::MotionDetection && !state.enabled),
+        )
+        .await;
+        camera.stop();
+    }
+
+    #[tokio::test]
+    async fn test_simulated_camera_reboot() {
+        use super::{CameraCommand, CameraEventType};
+
+        let (tx, mut rx) = super::EventQueue::channel(100);
+        let config = crate::config::ConfigCamera {
+            expose_controls: true,
+            ..simulated_camera("password")
+        };
+        let camera = super::run_camera(config, tx, false, None, super::ConnectLimit::default());
+        wait_for(&mut rx, |e| matches!(e, CameraEventType::Connected { .. })).await;
+        assert!(camera.send(CameraCommand::Reboot));
+        wait_for(&mut rx, |e| {
+            matches!(e, CameraEventType::Rebooting { retry_in_secs: 30 })
+        })
+        .await;
+        // Skips waiting for the camera to come back
+        assert!(camera.send(CameraCommand::Reconnect));
+        wait_for(&mut rx, |e| matches!(e, CameraEventType::Connected { .. })).await;
+        camera.stop();
     }
 }
@@ -0,0 +1,241 @@
+//! Saving the raw parts of alert streams to files, to gather samples from devices that aren't supported yet
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// How many parts can wait for the writer before new ones are dropped
+const CAPTURE_QUEUE_SIZE: usize = 256;
+
+/// Queues raw alert parts to be written under a directory, with a subdirectory per camera.
+/// Cheap to clone, and never blocks the alert stream.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    tx: mpsc::Sender<CapturedPart>,
+    all: bool,
+}
+
+/// A part of an alert stream waiting to be written
+#[derive(Debug)]
+struct CapturedPart {
+    camera: String,
+    body: Vec<u8>,
+    failed: bool,
+    time: DateTime<Utc>,
+}
+
+impl Capture {
+    /// Starts writing captures to `dir`, deleting the oldest once they take up more than `max_bytes`.
+    /// With `all`, every alert part is captured instead of only those that couldn't be parsed.
+    /// Must be called from within a tokio runtime.
+    pub fn start(dir: PathBuf, max_bytes: u64, all: bool) -> Capture {
+        let (tx, mut rx) = mpsc::channel::<CapturedPart>(CAPTURE_QUEUE_SIZE);
+        tokio::task::spawn_blocking(move || {
+            let mut files = CaptureFiles::new(dir, max_bytes);
+            while let Some(part) = rx.blocking_recv() {
+                // Captures are only diagnostics, so they must never take HikSink down
+                if let Err(e) = files.write(&part) {
+                    warn!(
+                        camera = part.camera.as_str(),
+                        "Unable to write capture: {}", e
+                    );
+                }
+            }
+        });
+        Capture { tx, all }
+    }
+
+    /// Captures a part of a camera's alert stream, if it failed to parse or everything is captured
+    pub fn part(&self, camera: &str, body: &[u8], failed: bool) {
+        if !failed && !self.all {
+            return;
+        }
+        let part = CapturedPart {
+            camera: camera.to_string(),
+            body: body.to_vec(),
+            failed,
+            time: Utc::now(),
+        };
+        if self.tx.try_send(part).is_err() {
+            debug!(camera, "Capture writer is behind, dropping part");
+        }
+    }
+}
+
+/// The capture files on disk, oldest first, so the total size can be kept under the limit
+struct CaptureFiles {
+    dir: PathBuf,
+    max_bytes: u64,
+    files: VecDeque<(PathBuf, u64)>,
+    total_bytes: u64,
+    scanned: bool,
+}
+
+impl CaptureFiles {
+    fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            files: VecDeque::new(),
+            total_bytes: 0,
+            scanned: false,
+        }
+    }
+
+    /// Writes a part to its camera's directory, then deletes the oldest captures while over the limit
+    fn write(&mut self, part: &CapturedPart) -> std::io::Result<()> {
+        if !self.scanned {
+            // Captures from previous runs count towards the limit too
+            self.scanned = true;
+            self.scan()?;
+        }
+        let camera_dir = self.dir.join(&part.camera);
+        std::fs::create_dir_all(&camera_dir)?;
+        let path = unique_path(&camera_dir, &capture_file_name(part));
+        std::fs::write(&path, &part.body)?;
+        self.files.push_back((path, part.body.len() as u64));
+        self.total_bytes += part.body.len() as u64;
+        while self.total_bytes > self.max_bytes {
+            let (oldest, size) = match self.files.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            self.total_bytes -= size;
+            if let Err(e) = std::fs::remove_file(&oldest) {
+                warn!("Unable to remove old capture {}: {}", oldest.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the captures already in the directory
+    fn scan(&mut self) -> std::io::Result<()> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut found: Vec<(SystemTime, PathBuf, u64)> = Vec::new();
+        for camera_dir in entries.flatten() {
+            if !camera_dir.path().is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(camera_dir.path())?.flatten() {
+                let metadata = file.metadata()?;
+                if metadata.is_file() {
+                    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    found.push((modified, file.path(), metadata.len()));
+                }
+            }
+        }
+        found.sort();
+        for (_, path, size) in found {
+            self.total_bytes += size;
+            self.files.push_back((path, size));
+        }
+        Ok(())
+    }
+}
+
+/// The name of a capture, e.g. `20211002T180402.123Z_failed.xml`, sorting in the order they were received
+fn capture_file_name(part: &CapturedPart) -> String {
+    let first = part.body.iter().find(|b| !b.is_ascii_whitespace());
+    let extension = if first == Some(&b'{') { "json" } else { "xml" };
+    format!(
+        "{}_{}.{}",
+        part.time.format("%Y%m%dT%H%M%S%.3fZ"),
+        if part.failed { "failed" } else { "ok" },
+        extension
+    )
+}
+
+/// Adds a number to the name if a capture with it already exists, for parts received in the same millisecond
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let mut path = dir.join(name);
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{}-{}", n, name));
+        n += 1;
+    }
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CaptureFiles, CapturedPart};
+    use chrono::{TimeZone, Utc};
+
+    fn part(body: &str, failed: bool, secs: i64) -> CapturedPart {
+        CapturedPart {
+            camera: "front_porch".to_string(),
+            body: body.as_bytes().to_vec(),
+            failed,
+            time: Utc.timestamp(1633168842 + secs, 0),
+        }
+    }
+
+    fn captured(dir: &std::path::Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir.join("front_porch"))
+            .unwrap()
+            .map(|f| f.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_capture_rotation() {
+        let dir = std::env::temp_dir().join(format!("hiksink-captures-{}", std::process::id()));
+        let mut files = CaptureFiles::new(dir.clone(), 25);
+        files.write(&part("<Alert>1</Alert>", true, 0)).unwrap();
+        files.write(&part(r#"{"a":2}"#, false, 1)).unwrap();
+        assert_eq!(
+            captured(&dir),
+            vec![
+                "20211002T100042.000Z_failed.xml",
+                "20211002T100043.000Z_ok.json"
+            ]
+        );
+        assert_eq!(
+            std::fs::read(dir.join("front_porch/20211002T100042.000Z_failed.xml")).unwrap(),
+            b"<Alert>1</Alert>"
+        );
+
+        // The oldest are removed to stay under the limit
+        files.write(&part("<Alert>3</Alert>", true, 2)).unwrap();
+        assert_eq!(
+            captured(&dir),
+            vec![
+                "20211002T100043.000Z_ok.json",
+                "20211002T100044.000Z_failed.xml"
+            ]
+        );
+        // Parts received at the same time don't overwrite each other
+        files.write(&part("<A/>", true, 2)).unwrap();
+        assert_eq!(captured(&dir).len(), 2);
+        assert!(captured(&dir).contains(&"1-20211002T100044.000Z_failed.xml".to_string()));
+
+        // Earlier captures count towards the limit after a restart
+        let mut files = CaptureFiles::new(dir.clone(), 25);
+        files.write(&part("<Alert>5</Alert>", true, 4)).unwrap();
+        let remaining = captured(&dir);
+        assert!(remaining.contains(&"20211002T100046.000Z_failed.xml".to_string()));
+        let total: u64 = remaining
+            .iter()
+            .map(|f| {
+                std::fs::metadata(dir.join("front_porch").join(f))
+                    .unwrap()
+                    .len()
+            })
+            .sum();
+        assert!(total <= 25, "{:?}", remaining);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
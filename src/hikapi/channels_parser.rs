@@ -0,0 +1,84 @@
+use minidom::Element;
+use serde::{Deserialize, Serialize};
+
+/// A video input of an NVR/DVR, as configured on the recorder
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
+pub struct InputChannel {
+    /// The channel number used in alerts
+    pub id: String,
+    /// The name of the channel
+    pub name: String,
+}
+
+impl InputChannel {
+    /// Parses the channel list returned by `/ISAPI/ContentMgmt/InputProxy/channels` (IP cameras)
+    /// or `/ISAPI/System/Video/inputs/channels` (analog cameras)
+    pub fn parse(s: &str) -> Result<Vec<InputChannel>, ChannelParseError> {
+        let root: Element = s.parse()?;
+        if root.name() != "InputProxyChannelList" && root.name() != "VideoInputChannelList" {
+            return Err(ChannelParseError::RootNodeIncorrect(root.name().into()));
+        }
+
+        root.children()
+            .map(|channel| {
+                Ok(InputChannel {
+                    id: channel
+                        .get_child("id", minidom::NSChoice::Any)
+                        .ok_or_else(|| ChannelParseError::FieldMissing("id".to_string()))?
+                        .text(),
+                    name: channel
+                        .get_child("name", minidom::NSChoice::Any)
+                        .ok_or_else(|| ChannelParseError::FieldMissing("name".to_string()))?
+                        .text(),
+                })
+            })
+            .collect()
+    }
+}
+
+quick_error! {
+    /// Why a channel list couldn't be parsed
+    #[derive(Debug)]
+    pub enum ChannelParseError {
+        /// The body isn't well formed XML
+        XmlInvalid(error: minidom::Error) {
+            from()
+        }
+        /// The XML is for something else
+        RootNodeIncorrect(name: String) {
+            display("Returned root node invalid: {}", name)
+        }
+        /// A required field wasn't found
+        FieldMissing(field: String) {
+            display("Field was expected but missing: {}", field)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InputChannel;
+    const CHANNELS_NVR: &str = include_str!("../../samples/channels_nvr.xml");
+    const CHANNELS_DVR: &str = include_str!("../../samples/channels_dvr.xml");
+
+    #[test]
+    fn test_parse_nvr_samples() {
+        let parsed = InputChannel::parse(CHANNELS_NVR).unwrap();
+        insta::assert_yaml_snapshot!(parsed);
+    }
+
+    #[test]
+    fn test_parse_dvr_samples() {
+        let parsed = InputChannel::parse(CHANNELS_DVR).unwrap();
+        insta::assert_yaml_snapshot!(parsed);
+    }
+
+    #[test]
+    fn test_bad_channels() {
+        assert!(InputChannel::parse("").is_err());
+        assert!(InputChannel::parse(
+            "<DeviceInfo xmlns=\"http://www.hikvision.com/ver20/XMLSchema\"></DeviceInfo>"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Limiting how many cameras are connecting at once, as NVRs and intrusion detection don't like bursts of logins
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Shared by all cameras, so only so many of them connect at the same time. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectLimit {
+    /// `None` when unlimited
+    semaphore: Option<Arc<Semaphore>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Allows a camera to connect until dropped
+#[derive(Debug)]
+pub struct ConnectPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ConnectLimit {
+    /// Allows up to `max` connection attempts at once, or any number with `None`
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            semaphore: max.map(|max| Arc::new(Semaphore::new(max))),
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// Waits until the camera is allowed to connect
+    pub async fn acquire(&self) -> ConnectPermit {
+        let permit = match &self.semaphore {
+            // The semaphore is never closed
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        ConnectPermit {
+            _permit: permit,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    /// How many cameras are connecting right now, not counting those waiting their turn
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits are equal when they are the same limit, shared by the same cameras
+impl PartialEq for ConnectLimit {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.in_flight, &other.in_flight)
+    }
+}
+
+impl Drop for ConnectPermit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConnectLimit;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_connect_limit() {
+        let limit = ConnectLimit::new(Some(2));
+        let first = limit.acquire().await;
+        let second = limit.acquire().await;
+        assert_eq!(limit.in_flight(), 2);
+        // A third waits for one of the others to finish
+        let third = tokio::time::timeout(Duration::from_millis(20), limit.acquire()).await;
+        assert!(third.is_err());
+        drop(first);
+        let third = limit.acquire().await;
+        assert_eq!(limit.in_flight(), 2);
+        drop(second);
+        drop(third);
+        assert_eq!(limit.in_flight(), 0);
+
+        let unlimited = ConnectLimit::default();
+        let permits = futures::future::join_all((0..10).map(|_| unlimited.acquire())).await;
+        assert_eq!(unlimited.in_flight(), 10);
+        drop(permits);
+        assert_eq!(unlimited.in_flight(), 0);
+    }
+}
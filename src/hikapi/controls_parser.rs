@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use minidom::Element;
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+/// A setting of a camera that can be switched on and off
+#[derive(Debug, PartialEq, Eq, Hash, Deserialize, Serialize, Clone, Copy, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum ControlType {
+    /// Whether a channel detects motion, from `/ISAPI/System/Video/inputs/channels/<id>/motionDetection`
+    MotionDetection,
+    /// An alarm output port, e.g. wired to a siren. Read from `/ISAPI/System/IO/outputs/<id>/status`
+    /// and set with `/ISAPI/System/IO/outputs/<id>/trigger`.
+    AlarmOutput,
+    /// The white light of ColorVu cameras, from `/ISAPI/Image/channels/<id>/supplementLight`. Any mode
+    /// but `close` counts as on.
+    SupplementLight,
+}
+
+/// XML namespace of documents sent to the camera
+const XMLNS: &str = "http://www.hikvision.com/ver20/XMLSchema";
+/// Supplement light mode that switches the light off
+const SUPPLEMENT_LIGHT_OFF: &str = "close";
+/// Supplement light mode used to switch the light on, unless it's already on in another mode
+const SUPPLEMENT_LIGHT_ON: &str = "colorVuWhiteLight";
+
+impl ControlType {
+    /// The ISAPI path the state of the setting is read from
+    pub fn path(&self, channel: &str) -> String {
+        match self {
+            ControlType::MotionDetection => {
+                format!(
+                    "/ISAPI/System/Video/inputs/channels/{}/motionDetection",
+                    channel
+                )
+            }
+            ControlType::AlarmOutput => format!("/ISAPI/System/IO/outputs/{}/status", channel),
+            ControlType::SupplementLight => {
+                format!("/ISAPI/Image/channels/{}/supplementLight", channel)
+            }
+        }
+    }
+
+    /// The ISAPI path the setting is PUT to
+    pub fn set_path(&self, channel: &str) -> String {
+        match self {
+            ControlType::MotionDetection | ControlType::SupplementLight => self.path(channel),
+            ControlType::AlarmOutput => format!("/ISAPI/System/IO/outputs/{}/trigger", channel),
+        }
+    }
+
+    /// The root node of the setting's XML
+    fn root_name(&self) -> &'static str {
+        match self {
+            ControlType::MotionDetection => "MotionDetection",
+            ControlType::AlarmOutput => "IOPortStatus",
+            ControlType::SupplementLight => "SupplementLight",
+        }
+    }
+
+    /// The field of the setting's XML holding its state
+    fn field(&self) -> &'static str {
+        match self {
+            ControlType::MotionDetection => "enabled",
+            ControlType::AlarmOutput => "ioState",
+            ControlType::SupplementLight => "supplementLightMode",
+        }
+    }
+
+    /// Reads whether the setting is enabled from the XML returned by its [path](ControlType::path)
+    pub fn parse_enabled(&self, s: &str) -> Result<bool, ControlParseError> {
+        let root = self.parse_root(s)?;
+        let value = self.parse_value(&root)?;
+        let (on, off) = match self {
+            ControlType::MotionDetection => ("true", "false"),
+            ControlType::AlarmOutput => ("active", "inactive"),
+            ControlType::SupplementLight => return Ok(value != SUPPLEMENT_LIGHT_OFF),
+        };
+        match value.as_str() {
+            value if value == on => Ok(true),
+            value if value == off => Ok(false),
+            other => Err(ControlParseError::ValueInvalid(other.to_string())),
+        }
+    }
+
+    fn parse_value(&self, root: &Element) -> Result<String, ControlParseError> {
+        let field = self.field();
+        Ok(root
+            .get_child(field, minidom::NSChoice::Any)
+            .ok_or_else(|| ControlParseError::FieldMissing(field.to_string()))?
+            .text()
+            .trim()
+            .to_string())
+    }
+
+    /// The XML to PUT to the setting's [set path](ControlType::set_path), given the XML returned by its
+    /// [path](ControlType::path). Motion detection and supplement lights are sent back with everything
+    /// but their state kept as the camera sent it. A supplement light that's already on keeps its mode.
+    pub fn set_enabled(&self, s: &str, enabled: bool) -> Result<String, ControlParseError> {
+        let mut root = self.parse_root(s)?;
+        if *self == ControlType::AlarmOutput {
+            // Outputs are set with a document of their own, the status only shows the port exists
+            root = Element::builder("IOPortData", XMLNS)
+                .attr("version", "2.0")
+                .append(
+                    Element::builder("outputState", XMLNS)
+                        .append(if enabled { "high" } else { "low" })
+                        .build(),
+                )
+                .build();
+        } else {
+            let value = match self {
+                ControlType::SupplementLight if !enabled => SUPPLEMENT_LIGHT_OFF.to_string(),
+                ControlType::SupplementLight => match self.parse_value(&root)? {
+                    mode if mode != SUPPLEMENT_LIGHT_OFF => mode,
+                    _ => SUPPLEMENT_LIGHT_ON.to_string(),
+                },
+                _ => enabled.to_string(),
+            };
+            let field = self.field();
+            let node = root
+                .get_child_mut(field, minidom::NSChoice::Any)
+                .ok_or_else(|| ControlParseError::FieldMissing(field.to_string()))?;
+            *node = Element::builder(field, node.ns()).append(value).build();
+        }
+        let mut xml = Vec::new();
+        root.write_to_decl(&mut xml)?;
+        Ok(String::from_utf8_lossy(&xml).into_owned())
+    }
+
+    fn parse_root(&self, s: &str) -> Result<Element, ControlParseError> {
+        let root: Element = s.parse()?;
+        if root.name() != self.root_name() {
+            return Err(ControlParseError::RootNodeIncorrect(root.name().into()));
+        }
+        Ok(root)
+    }
+}
+
+/// Parses the ids of the alarm outputs returned by `/ISAPI/System/IO/outputs`
+pub fn parse_outputs(s: &str) -> Result<Vec<String>, ControlParseError> {
+    let root: Element = s.parse()?;
+    if root.name() != "IOOutputPortList" {
+        return Err(ControlParseError::RootNodeIncorrect(root.name().into()));
+    }
+    root.children()
+        .map(|port| {
+            Ok(port
+                .get_child("id", minidom::NSChoice::Any)
+                .ok_or_else(|| ControlParseError::FieldMissing("id".to_string()))?
+                .text())
+        })
+        .collect()
+}
+
+/// Parses the names of the alarm inputs returned by `/ISAPI/System/IO/inputs`, keyed by port id.
+/// Ports without a name are left out.
+pub fn parse_inputs(s: &str) -> Result<HashMap<String, String>, ControlParseError> {
+    let root: Element = s.parse()?;
+    if root.name() != "IOInputPortList" {
+        return Err(ControlParseError::RootNodeIncorrect(root.name().into()));
+    }
+    let mut names = HashMap::new();
+    for port in root.children() {
+        let id = port
+            .get_child("id", minidom::NSChoice::Any)
+            .ok_or_else(|| ControlParseError::FieldMissing("id".to_string()))?
+            .text();
+        let name = port
+            .get_child("name", minidom::NSChoice::Any)
+            .map(|n| n.text().trim().to_string())
+            .unwrap_or_default();
+        if !name.is_empty() {
+            names.insert(id, name);
+        }
+    }
+    Ok(names)
+}
+
+/// A control on one of the camera's channels, and whether it's switched on
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
+pub struct ControlState {
+    /// The setting
+    pub control: ControlType,
+    /// The video channel the setting is for, or the port of an alarm output
+    pub channel: String,
+    /// Whether the setting is switched on
+    pub enabled: bool,
+}
+
+quick_error! {
+    /// Why a camera setting couldn't be read or changed
+    #[derive(Debug)]
+    pub enum ControlParseError {
+        /// The body isn't well formed XML, or couldn't be written back
+        XmlInvalid(error: minidom::Error) {
+            from()
+        }
+        /// The XML is for something else
+        RootNodeIncorrect(name: String) {
+            display("Returned root node invalid: {}", name)
+        }
+        /// A required field wasn't found
+        FieldMissing(field: String) {
+            display("Field was expected but missing: {}", field)
+        }
+        /// The field isn't one of the states expected, e.g. `true` or `false`
+        ValueInvalid(value: String) {
+            display("Expected on or off state, found: {}", value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_inputs, parse_outputs, ControlType};
+    const MOTION_DETECTION: &str = include_str!("../../samples/motion_detection.xml");
+    const INPUTS: &str = include_str!("../../samples/io_inputs.xml");
+    const OUTPUTS: &str = include_str!("../../samples/io_outputs.xml");
+    const OUTPUT_STATUS: &str = include_str!("../../samples/io_output_status.xml");
+    const SUPPLEMENT_LIGHT: &str = include_str!("../../samples/supplement_light.xml");
+
+    #[test]
+    fn test_motion_detection() {
+        let control = ControlType::MotionDetection;
+        assert_eq!(
+            control.path("1"),
+            "/ISAPI/System/Video/inputs/channels/1/motionDetection"
+        );
+        assert!(control.parse_enabled(MOTION_DETECTION).unwrap());
+
+        let disabled = control.set_enabled(MOTION_DETECTION, false).unwrap();
+        assert!(!control.parse_enabled(&disabled).unwrap());
+        // The rest of the settings are sent back unchanged
+        assert!(disabled.contains("<sensitivityLevel>60</sensitivityLevel>"));
+        assert!(control
+            .parse_enabled(&control.set_enabled(&disabled, true).unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_alarm_outputs() {
+        assert_eq!(parse_outputs(OUTPUTS).unwrap(), vec!["1", "2"]);
+        let control = ControlType::AlarmOutput;
+        assert_eq!(control.path("1"), "/ISAPI/System/IO/outputs/1/status");
+        assert_eq!(control.set_path("1"), "/ISAPI/System/IO/outputs/1/trigger");
+        assert!(!control.parse_enabled(OUTPUT_STATUS).unwrap());
+        insta::assert_snapshot!(control.set_enabled(OUTPUT_STATUS, true).unwrap());
+        assert!(control.set_enabled(MOTION_DETECTION, true).is_err());
+    }
+
+    #[test]
+    fn test_alarm_inputs() {
+        let names = parse_inputs(INPUTS).unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names["1"], "Doorbell");
+        assert!(parse_inputs(OUTPUTS).is_err());
+    }
+
+    #[test]
+    fn test_supplement_light() {
+        let control = ControlType::SupplementLight;
+        assert_eq!(
+            control.set_path("1"),
+            "/ISAPI/Image/channels/1/supplementLight"
+        );
+        assert!(!control.parse_enabled(SUPPLEMENT_LIGHT).unwrap());
+
+        let on = control.set_enabled(SUPPLEMENT_LIGHT, true).unwrap();
+        insta::assert_snapshot!(on);
+        assert!(control.parse_enabled(&on).unwrap());
+        // Already on in another mode, which is kept
+        let smart = on.replace("colorVuWhiteLight", "eventIntelligence");
+        assert!(control
+            .set_enabled(&smart, true)
+            .unwrap()
+            .contains("<supplementLightMode>eventIntelligence</supplementLightMode>"));
+        assert!(!control
+            .parse_enabled(&control.set_enabled(&smart, false).unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_bad_controls() {
+        let control = ControlType::MotionDetection;
+        assert!(control.parse_enabled("").is_err());
+        assert!(control
+            .parse_enabled(
+                "<DeviceInfo xmlns=\"http://www.hikvision.com/ver20/XMLSchema\"></DeviceInfo>"
+            )
+            .is_err());
+        assert!(control
+            .parse_enabled(
+                "<MotionDetection xmlns=\"http://www.hikvision.com/ver20/XMLSchema\"><enabled>maybe</enabled></MotionDetection>"
+            )
+            .is_err());
+    }
+}
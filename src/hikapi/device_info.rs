@@ -1,19 +1,29 @@
 use minidom::Element;
 use serde::{Deserialize, Serialize};
 
+/// Details of a camera or recorder, from `/ISAPI/System/deviceInfo`
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct DeviceInfo {
+    /// The name given to the device on its web interface
     pub device_name: String,
+    /// A UUID unique to the device
     pub device_id: String,
+    /// The model number, e.g. `DS-2CD2385G1-I`
     pub model: String,
+    /// The serial number, usually the model followed by a date and unique number
     pub serial_number: String,
+    /// The MAC address of the device's network interface
     pub mac_address: String,
+    /// The firmware version, e.g. `V5.6.3`
     pub firmware_version: String,
+    /// When the firmware was built, e.g. `build 190923`
     pub firmware_release_date: String,
+    /// The kind of device, e.g. `IPCamera` or `NVR`
     pub device_type: String,
 }
 
 impl DeviceInfo {
+    /// Parses the XML returned by `/ISAPI/System/deviceInfo`
     pub fn parse(s: &str) -> Result<DeviceInfo, DeviceInfoParseError> {
         let root: Element = s.parse()?;
         if root.name() != "DeviceInfo" {
@@ -57,17 +67,27 @@ impl DeviceInfo {
                 .text(),
         })
     }
+
+    /// Whether the device is a video recorder (NVR/DVR) rather than a camera
+    pub fn is_recorder(&self) -> bool {
+        let device_type = self.device_type.to_ascii_uppercase();
+        device_type.contains("NVR") || device_type.contains("DVR")
+    }
 }
 
 quick_error! {
+    /// Why the device info couldn't be parsed
     #[derive(Debug)]
     pub enum DeviceInfoParseError {
+        /// The body isn't well formed XML
         XmlInvalid(error: minidom::Error) {
             from()
         }
+        /// The XML is for something else
         RootNodeIncorrect(name: String) {
             display("Returned root node invalid: {}", name)
         }
+        /// A required field wasn't found
         FieldMissing(field: String) {
             display("Field was expected but missing: {}", field)
         }
@@ -107,6 +127,7 @@ mod test {
         "#})
         .unwrap();
         insta::assert_yaml_snapshot!(parsed);
+        assert!(!parsed.is_recorder());
     }
 
     #[test]
@@ -0,0 +1,360 @@
+//! The queue camera events are sent to the sink on, which drops the oldest alerts rather than holding up the cameras
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{broadcast, mpsc::error::SendError, Notify};
+
+use super::{CameraEvent, CameraEventType};
+
+/// Shared by all cameras to send their events to the sink. Cheap to clone.
+///
+/// While the queue is full, the oldest alerts and the like are dropped and counted to make room, so a slow sink
+/// doesn't stop the cameras from reading their alert streams and the latest alerts get through. Connection changes
+/// are never dropped, and wait for room when nothing else can make it. Neither are the ends of alerts, so triggers
+/// can't get stuck on, but they don't wait: they go over capacity instead, dropping only repeats of an end.
+#[derive(Debug)]
+pub struct EventQueue {
+    shared: Arc<Shared>,
+    /// Get a copy of every event, one for each output running alongside the sink
+    copies: Vec<broadcast::Sender<CameraEvent>>,
+}
+
+/// The sink's end of an [`EventQueue`]
+#[derive(Debug)]
+pub struct EventReceiver {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    capacity: usize,
+    state: Mutex<State>,
+    /// Woken when an event is queued, or the last sender is dropped
+    queued: Notify,
+    /// Woken when an event is taken off the queue, or the receiver is dropped
+    room: Notify,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    events: VecDeque<CameraEvent>,
+    /// How many clones of the queue are left
+    senders: usize,
+    /// Whether the receiver was dropped
+    closed: bool,
+}
+
+/// What became of an event offered to the queue
+enum Push {
+    Queued,
+    /// Lost because the queue was full
+    Dropped,
+    /// Kept out of the queue until there's room
+    Full(CameraEvent),
+    Closed(CameraEvent),
+}
+
+impl Shared {
+    fn push(&self, event: CameraEvent) -> Push {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Push::Closed(event);
+        }
+        if state.events.len() >= self.capacity {
+            match state.events.iter().position(|e| droppable(&e.event)) {
+                Some(oldest) => {
+                    state.events.remove(oldest);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                // Nothing older can go, so this is the oldest
+                None if droppable(&event.event) || already_ended(&state.events, &event) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Push::Dropped;
+                }
+                None if matches!(&event.event, CameraEventType::Alert(_)) => {}
+                None => return Push::Full(event),
+            }
+        }
+        state.events.push_back(event);
+        drop(state);
+        self.queued.notify_one();
+        Push::Queued
+    }
+}
+
+impl EventQueue {
+    /// Creates a queue holding up to `capacity` events, with the receiver for the sink
+    pub fn channel(capacity: usize) -> (Self, EventReceiver) {
+        let shared = Arc::new(Shared {
+            capacity: capacity.max(1),
+            state: Mutex::new(State {
+                senders: 1,
+                ..Default::default()
+            }),
+            queued: Notify::new(),
+            room: Notify::new(),
+            dropped: AtomicU64::new(0),
+        });
+        (
+            Self {
+                shared: shared.clone(),
+                copies: Vec::new(),
+            },
+            EventReceiver { shared },
+        )
+    }
+
+    /// Queues an event, dropping the oldest one that can be lost if the queue is full. Fails only once the sink has
+    /// stopped.
+    pub async fn send(&self, mut event: CameraEvent) -> Result<(), SendError<CameraEvent>> {
+        for copies in &self.copies {
+            // Fails only while nobody is listening
+            let _ = copies.send(event.clone());
+        }
+        loop {
+            // Created first so room made in the meantime isn't missed
+            let room = self.shared.room.notified();
+            match self.shared.push(event) {
+                Push::Queued | Push::Dropped => return Ok(()),
+                Push::Full(returned) => event = returned,
+                Push::Closed(event) => return Err(SendError(event)),
+            }
+            room.await;
+        }
+    }
+
+    /// How many events were dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Also sends a copy of every event to `copies` from now on, for an output running alongside the sink. It loses
+    /// events when it lags behind, rather than holding up the queue.
+    pub fn copy_to(&mut self, copies: broadcast::Sender<CameraEvent>) {
+        self.copies.push(copies);
+    }
+}
+
+impl Clone for EventQueue {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+            copies: self.copies.clone(),
+        }
+    }
+}
+
+impl Drop for EventQueue {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().senders -= 1;
+        self.shared.queued.notify_one();
+    }
+}
+
+/// Queues are equal when they share the same count of dropped events
+impl PartialEq for EventQueue {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+}
+
+impl EventReceiver {
+    /// Takes the next event off the queue, waiting for one. Returns `None` once every sender has been dropped.
+    pub async fn recv(&mut self) -> Option<CameraEvent> {
+        loop {
+            let queued = self.shared.queued.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(event) = state.events.pop_front() {
+                    drop(state);
+                    self.shared.room.notify_waiters();
+                    return Some(event);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            queued.await;
+        }
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.room.notify_waiters();
+    }
+}
+
+/// Whether `event` ends an alert whose last queued alert already ended it
+fn already_ended(events: &VecDeque<CameraEvent>, event: &CameraEvent) -> bool {
+    let end = match &event.event {
+        CameraEventType::Alert(alert) if !alert.active => alert,
+        _ => return false,
+    };
+    events
+        .iter()
+        .rev()
+        .find_map(|queued| match &queued.event {
+            CameraEventType::Alert(alert)
+                if queued.id == event.id && alert.identifier == end.identifier =>
+            {
+                Some(!alert.active)
+            }
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+/// Events that are only of use right away, and that the sink can do without. The end of an alert
+/// is kept, or its trigger would stay on.
+fn droppable(event: &CameraEventType) -> bool {
+    match event {
+        CameraEventType::Alert(alert) => alert.active,
+        CameraEventType::Snapshot { .. }
+        | CameraEventType::ParseFailure { .. }
+        | CameraEventType::RawPart(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EventQueue;
+    use crate::hikapi::{AlertItem, CameraEvent, CameraEventType, DisconnectKind};
+
+    fn event(event: CameraEventType) -> CameraEvent {
+        CameraEvent {
+            id: "cam".into(),
+            event,
+        }
+    }
+
+    fn alert(active: bool, post_count: u64) -> CameraEvent {
+        let alert = AlertItem::parse(&format!(
+            r#"<EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <channelID>1</channelID>
+            <dateTime>2021-10-02T18:04:02+08:00</dateTime>
+            <activePostCount>{}</activePostCount>
+            <eventType>VMD</eventType>
+            <eventState>{}</eventState>
+            <eventDescription>Motion alarm</eventDescription>
+            </EventNotificationAlert>"#,
+            post_count,
+            if active { "active" } else { "inactive" }
+        ))
+        .unwrap();
+        event(CameraEventType::Alert(alert))
+    }
+
+    #[tokio::test]
+    async fn test_drops_oldest() {
+        let (queue, mut rx) = EventQueue::channel(3);
+        queue.send(event(CameraEventType::Paused)).await.unwrap();
+        for post_count in 1..=5 {
+            queue.send(alert(true, post_count)).await.unwrap();
+        }
+        assert_eq!(queue.dropped(), 3);
+        // The last alerts of the burst are kept, and the connection change isn't dropped for them
+        assert_eq!(rx.recv().await.unwrap().event, CameraEventType::Paused);
+        assert_eq!(rx.recv().await.unwrap(), alert(true, 4));
+        assert_eq!(rx.recv().await.unwrap(), alert(true, 5));
+
+        // The end of an alert makes room rather than being dropped
+        for post_count in 1..=3 {
+            queue.send(alert(true, post_count)).await.unwrap();
+        }
+        for post_count in 4..=6 {
+            queue.send(alert(false, post_count)).await.unwrap();
+        }
+        assert_eq!(queue.dropped(), 6);
+        // Once only ends are left, new alerts and repeated ends are dropped, but the ends of other triggers are
+        // queued anyway
+        queue.send(alert(true, 7)).await.unwrap();
+        queue.send(alert(false, 8)).await.unwrap();
+        assert_eq!(queue.dropped(), 8);
+        let mut other = alert(false, 9);
+        other.id = "other".into();
+        queue.send(other.clone()).await.unwrap();
+        assert_eq!(queue.dropped(), 8);
+        for post_count in 4..=6 {
+            assert_eq!(rx.recv().await.unwrap(), alert(false, post_count));
+        }
+        assert_eq!(rx.recv().await.unwrap(), other);
+
+        // Closed once every sender is gone
+        drop(queue);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_copies() {
+        let (mut queue, mut rx) = EventQueue::channel(1);
+        let (copies, mut copies_rx) = tokio::sync::broadcast::channel(2);
+        queue.copy_to(copies);
+        let (more_copies, mut more_copies_rx) = tokio::sync::broadcast::channel(3);
+        queue.copy_to(more_copies);
+        for post_count in 1..=3 {
+            queue.send(alert(true, post_count)).await.unwrap();
+        }
+        // Each keeps to its own capacity
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(rx.recv().await.unwrap(), alert(true, 3));
+        assert!(copies_rx.recv().await.is_err());
+        assert_eq!(copies_rx.recv().await.unwrap(), alert(true, 2));
+        assert_eq!(copies_rx.recv().await.unwrap(), alert(true, 3));
+        for post_count in 1..=3 {
+            assert_eq!(
+                more_copies_rx.recv().await.unwrap(),
+                alert(true, post_count)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drops_when_full() {
+        let (queue, mut rx) = EventQueue::channel(1);
+        queue.send(event(CameraEventType::Paused)).await.unwrap();
+        // Dropped without waiting for the sink
+        queue
+            .send(event(CameraEventType::RawPart("part".into())))
+            .await
+            .unwrap();
+        assert_eq!(queue.dropped(), 1);
+
+        // Connection changes wait for room instead
+        let disconnected = {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                queue
+                    .send(event(CameraEventType::Disconnected {
+                        error: "Camera closed connection".into(),
+                        kind: DisconnectKind::Closed,
+                        retry_in_secs: None,
+                    }))
+                    .await
+            })
+        };
+        assert_eq!(rx.recv().await.unwrap().event, CameraEventType::Paused);
+        disconnected.await.unwrap().unwrap();
+        assert!(matches!(
+            rx.recv().await.unwrap().event,
+            CameraEventType::Disconnected { .. }
+        ));
+        assert_eq!(queue.dropped(), 1);
+
+        drop(rx);
+        assert!(queue
+            .send(event(CameraEventType::RawPart("part".into())))
+            .await
+            .is_err());
+    }
+}
@@ -2,13 +2,17 @@ use std::{fmt, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
+/// Identifies a trigger of a camera, which can alert independently of the others
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Hash, Clone)]
 pub struct EventIdentifier {
+    /// The video channel, or `None` for events of the whole device, e.g. alarm inputs
     pub channel: Option<String>,
+    /// What kind of event it is
     pub event_type: EventType,
 }
 
 impl EventIdentifier {
+    /// Identifies the trigger for an event type on a channel
     pub fn new(channel: Option<String>, event_type: EventType) -> Self {
         Self {
             channel,
@@ -17,6 +21,22 @@ impl EventIdentifier {
     }
 }
 
+impl EventIdentifier {
+    /// Like the [`Display`](fmt::Display) output, but with the channel number replaced by a name if there is one
+    /// and the event type's friendly name replaced. e.g. `Driveway Motion`.
+    /// Named alarm inputs keep their port number instead, e.g. `Doorbell (Input 1)`.
+    pub fn display_with_names(&self, channel_name: Option<&str>, event_name: &str) -> String {
+        match (channel_name, &self.channel) {
+            (Some(name), Some(port)) if self.event_type == EventType::Io => {
+                format!("{} (Input {})", name, port)
+            }
+            (Some(name), _) => format!("{} {}", name, event_name),
+            (None, Some(ch)) => format!("CH{} {}", ch, event_name),
+            (None, None) => event_name.to_string(),
+        }
+    }
+}
+
 impl fmt::Display for EventIdentifier {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(ch) = &self.channel {
@@ -26,31 +46,80 @@ impl fmt::Display for EventIdentifier {
     }
 }
 
+/// The kind of event an alert or trigger is for
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Hash, Clone)]
 pub enum EventType {
+    /// An alarm input (`IO`)
     Io,
+    /// Motion detection (`VMD`)
     Motion,
+    /// Line crossing (`linedetection`)
     LineDetection,
+    /// An object left behind (`unattendedBaggage`)
     UnattendedBaggage,
+    /// An object removed (`attendedBaggage`)
     AttendedBaggage,
+    /// Something entered a region (`regionEntrance`)
     RegionEntrance,
+    /// Something left a region (`regionExiting`)
     RegionExiting,
+    /// The camera was moved (`scenechangedetection`)
     SceneChangeDetection,
+    /// Intrusion into a region (`fielddetection`)
     FieldDetection,
+    /// A face was seen (`facedetection`)
     FaceDetection,
+    /// A picture of a face was captured (`faceSnap`)
     FaceSnap,
+    /// Audio input missing or a sudden change in volume (`audioexception`)
     AudioException,
+    /// The video signal was lost (`videoloss`)
     VideoLoss,
+    /// The lens was covered or defocused (`tamperdetection`, `shelteralarm` or `defocus`)
     Tamper,
+    /// The video standard doesn't match (`videomismatch`)
     VideoMismatch,
+    /// Video quality is too poor to use (`badvideo`)
     BadVideo,
+    /// Storage health warnings (`storageDetection`)
     StorageDetection,
+    /// Recording stopped working (`recordingfailure`)
     RecordingFailure,
+    /// Storage is full (`diskfull`)
     DiskFull,
+    /// Storage failed (`diskerror`)
     DiskError,
+    /// The network was disconnected (`nicbroken`)
     NicBroken,
+    /// Another device has the same IP address (`ipconflict`)
     IpConflict,
+    /// Failed logins (`illaccess`)
     IllegalAccess,
+    /// Passive infrared sensor (`PIR`)
+    Pir,
+    /// A temperature threshold was crossed by a thermal camera (`thermometry`)
+    Thermometry,
+    /// Fire seen by a thermal camera (`fireDetection`)
+    FireDetection,
+    /// A license plate was read (`ANPR`)
+    Anpr,
+    /// A vehicle was seen (`vehicledetection`)
+    VehicleDetection,
+    /// People counts were updated (`PeopleCounting`)
+    PeopleCounting,
+    /// Someone stayed in a region (`loitering`)
+    Loitering,
+    /// A vehicle stopped in a region (`parking`)
+    Parking,
+    /// Someone fell down (`falldown` or `fallDetection`)
+    FallDetection,
+    /// Storage SMART warnings (`hddSmart` or `HDImpairDetection`)
+    HddSmart,
+    /// Too many people in a region (`groupDensity` or `crowdDensity`)
+    CrowdDensity,
+    /// A generic smart event (`intelligent`)
+    Intelligent,
+    /// An event type HikSink doesn't know, with the name the camera sent
     Unknown(String),
 }
 
@@ -62,6 +131,39 @@ impl EventType {
         matches!(self, Self::VideoLoss)
     }
 
+    /// Returns `true` if smart (AcuSense) cameras report what was detected (human / vehicle) for this event type
+    pub fn supports_smart_targets(&self) -> bool {
+        matches!(
+            self,
+            Self::LineDetection | Self::FieldDetection | Self::RegionEntrance | Self::RegionExiting
+        )
+    }
+
+    /// Returns `true` if alerts of this event type carry the license plate that was read
+    pub fn reports_plates(&self) -> bool {
+        matches!(self, Self::Anpr | Self::VehicleDetection)
+    }
+
+    /// Returns `true` if the event is published as count sensors rather than a binary sensor
+    pub fn is_counter(&self) -> bool {
+        matches!(self, Self::PeopleCounting)
+    }
+
+    /// Returns `true` if the event marks a single moment rather than an ongoing condition
+    pub fn is_momentary(&self) -> bool {
+        matches!(self, Self::FaceSnap)
+    }
+
+    /// Parses an event type from either the camera's name (e.g. `VMD`) or the name used in MQTT topics (e.g. `Motion`)
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "motion" => Ok(EventType::Motion),
+            "tamper" => Ok(EventType::Tamper),
+            "illegalaccess" => Ok(EventType::IllegalAccess),
+            _ => name.parse(),
+        }
+    }
+
     /// Friendly name for output to home assistant / discovery protocols
     pub fn friendly_name(&self) -> String {
         match self {
@@ -88,13 +190,25 @@ impl EventType {
             EventType::NicBroken => "Network Card Broken".to_string(),
             EventType::IpConflict => "IP Address Conflict".to_string(),
             EventType::IllegalAccess => "Illegal Access".to_string(),
+            EventType::Pir => "PIR".to_string(),
+            EventType::Thermometry => "Temperature Alarm".to_string(),
+            EventType::FireDetection => "Fire Detection".to_string(),
+            EventType::Anpr => "License Plate".to_string(),
+            EventType::VehicleDetection => "Vehicle Detection".to_string(),
+            EventType::PeopleCounting => "People Counting".to_string(),
+            EventType::Loitering => "Loitering".to_string(),
+            EventType::Parking => "Parking".to_string(),
+            EventType::FallDetection => "Fall Detection".to_string(),
+            EventType::HddSmart => "Disk Health".to_string(),
+            EventType::CrowdDensity => "Crowd Density".to_string(),
+            EventType::Intelligent => "Smart Event".to_string(),
             EventType::Unknown(s) => s.clone(),
         }
     }
 
     /// Maps to a homeassistant binary sensor device class
-    /// See https://www.home-assistant.io/integrations/binary_sensor/#device-class
-    pub fn device_class(&self) -> Option<&str> {
+    /// See <https://www.home-assistant.io/integrations/binary_sensor/#device-class>
+    pub fn device_class(&self) -> Option<&'static str> {
         match self {
             EventType::Io => None,
             EventType::Motion
@@ -108,7 +222,37 @@ impl EventType {
             | EventType::FaceDetection
             | EventType::FaceSnap
             | EventType::AudioException
-            | EventType::Unknown(_) => Some("motion"),
+            | EventType::Pir
+            | EventType::Anpr
+            | EventType::VehicleDetection
+            | EventType::PeopleCounting
+            | EventType::Loitering
+            | EventType::Parking
+            | EventType::FallDetection
+            | EventType::CrowdDensity
+            | EventType::Intelligent => Some("motion"),
+            // Unknown events are usually detections, unless the name says it's about the device's health
+            EventType::Unknown(s) => {
+                let s = s.to_ascii_lowercase();
+                if [
+                    "disk",
+                    "hdd",
+                    "storage",
+                    "record",
+                    "network",
+                    "nic",
+                    "exception",
+                ]
+                .iter()
+                .any(|health| s.contains(health))
+                {
+                    Some("problem")
+                } else {
+                    Some("motion")
+                }
+            }
+            EventType::Thermometry => Some("heat"),
+            EventType::FireDetection => Some("smoke"),
             EventType::VideoLoss
             | EventType::Tamper
             | EventType::VideoMismatch
@@ -119,7 +263,8 @@ impl EventType {
             | EventType::DiskError
             | EventType::NicBroken
             | EventType::IpConflict
-            | EventType::IllegalAccess => Some("problem"),
+            | EventType::IllegalAccess
+            | EventType::HddSmart => Some("problem"),
         }
     }
 
@@ -143,9 +288,20 @@ impl EventType {
             EventType::StorageDetection
             | EventType::RecordingFailure
             | EventType::DiskFull
-            | EventType::DiskError => Some("mdi:harddisk"),
+            | EventType::DiskError
+            | EventType::HddSmart => Some("mdi:harddisk"),
             EventType::NicBroken | EventType::IpConflict => Some("mdi:lan-disconnect"),
             EventType::IllegalAccess => Some("mdi:account-alert"),
+            EventType::Pir => Some("mdi:motion-sensor"),
+            EventType::Thermometry => Some("mdi:thermometer-alert"),
+            EventType::FireDetection => Some("mdi:fire"),
+            EventType::Anpr | EventType::VehicleDetection => Some("mdi:car"),
+            EventType::PeopleCounting => Some("mdi:account-multiple"),
+            EventType::Loitering => Some("mdi:account-clock"),
+            EventType::Parking => Some("mdi:parking"),
+            EventType::FallDetection => Some("mdi:human-handsdown"),
+            EventType::CrowdDensity => Some("mdi:account-group"),
+            EventType::Intelligent => None,
             EventType::Unknown(_) => None,
         }
     }
@@ -193,6 +349,21 @@ impl FromStr for EventType {
             "nicbroken" => EventType::NicBroken,
             "ipconflict" => EventType::IpConflict,
             "illaccess" => EventType::IllegalAccess,
+            "pir" => EventType::Pir,
+            "thermometry" => EventType::Thermometry,
+            "firedetection" => EventType::FireDetection,
+            "anpr" => EventType::Anpr,
+            "vehicledetection" => EventType::VehicleDetection,
+            "peoplecounting" => EventType::PeopleCounting,
+            "loitering" => EventType::Loitering,
+            "parking" => EventType::Parking,
+            "falldown" => EventType::FallDetection,
+            "falldetection" => EventType::FallDetection,
+            "hddsmart" => EventType::HddSmart,
+            "hdimpairdetection" => EventType::HddSmart,
+            "groupdensity" => EventType::CrowdDensity,
+            "crowddensity" => EventType::CrowdDensity,
+            "intelligent" => EventType::Intelligent,
             _ => {
                 // Ensure the input is valid
                 if s.chars().all(|c| c.is_ascii_alphanumeric()) {
@@ -207,34 +378,47 @@ impl FromStr for EventType {
     }
 }
 
-impl ToString for EventType {
-    fn to_string(&self) -> String {
-        match self {
-            EventType::Io => "Io".to_string(),
-            EventType::Motion => "Motion".to_string(),
-            EventType::LineDetection => "LineDetection".to_string(),
-            EventType::UnattendedBaggage => "UnattendedBaggage".to_string(),
-            EventType::AttendedBaggage => "AttendedBaggage".to_string(),
-            EventType::RegionEntrance => "RegionEntrance".to_string(),
-            EventType::RegionExiting => "RegionExiting".to_string(),
-            EventType::SceneChangeDetection => "SceneChangeDetection".to_string(),
-            EventType::FieldDetection => "FieldDetection".to_string(),
-            EventType::FaceDetection => "FaceDetection".to_string(),
-            EventType::FaceSnap => "FaceSnap".to_string(),
-            EventType::AudioException => "AudioException".to_string(),
-            EventType::VideoLoss => "VideoLoss".to_string(),
-            EventType::Tamper => "Tamper".to_string(),
-            EventType::VideoMismatch => "VideoMismatch".to_string(),
-            EventType::BadVideo => "BadVideo".to_string(),
-            EventType::StorageDetection => "StorageDetection".to_string(),
-            EventType::RecordingFailure => "RecordingFailure".to_string(),
-            EventType::DiskFull => "DiskFull".to_string(),
-            EventType::DiskError => "DiskError".to_string(),
-            EventType::NicBroken => "NicBroken".to_string(),
-            EventType::IpConflict => "IpConflict".to_string(),
-            EventType::IllegalAccess => "IllegalAccess".to_string(),
-            EventType::Unknown(s) => s.clone(),
-        }
+impl fmt::Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EventType::Io => "Io",
+            EventType::Motion => "Motion",
+            EventType::LineDetection => "LineDetection",
+            EventType::UnattendedBaggage => "UnattendedBaggage",
+            EventType::AttendedBaggage => "AttendedBaggage",
+            EventType::RegionEntrance => "RegionEntrance",
+            EventType::RegionExiting => "RegionExiting",
+            EventType::SceneChangeDetection => "SceneChangeDetection",
+            EventType::FieldDetection => "FieldDetection",
+            EventType::FaceDetection => "FaceDetection",
+            EventType::FaceSnap => "FaceSnap",
+            EventType::AudioException => "AudioException",
+            EventType::VideoLoss => "VideoLoss",
+            EventType::Tamper => "Tamper",
+            EventType::VideoMismatch => "VideoMismatch",
+            EventType::BadVideo => "BadVideo",
+            EventType::StorageDetection => "StorageDetection",
+            EventType::RecordingFailure => "RecordingFailure",
+            EventType::DiskFull => "DiskFull",
+            EventType::DiskError => "DiskError",
+            EventType::NicBroken => "NicBroken",
+            EventType::IpConflict => "IpConflict",
+            EventType::IllegalAccess => "IllegalAccess",
+            EventType::Pir => "Pir",
+            EventType::Thermometry => "Thermometry",
+            EventType::FireDetection => "FireDetection",
+            EventType::Anpr => "Anpr",
+            EventType::VehicleDetection => "VehicleDetection",
+            EventType::PeopleCounting => "PeopleCounting",
+            EventType::Loitering => "Loitering",
+            EventType::Parking => "Parking",
+            EventType::FallDetection => "FallDetection",
+            EventType::HddSmart => "HddSmart",
+            EventType::CrowdDensity => "CrowdDensity",
+            EventType::Intelligent => "Intelligent",
+            EventType::Unknown(s) => s.as_str(),
+        };
+        f.write_str(name)
     }
 }
 
@@ -245,6 +429,7 @@ mod test {
     #[test]
     fn test_parses_all_known() {
         let tests = [
+            "ANPR",
             "IO",
             "VMD",
             "attendedBaggage",
@@ -252,22 +437,35 @@ mod test {
             "badvideo",
             "diskerror",
             "diskfull",
+            "falldown",
             "faceSnap",
             "facedetection",
             "facedetection-1",
             "faceDetection-Unknown",
             "fielddetection",
+            "fireDetection",
+            "groupdensity",
+            "crowdDensity",
+            "HDDSMART",
+            "hdImpairDetection",
             "illAccess",
+            "intelligent",
             "ipconflict",
             "linedetection",
+            "loitering",
             "nicbroken",
+            "parking",
+            "peopleCounting",
+            "PIR",
             "recordingfailure",
             "regionEntrance",
             "regionExiting",
             "scenechangedetection",
             "storageDetection",
             "tamperdetection",
+            "thermometry",
             "unattendedBaggage",
+            "vehicleDetection",
             "videoloss",
             "videomismatch",
         ];
@@ -285,13 +483,40 @@ mod test {
         insta::assert_yaml_snapshot!(tests_normal_case);
     }
     #[test]
+    fn test_display_round_trip() {
+        for event_type in [
+            EventType::Pir,
+            EventType::Thermometry,
+            EventType::FireDetection,
+            EventType::Anpr,
+            EventType::VehicleDetection,
+            EventType::PeopleCounting,
+            EventType::Loitering,
+            EventType::Parking,
+            EventType::FallDetection,
+            EventType::HddSmart,
+            EventType::CrowdDensity,
+            EventType::Intelligent,
+        ] {
+            assert_eq!(event_type.to_string().parse(), Ok(event_type));
+        }
+    }
+    #[test]
+    fn test_unknown_device_class() {
+        let device_class = |s: &str| EventType::Unknown(s.to_string()).device_class();
+        assert_eq!(device_class("newSmartDetection"), Some("motion"));
+        assert_eq!(device_class("diskUnformatted"), Some("problem"));
+        assert_eq!(device_class("networkDisconnected"), Some("problem"));
+        assert_eq!(device_class("recordException"), Some("problem"));
+    }
+    #[test]
     fn test_handles_unknown() {
         assert_eq!(
             "random".parse(),
             Ok(EventType::Unknown("random".to_string()))
         );
 
-        let invalids = vec![
+        let invalids = [
             "random space",
             "line-detection",
             "",
@@ -1,11 +1,32 @@
+//! Talking to cameras over ISAPI: fetching device info and triggers, and parsing the alert stream
+
 mod alert_parser;
 mod camera;
+mod capture;
+mod channels_parser;
+mod connect_limit;
+mod controls_parser;
 mod device_info;
+mod event_queue;
 mod event_type;
+mod ptz_parser;
+mod time_parser;
 mod triggers_parser;
 
-pub use alert_parser::{AlertItem, DetectionRegion, RegionCoordinates};
-pub use camera::{run_camera, Camera, CameraEvent, CameraEventType};
-pub use device_info::DeviceInfo;
+pub use alert_parser::{
+    AlertItem, AlertParseError, AnprDetails, DetectionRegion, PeopleCount, RegionCoordinates,
+};
+pub use camera::{
+    parse_part, run_camera, stream_boundary, Camera, CameraCommand, CameraError, CameraEvent,
+    CameraEventType, CameraHandle, DisconnectKind,
+};
+pub use capture::Capture;
+pub use channels_parser::ChannelParseError;
+pub use connect_limit::{ConnectLimit, ConnectPermit};
+pub use controls_parser::{ControlParseError, ControlState, ControlType};
+pub use device_info::{DeviceInfo, DeviceInfoParseError};
+pub use event_queue::{EventQueue, EventReceiver};
 pub use event_type::{EventIdentifier, EventType};
-pub use triggers_parser::TriggerItem;
+pub use ptz_parser::{PtzParseError, PtzPreset};
+pub use time_parser::TimeParseError;
+pub use triggers_parser::{TriggerItem, TriggerParseError};
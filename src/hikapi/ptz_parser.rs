@@ -0,0 +1,94 @@
+use minidom::Element;
+use serde::{Deserialize, Serialize};
+
+/// A saved position of a PTZ camera
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
+pub struct PtzPreset {
+    /// The preset number used to recall it
+    pub id: String,
+    /// The name of the preset, as configured on the camera
+    pub name: String,
+}
+
+impl PtzPreset {
+    /// Parses the preset list returned by `/ISAPI/PTZCtrl/channels/<id>/presets`. Presets that
+    /// haven't been set are left out.
+    pub fn parse(s: &str) -> Result<Vec<PtzPreset>, PtzParseError> {
+        let root: Element = s.parse()?;
+        if root.name() != "PTZPresetList" {
+            return Err(PtzParseError::RootNodeIncorrect(root.name().into()));
+        }
+
+        let mut presets = Vec::new();
+        for preset in root.children() {
+            let enabled = preset
+                .get_child("enabled", minidom::NSChoice::Any)
+                .map(|e| e.text());
+            if enabled.as_deref() == Some("false") {
+                continue;
+            }
+            let id = preset
+                .get_child("id", minidom::NSChoice::Any)
+                .ok_or_else(|| PtzParseError::FieldMissing("id".to_string()))?
+                .text();
+            let name = match preset.get_child("presetName", minidom::NSChoice::Any) {
+                Some(name) if !name.text().trim().is_empty() => name.text().trim().to_string(),
+                _ => format!("Preset {}", id),
+            };
+            presets.push(PtzPreset { id, name });
+        }
+        Ok(presets)
+    }
+
+    /// The preset a command refers to, by number or by name
+    pub fn find<'a>(presets: &'a [PtzPreset], preset: &str) -> Option<&'a PtzPreset> {
+        presets
+            .iter()
+            .find(|p| p.id == preset)
+            .or_else(|| presets.iter().find(|p| p.name == preset))
+    }
+}
+
+quick_error! {
+    /// Why a preset list couldn't be parsed
+    #[derive(Debug)]
+    pub enum PtzParseError {
+        /// The body isn't well formed XML
+        XmlInvalid(error: minidom::Error) {
+            from()
+        }
+        /// The XML is for something else
+        RootNodeIncorrect(name: String) {
+            display("Returned root node invalid: {}", name)
+        }
+        /// A required field wasn't found
+        FieldMissing(field: String) {
+            display("Field was expected but missing: {}", field)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PtzPreset;
+    const PRESETS: &str = include_str!("../../samples/ptz_presets.xml");
+
+    #[test]
+    fn test_parse_presets() {
+        let presets = PtzPreset::parse(PRESETS).unwrap();
+        insta::assert_yaml_snapshot!(presets);
+        assert_eq!(PtzPreset::find(&presets, "2").unwrap().name, "Gate");
+        assert_eq!(PtzPreset::find(&presets, "Driveway").unwrap().id, "1");
+        assert_eq!(PtzPreset::find(&presets, "3"), None);
+        assert_eq!(PtzPreset::find(&presets, "Garden"), None);
+    }
+
+    #[test]
+    fn test_bad_presets() {
+        assert!(PtzPreset::parse("").is_err());
+        assert!(PtzPreset::parse(
+            "<DeviceInfo xmlns=\"http://www.hikvision.com/ver20/XMLSchema\"></DeviceInfo>"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone};
+use minidom::Element;
+
+/// Parses the camera's current time returned by `/ISAPI/System/time`. Firmwares that leave the offset
+/// out of `localTime` have it read from the POSIX style `timeZone`, e.g. `CST-8:00:00` for UTC+8.
+pub fn parse_time(s: &str) -> Result<DateTime<FixedOffset>, TimeParseError> {
+    let root: Element = s.parse()?;
+    if root.name() != "Time" {
+        return Err(TimeParseError::RootNodeIncorrect(root.name().into()));
+    }
+    let local_time = root
+        .get_child("localTime", minidom::NSChoice::Any)
+        .ok_or_else(|| TimeParseError::FieldMissing("localTime".to_string()))?
+        .text();
+    let local_time = local_time.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(local_time) {
+        return Ok(time);
+    }
+    let naive = NaiveDateTime::parse_from_str(local_time, "%Y-%m-%dT%H:%M:%S")
+        .map_err(|_| TimeParseError::TimeInvalid(local_time.to_string()))?;
+    let time_zone = root
+        .get_child("timeZone", minidom::NSChoice::Any)
+        .ok_or_else(|| TimeParseError::FieldMissing("timeZone".to_string()))?
+        .text();
+    let offset = parse_posix_offset(&time_zone)
+        .ok_or_else(|| TimeParseError::TimeInvalid(time_zone.trim().to_string()))?;
+    offset
+        .from_local_datetime(&naive)
+        .single()
+        .ok_or_else(|| TimeParseError::TimeInvalid(local_time.to_string()))
+}
+
+/// Parses the standard offset of a POSIX time zone, whose sign is the opposite of the UTC offset.
+/// Daylight saving rules after the offset are ignored.
+fn parse_posix_offset(time_zone: &str) -> Option<FixedOffset> {
+    let time_zone = time_zone.trim();
+    let start = time_zone.find(|c: char| c == '+' || c == '-' || c.is_ascii_digit())?;
+    let rest = &time_zone[start..];
+    let (sign, rest) = match rest.as_bytes()[0] {
+        b'-' => (1, &rest[1..]),
+        b'+' => (-1, &rest[1..]),
+        _ => (-1, rest),
+    };
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit() && c != ':')
+        .unwrap_or(rest.len());
+    let mut parts = rest[..end].split(':').map(|p| p.parse::<i32>().ok());
+    let hours = parts.next()??;
+    let minutes = parts.next().unwrap_or(Some(0))?;
+    let seconds = parts.next().unwrap_or(Some(0))?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+quick_error! {
+    /// Why the camera's time couldn't be parsed
+    #[derive(Debug)]
+    pub enum TimeParseError {
+        /// The body isn't well formed XML
+        XmlInvalid(error: minidom::Error) {
+            from()
+        }
+        /// The XML is for something else
+        RootNodeIncorrect(name: String) {
+            display("Returned root node invalid: {}", name)
+        }
+        /// A required field wasn't found
+        FieldMissing(field: String) {
+            display("Field was expected but missing: {}", field)
+        }
+        /// The time or time zone isn't in a recognised format
+        TimeInvalid(value: String) {
+            display("Time could not be parsed: {}", value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_posix_offset, parse_time};
+    const SYSTEM_TIME: &str = include_str!("../../samples/system_time.xml");
+    const SYSTEM_TIME_NO_OFFSET: &str = include_str!("../../samples/system_time_no_offset.xml");
+
+    #[test]
+    fn test_parse_time() {
+        let time = parse_time(SYSTEM_TIME).unwrap();
+        assert_eq!(time.to_rfc3339(), "2023-05-11T21:14:09+10:00");
+        // The same moment, with the offset taken from the time zone
+        let time = parse_time(SYSTEM_TIME_NO_OFFSET).unwrap();
+        assert_eq!(time.to_rfc3339(), "2023-05-11T19:14:09+08:00");
+    }
+
+    #[test]
+    fn test_posix_offset() {
+        let offset = |tz: &str| parse_posix_offset(tz).map(|o| o.local_minus_utc());
+        assert_eq!(offset("CST-8:00:00"), Some(8 * 3600));
+        assert_eq!(offset("EST+5:00:00EDT,M3.2.0/2,M11.1.0/2"), Some(-5 * 3600));
+        assert_eq!(offset("IST-5:30:00"), Some(5 * 3600 + 30 * 60));
+        assert_eq!(offset("UTC0"), Some(0));
+        assert_eq!(offset("CST"), None);
+    }
+
+    #[test]
+    fn test_bad_time() {
+        assert!(parse_time("").is_err());
+        assert!(parse_time(
+            "<DeviceInfo xmlns=\"http://www.hikvision.com/ver20/XMLSchema\"></DeviceInfo>"
+        )
+        .is_err());
+        assert!(parse_time(
+            "<Time xmlns=\"http://www.hikvision.com/ver20/XMLSchema\"><localTime>soon</localTime></Time>"
+        )
+        .is_err());
+    }
+}
@@ -3,14 +3,27 @@ use serde::{Deserialize, Serialize};
 
 use super::EventIdentifier;
 
+/// An event trigger configured on the camera, which can send alerts
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub struct TriggerItem {
+    /// The channel and event type of the trigger
     pub identifier: EventIdentifier,
+    /// The camera's ID for the trigger, e.g. `VMD-1`
     pub hik_id: String,
+    /// The camera's description of the trigger
     pub description: String,
+    /// The name of the channel as configured on an NVR
+    #[serde(default)]
+    pub channel_name: Option<String>,
+    /// The name of the alarm input as configured on the camera, for I/O triggers
+    #[serde(default)]
+    pub port_name: Option<String>,
+    /// Whether the camera is set to notify the surveillance center (i.e. the alert stream) when the trigger fires
+    pub notifies_center: bool,
 }
 
 impl TriggerItem {
+    /// Parses the trigger list returned by `/ISAPI/Event/triggers`
     pub fn parse(s: &str) -> Result<Vec<TriggerItem>, TriggerParseError> {
         let root: Element = s.parse()?;
         let event_triggers = root
@@ -42,6 +55,21 @@ impl TriggerItem {
                 .or_else(|| event_trigger.get_child("inputIOPortID", minidom::NSChoice::Any))
                 .or_else(|| event_trigger.get_child("dynInputIOPortID", minidom::NSChoice::Any))
                 .map(|e| e.text());
+            let notifies_center = event_trigger
+                .get_child("EventTriggerNotificationList", minidom::NSChoice::Any)
+                .map(|list| {
+                    list.children().any(|notification| {
+                        notification
+                            .get_child("notificationMethod", minidom::NSChoice::Any)
+                            .map(|method| {
+                                let method = method.text();
+                                method.eq_ignore_ascii_case("center")
+                                    || method.eq_ignore_ascii_case("HTTP")
+                            })
+                            .unwrap_or(false)
+                    })
+                })
+                .unwrap_or(false);
 
             let event_type = event_type
                 .parse()
@@ -52,6 +80,9 @@ impl TriggerItem {
                 hik_id,
                 identifier,
                 description,
+                channel_name: None,
+                port_name: None,
+                notifies_center,
             })
         }
 
@@ -65,29 +96,38 @@ impl From<EventIdentifier> for TriggerItem {
             description: String::new(),
             hik_id: format!(
                 "{}{}",
-                e.event_type.to_string(),
+                e.event_type,
                 e.channel
                     .as_ref()
                     .map(|c| format!("-{}", c))
                     .unwrap_or_default()
             ),
             identifier: e,
+            channel_name: None,
+            port_name: None,
+            // Configured or learnt from an alert, so events are known to be sent
+            notifies_center: true,
         }
     }
 }
 
 quick_error! {
+    /// Why the trigger list couldn't be parsed
     #[derive(Debug)]
     pub enum TriggerParseError {
+        /// The body isn't well formed XML
         XmlInvalid(error: minidom::Error) {
             from()
         }
+        /// The trigger doesn't say which channel it's for
         ChannelMissing {
             display("Event should contain either channelID or dynChannelID but neither were found")
         }
+        /// A required field wasn't found
         FieldMissing(field: String) {
             display("Field was expected but missing: {}", field)
         }
+        /// The event type couldn't be read
         EventTypeInvalid(provided: String, error: String) {
             display("Event type `{}` was incorrectly formatted: {}", provided, error)
         }
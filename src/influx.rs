@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use crate::{
+    config::ConfigInflux,
+    hikapi::{CameraEvent, CameraEventType, EventIdentifier},
+};
+
+/// The measurement every alert is written to
+const MEASUREMENT: &str = "hik_event";
+
+/// Outcomes of the writes so far, shared with the sink for its stats. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct InfluxStats {
+    written: Arc<AtomicU64>,
+    write_errors: Arc<AtomicU64>,
+    /// Points that were never written, because the server or HikSink couldn't keep up
+    dropped: Arc<AtomicU64>,
+}
+
+impl InfluxStats {
+    /// The counters as they appear in the stats
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "points_written": self.written.load(Ordering::Relaxed),
+            "write_errors": self.write_errors.load(Ordering::Relaxed),
+            "points_dropped": self.dropped.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Stats are equal when they are the same counters
+impl PartialEq for InfluxStats {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.written, &other.written)
+    }
+}
+
+/// Why a write failed
+enum WriteError {
+    /// Worth trying again, e.g. a timeout or a server error
+    Transient(String),
+    /// Will fail the same way every time, e.g. the points were rejected
+    Permanent(String),
+}
+
+/// The last state of each trigger, so only changes are written
+#[derive(Debug, Default)]
+struct Transitions {
+    states: HashMap<(String, EventIdentifier), bool>,
+}
+
+impl Transitions {
+    /// The line protocol for an event, if it's an alert changing the state of its trigger. Alerts without a
+    /// recognised time are written at `now`.
+    fn line(&mut self, event: &CameraEvent, now: DateTime<Utc>) -> Option<String> {
+        let alert = match &event.event {
+            CameraEventType::Alert(alert) => alert,
+            // The camera's triggers start from scratch when it reconnects
+            CameraEventType::Disconnected { .. } => {
+                self.states.retain(|(id, _), _| *id != event.id);
+                return None;
+            }
+            _ => return None,
+        };
+        let previous = self
+            .states
+            .insert((event.id.clone(), alert.identifier.clone()), alert.active);
+        if previous == Some(alert.active) {
+            return None;
+        }
+        let mut line = format!("{},camera={}", MEASUREMENT, escape_tag(&event.id));
+        if let Some(channel) = &alert.identifier.channel {
+            line.push_str(&format!(",channel={}", escape_tag(channel)));
+        }
+        line.push_str(&format!(
+            ",type={} active={}i {}",
+            escape_tag(&alert.identifier.event_type.to_string()),
+            alert.active as u8,
+            alert.time.map_or(now.timestamp(), |time| time.timestamp())
+        ));
+        Some(line)
+    }
+}
+
+/// Escapes the characters line protocol gives a meaning to in tag values
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Starts writing the alerts from the broadcast to InfluxDB. Runs until the broadcast is closed.
+pub fn start(
+    config: ConfigInflux,
+    stats: InfluxStats,
+    mut events: broadcast::Receiver<CameraEvent>,
+) -> Result<(), String> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()
+        .map_err(|e| e.to_string())?;
+    info!(url = config.url.as_str(), "Writing alerts to InfluxDB");
+    tokio::task::spawn(async move {
+        let mut transitions = Transitions::default();
+        let mut points = VecDeque::new();
+        let period = Duration::from_secs(config.flush_interval_secs);
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        if let Some(line) = transitions.line(&event, Utc::now()) {
+                            points.push_back(line);
+                            if points.len() > config.max_points {
+                                points.pop_front();
+                                stats.dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        stats.dropped.fetch_add(count, Ordering::Relaxed);
+                        warn!("InfluxDB is too slow, dropped {} alerts", count);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+
+                _ = ticker.tick() => {
+                    if points.is_empty() {
+                        continue;
+                    }
+                    let body = points.iter().map(String::as_str).collect::<Vec<_>>().join("\n");
+                    match write(&client, &config, body).await {
+                        Ok(()) => {
+                            stats.written.fetch_add(points.len() as u64, Ordering::Relaxed);
+                            points.clear();
+                        }
+                        Err(WriteError::Transient(e)) => {
+                            stats.write_errors.fetch_add(1, Ordering::Relaxed);
+                            warn!(points = points.len(), "Unable to write to InfluxDB, retrying with the next batch: {}", e);
+                        }
+                        Err(WriteError::Permanent(e)) => {
+                            stats.write_errors.fetch_add(1, Ordering::Relaxed);
+                            stats.dropped.fetch_add(points.len() as u64, Ordering::Relaxed);
+                            warn!(points = points.len(), "InfluxDB rejected the points, dropping them: {}", e);
+                            points.clear();
+                        }
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Writes the points to the bucket, or the database of InfluxDB 1
+async fn write(client: &Client, config: &ConfigInflux, body: String) -> Result<(), WriteError> {
+    let url = config.url.trim_end_matches('/');
+    let request = match (&config.org, &config.bucket, &config.database) {
+        (Some(org), Some(bucket), _) => client
+            .post(format!("{}/api/v2/write", url))
+            .query(&[("org", org), ("bucket", bucket)]),
+        (_, _, Some(database)) => client
+            .post(format!("{}/write", url))
+            .query(&[("db", database)]),
+        // Refused when validating the config
+        _ => return Err(WriteError::Permanent("No bucket or database".to_string())),
+    };
+    let request = match &config.token {
+        Some(token) => request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token)),
+        None => request,
+    };
+    let response = request
+        .query(&[("precision", "s")])
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| WriteError::Transient(e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(WriteError::Transient(format!(
+            "InfluxDB returned {}",
+            status
+        )))
+    } else {
+        let message = response.text().await.unwrap_or_default();
+        Err(WriteError::Permanent(format!(
+            "InfluxDB returned {}: {}",
+            status, message
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use chrono::TimeZone;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::{start, InfluxStats, Transitions};
+    use crate::{
+        config::ConfigInflux,
+        hikapi::{AlertItem, CameraEvent, CameraEventType, DisconnectKind},
+    };
+
+    fn alert(id: &str, channel: &str, event_type: &str, state: &str) -> CameraEvent {
+        let alert = AlertItem::parse(&format!(
+            r#"<EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <channelID>{}</channelID>
+            <dateTime>2021-10-02T18:04:02+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>{}</eventType>
+            <eventState>{}</eventState>
+            <eventDescription>alarm</eventDescription>
+            </EventNotificationAlert>"#,
+            channel, event_type, state
+        ))
+        .unwrap();
+        CameraEvent {
+            id: id.into(),
+            event: CameraEventType::Alert(alert),
+        }
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        let now = chrono::Utc.ymd(2021, 10, 2).and_hms(10, 5, 0);
+        let mut transitions = Transitions::default();
+        let lines: Vec<_> = [
+            alert("front_door", "1", "VMD", "active"),
+            // Repeated while the motion lasts
+            alert("front_door", "1", "VMD", "active"),
+            alert("front_door", "2", "linedetection", "active"),
+            alert("front_door", "1", "VMD", "inactive"),
+            alert("back yard", "1", "VMD", "active"),
+            CameraEvent {
+                id: "front_door".into(),
+                event: CameraEventType::Disconnected {
+                    error: "Camera closed connection".into(),
+                    kind: DisconnectKind::Closed,
+                    retry_in_secs: Some(1),
+                },
+            },
+            alert("front_door", "2", "linedetection", "active"),
+        ]
+        .iter()
+        .filter_map(|event| transitions.line(event, now))
+        .collect();
+        insta::assert_yaml_snapshot!(lines);
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let received_tx = received_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let received_tx = received_tx.clone();
+                    async move {
+                        let uri = req.uri().to_string();
+                        let auth = req.headers()["authorization"].to_str().unwrap().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let _ = received_tx.send((uri, auth, body));
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(StatusCode::NO_CONTENT)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let config = ConfigInflux {
+            url: format!("http://{}/", addr),
+            token: Some("secret".into()),
+            org: Some("home".into()),
+            bucket: Some("cameras".into()),
+            database: None,
+            flush_interval_secs: 1,
+            timeout_secs: 5,
+            max_points: 10,
+            queue_size: 10,
+        };
+        let stats = InfluxStats::default();
+        let (events, _) = broadcast::channel(10);
+        start(config, stats.clone(), events.subscribe()).unwrap();
+        events
+            .send(alert("front_door", "1", "VMD", "active"))
+            .unwrap();
+        events
+            .send(alert("front_door", "1", "VMD", "inactive"))
+            .unwrap();
+
+        let (uri, auth, body) = received_rx.recv().await.unwrap();
+        assert_eq!(uri, "/api/v2/write?org=home&bucket=cameras&precision=s");
+        assert_eq!(auth, "Token secret");
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "hik_event,camera=front_door,channel=1,type=Motion active=1i 1633169042\n\
+             hik_event,camera=front_door,channel=1,type=Motion active=0i 1633169042"
+        );
+        // The counters are updated once the response has been handled
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(stats.to_json()["points_written"], 2);
+        assert_eq!(stats.to_json()["write_errors"], 0);
+    }
+}
@@ -0,0 +1,15 @@
+//! A client for the event notification API (ISAPI) of Hikvision cameras and recorders, and the
+//! config of the HikSink MQTT bridge built on it.
+//!
+//! [`hikapi::Camera`] connects to a camera and reads its alert stream, while [`hikapi::run_camera`]
+//! keeps a connection going, forwarding everything that happens to a channel.
+
+#![warn(missing_docs)]
+
+#[macro_use]
+extern crate quick_error;
+
+pub mod config;
+pub mod hikapi;
+#[cfg(test)]
+mod sim;
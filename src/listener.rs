@@ -0,0 +1,598 @@
+use std::{convert::Infallible, net::IpAddr};
+
+use bytes::{Bytes, BytesMut};
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{CameraMode, ConfigCamera, ConfigListener},
+    hikapi::{
+        parse_part, stream_boundary, CameraError, CameraEvent, CameraEventType, Capture,
+        DeviceInfo, EventQueue,
+    },
+};
+
+/// The largest request accepted, enough for an alert with a few pictures. Anyone who can reach the listener can
+/// send requests, so they aren't read whole regardless of their size.
+const MAX_PUSH_SIZE: usize = 4 * 1024 * 1024;
+
+/// A camera in push mode, as known to the listener
+#[derive(Debug, Clone)]
+pub struct PushCamera {
+    config: ConfigCamera,
+    /// The camera's addresses, resolved when the config was loaded
+    addresses: Vec<IpAddr>,
+    /// The device info read from the camera when HikSink connected to it
+    info: watch::Receiver<Option<DeviceInfo>>,
+}
+
+/// The cameras in push mode, with their addresses resolved. Resolving is left to here so it happens once
+/// per config (re)load rather than for every pushed alert.
+pub async fn push_cameras<'a>(
+    cameras: impl IntoIterator<Item = (&'a ConfigCamera, watch::Receiver<Option<DeviceInfo>>)>,
+) -> Vec<PushCamera> {
+    let mut pushing = Vec::new();
+    for (cam, info) in cameras {
+        if cam.mode != CameraMode::Push {
+            continue;
+        }
+        let mut addresses = Vec::new();
+        for address in std::iter::once(&cam.address).chain(&cam.fallback_address) {
+            addresses.extend(resolve(address).await);
+        }
+        pushing.push(PushCamera {
+            config: cam.clone(),
+            addresses,
+            info,
+        });
+    }
+    pushing
+}
+
+/// The IP addresses of a camera's hostname or IP address
+async fn resolve(address: &str) -> Vec<IpAddr> {
+    match address.parse::<IpAddr>() {
+        Ok(address) => vec![address],
+        Err(_) => match tokio::net::lookup_host((address, 0)).await {
+            Ok(addresses) => addresses.map(|a| a.ip()).collect(),
+            Err(e) => {
+                warn!(address, "Unable to resolve camera address: {}", e);
+                Vec::new()
+            }
+        },
+    }
+}
+
+/// Accepts alerts POSTed by cameras in push mode, the way an ISAPI alarm server (the camera's
+/// "HTTP listening" setting) would, and queues them like alerts from an alert stream.
+/// With a `capture`, the raw alert parts are saved to it.
+pub fn serve(
+    config: &ConfigListener,
+    cameras: watch::Receiver<Vec<PushCamera>>,
+    queue: EventQueue,
+    capture: Option<Capture>,
+) {
+    let server = match Server::try_bind(&config.bind) {
+        Ok(server) => server,
+        Err(e) => {
+            error!(bind=%config.bind, "Unable to start alert listener: {}", e);
+            return;
+        }
+    };
+    info!(bind=%config.bind, "Listening for pushed alerts");
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let source = conn.remote_addr().ip();
+        let cameras = cameras.clone();
+        let queue = queue.clone();
+        let capture = capture.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let cameras = cameras.clone();
+                let queue = queue.clone();
+                let capture = capture.clone();
+                async move {
+                    let cameras = cameras.borrow().clone();
+                    Ok::<_, Infallible>(
+                        respond(req, source, &cameras, &queue, capture.as_ref()).await,
+                    )
+                }
+            }))
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = server.serve(make_service).await {
+            error!("Alert listener stopped: {}", e);
+        }
+    });
+}
+
+async fn respond(
+    req: Request<Body>,
+    source: IpAddr,
+    cameras: &[PushCamera],
+    queue: &EventQueue,
+    capture: Option<&Capture>,
+) -> Response<Body> {
+    if req.method() != Method::POST {
+        return plain(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+    let headers = req.headers().clone();
+    let too_large = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|length| length > MAX_PUSH_SIZE);
+    if too_large {
+        warn!(source=%source, "Pushed alert is larger than {} bytes", MAX_PUSH_SIZE);
+        return plain(StatusCode::PAYLOAD_TOO_LARGE, "Body too large");
+    }
+    let body = match read_body(req.into_body(), MAX_PUSH_SIZE).await {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+            warn!(source=%source, "Pushed alert is larger than {} bytes", MAX_PUSH_SIZE);
+            return plain(StatusCode::PAYLOAD_TOO_LARGE, "Body too large");
+        }
+        Err(e) => {
+            warn!(source=%source, "Unable to read pushed alert: {}", e);
+            return plain(StatusCode::BAD_REQUEST, "Unreadable body");
+        }
+    };
+    let parts = match split_push(&headers, body) {
+        Ok(parts) => parts,
+        Err(e) => {
+            warn!(source=%source, "Unable to parse pushed alert: {}", e);
+            return plain(StatusCode::BAD_REQUEST, "Invalid body");
+        }
+    };
+    let cam = match find_camera(cameras, source, pushed_serial(&parts).as_deref()) {
+        Some(cam) => cam,
+        None => {
+            warn!(source=%source, "Received an alert from an unknown camera. Is it in push mode?");
+            return plain(StatusCode::NOT_FOUND, "Unknown camera");
+        }
+    };
+    let events = match parse_push(&cam.config, parts, capture) {
+        Ok(events) => events,
+        Err(e) => {
+            warn!(
+                camera = cam.config.identifier(),
+                "Unable to parse pushed alert: {}", e
+            );
+            return plain(StatusCode::BAD_REQUEST, "Invalid body");
+        }
+    };
+    for event in events {
+        if queue.send(event).await.is_err() {
+            return plain(StatusCode::SERVICE_UNAVAILABLE, "Shutting down");
+        }
+    }
+    plain(StatusCode::OK, "OK")
+}
+
+/// Reads a request body, or returns `None` once it's larger than `limit`, e.g. when sent without a length
+async fn read_body(mut body: Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+    let mut read = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if read.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(Some(read.freeze()))
+}
+
+/// Finds the camera in push mode that sent an alert, by the request's source. As anyone can put a serial number
+/// in an alert, the one in the alert only picks between cameras sharing the source address, e.g. behind a proxy
+/// or NAT, and finds cameras whose address couldn't be resolved. Serial numbers are matched against the device
+/// info of the cameras HikSink has connected to.
+fn find_camera<'a>(
+    cameras: &'a [PushCamera],
+    source: IpAddr,
+    serial: Option<&str>,
+) -> Option<&'a PushCamera> {
+    let has_serial = |c: &&PushCamera| match (serial, c.info.borrow().as_ref()) {
+        (Some(serial), Some(info)) => info.serial_number == serial,
+        _ => false,
+    };
+    let from_source: Vec<&PushCamera> = cameras
+        .iter()
+        .filter(|c| c.addresses.contains(&source))
+        .collect();
+    match from_source.as_slice() {
+        [] => cameras
+            .iter()
+            .filter(|c| c.addresses.is_empty())
+            .find(has_serial),
+        [cam] => Some(cam),
+        shared => shared
+            .iter()
+            .copied()
+            .find(has_serial)
+            .or_else(|| shared.first().copied()),
+    }
+}
+
+/// The serial number of the device that pushed an alert, from the `Extensions` of its XML, if it sent one
+fn pushed_serial(parts: &[(HeaderMap, Bytes)]) -> Option<String> {
+    parts.iter().find_map(|(_, body)| {
+        let root: minidom::Element = std::str::from_utf8(body).ok()?.trim().parse().ok()?;
+        let serial = root
+            .get_child("Extensions", minidom::NSChoice::Any)?
+            .get_child("serialNumber", minidom::NSChoice::Any)?
+            .text();
+        let serial = serial.trim();
+        (!serial.is_empty()).then(|| serial.to_string())
+    })
+}
+
+/// The parts of a pushed request. Cameras send either the alert alone, or a multipart form with the alert
+/// and any pictures taken.
+fn split_push(headers: &HeaderMap, body: Bytes) -> Result<Vec<(HeaderMap, Bytes)>, String> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("multipart/") {
+        let boundary = stream_boundary(content_type).map_err(|e| e.to_string())?;
+        split_multipart(&body, &boundary)
+    } else {
+        Ok(vec![(headers.clone(), body)])
+    }
+}
+
+/// The events in the [parts](split_push) of a pushed request
+fn parse_push(
+    cam: &ConfigCamera,
+    parts: Vec<(HeaderMap, Bytes)>,
+    capture: Option<&Capture>,
+) -> Result<Vec<CameraEvent>, String> {
+    let mut events = Vec::new();
+    let mut pictures = Vec::new();
+    let event = |event| CameraEvent {
+        id: cam.identifier().to_string(),
+        event,
+    };
+    for (headers, body) in parts {
+        let parsed = parse_part(&headers, &body, cam.alert_format);
+        if let (Some(capture), Some(parsed)) = (capture, &parsed) {
+            capture.part(cam.identifier(), &body, parsed.is_err());
+        }
+        match parsed {
+            Some(Ok((_, alerts))) => events.extend(
+                alerts
+                    .into_iter()
+                    .map(|alert| event(CameraEventType::Alert(alert))),
+            ),
+            Some(Err(CameraError::AlertUnparseable(error, raw))) => {
+                warn!(
+                    camera = cam.identifier(),
+                    "Unable to parse alert: {}", error
+                );
+                events.push(event(CameraEventType::ParseFailure {
+                    error: error.to_string(),
+                    raw,
+                }));
+            }
+            Some(Err(e)) => return Err(e.to_string()),
+            None if is_image(&headers) => pictures.push(body),
+            None => {}
+        }
+    }
+
+    // Pictures are of the first alert, as cameras push one event at a time
+    let alerted = events.iter().find_map(|e| match &e.event {
+        CameraEventType::Alert(alert) if alert.active => Some(alert.identifier.clone()),
+        _ => None,
+    });
+    if let (true, Some(identifier), Some(image)) =
+        (cam.snapshot_on_alert, alerted, pictures.into_iter().next())
+    {
+        events.push(event(CameraEventType::Snapshot {
+            identifier,
+            image: image.to_vec(),
+        }));
+    }
+    Ok(events)
+}
+
+fn is_image(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .map(|c| c.starts_with("image/"))
+        .unwrap_or(false)
+}
+
+/// Splits a complete multipart body into the headers and body of each part. Unlike the alert
+/// stream, parts of a form usually don't have a `Content-Length`.
+fn split_multipart(body: &[u8], boundary: &str) -> Result<Vec<(HeaderMap, Bytes)>, String> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut rest = match find(body, delimiter.as_bytes()) {
+        Some(start) => &body[start + delimiter.len()..],
+        None => return Err("Multipart body has no parts".to_string()),
+    };
+    // The final delimiter is followed by `--`
+    while !rest.starts_with(b"--") {
+        let end = find(rest, delimiter.as_bytes()).ok_or("Multipart body is incomplete")?;
+        let part = rest[..end].strip_prefix(b"\r\n").unwrap_or(&rest[..end]);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        parts.push(parse_headers(part)?);
+        rest = &rest[end + delimiter.len()..];
+    }
+    Ok(parts)
+}
+
+/// Separates the headers of a part of a multipart body from its body
+fn parse_headers(part: &[u8]) -> Result<(HeaderMap, Bytes), String> {
+    let (head, body) = match find(part, b"\r\n\r\n") {
+        Some(end) => (&part[..end], &part[end + 4..]),
+        // No headers at all
+        None if part.starts_with(b"\r\n") => (&part[..0], &part[2..]),
+        None => return Err("Multipart part has no end of headers".to_string()),
+    };
+    let mut headers = HeaderMap::new();
+    for line in String::from_utf8_lossy(head).split("\r\n") {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid part header: {}", line))?;
+        headers.append(
+            HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("Invalid part header name: {}", name))?,
+            HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("Invalid part header value: {}", value))?,
+        );
+    }
+    Ok((headers, Bytes::copy_from_slice(body)))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn plain(code: StatusCode, text: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(text))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use bytes::Bytes;
+    use figment::providers::{Format, Toml};
+    use hyper::{header, Body, HeaderMap, Request, StatusCode};
+
+    use tokio::sync::watch;
+
+    use super::{
+        find_camera, parse_push, push_cameras, pushed_serial, read_body, respond, split_push,
+        PushCamera, MAX_PUSH_SIZE,
+    };
+    use crate::{
+        config::{CameraMode, ConfigCamera},
+        hikapi::{CameraEventType, DeviceInfo, EventQueue},
+    };
+
+    const ALERT: &str = r#"<EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+<ipAddress>192.168.1.200</ipAddress>
+<channelID>1</channelID>
+<dateTime>2021-10-02T18:04:02+08:00</dateTime>
+<activePostCount>1</activePostCount>
+<eventType>VMD</eventType>
+<eventState>active</eventState>
+<eventDescription>Motion alarm</eventDescription>
+</EventNotificationAlert>"#;
+
+    fn cameras() -> Vec<ConfigCamera> {
+        let mut cameras =
+            crate::config::load_config(Toml::string(include_str!("../sample_config.toml")))
+                .unwrap()
+                .camera;
+        for cam in &mut cameras {
+            cam.mode = CameraMode::Push;
+            cam.snapshot_on_alert = true;
+        }
+        let mut garage = cameras[0].clone();
+        garage.generated_id = "garage".into();
+        garage.address = "192.168.1.201".into();
+        cameras.push(garage);
+        cameras
+    }
+
+    fn headers(content_type: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        headers
+    }
+
+    /// The cameras as known to the listener, sharing the device info
+    async fn pushing(
+        cameras: &[ConfigCamera],
+        info: &watch::Receiver<Option<DeviceInfo>>,
+    ) -> Vec<PushCamera> {
+        push_cameras(cameras.iter().map(|c| (c, info.clone()))).await
+    }
+
+    #[tokio::test]
+    async fn test_find_camera() {
+        let mut cameras = cameras();
+        let front: IpAddr = cameras[0].address.parse().unwrap();
+        let other: IpAddr = "10.0.0.1".parse().unwrap();
+        let serial = "DS-2CD2185FWD-I20180101AAWR111111111";
+        let (info_tx, info) = watch::channel(None);
+
+        let pushed = pushing(&cameras, &info).await;
+        assert_eq!(
+            find_camera(&pushed, front, None).map(|c| c.config.identifier()),
+            Some(cameras[0].identifier())
+        );
+        assert!(find_camera(&pushed, other, None).is_none());
+        // Serial numbers are only known once HikSink has connected to the camera
+        assert!(find_camera(&pushed, other, Some(serial)).is_none());
+
+        // Alerts can come from a dual-homed camera's other address
+        cameras[0].fallback_address = Some(other.to_string());
+        let pushed = pushing(&cameras, &info).await;
+        assert_eq!(
+            find_camera(&pushed, other, None).map(|c| c.config.identifier()),
+            Some(cameras[0].identifier())
+        );
+
+        // The serial number of a connected camera picks between cameras behind the same address, e.g. NAT
+        let mut back_door = cameras[1].clone();
+        back_door.generated_id = "back_door".into();
+        back_door.address = cameras[0].address.clone();
+        cameras.push(back_door);
+        let mut pushed = pushing(&cameras, &watch::channel(None).1).await;
+        pushed[2].info = info.clone();
+        info_tx.send_replace(Some(DeviceInfo {
+            device_name: "Cam 1".into(),
+            device_id: "7ccc4404-e05d-4376-8ebf-81127da67c11".into(),
+            model: "DS-2CD2185FWD-I".into(),
+            serial_number: serial.into(),
+            mac_address: "ff:ff:ff:ff:ff:ff".into(),
+            firmware_version: "V5.5.71".into(),
+            firmware_release_date: "build 180725".into(),
+            device_type: "IPCamera".into(),
+        }));
+        assert_eq!(
+            find_camera(&pushed, front, Some(serial)).map(|c| c.config.identifier()),
+            Some(cameras[2].identifier())
+        );
+        assert_eq!(
+            find_camera(&pushed, front, None).map(|c| c.config.identifier()),
+            Some(cameras[0].identifier())
+        );
+        // but can't claim an alert from another camera's address
+        let garage: IpAddr = cameras[1].address.parse().unwrap();
+        assert_eq!(
+            find_camera(&pushed, garage, Some(serial)).map(|c| c.config.identifier()),
+            Some(cameras[1].identifier())
+        );
+        assert!(find_camera(&pushed, "10.0.0.2".parse().unwrap(), Some(serial)).is_none());
+        // Cameras whose address couldn't be resolved are only found by their serial number
+        pushed[2].addresses.clear();
+        assert_eq!(
+            find_camera(&pushed, "10.0.0.2".parse().unwrap(), Some(serial))
+                .map(|c| c.config.identifier()),
+            Some(cameras[2].identifier())
+        );
+
+        // Cameras with an alert stream would send every alert twice
+        cameras[0].mode = CameraMode::Stream;
+        assert!(find_camera(&pushing(&cameras[..1], &info).await, front, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_body_size() {
+        let (queue, _events) = EventQueue::channel(10);
+        let request = Request::post("/")
+            .header(header::CONTENT_LENGTH, MAX_PUSH_SIZE + 1)
+            .body(Body::empty())
+            .unwrap();
+        let response = respond(request, "10.0.0.1".parse().unwrap(), &[], &queue, None).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Bodies without a length stop being read at the limit
+        assert_eq!(
+            read_body(Body::from("0123456789"), 10).await.unwrap(),
+            Some(Bytes::from("0123456789"))
+        );
+        assert_eq!(
+            read_body(Body::from("0123456789!"), 10).await.unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_pushed_serial() {
+        let alert = ALERT.replace(
+            "</EventNotificationAlert>",
+            "<Extensions><serialNumber>DS-2CD2185FWD-I20180101AAWR111111111</serialNumber></Extensions>\n</EventNotificationAlert>",
+        );
+        let parts = split_push(&headers("application/xml"), Bytes::from(alert)).unwrap();
+        assert_eq!(
+            pushed_serial(&parts).as_deref(),
+            Some("DS-2CD2185FWD-I20180101AAWR111111111")
+        );
+        let parts = split_push(&headers("application/xml"), Bytes::from(ALERT)).unwrap();
+        assert_eq!(pushed_serial(&parts), None);
+    }
+
+    #[test]
+    fn test_parse_push() {
+        let cameras = cameras();
+        let events = parse_push(
+            &cameras[0],
+            split_push(
+                &headers("application/xml; charset=\"UTF-8\""),
+                Bytes::from(ALERT),
+            )
+            .unwrap(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0].event, CameraEventType::Alert(a) if a.active));
+
+        let form = [
+            "--boundary\r\n",
+            "Content-Disposition: form-data; name=\"MoveDetection.xml\"\r\n",
+            "Content-Type: application/xml\r\n\r\n",
+            ALERT,
+            "\r\n--boundary\r\n",
+            "Content-Disposition: form-data; name=\"Picture\"; filename=\"picture.jpg\"\r\n",
+            "Content-Type: image/jpeg\r\n\r\n",
+            "jpeg",
+            "\r\n--boundary--\r\n",
+        ]
+        .concat();
+        let events = parse_push(
+            &cameras[0],
+            split_push(
+                &headers("multipart/form-data; boundary=boundary"),
+                Bytes::from(form),
+            )
+            .unwrap(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            &events[1].event,
+            CameraEventType::Snapshot { image, .. } if image == b"jpeg"
+        ));
+
+        let events = parse_push(
+            &cameras[0],
+            split_push(
+                &headers("application/xml"),
+                Bytes::from("<EventNotificationAlert>"),
+            )
+            .unwrap(),
+            None,
+        )
+        .unwrap();
+        assert!(matches!(
+            &events[0].event,
+            CameraEventType::ParseFailure { .. }
+        ));
+
+        let incomplete = split_push(
+            &headers("multipart/form-data; boundary=boundary"),
+            Bytes::from("--boundary\r\nContent-Type: application/xml\r\n\r\n"),
+        );
+        assert!(incomplete.is_err());
+    }
+}
@@ -1,14 +1,23 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
+use hik_sink::{config, hikapi};
 use structopt::StructOpt;
-use tracing::{info, trace};
+use tracing::{error, info, trace, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
-#[macro_use]
-extern crate quick_error;
-
-mod config;
-mod hikapi;
+mod backoff;
+mod health;
+mod influx;
+mod listener;
+mod monitor;
 mod mqtt;
+mod probe;
+mod recording;
+mod sink;
+mod webhook;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "hik_sink", about = "Hiksink camera events to MQTT service.")]
@@ -19,32 +28,477 @@ struct CliArgs {
         long = "config",
         default_value = "config.toml",
         help = "Path to configuration file. See sample_config.toml for format.",
-        env = "HIKSINK_CONFIG"
+        env = "HIKSINK_CONFIG",
+        global = true
     )]
     config: PathBuf,
+    #[structopt(
+        long = "validate",
+        help = "Check the configuration, print a summary and exit."
+    )]
+    validate: bool,
+    #[structopt(
+        long = "print-config",
+        help = "Print the effective configuration, including environment variables, with secrets masked and exit."
+    )]
+    print_config: bool,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Connect to a camera and print its device info and triggers, without connecting to MQTT
+    Probe {
+        #[structopt(
+            long = "camera",
+            help = "ID of a camera in the config file.",
+            required_unless = "address"
+        )]
+        camera: Option<String>,
+        #[structopt(
+            long = "address",
+            help = "Address of a camera that isn't in the config file.",
+            conflicts_with = "camera",
+            requires_all = &["username", "password"]
+        )]
+        address: Option<String>,
+        #[structopt(long = "port", help = "Port of the camera given by --address.")]
+        port: Option<u16>,
+        #[structopt(
+            long = "username",
+            help = "Username for the camera given by --address."
+        )]
+        username: Option<String>,
+        #[structopt(
+            long = "password",
+            help = "Password for the camera given by --address."
+        )]
+        password: Option<String>,
+        #[structopt(
+            long = "raw",
+            help = "Also print the unparsed responses, e.g. to paste into an issue."
+        )]
+        raw: bool,
+    },
+    /// Connect to a single camera and print its alerts until Ctrl-C, without connecting to MQTT
+    Monitor {
+        #[structopt(long = "camera", help = "ID of a camera in the config file.")]
+        camera: String,
+        #[structopt(
+            long = "json",
+            help = "Print each alert as a line of JSON instead of a table."
+        )]
+        json: bool,
+        #[structopt(
+            long = "raw",
+            help = "Also print the unparsed contents of the alert stream, e.g. to collect samples of new event types."
+        )]
+        raw: bool,
+    },
+    /// Record the raw alert stream of a camera to a file, until Ctrl-C
+    Record {
+        #[structopt(long = "camera", help = "ID of a camera in the config file.")]
+        camera: String,
+        #[structopt(parse(from_os_str), long = "out", help = "File to record to.")]
+        out: PathBuf,
+    },
+    /// Parse a recorded alert stream, printing what was found in each part and where
+    Replay {
+        #[structopt(parse(from_os_str), help = "File made by the record command.")]
+        file: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     let args = CliArgs::from_args();
-    let cfg = config::load_config_from_path(args.config).unwrap();
+    if let Some(Command::Probe {
+        camera,
+        address,
+        port,
+        username,
+        password,
+        raw,
+    }) = &args.command
+    {
+        let camera = match (camera, address) {
+            (Some(id), _) => find_camera(&args.config, id),
+            (None, Some(address)) => config::adhoc_camera(
+                address,
+                *port,
+                username.as_deref().unwrap_or_default(),
+                password.as_deref().unwrap_or_default(),
+            ),
+            (None, None) => Err("Either --camera or --address is required".to_string()),
+        };
+        // Shows warnings from loading the camera, e.g. triggers that couldn't be scanned
+        log_warnings_to_stderr();
+        if let Err(e) = match camera {
+            Ok(camera) => probe::probe(camera, *raw).await,
+            Err(e) => Err(e),
+        } {
+            eprintln!("Probe failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Record { camera, out }) = &args.command {
+        if let Err(e) = match find_camera(&args.config, camera) {
+            Ok(camera) => recording::record(camera, out).await,
+            Err(e) => Err(e),
+        } {
+            eprintln!("Recording failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Replay { file }) = &args.command {
+        // Parts are parsed the same way as cameras with the default `alert_format`
+        if let Err(e) = recording::replay(file, config::AlertFormat::Auto).await {
+            eprintln!("Replay failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Monitor { camera, json, raw }) = &args.command {
+        // Connection errors are printed from the camera events, so there's no logging
+        if let Err(e) = match find_camera(&args.config, camera) {
+            Ok(camera) => monitor::monitor(camera, *json, *raw).await,
+            Err(e) => Err(e),
+        } {
+            eprintln!("Monitor failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut cfg = match config::load_config_from_path(&args.config) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("Invalid config {}: {}", args.config.display(), e);
+            std::process::exit(1);
+        }
+    };
+    if args.validate {
+        print_summary(&cfg);
+        return;
+    }
+    if args.print_config {
+        match toml::Value::try_from(cfg.redacted()) {
+            Ok(value) => print!("{}", value),
+            Err(e) => {
+                eprintln!("Unable to print config: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    let filter = tracing_subscriber::EnvFilter::new(&cfg.system.log_level);
-    let stdout_subscriber = tracing_subscriber::fmt()
+    let filter = tracing_subscriber::EnvFilter::new(cfg.log_directives());
+    // Keep stdout for the events when they're printed there
+    let writer = if cfg.sink.kind == config::SinkType::Stdout {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let subscriber = tracing_subscriber::fmt()
         // Filter from user
         .with_env_filter(filter)
-        .finish();
-    tracing::subscriber::set_global_default(stdout_subscriber).unwrap();
+        .with_ansi(cfg.system.log_ansi)
+        .with_writer(writer);
+    match cfg.system.log_format {
+        config::SystemLogFormat::Text => {
+            tracing::subscriber::set_global_default(subscriber.finish()).unwrap()
+        }
+        config::SystemLogFormat::Json => {
+            tracing::subscriber::set_global_default(subscriber.json().finish()).unwrap()
+        }
+    }
 
-    info!("HikSink MQTT bridge running");
+    info!("HikSink bridge running");
+    for warning in &cfg.warnings {
+        warn!("Config: {}", warning);
+    }
     trace!("Config: {:?}", cfg);
-    // Connect to MQTT
-    let tx = mqtt::initiate_connection(&cfg).unwrap();
+    let connects = hikapi::ConnectLimit::new(cfg.system.max_concurrent_connects);
+    let influx_stats = cfg.influx.as_ref().map(|_| influx::InfluxStats::default());
+    let mut bridge = match sink::start(&cfg, &connects, influx_stats.as_ref()) {
+        Ok(bridge) => bridge,
+        Err(e) => {
+            error!("Unable to start sink: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // The outputs running alongside the sink each get the camera events queued up to their own queue_size
+    if let Some(webhook) = &cfg.webhook {
+        let events = sink::broadcast(&mut bridge, webhook.queue_size);
+        if let Err(e) = webhook::start(webhook.clone(), events) {
+            error!("Unable to start webhook: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let (Some(influx), Some(stats)) = (&cfg.influx, influx_stats) {
+        let events = sink::broadcast(&mut bridge, influx.queue_size);
+        if let Err(e) = influx::start(influx.clone(), stats, events) {
+            error!("Unable to start InfluxDB writer: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(health) = &cfg.health {
+        health::serve(health, bridge.status.clone());
+    }
+
+    let capture = cfg.system.capture_dir.clone().map(|dir| {
+        info!(dir = %dir.display(), "Capturing raw alerts");
+        hikapi::Capture::start(
+            dir,
+            cfg.system.capture_max_mb * 1024 * 1024,
+            cfg.system.capture_all,
+        )
+    });
+
+    // Cameras in push mode are found by the listener once they're started, and can change when reloading
+    let (pushing_tx, pushing_rx) = tokio::sync::watch::channel(Vec::new());
+    if let Some(listener) = &cfg.listener {
+        listener::serve(listener, pushing_rx, bridge.events.clone(), capture.clone());
+    }
 
     // Start connections to cameras
-    for cam in cfg.camera {
-        hikapi::run_camera(cam, tx.clone());
+    let mut running = RunningCameras::new();
+    let stagger = std::time::Duration::from_millis(cfg.system.connect_stagger_ms);
+    for (i, cam) in cfg.camera.iter().filter(|c| c.enabled).enumerate() {
+        if i > 0 && !stagger.is_zero() {
+            tokio::time::sleep(stagger).await;
+        }
+        start_camera(cam, &bridge, &capture, &connects, &mut running);
+    }
+    if cfg.listener.is_some() {
+        let _ = pushing_tx.send(push_cameras(&running).await);
+    }
+
+    #[cfg(unix)]
+    let mut hangup =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+    #[cfg(unix)]
+    let mut terminate =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+    loop {
+        #[cfg(unix)]
+        let reload = hangup.recv();
+        #[cfg(not(unix))]
+        let reload = futures::future::pending::<Option<()>>();
+        #[cfg(unix)]
+        let stop = terminate.recv();
+        #[cfg(not(unix))]
+        let stop = futures::future::pending::<Option<()>>();
+        tokio::select! {
+            Some((id, command)) = bridge.commands.recv() => match running.get(&id) {
+                Some((_, handle)) => {
+                    handle.send(command);
+                }
+                None => warn!(camera = id.as_str(), "Command for a camera that isn't running"),
+            },
+            Some(()) = reload => {
+                info!("Received SIGHUP, reloading config");
+                reload_config(
+                    &args.config,
+                    &mut cfg,
+                    &bridge,
+                    &capture,
+                    &connects,
+                    &mut running,
+                );
+                if cfg.listener.is_some() {
+                    let _ = pushing_tx.send(push_cameras(&running).await);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down");
+                bridge.shutdown().await;
+                return;
+            }
+            Some(()) = stop => {
+                info!("Received SIGTERM, shutting down");
+                bridge.shutdown().await;
+                return;
+            }
+            Some(e) = bridge.fatal.recv() => {
+                error!("{}", e);
+                for (_, handle) in running.values() {
+                    handle.stop();
+                }
+                bridge.shutdown().await;
+                std::process::exit(1);
+            }
+            else => break,
+        }
     }
 
     let () = futures::future::pending().await;
 }
+
+/// Logging for the commands that print to stdout
+fn log_warnings_to_stderr() {
+    let stderr_subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::new("warn"))
+        .with_writer(std::io::stderr)
+        .finish();
+    tracing::subscriber::set_global_default(stderr_subscriber).unwrap();
+}
+
+/// Finds a camera in the config file by its ID
+fn find_camera(path: &Path, id: &str) -> Result<config::ConfigCamera, String> {
+    let cfg = config::load_config_from_path(path)
+        .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+    let ids: Vec<_> = cfg.camera.iter().map(|c| c.identifier()).collect();
+    let known = ids.join(", ");
+    cfg.camera
+        .iter()
+        .find(|c| c.identifier() == id)
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "No camera with ID {} in the config. Known IDs: {}",
+                id, known
+            )
+        })
+}
+
+/// Prints what `--validate` found in a valid config
+fn print_summary(cfg: &config::Config) {
+    let cfg = cfg.redacted();
+    println!("Config is valid");
+    for warning in &cfg.warnings {
+        println!("Warning: {}", warning);
+    }
+    println!("Sink: {:?}", cfg.sink.kind);
+    if let Some(mqtt) = &cfg.mqtt {
+        println!(
+            "MQTT: {}:{} as {} (username: {}, password: {})",
+            mqtt.address,
+            mqtt.port,
+            mqtt.client_id,
+            mqtt.username.as_deref().unwrap_or("none"),
+            mqtt.password.as_deref().unwrap_or("none")
+        );
+        for broker in &mqtt.brokers {
+            println!(
+                "MQTT mirror: {}:{} as {} (username: {}, password: {})",
+                broker.address,
+                broker.port,
+                broker.client_id,
+                broker.username.as_deref().unwrap_or("none"),
+                broker.password.as_deref().unwrap_or("none")
+            );
+        }
+    }
+    if let Some(webhook) = &cfg.webhook {
+        println!("Webhook: {}", webhook.url);
+    }
+    if let Some(influx) = &cfg.influx {
+        println!(
+            "InfluxDB: {} ({}, token: {})",
+            influx.url,
+            match (&influx.bucket, &influx.database) {
+                (Some(bucket), _) => format!("bucket {}", bucket),
+                (_, database) => format!("database {}", database.as_deref().unwrap_or("none")),
+            },
+            influx.token.as_deref().unwrap_or("none")
+        );
+    }
+    println!("Cameras: {}", cfg.camera.len());
+    for cam in &cfg.camera {
+        println!(
+            "  {} ({}): {} (username: {}, password: {})",
+            cam.name,
+            cam.identifier(),
+            cam.base_url(),
+            cam.username,
+            cam.password
+        );
+    }
+}
+
+/// The config of each running camera and the handle to control it, keyed by camera id
+type RunningCameras = HashMap<String, (config::ConfigCamera, hikapi::CameraHandle)>;
+
+/// The running cameras in push mode, for the listener
+async fn push_cameras(running: &RunningCameras) -> Vec<listener::PushCamera> {
+    listener::push_cameras(
+        running
+            .values()
+            .map(|(cam, handle)| (cam, handle.device_info())),
+    )
+    .await
+}
+
+fn start_camera(
+    cam: &config::ConfigCamera,
+    bridge: &sink::SinkHandle,
+    capture: &Option<hikapi::Capture>,
+    connects: &hikapi::ConnectLimit,
+    running: &mut RunningCameras,
+) {
+    let handle = hikapi::run_camera(
+        cam.clone(),
+        bridge.events.clone(),
+        false,
+        capture.clone(),
+        connects.clone(),
+    );
+    running.insert(cam.identifier().to_string(), (cam.clone(), handle));
+}
+
+/// Loads the config again and starts, stops, or restarts cameras to match it.
+/// Everything other than cameras needs a restart to change.
+fn reload_config(
+    path: &Path,
+    cfg: &mut config::Config,
+    bridge: &sink::SinkHandle,
+    capture: &Option<hikapi::Capture>,
+    connects: &hikapi::ConnectLimit,
+    running: &mut RunningCameras,
+) {
+    let reloaded = match config::load_config_from_path(path) {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            error!("Unable to reload config, keeping the current one: {}", e);
+            return;
+        }
+    };
+    for warning in &reloaded.warnings {
+        warn!("Config: {}", warning);
+    }
+    if reloaded.system != cfg.system
+        || reloaded.sink != cfg.sink
+        || reloaded.mqtt != cfg.mqtt
+        || reloaded.event_types != cfg.event_types
+        || reloaded.health != cfg.health
+        || reloaded.webhook != cfg.webhook
+        || reloaded.influx != cfg.influx
+        || reloaded.listener != cfg.listener
+    {
+        warn!("Only changes to cameras are applied when reloading, restart required for the rest of the config");
+    }
+
+    // Stop removed cameras, and changed cameras so they can be started with their new config
+    running.retain(|id, (cam, handle)| {
+        let unchanged = reloaded.camera.contains(cam);
+        if !unchanged {
+            info!(camera = id.as_str(), "Stopping camera");
+            handle.stop();
+        }
+        unchanged
+    });
+    // The manager has to know about new cameras before they connect
+    let _ = bridge.cameras.send(reloaded.camera.clone());
+    for cam in &reloaded.camera {
+        if cam.enabled && !running.contains_key(cam.identifier()) {
+            info!(camera = cam.identifier(), "Starting camera");
+            start_camera(cam, bridge, capture, connects, running);
+        }
+    }
+    cfg.camera = reloaded.camera;
+}
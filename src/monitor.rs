@@ -0,0 +1,162 @@
+use crate::{
+    config::ConfigCamera,
+    hikapi::{run_camera, AlertItem, CameraEventType, ConnectLimit, EventQueue},
+};
+
+/// Enough for bursts of alerts while stdout is slow
+const QUEUE_CAPACITY: usize = 100;
+/// Column widths of the alert table
+const WIDTHS: [usize; 6] = [26, 20, 8, 8, 8, 6];
+
+/// Connects to a single camera without MQTT and prints its alerts until Ctrl-C
+pub async fn monitor(camera: ConfigCamera, json: bool, raw: bool) -> Result<(), String> {
+    let (tx, mut rx) = EventQueue::channel(QUEUE_CAPACITY);
+    eprintln!("Connecting to {}, press Ctrl-C to stop", camera.base_url());
+    let camera = run_camera(camera, tx, raw, None, ConnectLimit::default());
+    if !json {
+        println!(
+            "{}",
+            table_row(["TIME", "EVENT", "CHANNEL", "STATE", "REGIONS", "POSTS"])
+        );
+    }
+    loop {
+        let event = tokio::select! {
+            event = rx.recv() => event,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let event = match event {
+            Some(event) => event.event,
+            // The camera stopped retrying, e.g. because its credentials were rejected
+            None => return Err("Camera connection failed".to_string()),
+        };
+        match event {
+            CameraEventType::Connected {
+                info,
+                triggers,
+                address,
+            } => eprintln!(
+                "Connected to {} ({}) at {} with {} triggers",
+                info.device_name,
+                info.model,
+                address,
+                triggers.len()
+            ),
+            CameraEventType::Disconnected { error, .. } => eprintln!("Disconnected: {}", error),
+            CameraEventType::Paused => eprintln!("Paused"),
+            CameraEventType::Rebooting { retry_in_secs } => {
+                eprintln!("Rebooting, reconnecting in {}s", retry_in_secs)
+            }
+            CameraEventType::RebootFailed { error } => eprintln!("Unable to reboot: {}", error),
+            CameraEventType::Alert(alert) if json => match serde_json::to_string(&alert) {
+                Ok(line) => println!("{}", line),
+                Err(e) => eprintln!("Unable to serialize alert: {}", e),
+            },
+            CameraEventType::Alert(alert) => println!("{}", alert_row(&alert)),
+            CameraEventType::RawPart(raw) => println!("{}", raw.trim_end()),
+            CameraEventType::ParseFailure { error, raw } => {
+                eprintln!("Unable to parse alert: {}", error);
+                eprintln!("{}", raw.trim_end());
+            }
+            CameraEventType::TriggersUpdated { added, removed } => eprintln!(
+                "Triggers changed: {} added, {} removed",
+                added.len(),
+                removed.len()
+            ),
+            CameraEventType::Control(state) => eprintln!(
+                "Channel {} {}: {}",
+                state.channel,
+                state.control,
+                if state.enabled { "on" } else { "off" }
+            ),
+            CameraEventType::ControlFailed {
+                control,
+                channel,
+                error,
+            } => eprintln!(
+                "Unable to change channel {} {}: {}",
+                channel, control, error
+            ),
+            CameraEventType::PtzPresets(presets) => {
+                let presets: Vec<String> = presets
+                    .iter()
+                    .map(|p| format!("{} ({})", p.name, p.id))
+                    .collect();
+                eprintln!("PTZ presets: {}", presets.join(", "))
+            }
+            CameraEventType::PresetFailed { preset, error } => {
+                eprintln!("Unable to go to preset {}: {}", preset, error)
+            }
+            CameraEventType::InfoUpdated(info) => eprintln!(
+                "Device info changed: {} ({}) firmware {}",
+                info.device_name, info.model, info.firmware_version
+            ),
+            CameraEventType::ClockDrift { drift_secs } if drift_secs < 0 => {
+                eprintln!("Camera clock is {}s behind", -drift_secs)
+            }
+            CameraEventType::ClockDrift { drift_secs } => {
+                eprintln!("Camera clock is {}s ahead", drift_secs)
+            }
+            CameraEventType::Snapshot { .. } => {}
+        }
+    }
+    camera.stop();
+    Ok(())
+}
+
+/// A line of the alert table
+fn alert_row(alert: &AlertItem) -> String {
+    let regions = alert.regions.len().to_string();
+    let posts = alert.post_count.to_string();
+    let mut event = alert.identifier.event_type.to_string();
+    if let Some(target) = &alert.target {
+        event = format!("{} ({})", event, target);
+    }
+    table_row([
+        &alert.date,
+        &event,
+        alert.identifier.channel.as_deref().unwrap_or("-"),
+        if alert.active { "active" } else { "inactive" },
+        &regions,
+        &posts,
+    ])
+}
+
+fn table_row(columns: [&str; 6]) -> String {
+    columns
+        .iter()
+        .zip(WIDTHS)
+        .map(|(column, width)| format!("{:<width$}", column, width = width))
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::alert_row;
+    use crate::hikapi::AlertItem;
+
+    #[test]
+    fn test_alert_row() {
+        let alert = AlertItem::parse(indoc::indoc! {r#"
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <ipAddress>192.168.1.200</ipAddress>
+            <portNo>80</portNo>
+            <protocol>HTTP</protocol>
+            <macAddress>ff:ff:ff:ff:ff:ff</macAddress>
+            <channelID>1</channelID>
+            <dateTime>2021-10-02T18:04:02+08:00</dateTime>
+            <activePostCount>3</activePostCount>
+            <eventType>VMD</eventType>
+            <eventState>active</eventState>
+            <eventDescription>Motion alarm</eventDescription>
+            </EventNotificationAlert>
+        "#})
+        .unwrap();
+        assert_eq!(
+            alert_row(&alert),
+            "2021-10-02T18:04:02+08:00  Motion               1        active   0        3"
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+
+use super::manager::MqttMessage;
+
+/// Holds messages that couldn't be published while the broker is unreachable
+#[derive(Debug)]
+pub struct OfflineBuffer {
+    capacity: usize,
+    messages: VecDeque<MqttMessage>,
+}
+
+impl OfflineBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Queues a message, returning the number of messages dropped to make room for it.
+    /// Only the latest retained message for each topic is kept, as older states would be overwritten anyway.
+    pub fn push(&mut self, message: MqttMessage) -> u64 {
+        if message.retain {
+            self.messages
+                .retain(|m| !(m.retain && m.topic == message.topic));
+        }
+        self.messages.push_back(message);
+
+        let mut dropped = 0;
+        while self.messages.len() > self.capacity {
+            self.messages.pop_front();
+            dropped += 1;
+        }
+        dropped
+    }
+
+    /// Removes all queued messages, oldest first
+    pub fn drain(&mut self) -> Vec<MqttMessage> {
+        self.messages.drain(..).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OfflineBuffer;
+    use crate::mqtt::manager::{MqttMessage, MqttQoS};
+
+    fn message(topic: &str, retain: bool, payload: &str) -> MqttMessage {
+        MqttMessage::new(
+            topic.into(),
+            MqttQoS::AtLeastOnce,
+            retain,
+            payload.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_deduplicates_retained() {
+        let mut buffer = OfflineBuffer::new(10);
+        assert_eq!(buffer.push(message("a", true, "1")), 0);
+        assert_eq!(buffer.push(message("b", true, "1")), 0);
+        assert_eq!(buffer.push(message("a", true, "2")), 0);
+        // Momentary messages are all kept
+        assert_eq!(buffer.push(message("c", false, "1")), 0);
+        assert_eq!(buffer.push(message("c", false, "2")), 0);
+
+        assert_eq!(
+            buffer.drain(),
+            vec![
+                message("b", true, "1"),
+                message("a", true, "2"),
+                message("c", false, "1"),
+                message("c", false, "2"),
+            ]
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_drops_oldest() {
+        let mut buffer = OfflineBuffer::new(2);
+        assert_eq!(buffer.push(message("a", true, "1")), 0);
+        assert_eq!(buffer.push(message("b", true, "1")), 0);
+        assert_eq!(buffer.push(message("c", true, "1")), 1);
+        assert_eq!(
+            buffer.drain(),
+            vec![message("b", true, "1"), message("c", true, "1")]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! The rumqttc clients of both MQTT versions behind one interface, chosen by `protocol`
+
+use rumqttc::v5::mqttbytes::v5::{ConnectReturnCode as ConnectReturnCodeV5, PublishProperties};
+use rumqttc::{v5, ConnectReturnCode, QoS};
+
+use super::manager::MqttMessage;
+
+/// Publishes to and subscribes on the broker. Cheap to clone.
+#[derive(Debug, Clone)]
+pub enum Client {
+    V4(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl Client {
+    /// Queues a message without waiting. With MQTT 5 its expiry is sent along.
+    pub fn try_publish(&self, message: &MqttMessage, payload: Vec<u8>) -> Result<(), String> {
+        match self {
+            Client::V4(client) => client
+                .try_publish(
+                    message.topic.clone(),
+                    message.qos.clone().into(),
+                    message.retain,
+                    payload,
+                )
+                .map_err(|e| e.to_string()),
+            Client::V5(client) => {
+                let properties = PublishProperties {
+                    message_expiry_interval: message.expiry_secs,
+                    ..Default::default()
+                };
+                client
+                    .try_publish_with_properties(
+                        message.topic.clone(),
+                        message.qos.clone().into(),
+                        message.retain,
+                        payload,
+                        properties,
+                    )
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Subscribes to a topic filter without waiting
+    pub fn try_subscribe(&self, topic: String) -> Result<(), String> {
+        match self {
+            Client::V4(client) => client
+                .try_subscribe(topic, QoS::AtLeastOnce)
+                .map_err(|e| e.to_string()),
+            Client::V5(client) => client
+                .try_subscribe(topic, v5::mqttbytes::QoS::AtLeastOnce)
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Drives the connection to the broker. Boxed, as the event loops are large and differ in size.
+pub enum EventLoop {
+    V4(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+/// What HikSink cares about of the packets received from the broker
+#[derive(Debug, PartialEq)]
+pub enum BrokerEvent {
+    /// The broker acknowledged the connection
+    Connected {
+        session_present: bool,
+    },
+    /// A message on one of the subscribed topics
+    Received {
+        topic: String,
+        payload: Vec<u8>,
+    },
+    Other,
+}
+
+impl EventLoop {
+    /// Handles the connection until something of note happens, reconnecting if called after an error
+    pub async fn poll(&mut self) -> Result<BrokerEvent, ConnectionError> {
+        Ok(match self {
+            EventLoop::V4(eventloop) => {
+                match eventloop.poll().await.map_err(ConnectionError::V4)? {
+                    rumqttc::Event::Incoming(rumqttc::Incoming::ConnAck(connack)) => {
+                        BrokerEvent::Connected {
+                            session_present: connack.session_present,
+                        }
+                    }
+                    rumqttc::Event::Incoming(rumqttc::Incoming::Publish(publish)) => {
+                        BrokerEvent::Received {
+                            topic: publish.topic,
+                            payload: publish.payload.to_vec(),
+                        }
+                    }
+                    _ => BrokerEvent::Other,
+                }
+            }
+            EventLoop::V5(eventloop) => {
+                match eventloop.poll().await.map_err(ConnectionError::V5)? {
+                    v5::Event::Incoming(v5::Incoming::ConnAck(connack)) => BrokerEvent::Connected {
+                        session_present: connack.session_present,
+                    },
+                    v5::Event::Incoming(v5::Incoming::Publish(publish)) => BrokerEvent::Received {
+                        topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                        payload: publish.payload.to_vec(),
+                    },
+                    _ => BrokerEvent::Other,
+                }
+            }
+        })
+    }
+}
+
+/// A failure of the connection to the broker
+#[derive(Debug)]
+pub enum ConnectionError {
+    V4(rumqttc::ConnectionError),
+    V5(v5::ConnectionError),
+}
+
+impl ConnectionError {
+    /// Whether the broker refused the connection because of the credentials, which retrying won't fix
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self,
+            ConnectionError::V4(rumqttc::ConnectionError::ConnectionRefused(
+                ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized
+            )) | ConnectionError::V5(v5::ConnectionError::ConnectionRefused(
+                ConnectReturnCodeV5::BadUserNamePassword | ConnectReturnCodeV5::NotAuthorized
+            ))
+        )
+    }
+}
+
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::V4(e) => e.fmt(f),
+            ConnectionError::V5(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConnectionError;
+    use rumqttc::v5::mqttbytes::v5::ConnectReturnCode as ConnectReturnCodeV5;
+    use rumqttc::{v5, ConnectReturnCode};
+
+    #[test]
+    fn test_auth_failure() {
+        let refused = |code| ConnectionError::V4(rumqttc::ConnectionError::ConnectionRefused(code));
+        assert!(refused(ConnectReturnCode::BadUserNamePassword).is_auth_failure());
+        assert!(refused(ConnectReturnCode::NotAuthorized).is_auth_failure());
+        assert!(!refused(ConnectReturnCode::ServiceUnavailable).is_auth_failure());
+
+        let refused = |code| ConnectionError::V5(v5::ConnectionError::ConnectionRefused(code));
+        assert!(refused(ConnectReturnCodeV5::BadUserNamePassword).is_auth_failure());
+        assert!(refused(ConnectReturnCodeV5::NotAuthorized).is_auth_failure());
+        assert!(!refused(ConnectReturnCodeV5::ServerBusy).is_auth_failure());
+
+        assert!(
+            !ConnectionError::V4(rumqttc::ConnectionError::Io(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "refused"
+            )))
+            .is_auth_failure()
+        );
+    }
+}
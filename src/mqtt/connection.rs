@@ -1,91 +1,677 @@
-use super::manager;
-use crate::{config::Config, hikapi::CameraEvent};
-use rumqttc::{AsyncClient, Incoming, MqttOptions};
-use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use super::{
+    buffer::OfflineBuffer,
+    client::{BrokerEvent, Client, EventLoop},
+    manager,
+    state::PersistedState,
+};
+use crate::backoff::Backoff;
+use crate::{
+    config::{Config, ConfigCamera, ConfigMqtt, MqttProtocol},
+    hikapi::{CameraEventType, ConnectLimit, EventQueue},
+    influx::InfluxStats,
+    sink::{EventSink, SinkHandle},
+};
+use rumqttc::{
+    v5::{self, mqttbytes::v5::ConnectProperties},
+    MqttOptions,
+};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{debug, error, info, warn};
 
 use std::time::Duration;
 
-pub fn initiate_connection(config: &Config) -> Result<mpsc::Sender<CameraEvent>, String> {
-    let (camera_tx, mut camera_rx) = mpsc::channel::<CameraEvent>(20);
+/// Large enough for a snapshot from a high resolution camera
+const MAX_OUTGOING_PACKET_SIZE: usize = 8 * 1024 * 1024;
+
+/// Builds the options used to connect to the broker
+fn mqtt_options(config: &ConfigMqtt) -> MqttOptions {
+    let mut mqttoptions = MqttOptions::new(
+        config.client_id.clone(),
+        config.address.clone(),
+        config.port,
+    );
+    mqttoptions
+        .set_keep_alive(Duration::from_secs(config.keep_alive_secs))
+        .set_pending_throttle(Duration::from_millis(10))
+        // Snapshots are much larger than the default packet size limit
+        .set_max_packet_size(10 * 1024, MAX_OUTGOING_PACKET_SIZE);
+    // Brokers allowing anonymous access may reject empty credentials, so only send them when configured
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        mqttoptions.set_credentials(username.clone(), password.clone());
+    }
+    // We need to retain the session state between broker reboots so we don't lose our subscriptions
+    mqttoptions.set_clean_session(false);
+    mqttoptions
+}
+
+/// Builds the options used to connect to an MQTT 5 broker, matching those of `mqtt_options`
+fn mqtt_options_v5(config: &ConfigMqtt) -> v5::MqttOptions {
+    let mut mqttoptions = v5::MqttOptions::new(
+        config.client_id.clone(),
+        config.address.clone(),
+        config.port,
+    );
+    let mut properties = ConnectProperties::new();
+    // The broker announces how large packets it accepts, so only incoming ones are limited here
+    properties.max_packet_size = Some(10 * 1024);
+    // MQTT 5 brokers drop the session on disconnect unless asked to keep it
+    properties.session_expiry_interval = Some(u32::MAX);
+    mqttoptions
+        .set_keep_alive(Duration::from_secs(config.keep_alive_secs))
+        .set_pending_throttle(Duration::from_millis(10))
+        .set_connect_properties(properties);
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        mqttoptions.set_credentials(username.clone(), password.clone());
+    }
+    mqttoptions.set_clean_start(false);
+    mqttoptions
+}
+
+/// Creates the client of the configured MQTT version, with `lwt` as its last will
+fn connect(config: &ConfigMqtt, lwt: manager::MqttMessage) -> (Client, EventLoop) {
+    match config.protocol {
+        MqttProtocol::V4 => {
+            let mut mqttoptions = mqtt_options(config);
+            mqttoptions.set_last_will(lwt.into());
+            let (client, eventloop) = rumqttc::AsyncClient::new(mqttoptions, 10);
+            (Client::V4(client), EventLoop::V4(Box::new(eventloop)))
+        }
+        MqttProtocol::V5 => {
+            let mut mqttoptions = mqtt_options_v5(config);
+            mqttoptions.set_last_will(lwt.into());
+            let (client, eventloop) = v5::AsyncClient::new(mqttoptions, 10);
+            (Client::V5(client), EventLoop::V5(Box::new(eventloop)))
+        }
+    }
+}
+
+/// Publishes camera events to an MQTT broker, with Home Assistant discovery
+pub struct MqttSink<'a> {
+    config: &'a Config,
+    mqtt: &'a ConfigMqtt,
+    connects: ConnectLimit,
+    influx: Option<InfluxStats>,
+}
+
+impl<'a> MqttSink<'a> {
+    /// Fails if the config has no MQTT section
+    pub fn new(config: &'a Config, connects: ConnectLimit) -> Result<Self, String> {
+        let mqtt = config
+            .mqtt
+            .as_ref()
+            .ok_or("The [mqtt] section is required when the sink is MQTT")?;
+        Ok(Self {
+            config,
+            mqtt,
+            connects,
+            influx: None,
+        })
+    }
+
+    /// Includes the outcomes of the InfluxDB writes in the stats
+    pub fn with_influx(mut self, influx: Option<InfluxStats>) -> Self {
+        self.influx = influx;
+        self
+    }
+}
+
+impl EventSink for MqttSink<'_> {
+    fn start(self) -> SinkHandle {
+        initiate_connection(self.config, self.mqtt, self.connects, self.influx)
+    }
+}
+
+/// Starts publishing camera events to MQTT
+fn initiate_connection(
+    config: &Config,
+    mqtt: &ConfigMqtt,
+    connects: ConnectLimit,
+    influx: Option<InfluxStats>,
+) -> SinkHandle {
+    let (camera_tx, mut camera_rx) = EventQueue::channel(mqtt.event_channel_capacity);
+    let (cameras_tx, mut cameras_rx) = mpsc::unbounded_channel::<Vec<ConfigCamera>>();
     let mut manager = manager::Manager::new(
         config.camera.clone(),
         manager::MqttTopics::new(
-            config.mqtt.base_topic.clone(),
-            config.mqtt.home_assistant_topic.clone(),
+            mqtt.base_topic.clone(),
+            mqtt.home_assistant_topic.clone(),
+            mqtt.camera_topic.clone(),
+            mqtt.trigger_topic.clone(),
+            mqtt.stable_ids,
+        )
+        .with_availability(
+            mqtt.availability_topic.clone(),
+            mqtt.payload_available.clone(),
+            mqtt.payload_not_available.clone(),
         ),
+        config.system.clone(),
+        manager::MqttPublishing::new(mqtt, &config.event_types),
     );
+    manager.report_connects(connects);
+    if let Some(influx) = influx {
+        manager.report_influx(influx);
+    }
+    manager.report_queue(camera_tx.clone());
+    let state_file = config.system.state_file.clone();
+    if let Some(path) = &state_file {
+        match PersistedState::load(path) {
+            Ok(state) => manager.restore(state),
+            Err(e) => warn!(path=?path, "Unable to load state file, starting fresh: {}", e),
+        }
+    }
 
-    let mut mqttoptions = MqttOptions::new(
-        config.mqtt.client_id.clone(),
-        config.mqtt.address.clone(),
-        config.mqtt.port,
+    let (status_tx, status_rx) = watch::channel(manager.status(false, None));
+
+    // The configured broker comes first, followed by any mirrors which receive the same messages
+    let brokers: Vec<ConfigMqtt> = std::iter::once(mqtt.clone())
+        .chain(mqtt.brokers.iter().map(|broker| mqtt.mirror(broker)))
+        .collect();
+    manager.report_brokers(
+        brokers
+            .iter()
+            .map(|broker| format!("{}:{}", broker.address, broker.port))
+            .collect(),
     );
-    mqttoptions
-        .set_keep_alive(std::time::Duration::from_secs(5))
-        .set_pending_throttle(Duration::from_millis(10));
-    mqttoptions.set_credentials(config.mqtt.username.clone(), config.mqtt.password.clone());
-    // We need to retain the session state between broker reboots so we don't lose our subscriptions
-    mqttoptions.set_clean_session(false);
-    mqttoptions.set_last_will(manager.mqtt_lwt().into());
 
-    let (connection_notify_tx, mut connection_notify_rx) = mpsc::unbounded_channel::<()>();
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    // Notifies the client task whenever a broker connection is established (with whether the broker kept the
+    // session) or lost (with the error), along with the index of the broker
+    let (connection_notify_tx, mut connection_notify_rx) =
+        mpsc::unbounded_channel::<(usize, Result<bool, String>)>();
+    let (ha_online_tx, mut ha_online_rx) = mpsc::unbounded_channel::<usize>();
+    // Messages received on the command topics, turned into camera commands by the client task
+    let (command_tx, mut command_rx) = mpsc::unbounded_channel::<(String, Vec<u8>)>();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel::<oneshot::Sender<()>>();
+    let (fatal_tx, fatal_rx) = mpsc::unbounded_channel();
+
+    // Cameras with their own base topic can change on a reload, so the event loops always resubscribe to the latest
+    let (subscriptions_tx, subscriptions_rx) = watch::channel(manager.command_subscriptions());
+    let mut links = Vec::new();
+    for (index, broker) in brokers.iter().enumerate() {
+        let (client, eventloop) = connect(broker, manager.mqtt_lwt());
+        spawn_event_loop(
+            index,
+            broker,
+            client.clone(),
+            eventloop,
+            EventLoopChannels {
+                ha_status_topic: manager.home_assistant_status_topic(),
+                subscriptions: subscriptions_rx.clone(),
+                connection_notify: connection_notify_tx.clone(),
+                ha_online: ha_online_tx.clone(),
+                commands: command_tx.clone(),
+                // Only the main broker decides whether HikSink can run at all
+                fatal: (index == 0 && config.system.exit_on_mqtt_auth_failure)
+                    .then(|| fatal_tx.clone()),
+            },
+        );
+        links.push(BrokerLink {
+            client,
+            connected: false,
+            error: None,
+            buffer: OfflineBuffer::new(mqtt.offline_buffer_size),
+        });
+    }
 
-    // Launch the event loop as a task
+    // Launch the client as a task
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            // Replays after a (re)connection are only needed by the broker concerned
+            let mut only = None;
+            let messages = tokio::select! {
+                camera_update = camera_rx.recv() => {
+                    let camera_update = camera_update.expect("Camera event stream closed");
+                    match &camera_update.event {
+                        // Don't dump the whole image into the log
+                        CameraEventType::Snapshot { identifier, image } => {
+                            debug!(id=?camera_update.id, trigger=%identifier, bytes=image.len(), "Camera snapshot")
+                        }
+                        event => debug!(id=?camera_update.id, event=?event, "Camera event"),
+                    }
+                    manager.next_event(camera_update)
+                }
+
+                // Closed once the event loops have stopped, i.e. the credentials were rejected and HikSink is
+                // shutting down, which still needs this task to save the state
+                Some((index, session)) = connection_notify_rx.recv() => {
+                    let link = &mut links[index];
+                    link.connected = session.is_ok();
+                    match session {
+                        // Publish all discovery
+                        Ok(session_present) => {
+                            link.error = None;
+                            only = Some(index);
+                            manager.mqtt_connection_established(index, session_present)
+                        }
+                        Err(e) => {
+                            link.error = Some(e);
+                            manager.mqtt_connection_lost(index);
+                            Vec::new()
+                        }
+                    }
+                }
+
+                Some(index) = ha_online_rx.recv() => {
+                    only = Some(index);
+                    manager.home_assistant_online()
+                }
+
+                Some((topic, payload)) = command_rx.recv() => {
+                    if let Some(messages) = manager
+                        .reset_occupancy(&topic)
+                        .or_else(|| manager.mute(&topic, &payload, chrono::Utc::now()))
+                    {
+                        messages
+                    } else {
+                        if let Some((id, command)) = manager.parse_command(&topic, &payload) {
+                            info!(camera = id.as_str(), ?command, "Received camera command");
+                            let _ = commands_tx.send((id, command));
+                        }
+                        Vec::new()
+                    }
+                }
+
+                Some(cameras) = cameras_rx.recv() => {
+                    let messages = manager.apply_config(cameras);
+                    let subscriptions = manager.command_subscriptions();
+                    for topic in subscriptions.iter().filter(|t| !subscriptions_tx.borrow().contains(t)) {
+                        for link in &links {
+                            if let Err(e) = link.client.try_subscribe(topic.clone()) {
+                                error!("Unable to subscribe to camera commands: {}", e);
+                            }
+                        }
+                    }
+                    subscriptions_tx.send_replace(subscriptions);
+                    messages
+                }
+
+                _ = ticker.tick() => {
+                    manager.tick(chrono::Utc::now())
+                }
+
+                Some(done) = shutdown_rx.recv() => {
+                    // Whatever changed since the last save would be lost otherwise
+                    if let Some(path) = &state_file {
+                        if let Err(e) = manager.persisted_state(chrono::Utc::now()).save(path) {
+                            warn!(path=?path, "Unable to save state file: {}", e);
+                        }
+                    }
+                    let _ = done.send(());
+                    Vec::new()
+                }
+            };
+
+            for (index, link) in links.iter_mut().enumerate() {
+                if only.is_some_and(|only| only != index) {
+                    continue;
+                }
+                let dropped = link.publish(index, &mut manager, messages.clone());
+                if dropped > 0 {
+                    warn!(
+                        dropped,
+                        broker = index,
+                        broker_connected = link.connected,
+                        "MQTT offline buffer is full, dropped the oldest messages"
+                    );
+                    let stats = manager.messages_dropped(dropped);
+                    link.buffer.push(stats);
+                }
+            }
+            if let (Some(path), Some(state)) = (
+                &state_file,
+                manager.persisted_state_if_changed(chrono::Utc::now()),
+            ) {
+                if let Err(e) = state.save(path) {
+                    warn!(path=?path, "Unable to save state file: {}", e);
+                }
+            }
+            let _ = status_tx.send(manager.status(links[0].connected, links[0].error.clone()));
+        }
+    });
+
+    SinkHandle {
+        events: camera_tx,
+        cameras: cameras_tx,
+        status: status_rx,
+        commands: commands_rx,
+        shutdown: shutdown_tx,
+        fatal: fatal_rx,
+    }
+}
+
+/// The client task's side of one broker connection
+struct BrokerLink {
+    client: Client,
+    connected: bool,
+    error: Option<String>,
+    buffer: OfflineBuffer,
+}
+
+impl BrokerLink {
+    /// Publishes the messages, or buffers them while disconnected. Returns how many buffered messages were dropped.
+    fn publish(
+        &mut self,
+        index: usize,
+        manager: &mut manager::Manager,
+        mut messages: Vec<manager::MqttMessage>,
+    ) -> u64 {
+        // Anything buffered while the broker was unreachable goes out first
+        if self.connected && !self.buffer.is_empty() {
+            info!(
+                broker = index,
+                "Publishing messages buffered while disconnected from the MQTT broker."
+            );
+            let mut buffered = self.buffer.drain();
+            buffered.append(&mut messages);
+            messages = buffered;
+        }
+
+        let mut dropped = 0;
+        for message in messages {
+            if !self.connected {
+                dropped += self.buffer.push(message);
+                continue;
+            }
+            let payload = message.payload.clone().render();
+            let digest = message.retain.then(|| manager::payload_digest(&payload));
+            match self.client.try_publish(&message, payload) {
+                Ok(()) => {
+                    if let Some(digest) = digest {
+                        manager.retained_published(index, &message.topic, digest);
+                    }
+                }
+                Err(e) => {
+                    warn!("Unable to publish MQTT message, buffering it: {}", e);
+                    dropped += self.buffer.push(message);
+                }
+            }
+        }
+        dropped
+    }
+}
+
+/// What an event loop reports to, and needs from, the client task
+struct EventLoopChannels {
+    ha_status_topic: String,
+    subscriptions: watch::Receiver<Vec<String>>,
+    connection_notify: mpsc::UnboundedSender<(usize, Result<bool, String>)>,
+    ha_online: mpsc::UnboundedSender<usize>,
+    commands: mpsc::UnboundedSender<(String, Vec<u8>)>,
+    /// Where to report the credentials being rejected, when HikSink should exit rather than retry
+    fatal: Option<mpsc::UnboundedSender<String>>,
+}
+
+/// Launches the event loop of the broker at `index` as a task
+fn spawn_event_loop(
+    index: usize,
+    broker: &ConfigMqtt,
+    subscribe_client: Client,
+    mut eventloop: EventLoop,
+    channels: EventLoopChannels,
+) {
+    let mut backoff = Backoff::new(broker.reconnect_backoff.clone());
+    let address = format!("{}:{}", broker.address, broker.port);
+    let EventLoopChannels {
+        ha_status_topic,
+        subscriptions,
+        connection_notify,
+        ha_online,
+        commands,
+        fatal,
+    } = channels;
     tokio::task::spawn(async move {
         loop {
             let event = eventloop.poll().await;
             match event {
                 Ok(event) => match event {
-                    rumqttc::Event::Incoming(Incoming::Publish(_)) => {
-                        // Currently unused, but we can subscribe to topics to get messages here
+                    BrokerEvent::Received { topic, payload }
+                        if topic == ha_status_topic && payload == b"online" =>
+                    {
+                        info!("Home Assistant came online. Republishing discovery.");
+                        let _ = ha_online.send(index);
                     }
-                    rumqttc::Event::Incoming(Incoming::ConnAck(_)) => {
+                    BrokerEvent::Received { topic, payload } if topic != ha_status_topic => {
+                        let _ = commands.send((topic, payload));
+                    }
+                    BrokerEvent::Connected { session_present } => {
                         // Connection was established. Notify the client to send all discovery messages
-                        info!("Connected to MQTT broker.");
-                        let _ = connection_notify_tx.send(());
+                        info!(broker = %address, "Connected to MQTT broker.");
+                        backoff.reset();
+                        let _ = connection_notify.send((index, Ok(session_present)));
+                        // The broker may not have kept our session, so always subscribe again.
+                        // This can't be awaited here as the request is only sent once the event loop is polled.
+                        if let Err(e) = subscribe_client.try_subscribe(ha_status_topic.clone()) {
+                            error!("Unable to subscribe to Home Assistant status: {}", e);
+                        }
+                        for topic in subscriptions.borrow().iter() {
+                            if let Err(e) = subscribe_client.try_subscribe(topic.clone()) {
+                                error!("Unable to subscribe to camera commands: {}", e);
+                            }
+                        }
                     }
                     _ => {}
                 },
                 Err(e) => {
-                    error!("MQTT Connection error encountered: {}", e);
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    let _ = connection_notify.send((index, Err(e.to_string())));
+                    let delay = backoff.next_delay();
+                    if e.is_auth_failure() {
+                        if let Some(fatal) = &fatal {
+                            // Exiting is left to main, so the state is saved and the cameras are stopped first
+                            let _ = fatal.send(format!(
+                                "MQTT broker rejected the credentials, check mqtt.username/password. Exiting: {}",
+                                e
+                            ));
+                            return;
+                        }
+                        error!(
+                            broker = %address,
+                            "MQTT broker rejected the credentials, check mqtt.username/password. Retrying in {:?}: {}",
+                            delay, e
+                        );
+                    } else {
+                        error!(
+                            broker = %address,
+                            "MQTT Connection error encountered, retrying in {:?}: {}",
+                            delay, e
+                        );
+                    }
+                    tokio::time::sleep(delay).await;
                 }
             }
         }
     });
+}
 
-    // Launch the client as a task
-    tokio::task::spawn(async move {
-        loop {
-            let messages = tokio::select! {
-                camera_update = camera_rx.recv() => {
-                    let camera_update = camera_update.expect("Camera event stream closed");
-                    debug!(id=?camera_update.id, event=?camera_update.event, "Camera event");
-                    manager.next_event(camera_update)
-                }
+#[cfg(test)]
+mod test {
+    use super::{
+        mqtt_options, mqtt_options_v5, spawn_event_loop, Client, EventLoop, EventLoopChannels,
+        MqttSink,
+    };
+    use crate::{
+        config::{
+            load_config, ConfigBackoff, ConfigMqtt, ConfigMqttQoS, LogFormat, MqttProtocol,
+            TopicTemplate,
+        },
+        hikapi::ConnectLimit,
+        sink::EventSink,
+    };
+    use figment::providers::{Format, Toml};
+    use std::collections::HashMap;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::{mpsc, watch},
+    };
 
-                _ = connection_notify_rx.recv() => {
-                    // Publish all discovery
-                    manager.mqtt_connection_established()
-                }
-            };
-            for message in messages {
-                if let Err(e) = client
-                    .publish(
-                        message.topic,
-                        message.qos.into(),
-                        message.retain,
-                        message.payload.render(),
-                    )
-                    .await
-                {
-                    error!("Unable to publish MQTT message: {}", e);
-                }
-            }
+    fn sample_mqtt() -> ConfigMqtt {
+        ConfigMqtt {
+            address: "localhost".into(),
+            port: 1883,
+            username: Some("user".into()),
+            username_file: None,
+            username_env: None,
+            password: Some("pass".into()),
+            password_file: None,
+            password_env: None,
+            base_topic: "hikvision_cameras".into(),
+            home_assistant_topic: "homeassistant".into(),
+            camera_topic: TopicTemplate::camera_default(),
+            trigger_topic: TopicTemplate::trigger_default(),
+            stable_ids: false,
+            protocol: MqttProtocol::V4,
+            message_expiry_secs: None,
+            availability_topic: None,
+            payload_available: "online".into(),
+            payload_not_available: "offline".into(),
+            client_id: "hik-sink".into(),
+            keep_alive_secs: 5,
+            event_channel_capacity: 20,
+            offline_buffer_size: 1000,
+            retain_state: true,
+            retain_state_events: HashMap::new(),
+            qos: ConfigMqttQoS::default(),
+            plain_state_topics: false,
+            plain_state_payload_on: "ON".into(),
+            plain_state_payload_off: "OFF".into(),
+            regions_topic: false,
+            publish_parse_failures: false,
+            parse_failure_max_bytes: 4096,
+            log_format: LogFormat::Json,
+            names_from_alerts: false,
+            reconnect_backoff: ConfigBackoff::default(),
+            brokers: Vec::new(),
         }
-    });
+    }
+
+    /// A broker on a local port refusing the credentials of the first client to connect
+    async fn refusing_broker() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut connect = [0; 256];
+            let _ = socket.read(&mut connect).await.unwrap();
+            // CONNACK refusing the username and password
+            socket.write_all(&[0x20, 0x02, 0x00, 0x04]).await.unwrap();
+            let _ = socket.read(&mut connect).await;
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_reported() {
+        let mut broker = sample_mqtt();
+        broker.address = "127.0.0.1".into();
+        broker.port = refusing_broker().await;
+
+        let (client, eventloop) = rumqttc::AsyncClient::new(mqtt_options(&broker), 10);
+        let (connection_notify, mut connection_rx) = mpsc::unbounded_channel();
+        let (fatal, mut fatal_rx) = mpsc::unbounded_channel();
+        spawn_event_loop(
+            0,
+            &broker,
+            Client::V4(client),
+            EventLoop::V4(Box::new(eventloop)),
+            EventLoopChannels {
+                ha_status_topic: "homeassistant/status".into(),
+                subscriptions: watch::channel(Vec::new()).1,
+                connection_notify,
+                ha_online: mpsc::unbounded_channel().0,
+                commands: mpsc::unbounded_channel().0,
+                fatal: Some(fatal),
+            },
+        );
+        let (index, session) = connection_rx.recv().await.unwrap();
+        assert_eq!(index, 0);
+        assert!(session.is_err());
+        // Left to main to exit, and the event loop stops trying
+        let error = fatal_rx.recv().await.unwrap();
+        assert!(error.contains("rejected the credentials"), "{}", error);
+        assert!(connection_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_shutdown() {
+        let state_file = std::env::temp_dir().join(format!(
+            "hiksink_state_auth_failure_{}.json",
+            std::process::id()
+        ));
+        let config = load_config(Toml::string(&format!(
+            r#"
+            mqtt = {{ address = "127.0.0.1", port = {} }}
+            system = {{ exit_on_mqtt_auth_failure = true, state_file = {:?} }}
+            camera = []
+            "#,
+            refusing_broker().await,
+            state_file
+        )))
+        .unwrap();
+        let mut sink = MqttSink::new(&config, ConnectLimit::new(None))
+            .unwrap()
+            .start();
+
+        let error = sink.fatal.recv().await.unwrap();
+        assert!(error.contains("rejected the credentials"), "{}", error);
+        // The event loop has stopped, while the sink still saves its state when asked to
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let _ = std::fs::remove_file(&state_file);
+        sink.shutdown().await;
+        assert!(state_file.exists());
+        std::fs::remove_file(&state_file).unwrap();
+    }
+
+    #[test]
+    fn test_credentials() {
+        let options = mqtt_options(&sample_mqtt());
+        assert_eq!(
+            options.credentials(),
+            Some(("user".to_string(), "pass".to_string()))
+        );
+
+        // Anonymous brokers
+        let anonymous = ConfigMqtt {
+            username: None,
+            password: None,
+            ..sample_mqtt()
+        };
+        assert_eq!(mqtt_options(&anonymous).credentials(), None);
+
+        // Credentials are only used when both are present
+        let username_only = ConfigMqtt {
+            password: None,
+            ..sample_mqtt()
+        };
+        assert_eq!(mqtt_options(&username_only).credentials(), None);
+    }
+
+    #[test]
+    fn test_multiple_instances() {
+        // Two instances on the same broker need distinct client IDs, otherwise the broker disconnects one of them
+        let first = mqtt_options(&sample_mqtt());
+        let second = mqtt_options(&ConfigMqtt {
+            client_id: "hik-sink-garage".into(),
+            base_topic: "hikvision_garage".into(),
+            ..sample_mqtt()
+        });
+        assert_eq!(first.client_id(), "hik-sink");
+        assert_eq!(second.client_id(), "hik-sink-garage");
+        assert_eq!(first.broker_address(), second.broker_address());
+    }
 
-    Ok(camera_tx)
+    #[test]
+    fn test_options_v5() {
+        // The same connection as with MQTT 3.1.1, with a session that outlives the connection
+        let options = mqtt_options_v5(&sample_mqtt());
+        assert_eq!(options.client_id(), "hik-sink");
+        assert_eq!(options.credentials(), Some(("user".into(), "pass".into())));
+        assert!(!options.clean_start());
+        assert_eq!(
+            options
+                .connect_properties()
+                .unwrap()
+                .session_expiry_interval,
+            Some(u32::MAX)
+        );
+    }
 }
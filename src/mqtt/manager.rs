@@ -1,45 +1,290 @@
+use super::state::{PersistedState, STATE_VERSION};
 use crate::{
-    config::ConfigCamera,
-    hikapi::{CameraEvent, CameraEventType, DetectionRegion, DeviceInfo, TriggerItem},
+    config::{
+        event_type_override, trigger_key_matches, ConfigCamera, ConfigDebounce, ConfigEventType,
+        ConfigMqtt, ConfigMqttQoS, ConfigSystem, HaEntity, LogFormat, MqttProtocol, QoSLevel,
+        TopicTemplate,
+    },
+    hikapi::{
+        AlertItem, AnprDetails, CameraCommand, CameraEvent, CameraEventType, ConnectLimit,
+        ControlState, ControlType, DetectionRegion, DeviceInfo, DisconnectKind, EventIdentifier,
+        EventQueue, EventType, PeopleCount, PtzPreset, TriggerItem,
+    },
+    influx::InfluxStats,
+    sink::status::{BridgeStatus, CameraStatus},
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use tracing::{error, warn};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Manager {
     cameras: Vec<CameraDetails>,
     topics: MqttTopics,
+    system: ConfigSystem,
+    #[serde(skip)]
+    publishing: MqttPublishing,
+    /// The retained topics last published for each camera. Used to clean up topics that no longer exist.
+    #[serde(skip)]
+    retained_topics: BTreeMap<String, BTreeSet<String>>,
+    #[serde(skip)]
+    retained_topics_changed: bool,
+    /// A digest of the payload last handed to each broker on each retained topic. Used to skip unchanged
+    /// messages when reconnecting to a broker that kept the session.
+    #[serde(skip)]
+    published: Vec<HashMap<String, u64>>,
+    /// The name of each broker and whether it's connected, for the stats
+    #[serde(skip)]
+    brokers: Vec<(String, bool)>,
+    /// Something kept in the state file changed since it was last saved
+    #[serde(skip)]
+    state_changed: bool,
+    #[serde(skip, default = "Utc::now")]
+    state_saved: DateTime<Utc>,
+    /// Messages that were never published because the broker was unreachable for too long
+    dropped_messages: u64,
+    /// Alerts received from all cameras, including those that didn't change anything
+    events_processed: u64,
+    /// Alerts dropped because another device already sent the same event
+    deduplicated: u64,
+    /// The device that first sent each recent event, which owns it, and when it last sent it. Used to drop duplicates.
+    #[serde(skip)]
+    recent_alerts: HashMap<DedupKey, (String, DateTime<Utc>)>,
+    #[serde(skip, default = "Utc::now")]
+    started: DateTime<Utc>,
+    #[serde(skip, default = "Utc::now")]
+    stats_published: DateTime<Utc>,
+    /// Shared with the cameras, to report how many are connecting
+    #[serde(skip)]
+    connects: ConnectLimit,
+    /// The queue the cameras send their events on, to report how many were dropped
+    #[serde(skip)]
+    events: Option<EventQueue>,
+    /// The counters of the InfluxDB writes, when configured
+    #[serde(skip)]
+    influx: Option<InfluxStats>,
 }
 
+/// How often the global stats are refreshed, so the uptime doesn't go stale
+const STATS_INTERVAL_SECS: i64 = 60;
+/// Payloads of the control switches, matching Home Assistant's defaults
+const CONTROL_PAYLOAD_ON: &str = "ON";
+const CONTROL_PAYLOAD_OFF: &str = "OFF";
+/// Payload of the reboot button. Unlike the other commands it must match exactly.
+const REBOOT_PAYLOAD: &str = "REBOOT";
+/// How often a trigger's state is republished when only its post counters changed
+const COUNTERS_INTERVAL_SECS: i64 = 30;
+/// How long after an event another device's alert for it is treated as a duplicate
+const DEDUP_WINDOW_SECS: i64 = 5;
+/// The type of the events published for event entities and device triggers
+const TRIGGER_EVENT_TYPE: &str = "alert";
+/// How many channels NVRs commonly number their IP channels after in alerts, e.g. channel 33 is IP channel 1
+const NVR_CHANNEL_OFFSET: u32 = 32;
+/// How often the state file is saved at most while things change
+const STATE_SAVE_INTERVAL_SECS: i64 = 10;
+/// How many of a camera's latest connection errors are kept and published
+const ERROR_HISTORY_LEN: usize = 20;
+/// Device classes home assistant supports for event entities
+const EVENT_DEVICE_CLASSES: &[&str] = &["button", "doorbell", "motion"];
+
+/// Identifies an event regardless of which device sent it: the camera it resolves to, its
+/// channel on that camera and the event type
+type DedupKey = (String, Option<String>, EventType);
+
 impl Manager {
-    pub fn new(cameras: Vec<ConfigCamera>, topics: MqttTopics) -> Manager {
+    pub fn new(
+        cameras: Vec<ConfigCamera>,
+        topics: MqttTopics,
+        system: ConfigSystem,
+        publishing: MqttPublishing,
+    ) -> Manager {
+        let cameras = cameras
+            .into_iter()
+            .map(|camera| {
+                let stale_after = stale_after(&system, &camera);
+                let mut cam = CameraDetails::new(camera, publishing.clone(), stale_after);
+                cam.refresh_topics(&topics);
+                cam
+            })
+            .collect();
         Manager {
             topics,
-            cameras: cameras
-                .into_iter()
-                .map(|camera| CameraDetails {
-                    config: camera,
-                    info: None,
-                    triggers: Vec::new(),
-                    connected: false,
-                    log: "Initial connection in progress...".to_string(),
-                })
-                .collect(),
+            retained_topics: BTreeMap::new(),
+            retained_topics_changed: false,
+            published: Vec::new(),
+            brokers: Vec::new(),
+            state_changed: false,
+            state_saved: Utc::now(),
+            dropped_messages: 0,
+            events_processed: 0,
+            deduplicated: 0,
+            recent_alerts: HashMap::new(),
+            started: Utc::now(),
+            stats_published: Utc::now(),
+            connects: ConnectLimit::default(),
+            influx: None,
+            events: None,
+            cameras,
+            system,
+            publishing,
         }
     }
     /// Get the LWT for the entire Hik Sink bridge
     pub fn mqtt_lwt(&self) -> MqttMessage {
         MqttMessage::new(
             self.topics.get_global_availability(),
-            MqttQoS::AtLeastOnce,
+            self.publishing.qos.availability.clone(),
             true,
-            "offline",
+            self.topics.availability_payload(false),
         )
     }
-    /// Call this when an MQTT connection is established. This returns all state topics to be published, discovery messages, and an online notification
-    pub fn mqtt_connection_established(&self) -> Vec<MqttMessage> {
+    /// Restores state saved from a previous run. The cameras stay disconnected until they connect.
+    pub fn restore(&mut self, mut state: PersistedState) {
+        self.retained_topics = state.retained_topics;
+        let now = Utc::now();
+        for cam in &mut self.cameras {
+            let saved = match state.cameras.remove(cam.config.identifier()) {
+                Some(saved) => saved,
+                None => continue,
+            };
+            cam.triggers = saved
+                .triggers
+                .into_iter()
+                .filter(|t| is_published(&self.system, &cam.config, &t.trigger.identifier))
+                .map(|mut trigger| {
+                    // The camera posts ongoing alerts again once it's connected, so give it as long as
+                    // it would have had to clear them
+                    if trigger.alerting {
+                        trigger.last_active_post = Some(now);
+                    }
+                    trigger
+                })
+                .collect();
+            cam.occupancy = saved.occupancy;
+            cam.errors = saved.errors;
+            cam.error_counts = saved.error_counts;
+            cam.muted_until = saved.muted_until;
+            cam.refresh_topics(&self.topics);
+        }
+    }
+    /// Returns the state to save if it has changed, at most every `STATE_SAVE_INTERVAL_SECS`
+    pub fn persisted_state_if_changed(&mut self, now: DateTime<Utc>) -> Option<PersistedState> {
+        if !(self.retained_topics_changed || self.state_changed)
+            || now - self.state_saved < Duration::seconds(STATE_SAVE_INTERVAL_SECS)
+        {
+            return None;
+        }
+        Some(self.persisted_state(now))
+    }
+    /// Returns the state to save, whether it changed or not, e.g. when shutting down
+    pub fn persisted_state(&mut self, now: DateTime<Utc>) -> PersistedState {
+        self.retained_topics_changed = false;
+        self.state_changed = false;
+        self.state_saved = now;
+        PersistedState {
+            version: STATE_VERSION,
+            retained_topics: self.retained_topics.clone(),
+            cameras: self
+                .cameras
+                .iter()
+                .map(|cam| {
+                    (
+                        cam.config.identifier().to_string(),
+                        PersistedCamera {
+                            triggers: cam.triggers.clone(),
+                            occupancy: cam.occupancy.clone(),
+                            errors: cam.errors.clone(),
+                            error_counts: cam.error_counts.clone(),
+                            muted_until: cam.muted_until,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+    /// Call this when the config has been reloaded. Returns the messages needed to add and remove cameras.
+    /// Cameras that are still configured keep their state.
+    pub fn apply_config(&mut self, cameras: Vec<ConfigCamera>) -> Vec<MqttMessage> {
+        let mut messages = Vec::new();
+        let mut previous = std::mem::take(&mut self.cameras);
+        for config in cameras {
+            match previous
+                .iter()
+                .position(|c| c.config.identifier() == config.identifier())
+            {
+                Some(i) => {
+                    let mut cam = previous.remove(i);
+                    cam.stale_after = stale_after(&self.system, &config);
+                    if config.enabled != cam.config.enabled {
+                        messages.append(&mut cam.set_enabled(config.enabled, &self.topics));
+                    }
+                    cam.config = config;
+                    cam.refresh_topics(&self.topics);
+                    self.cameras.push(cam);
+                }
+                None => {
+                    info!(camera = config.identifier(), "Camera added to the config");
+                    let stale_after = stale_after(&self.system, &config);
+                    let mut cam = CameraDetails::new(config, self.publishing.clone(), stale_after);
+                    cam.refresh_topics(&self.topics);
+                    messages.push(cam.message_log(&self.topics));
+                    messages.push(cam.message_availability(&self.topics));
+                    self.cameras.push(cam);
+                }
+            }
+        }
+        for mut cam in previous {
+            info!(
+                camera = cam.config.identifier(),
+                "Camera removed from the config"
+            );
+            if !self.system.cleanup_stale_topics {
+                // The camera's entities are kept, so at least show them as unavailable
+                cam.connected = false;
+                messages.push(cam.message_availability(&self.topics));
+            }
+        }
+        messages.append(&mut self.remove_unconfigured_cameras());
+        messages.push(self.message_global_stats());
+        self.without_muted(messages)
+    }
+    /// Removes everything published for cameras that are no longer configured
+    fn remove_unconfigured_cameras(&mut self) -> Vec<MqttMessage> {
         let mut messages = Vec::new();
+        let cameras = &self.cameras;
+        let removed: Vec<String> = self
+            .retained_topics
+            .keys()
+            .filter(|id| !cameras.iter().any(|c| c.config.identifier() == id.as_str()))
+            .cloned()
+            .collect();
+        for id in removed {
+            let stale = self.retained_topics.remove(&id).unwrap_or_default();
+            self.retained_topics_changed = true;
+            if self.system.cleanup_stale_topics {
+                info!(camera = %id, "Removing topics of camera that is no longer configured");
+                messages.extend(stale.into_iter().map(MqttMessage::delete));
+            }
+        }
+        messages
+    }
+    /// Call this when an MQTT connection is established. This returns all state topics to be published, discovery messages, and an online notification
+    /// If the broker kept the session (`session_present`), retained messages it already has are skipped.
+    /// `broker` is the index of the broker, `0` for the main one.
+    pub fn mqtt_connection_established(
+        &mut self,
+        broker: usize,
+        session_present: bool,
+    ) -> Vec<MqttMessage> {
+        if let Some((_, connected)) = self.brokers.get_mut(broker) {
+            *connected = true;
+        }
+        let mut messages = self.remove_unconfigured_cameras();
 
         // Ensure all camera states are up to date
         for cam in &self.cameras {
@@ -47,39 +292,384 @@ impl Manager {
         }
 
         // Publish global online message
+        let availability = self.topics.get_global_availability();
         messages.push(MqttMessage::new(
-            self.topics.get_global_availability(),
-            MqttQoS::AtLeastOnce,
+            availability.clone(),
+            self.publishing.qos.availability.clone(),
             true,
-            "online",
+            self.topics.availability_payload(true),
         ));
 
-        // Publish stats
-        messages.push(self.message_global_stats());
+        // Publish stats. They're refreshed regularly anyway, so a kept session can do without.
+        if !session_present {
+            messages.push(self.message_global_stats());
+        }
 
         // Publish all discovery topics
+        messages.append(&mut self.message_discovery());
+        let mut messages = self.without_muted(messages);
+
+        // The broker publishes the last will when the connection drops, so availability always goes out
+        if session_present {
+            if let Some(published) = self.published.get(broker) {
+                messages.retain(|m| {
+                    !m.retain
+                        || m.topic == availability
+                        || published.get(&m.topic) != Some(&m.payload.digest())
+                });
+            }
+        }
+
+        messages
+    }
+    /// Call this when the connection to a broker is lost
+    pub fn mqtt_connection_lost(&mut self, broker: usize) {
+        if let Some((_, connected)) = self.brokers.get_mut(broker) {
+            *connected = false;
+        }
+    }
+    /// Call this for each retained message handed to a broker, with the digest of its payload
+    pub fn retained_published(&mut self, broker: usize, topic: &str, digest: u64) {
+        if self.published.len() <= broker {
+            self.published.resize_with(broker + 1, HashMap::new);
+        }
+        match self.published[broker].get_mut(topic) {
+            Some(previous) => *previous = digest,
+            None => {
+                self.published[broker].insert(topic.to_string(), digest);
+            }
+        }
+    }
+    /// Call this when home assistant announces it is online (e.g. after a restart). This returns all discovery messages.
+    pub fn home_assistant_online(&self) -> Vec<MqttMessage> {
+        self.without_muted(self.message_discovery())
+    }
+    /// The topic home assistant publishes its birth and last will messages to
+    pub fn home_assistant_status_topic(&self) -> String {
+        self.topics.get_home_assistant_status()
+    }
+    /// The topic filters matching the command topics of every camera and control, including those of
+    /// cameras with their own base topic
+    pub fn command_subscriptions(&self) -> Vec<String> {
+        let mut bases = vec![self.topics.base.as_str()];
+        for cam in &self.cameras {
+            if let Some(base) = &cam.config.base_topic {
+                if !bases.contains(&base.as_str()) {
+                    bases.push(base);
+                }
+            }
+        }
+        bases
+            .into_iter()
+            .flat_map(|base| {
+                [
+                    self.topics.get_command_subscription(base),
+                    self.topics.get_control_set_subscription(base),
+                    self.topics.get_occupancy_reset_subscription(base),
+                    self.topics.get_mute_subscription(base),
+                ]
+            })
+            .chain(std::iter::once(self.topics.get_global_mute()))
+            .collect()
+    }
+    /// Resets an occupancy counter if the message is for one, returning the messages to publish.
+    /// Returns `None` for messages on other topics. The counters live here rather than in the cameras.
+    pub fn reset_occupancy(&mut self, topic: &str) -> Option<Vec<MqttMessage>> {
+        for cam in &mut self.cameras {
+            if !cam.config.derive_occupancy {
+                continue;
+            }
+            if let Some(channel) = cam
+                .occupancy_channels()
+                .into_iter()
+                .find(|c| self.topics.get_occupancy_reset(cam, c) == topic)
+            {
+                info!(
+                    camera = cam.config.identifier(),
+                    channel = channel.as_str(),
+                    "Occupancy reset"
+                );
+                cam.occupancy.insert(channel.clone(), 0);
+                let message = cam.message_occupancy_state(&self.topics, &channel);
+                return Some(self.without_muted(vec![message]));
+            }
+        }
+        None
+    }
+    /// Mutes or unmutes a camera, or all of them, if the message is for a mute topic, returning the messages to
+    /// publish. Returns `None` for messages on other topics. Unmuting publishes the current trigger states and
+    /// occupancy counts.
+    pub fn mute(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        now: DateTime<Utc>,
+    ) -> Option<Vec<MqttMessage>> {
+        let global = topic == self.topics.get_global_mute();
+        let topics = &self.topics;
+        let cameras: Vec<&mut CameraDetails> = self
+            .cameras
+            .iter_mut()
+            .filter(|cam| global || topics.get_camera_mute(cam) == topic)
+            .collect();
+        if !global && cameras.is_empty() {
+            return None;
+        }
+        let payload = String::from_utf8_lossy(payload);
+        let until = match parse_mute(payload.trim(), now) {
+            Ok(until) => until,
+            Err(e) => {
+                warn!(topic, "Ignoring mute: {}", e);
+                return Some(Vec::new());
+            }
+        };
+        let mut messages = Vec::new();
+        for cam in cameras {
+            match until {
+                Some(until) => info!(
+                    camera = cam.config.identifier(),
+                    until = %until.to_rfc3339(),
+                    "Muting alerts"
+                ),
+                None => info!(camera = cam.config.identifier(), "Unmuting alerts"),
+            }
+            cam.muted_until = until;
+            messages.extend(cam.message_info(topics));
+            if until.is_none() {
+                messages.append(&mut cam.message_trigger_states(topics));
+                messages.append(&mut cam.message_occupancy_states(topics));
+            }
+        }
+        self.state_changed = true;
+        Some(messages)
+    }
+    /// Drops the trigger states and occupancy counts of muted cameras. They are published once the camera is
+    /// unmuted.
+    fn without_muted(&self, mut messages: Vec<MqttMessage>) -> Vec<MqttMessage> {
+        for cam in self.cameras.iter().filter(|cam| cam.muted_until.is_some()) {
+            let occupancy: Vec<_> = cam
+                .occupancy_channels()
+                .iter()
+                .map(|channel| self.topics.get_occupancy_state(cam, channel))
+                .collect();
+            messages.retain(|m| {
+                !cam.triggers.iter().any(|t| {
+                    m.topic
+                        .strip_prefix(&*t.topics.state)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                }) && !occupancy.contains(&m.topic)
+            });
+        }
+        messages
+    }
+    /// The camera and command for a message received on a command topic, if it's one we know
+    pub fn parse_command(&self, topic: &str, payload: &[u8]) -> Option<(String, CameraCommand)> {
+        let payload = String::from_utf8_lossy(payload);
+        let payload = payload.trim();
+        for cam in &self.cameras {
+            let id = cam.config.identifier();
+            if self.topics.get_camera_command(cam) == topic {
+                // Case sensitive, so a reboot is never sent by accident
+                if payload == REBOOT_PAYLOAD && cam.config.expose_controls {
+                    return Some((id.to_string(), CameraCommand::Reboot));
+                }
+                let command = match payload.to_lowercase().as_str() {
+                    "reconnect" => CameraCommand::Reconnect,
+                    "pause" => CameraCommand::Pause,
+                    "resume" => CameraCommand::Resume,
+                    "reboot" if cam.config.expose_controls => {
+                        warn!(
+                            camera = id,
+                            "Ignoring {:?}, the payload to reboot is {}", payload, REBOOT_PAYLOAD
+                        );
+                        return None;
+                    }
+                    "reboot" => {
+                        warn!(
+                            camera = id,
+                            "Ignoring reboot, the camera doesn't have expose_controls enabled"
+                        );
+                        return None;
+                    }
+                    _ => {
+                        warn!(
+                            camera = id,
+                            "Ignoring unknown command {:?}, expected reconnect, pause or resume",
+                            payload
+                        );
+                        return None;
+                    }
+                };
+                return Some((id.to_string(), command));
+            }
+            if let Some(control) = cam
+                .controls
+                .iter()
+                .find(|c| self.topics.get_control_set(cam, c) == topic)
+            {
+                let enabled = match payload {
+                    CONTROL_PAYLOAD_ON => true,
+                    CONTROL_PAYLOAD_OFF => false,
+                    _ => {
+                        warn!(
+                            camera = id,
+                            "Ignoring unknown control state {:?}, expected {} or {}",
+                            payload,
+                            CONTROL_PAYLOAD_ON,
+                            CONTROL_PAYLOAD_OFF
+                        );
+                        return None;
+                    }
+                };
+                return Some((
+                    id.to_string(),
+                    CameraCommand::SetControl {
+                        control: control.control,
+                        channel: control.channel.clone(),
+                        enabled,
+                    },
+                ));
+            }
+            if cam.config.expose_controls && self.topics.get_ptz_preset_set(cam) == topic {
+                if payload.is_empty() {
+                    warn!(camera = id, "Ignoring empty preset");
+                    return None;
+                }
+                return Some((
+                    id.to_string(),
+                    CameraCommand::GotoPreset(payload.to_string()),
+                ));
+            }
+        }
+        None
+    }
+    /// Discovery messages for all cameras and global stats
+    fn message_discovery(&self) -> Vec<MqttMessage> {
+        let mut messages = Vec::new();
         for cam in &self.cameras {
             messages.append(&mut cam.message_complete_discovery(&self.topics))
         }
         messages.append(&mut self.message_gloal_stats_discovery());
-
         messages
     }
+    /// The current state of the bridge, for health checks
+    pub fn status(&self, mqtt_connected: bool, mqtt_error: Option<String>) -> BridgeStatus {
+        BridgeStatus {
+            mqtt_connected,
+            mqtt_error,
+            cameras: self
+                .cameras
+                .iter()
+                .map(|c| CameraStatus {
+                    id: c.config.identifier().to_string(),
+                    name: c.config.name.clone(),
+                    connected: c.connected,
+                    running: match c.log.state {
+                        ConnectionState::Disconnected => c.log.retry_in_secs.is_some(),
+                        ConnectionState::Disabled => false,
+                        _ => true,
+                    },
+                    error: (c.log.state == ConnectionState::Disconnected)
+                        .then(|| c.log.detail.clone()),
+                    since: c.log.since,
+                    last_event: c.last_event,
+                    triggers: c.triggers.len(),
+                })
+                .collect(),
+        }
+    }
+    /// Call this when messages could not be published. Returns the updated stats.
+    pub fn messages_dropped(&mut self, count: u64) -> MqttMessage {
+        self.dropped_messages += count;
+        self.message_global_stats()
+    }
+    /// Includes whether each broker is connected in the stats, given their names in order
+    pub fn report_brokers(&mut self, names: Vec<String>) {
+        self.brokers = names.into_iter().map(|name| (name, false)).collect();
+    }
+    /// Includes how many cameras are connecting in the stats
+    pub fn report_connects(&mut self, connects: ConnectLimit) {
+        self.connects = connects;
+    }
+    /// Includes the outcomes of the InfluxDB writes in the stats
+    pub fn report_influx(&mut self, influx: InfluxStats) {
+        self.influx = Some(influx);
+    }
+    /// Includes how many camera events were dropped while the queue was full in the stats
+    pub fn report_queue(&mut self, events: EventQueue) {
+        self.events = Some(events);
+    }
     /// Updates system stats as an MQTT message
-    fn message_global_stats(&self) -> MqttMessage {
+    fn message_global_stats(&mut self) -> MqttMessage {
+        self.stats_published = Utc::now();
         let num_cameras = self.cameras.len();
         let num_cameras_connected = self.cameras.iter().filter(|c| c.connected).count();
         let num_triggers: usize = self.cameras.iter().map(|c| c.triggers.len()).sum();
-        MqttMessage::new(
-            self.topics.get_global_stats(),
-            MqttQoS::AtLeastOnce,
-            true,
-            serde_json::json!({
+        let num_events: u64 = self
+            .cameras
+            .iter()
+            .flat_map(|c| c.triggers.iter())
+            .map(|t| t.alert_count)
+            .sum();
+        let num_missed_posts: u64 = self
+            .cameras
+            .iter()
+            .flat_map(|c| c.triggers.iter())
+            .map(|t| t.missed_posts)
+            .sum();
+        let mut disconnects: BTreeMap<DisconnectKind, u64> = BTreeMap::new();
+        for (kind, count) in self.cameras.iter().flat_map(|c| c.error_counts.iter()) {
+            *disconnects.entry(*kind).or_default() += count;
+        }
+        let cameras: Vec<_> = self
+            .cameras
+            .iter()
+            .map(|c| {
+                serde_json::json!({
+                    "id": c.config.identifier(),
+                    "connected": c.connected,
+                    "last_event": c.last_event.map(|t| t.to_rfc3339()),
+                    "triggers": c.triggers.len(),
+                    "errors": c.error_counts,
+                })
+            })
+            .collect();
+        let brokers: Vec<_> = self
+            .brokers
+            .iter()
+            .map(|(name, connected)| {
+                serde_json::json!({
+                    "broker": name,
+                    "connected": connected,
+                })
+            })
+            .collect();
+        let mut stats = serde_json::json!({
                 "cameras_connected": num_cameras_connected,
                 "cameras_disconnected": num_cameras - num_cameras_connected,
                 "cameras_total": num_cameras,
                 "triggers_total": num_triggers,
-            }),
+                "events_total": num_events,
+                "messages_dropped": self.dropped_messages,
+                "events_dropped": self.events.as_ref().map_or(0, EventQueue::dropped),
+                "connects_in_flight": self.connects.in_flight(),
+                "events_processed_total": self.events_processed,
+                "deduplicated_total": self.deduplicated,
+                "missed_posts_total": num_missed_posts,
+                "disconnects": disconnects,
+                "uptime_secs": (Utc::now() - self.started).num_seconds(),
+                "version": env!("CARGO_PKG_VERSION"),
+                "cameras": cameras,
+                "brokers": brokers,
+        });
+        if let Some(influx) = &self.influx {
+            stats["influx"] = influx.to_json();
+        }
+        MqttMessage::new(
+            self.topics.get_global_stats(),
+            self.publishing.qos.stats.clone(),
+            true,
+            stats,
         )
     }
     /// Updates the discovery for the global stats
@@ -87,13 +677,11 @@ impl Manager {
         let discovery = |key: &str, name: &str, uom: &str| {
             MqttMessage::new(
                 self.topics.get_global_stats_discovery(key),
-                MqttQoS::AtLeastOnce,
+                self.publishing.qos.discovery.clone(),
                 true,
                 serde_json::json!({
                     "availability": [
-                        {
-                            "topic": self.topics.get_global_availability(),
-                        },
+                        self.topics.global_availability_entry(),
                     ],
                     "device": {
                         "identifiers": [
@@ -103,8 +691,10 @@ impl Manager {
                         "name": "HikSink Bridge",
                         "sw_version": format!("v{}", env!("CARGO_PKG_VERSION")),
                     },
+                    "entity_category": "diagnostic",
                     "json_attributes_topic": self.topics.get_global_stats(),
                     "name": name,
+                    "state_class": "measurement",
                     "state_topic": self.topics.get_global_stats(),
                     "unique_id": format!("hiksink_stat_{}", key),
                     "value_template": format!("{{{{ value_json.{} }}}}", key),
@@ -113,58 +703,711 @@ impl Manager {
             )
         };
 
+        let mut uptime = discovery("uptime_secs", "Uptime", "s");
+        if let MqttPayload::Json(json) = &mut uptime.payload {
+            json["device_class"] = "duration".into();
+            json["state_class"] = "total_increasing".into();
+        }
         vec![
             discovery("cameras_connected", "Cameras Connected", "Cameras"),
             discovery("cameras_disconnected", "Cameras Disconnected", "Cameras"),
             discovery("cameras_total", "Total Cameras", "Cameras"),
             discovery("triggers_total", "Total Triggers", "Triggers"),
+            discovery("events_total", "Total Events", "Events"),
+            discovery("messages_dropped", "Dropped Messages", "Messages"),
+            discovery("events_dropped", "Dropped Camera Events", "Events"),
+            discovery("events_processed_total", "Processed Events", "Events"),
+            discovery("deduplicated_total", "Deduplicated Events", "Events"),
+            discovery("missed_posts_total", "Missed Alert Posts", "Posts"),
+            uptime,
         ]
     }
+    /// Call this periodically. Clears alerts which the camera has stopped posting without ever sending an inactive state.
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Vec<MqttMessage> {
+        let mut messages = Vec::new();
+        self.recent_alerts
+            .retain(|_, (_, last)| now - *last < Duration::seconds(DEDUP_WINDOW_SECS));
+        for cam in &mut self.cameras {
+            let mut cleared = Vec::new();
+            let mut counters = Vec::new();
+            let mut regions = Vec::new();
+            for (i, trigger) in cam.triggers.iter_mut().enumerate() {
+                let debounce = debounce_for(
+                    &self.system,
+                    &cam.config,
+                    &trigger.trigger.identifier.event_type,
+                );
+                if trigger.off_pending {
+                    let off = match (debounce.off_delay, trigger.last_active_post) {
+                        (Some(delay), Some(last)) => now - last >= delay,
+                        _ => true,
+                    };
+                    if off {
+                        trigger.off_pending = false;
+                        trigger.alerting = false;
+                        trigger.regions = Vec::new();
+                        trigger.target = None;
+                        trigger.counters_pending = false;
+                        trigger.regions_pending = false;
+                        cleared.push(i);
+                        continue;
+                    }
+                }
+                let regions_due = trigger.regions_pending
+                    && match (debounce.publish_interval, trigger.regions_published) {
+                        (Some(interval), Some(last)) => now - last >= interval,
+                        _ => true,
+                    };
+                if regions_due {
+                    trigger.regions_pending = false;
+                    trigger.regions_published = Some(now);
+                    if !cam.publishing.regions_topic {
+                        // The regions are part of the state, which brings the counters up to date too
+                        trigger.counters_pending = false;
+                        trigger.state_published = Some(now);
+                    }
+                    regions.push(i);
+                }
+                let counters_due = trigger.counters_pending
+                    && trigger
+                        .state_published
+                        .map(|last| now - last >= Duration::seconds(COUNTERS_INTERVAL_SECS))
+                        .unwrap_or(true);
+                if counters_due {
+                    trigger.counters_pending = false;
+                    trigger.state_published = Some(now);
+                    counters.push(i);
+                }
+                if !trigger.alerting {
+                    continue;
+                }
+                let auto_clear = match auto_clear_after(
+                    &self.system,
+                    &cam.config,
+                    &trigger.trigger.identifier.event_type,
+                ) {
+                    Some(auto_clear) => auto_clear,
+                    None => continue,
+                };
+                let expired = trigger
+                    .last_active_post
+                    .map(|last| now - last >= auto_clear)
+                    .unwrap_or(true);
+                if expired {
+                    trigger.alerting = false;
+                    trigger.regions = Vec::new();
+                    trigger.target = None;
+                    trigger.counters_pending = false;
+                    trigger.off_pending = false;
+                    trigger.regions_pending = false;
+                    cleared.push(i);
+                }
+            }
+            counters.retain(|i| !cleared.contains(i));
+            regions.retain(|i| !cleared.contains(i));
+            if !cleared.is_empty() {
+                self.state_changed = true;
+            }
+            for i in cleared {
+                let trigger = &cam.triggers[i];
+                messages.append(&mut trigger.message_states(&self.topics, cam));
+                if cam.publishing.regions_topic {
+                    messages.push(trigger.message_regions(&self.topics, cam));
+                }
+            }
+            for i in regions {
+                let trigger = &cam.triggers[i];
+                if cam.publishing.regions_topic {
+                    messages.push(trigger.message_regions(&self.topics, cam));
+                } else {
+                    messages.push(trigger.message_state(&self.topics, cam));
+                }
+            }
+            for i in counters {
+                messages.push(cam.triggers[i].message_state(&self.topics, cam));
+            }
+            let grace = Duration::seconds(self.system.availability_grace_secs as i64);
+            if let Some(since) = cam.offline_pending {
+                if now - since >= grace {
+                    info!(
+                        camera = cam.config.identifier(),
+                        "Not reconnected within the availability grace period"
+                    );
+                    cam.offline_pending = None;
+                    messages.push(cam.message_availability(&self.topics));
+                }
+            }
+            // Only connected cameras can go quiet, the rest are already shown as unavailable
+            if let (Some(stale_after), Some(last_activity), true) =
+                (cam.stale_after, cam.last_activity, cam.connected)
+            {
+                let stale = now - last_activity >= stale_after;
+                if stale != cam.events_stale {
+                    if stale {
+                        warn!(
+                            camera = cam.config.identifier(),
+                            "No alerts received for {} seconds",
+                            stale_after.num_seconds()
+                        );
+                    }
+                    cam.events_stale = stale;
+                    messages.push(cam.message_events_stale(&self.topics));
+                }
+            }
+        }
+        if now - self.stats_published >= Duration::seconds(STATS_INTERVAL_SECS) {
+            messages.push(self.message_global_stats());
+        }
+        let mut messages = self.without_muted(messages);
+        for cam in &mut self.cameras {
+            if cam.muted_until.is_some_and(|until| until <= now) {
+                info!(camera = cam.config.identifier(), "Mute expired");
+                cam.muted_until = None;
+                messages.extend(cam.message_info(&self.topics));
+                messages.append(&mut cam.message_trigger_states(&self.topics));
+                messages.append(&mut cam.message_occupancy_states(&self.topics));
+                self.state_changed = true;
+            }
+        }
+        messages
+    }
+    /// Whether another device sent the same event within the dedup window. Otherwise the alert
+    /// is remembered, so the first device to send an event owns it while it repeats, and its end
+    /// isn't lost when the other device's inactive alert arrives first.
+    fn is_duplicate(&mut self, source: &str, alert: &AlertItem, now: DateTime<Utc>) -> bool {
+        let cam = match self
+            .cameras
+            .iter()
+            .find(|c| c.config.identifier() == source)
+        {
+            Some(cam) => cam,
+            None => return false,
+        };
+        let key = dedup_key(&self.cameras, cam, alert);
+        match self.recent_alerts.get(&key) {
+            Some((first, last))
+                if first != source && now - *last < Duration::seconds(DEDUP_WINDOW_SECS) =>
+            {
+                true
+            }
+            _ => {
+                self.recent_alerts.insert(key, (source.to_string(), now));
+                false
+            }
+        }
+    }
+
     pub fn next_event(&mut self, event: CameraEvent) -> Vec<MqttMessage> {
+        let messages = self.process_event(event);
+        self.without_muted(messages)
+    }
+    /// Handles an event, including for muted cameras
+    fn process_event(&mut self, mut event: CameraEvent) -> Vec<MqttMessage> {
         let mut messages = Vec::new();
+        if let CameraEventType::Alert(alert) = &mut event.event {
+            if let Some(normalized) = self
+                .cameras
+                .iter()
+                .find(|c| c.config.identifier() == event.id)
+                .and_then(|c| c.normalize_alert_identifier(&alert.identifier))
+            {
+                debug!(
+                    camera = event.id.as_str(),
+                    from = %alert.identifier,
+                    to = %normalized,
+                    "Normalized alert channel"
+                );
+                alert.identifier = normalized;
+            }
+        }
+        let duplicate = match &event.event {
+            CameraEventType::Alert(alert) if self.system.dedup => {
+                self.is_duplicate(&event.id, alert, Utc::now())
+            }
+            _ => false,
+        };
         if let Some(cam) = self
             .cameras
             .iter_mut()
             .find(|c| c.config.identifier() == event.id)
         {
+            if !cam.config.enabled {
+                // Left over from before the camera was disabled
+                return messages;
+            }
+            if !matches!(
+                event.event,
+                CameraEventType::Snapshot { .. } | CameraEventType::RawPart(_)
+            ) {
+                self.state_changed = true;
+            }
+            // Any alert shows the camera is still sending them, even video loss or filtered ones
+            if let (CameraEventType::Alert(_), Some(_)) = (&event.event, cam.stale_after) {
+                cam.last_activity = Some(Utc::now());
+                if cam.events_stale {
+                    info!(camera = cam.config.identifier(), "Alerts received again");
+                    cam.events_stale = false;
+                    messages.push(cam.message_events_stale(&self.topics));
+                }
+            }
+            if duplicate {
+                debug!(
+                    camera = cam.config.identifier(),
+                    "Dropping alert already sent by another device"
+                );
+                self.deduplicated += 1;
+                return messages;
+            }
             match event.event {
-                CameraEventType::Connected { info, triggers } => {
-                    // We don't check for deleted triggers. This shouldn't happen since triggers are static for the same camera model
+                CameraEventType::Connected {
+                    info,
+                    triggers,
+                    address,
+                } => {
+                    // Keep the state of triggers we already know about so a reconnect doesn't reset alerts that are in progress
+                    let mut previous = std::mem::take(&mut cam.triggers);
+                    let (triggers, silent): (Vec<_>, Vec<_>) = triggers
+                        .into_iter()
+                        .partition(|trigger| sends_alerts(&cam.config, trigger));
+                    if !silent.is_empty() {
+                        let skipped: Vec<String> =
+                            silent.iter().map(|t| t.identifier.to_string()).collect();
+                        info!(
+                            camera = cam.config.identifier(),
+                            "Skipping triggers that don't notify the surveillance center: {}",
+                            skipped.join(", ")
+                        );
+                    }
                     cam.triggers = triggers
                         .into_iter()
-                        .map(|trigger| TriggerDetails {
-                            trigger,
-                            alerting: false,
-                            regions: Vec::new(),
-                            last_alert: Utc::now(),
+                        .filter(|trigger| {
+                            is_published(&self.system, &cam.config, &trigger.identifier)
+                        })
+                        .map(|trigger| {
+                            match previous
+                                .iter()
+                                .position(|t| t.trigger.identifier == trigger.identifier)
+                            {
+                                Some(i) => {
+                                    let mut existing = previous.swap_remove(i);
+                                    existing.trigger = trigger;
+                                    existing
+                                }
+                                None => TriggerDetails::new(trigger),
+                            }
                         })
                         .collect();
+                    // Triggers learnt from alerts are never in the scan, so keep them
+                    cam.triggers.extend(previous.into_iter().filter(|t| {
+                        t.auto_registered
+                            && is_published(&self.system, &cam.config, &t.trigger.identifier)
+                    }));
+                    for key in cam.config.trigger_names.keys() {
+                        if !cam
+                            .triggers
+                            .iter()
+                            .any(|t| trigger_key_matches(key, &t.trigger.identifier))
+                        {
+                            warn!(
+                                camera = cam.config.identifier(),
+                                key = key.as_str(),
+                                "trigger_names has an entry for a trigger the camera does not have",
+                            );
+                        }
+                    }
+                    // Controls are sent by the camera after connecting, and kept until then so they don't flicker
+                    if !cam.config.expose_controls {
+                        cam.controls.clear();
+                        cam.presets.clear();
+                    }
+                    if cam.info.is_some() {
+                        cam.reconnect_count += 1;
+                    }
                     cam.info = Some(info);
-                    cam.log = "Connected".into();
+                    // With `stable_ids` the topics follow the serial number, which is only known now
+                    cam.refresh_topics(&self.topics);
+                    // Only worth mentioning when the camera can be reached another way
+                    let status = match &cam.config.fallback_address {
+                        Some(_) => format!("Connected to {}", address),
+                        None => "Connected".to_string(),
+                    };
+                    cam.log.update(ConnectionState::Connected, status, None);
+                    cam.address = Some(address);
                     cam.connected = true;
+                    cam.offline_pending = None;
+                    // A new connection gets the full time to send an alert
+                    cam.last_activity = Some(Utc::now());
+                    cam.events_stale = false;
                     messages.append(&mut cam.message_complete_refresh(&self.topics));
                     messages.append(&mut cam.message_complete_discovery(&self.topics));
+
+                    // Remove the topics of anything that has disappeared since we last connected
+                    messages.append(&mut update_retained_topics(
+                        &mut self.retained_topics,
+                        &mut self.retained_topics_changed,
+                        self.system.cleanup_stale_topics,
+                        cam,
+                        &self.topics,
+                    ));
                     messages.push(self.message_global_stats());
                 }
-                CameraEventType::Disconnected { error } => {
+                CameraEventType::TriggersUpdated { added, removed } => {
+                    cam.triggers
+                        .retain(|t| !removed.contains(&t.trigger.identifier));
+                    let first_added = cam.triggers.len();
+                    for trigger in added {
+                        if is_published(&self.system, &cam.config, &trigger.identifier)
+                            && sends_alerts(&cam.config, &trigger)
+                            && !cam
+                                .triggers
+                                .iter()
+                                .any(|t| t.trigger.identifier == trigger.identifier)
+                        {
+                            cam.triggers.push(TriggerDetails::new(trigger));
+                        }
+                    }
+                    cam.refresh_topics(&self.topics);
+                    if let Some(info) = cam.info.as_ref() {
+                        for trigger in &cam.triggers[first_added..] {
+                            info!(
+                                camera = cam.config.identifier(),
+                                trigger = trigger.display_name(cam).as_str(),
+                                "New trigger found"
+                            );
+                            messages.append(&mut trigger.message_states(&self.topics, cam));
+                            messages.append(&mut trigger.message_complete_discovery(
+                                &self.topics,
+                                cam,
+                                info,
+                            ));
+                        }
+                    }
+                    // Remove the topics of the triggers that are gone
+                    messages.append(&mut update_retained_topics(
+                        &mut self.retained_topics,
+                        &mut self.retained_topics_changed,
+                        self.system.cleanup_stale_topics,
+                        cam,
+                        &self.topics,
+                    ));
+                }
+                CameraEventType::Disconnected {
+                    error,
+                    kind,
+                    retry_in_secs,
+                } => {
+                    // A requested reconnect isn't a problem with the camera
+                    if kind != DisconnectKind::Requested {
+                        cam.record_error(kind, &error);
+                        messages.push(cam.message_errors(&self.topics));
+                    }
+                    // Only a camera that was connected gets the grace period, so it doesn't hide one that never connects
+                    if cam.connected && self.system.availability_grace_secs > 0 {
+                        cam.offline_pending = Some(Utc::now());
+                    }
+                    cam.connected = false;
+                    cam.log.update(
+                        ConnectionState::Disconnected,
+                        format!("Connection Error: {}", error),
+                        retry_in_secs,
+                    );
+                    cam.log.kind = Some(kind);
+                    messages.push(cam.message_log(&self.topics));
+                    if cam.offline_pending.is_none() {
+                        messages.push(cam.message_availability(&self.topics));
+                    }
+                }
+                CameraEventType::Paused => {
                     cam.connected = false;
-                    cam.log = format!("Connection Error: {}", error);
+                    cam.offline_pending = None;
+                    cam.log
+                        .update(ConnectionState::Paused, "Paused by command".into(), None);
                     messages.push(cam.message_log(&self.topics));
                     messages.push(cam.message_availability(&self.topics));
                 }
+                CameraEventType::Rebooting { retry_in_secs } => {
+                    cam.connected = false;
+                    cam.offline_pending = None;
+                    cam.log.update(
+                        ConnectionState::Disconnected,
+                        "Reboot requested".into(),
+                        Some(retry_in_secs),
+                    );
+                    messages.push(cam.message_log(&self.topics));
+                    messages.push(cam.message_availability(&self.topics));
+                }
+                CameraEventType::RebootFailed { error } => {
+                    let (state, retry_in_secs, kind) =
+                        (cam.log.state, cam.log.retry_in_secs, cam.log.kind);
+                    cam.log
+                        .update(state, format!("Unable to reboot: {}", error), retry_in_secs);
+                    cam.log.kind = kind;
+                    messages.push(cam.message_log(&self.topics));
+                }
+                CameraEventType::Control(state) => {
+                    let known = cam
+                        .controls
+                        .iter()
+                        .position(|c| c.control == state.control && c.channel == state.channel);
+                    match known {
+                        Some(i) => cam.controls[i] = state,
+                        None => {
+                            cam.controls.push(state);
+                            if let Some(info) = cam.info.as_ref() {
+                                let control = &cam.controls[cam.controls.len() - 1];
+                                messages.push(cam.message_control_discovery(
+                                    &self.topics,
+                                    control,
+                                    info,
+                                ));
+                            }
+                            messages.append(&mut update_retained_topics(
+                                &mut self.retained_topics,
+                                &mut self.retained_topics_changed,
+                                self.system.cleanup_stale_topics,
+                                cam,
+                                &self.topics,
+                            ));
+                        }
+                    }
+                    messages.append(&mut cam.message_control_states(&self.topics));
+                }
+                CameraEventType::ControlFailed {
+                    control,
+                    channel,
+                    error,
+                } => {
+                    let (state, retry_in_secs) = (cam.log.state, cam.log.retry_in_secs);
+                    cam.log.update(
+                        state,
+                        format!(
+                            "Unable to change {}: {}",
+                            cam.control_display_name(control, &channel),
+                            error
+                        ),
+                        retry_in_secs,
+                    );
+                    messages.push(cam.message_log(&self.topics));
+                }
+                CameraEventType::PtzPresets(presets) if presets != cam.presets => {
+                    cam.presets = presets;
+                    if let Some(info) = cam.info.as_ref() {
+                        messages.push(cam.message_preset_discovery(&self.topics, info));
+                    }
+                    messages.append(&mut update_retained_topics(
+                        &mut self.retained_topics,
+                        &mut self.retained_topics_changed,
+                        self.system.cleanup_stale_topics,
+                        cam,
+                        &self.topics,
+                    ));
+                }
+                CameraEventType::PtzPresets(_) => {
+                    // Already published
+                }
+                CameraEventType::PresetFailed { preset, error } => {
+                    let (state, retry_in_secs) = (cam.log.state, cam.log.retry_in_secs);
+                    cam.log.update(
+                        state,
+                        format!("Unable to go to preset {}: {}", preset, error),
+                        retry_in_secs,
+                    );
+                    messages.push(cam.message_log(&self.topics));
+                }
+                CameraEventType::ParseFailure { error, raw } => {
+                    if cam.publishing.publish_parse_failures {
+                        messages.push(cam.message_parse_failure(&self.topics, &error, &raw));
+                    }
+                }
+                CameraEventType::RawPart(_) => {
+                    // Only used when monitoring from the command line
+                }
+                CameraEventType::InfoUpdated(info) => {
+                    // Only the discovery changes, the triggers and their states stay as they are
+                    info!(
+                        camera = cam.config.identifier(),
+                        firmware = info.firmware_version.as_str(),
+                        "Camera device info changed, updating discovery"
+                    );
+                    cam.info = Some(info);
+                    cam.refresh_topics(&self.topics);
+                    messages.append(&mut cam.message_complete_discovery(&self.topics));
+                    messages.extend(cam.message_info(&self.topics));
+                }
+                CameraEventType::ClockDrift { drift_secs } => {
+                    let first = cam.clock_drift.is_none();
+                    let wrong = drift_secs.unsigned_abs() > self.system.clock_drift_threshold_secs;
+                    if wrong && !cam.clock_wrong {
+                        warn!(
+                            camera = cam.config.identifier(),
+                            drift_secs, "Camera clock is wrong, check its time settings"
+                        );
+                    } else if !wrong && cam.clock_wrong {
+                        info!(
+                            camera = cam.config.identifier(),
+                            "Camera clock is right again"
+                        );
+                    }
+                    cam.clock_drift = Some(drift_secs);
+                    cam.clock_wrong = wrong;
+                    if let (true, Some(info)) = (first, cam.info.as_ref()) {
+                        messages.append(&mut cam.message_clock_discovery(&self.topics, info));
+                    }
+                    messages.append(&mut cam.message_clock_states(&self.topics));
+                }
+                CameraEventType::Alert(alert)
+                    if !is_published(&self.system, &cam.config, &alert.identifier) =>
+                {
+                    // Filtered out by the user
+                }
                 CameraEventType::Alert(alert) => {
                     // Find the matching trigger
                     let mut changed = false;
+                    let mut regions_changed = false;
+                    let mut processed = false;
+                    let mut activated = false;
+                    let mut occurred = false;
+                    let mut registered = false;
+                    let mut counters_publish = false;
+                    let mut renamed = false;
                     let alert_identifier = alert.identifier;
+                    let debounce =
+                        debounce_for(&self.system, &cam.config, &alert_identifier.event_type);
+                    if cam.config.auto_register_triggers
+                        && (!alert_identifier.event_type.is_video_loss()
+                            || cam.config.ignore_videoloss == Some(false))
+                        && !cam
+                            .triggers
+                            .iter()
+                            .any(|t| t.trigger.identifier == alert_identifier)
+                    {
+                        let mut trigger = TriggerDetails::new(alert_identifier.clone().into());
+                        trigger.auto_registered = true;
+                        trigger.topics = self.topics.trigger_topics(cam, &alert_identifier);
+                        info!(
+                            camera = cam.config.identifier(),
+                            trigger = trigger.display_name(cam).as_str(),
+                            "Registering trigger that was not in the camera's trigger list"
+                        );
+                        if let Some(info) = cam.info.as_ref() {
+                            messages.append(&mut trigger.message_complete_discovery(
+                                &self.topics,
+                                cam,
+                                info,
+                            ));
+                        }
+                        cam.triggers.push(trigger);
+                        registered = true;
+                    }
                     if let Some(trigger) = cam
                         .triggers
                         .iter_mut()
                         .find(|t| t.trigger.identifier == alert_identifier)
                     {
+                        processed = true;
+                        let now = Utc::now();
+                        if alert.active {
+                            trigger.last_active_post = Some(now);
+                            trigger.off_pending = false;
+                        }
+                        // A flapping trigger is held on until the off delay has passed since its last active alert
+                        let held = !alert.active
+                            && trigger.alerting
+                            && match (debounce.off_delay, trigger.last_active_post) {
+                                (Some(delay), Some(last)) => now - last < delay,
+                                _ => false,
+                            };
+                        if held {
+                            trigger.off_pending = true;
+                        }
                         // Only update if changed (to prevent spamming messages)
-                        if trigger.alerting != alert.active || trigger.regions != alert.regions {
+                        regions_changed = !held && trigger.regions != alert.regions;
+                        let regions_due = trigger.alerting != alert.active
+                            || match (debounce.publish_interval, trigger.regions_published) {
+                                (Some(interval), Some(last)) => now - last >= interval,
+                                _ => true,
+                            };
+                        if regions_changed && !regions_due {
+                            regions_changed = false;
+                            trigger.regions_pending = true;
+                        }
+                        // A new plate is worth publishing even if the trigger is still alerting
+                        let plate_changed = alert.anpr.is_some() && trigger.anpr != alert.anpr;
+                        if plate_changed {
+                            trigger.anpr = alert.anpr;
+                        }
+                        // Learning the channel's name isn't a change of state, so it waits for the next publish
+                        if trigger.channel_name.is_none() && alert.channel_name.is_some() {
+                            trigger.channel_name = alert.channel_name;
+                            renamed = cam.publishing.names_from_alerts
+                                && alert_identifier.event_type != EventType::Io
+                                && trigger.trigger.channel_name.is_none()
+                                && !alert_identifier
+                                    .channel
+                                    .as_ref()
+                                    .is_some_and(|c| cam.config.channels.contains_key(c));
+                        }
+                        let counts_changed = alert.people_counting.is_some()
+                            && trigger.people_counting != alert.people_counting;
+                        if counts_changed {
+                            trigger.people_counting = alert.people_counting;
+                        }
+                        // Cameras number the posts of an ongoing alert, so a gap means some never arrived
+                        if alert.active
+                            && trigger.alerting
+                            && trigger.post_count > 0
+                            && alert.post_count > trigger.post_count + 1
+                        {
+                            trigger.missed_posts += alert.post_count - trigger.post_count - 1;
+                        }
+                        let counters_changed = trigger.post_count != alert.post_count;
+                        trigger.post_count = alert.post_count;
+                        if held {
+                            // Nothing changes until the off delay has passed
+                        } else if registered
+                            || plate_changed
+                            || counts_changed
+                            || trigger.alerting != alert.active
+                            || trigger.target != alert.target
+                            || (regions_changed && !cam.publishing.regions_topic)
+                        {
                             changed = true;
+                            if alert.active && !trigger.alerting {
+                                activated = true;
+                                trigger.alert_count += 1;
+                                trigger.last_alert = Some(
+                                    alert
+                                        .time
+                                        .map(|t| t.with_timezone(&Utc))
+                                        .unwrap_or_else(Utc::now),
+                                );
+                                trigger.camera_time = Some(alert.date);
+                            }
+                            // Each new plate read while alerting is an occurrence of its own
+                            occurred = activated || (plate_changed && alert.active);
                             trigger.alerting = alert.active;
+                            trigger.target = alert.target;
+                            trigger.state_published = Some(now);
+                            trigger.counters_pending = false;
+                        } else if counters_changed {
+                            // Every re-post changes the counters, so they are only published now and then
+                            let due = trigger
+                                .state_published
+                                .map(|last| now - last >= Duration::seconds(COUNTERS_INTERVAL_SECS))
+                                .unwrap_or(true);
+                            if due {
+                                trigger.state_published = Some(now);
+                                trigger.counters_pending = false;
+                                counters_publish = true;
+                            } else {
+                                trigger.counters_pending = true;
+                            }
+                        }
+                        if regions_changed || (changed && !cam.publishing.regions_topic) {
+                            trigger.regions_published = Some(now);
+                            trigger.regions_pending = false;
+                        }
+                        if !held {
                             trigger.regions = alert.regions;
                         }
                     } else {
@@ -180,18 +1423,99 @@ impl Manager {
                         }
                     }
 
-                    if changed {
-                        // Unwrap here is safe since `changed` only set when trigger was updated
+                    if processed {
+                        self.events_processed += 1;
+                        cam.last_event = Some(Utc::now());
+                    }
+                    if renamed {
                         let trigger = cam
                             .triggers
                             .iter()
                             .find(|t| t.trigger.identifier == alert_identifier)
                             .unwrap();
-                        messages.push(trigger.message_state(&self.topics, cam));
+                        info!(
+                            camera = cam.config.identifier(),
+                            trigger = trigger.display_name(cam).as_str(),
+                            "Naming trigger after the channel name in its alerts"
+                        );
+                        if let Some(info) = cam.info.as_ref() {
+                            messages.append(&mut trigger.message_complete_discovery(
+                                &self.topics,
+                                cam,
+                                info,
+                            ));
+                        }
                     }
-                }
-            }
-        } else {
+                    if changed || regions_changed || counters_publish {
+                        // Unwrap here is safe since these are only set when trigger was updated
+                        let trigger = cam
+                            .triggers
+                            .iter()
+                            .find(|t| t.trigger.identifier == alert_identifier)
+                            .unwrap();
+                        if changed {
+                            debug!(
+                                trigger = trigger.display_name(cam).as_str(),
+                                alerting = trigger.alerting,
+                                "Trigger state changed"
+                            );
+                            messages.append(&mut trigger.message_states(&self.topics, cam));
+                        } else if counters_publish {
+                            messages.push(trigger.message_state(&self.topics, cam));
+                        }
+                        if regions_changed && cam.publishing.regions_topic {
+                            messages.push(trigger.message_regions(&self.topics, cam));
+                        }
+                        if occurred && trigger.ha_entity(cam) != HaEntity::BinarySensor {
+                            messages.push(trigger.message_event(&self.topics, cam));
+                        }
+                    }
+                    if activated && cam.config.derive_occupancy {
+                        if let Some(message) = cam.count_occupancy(&self.topics, &alert_identifier)
+                        {
+                            messages.push(message);
+                        }
+                    }
+                    if registered {
+                        if let (Some(channel), Some(info)) =
+                            (occupancy_channel(cam, &alert_identifier), cam.info.as_ref())
+                        {
+                            messages.push(cam.message_occupancy_discovery(
+                                &self.topics,
+                                &channel,
+                                info,
+                            ));
+                            messages.push(cam.message_occupancy_reset_discovery(
+                                &self.topics,
+                                &channel,
+                                info,
+                            ));
+                            messages.push(cam.message_occupancy_state(&self.topics, &channel));
+                        }
+                        messages.append(&mut update_retained_topics(
+                            &mut self.retained_topics,
+                            &mut self.retained_topics_changed,
+                            self.system.cleanup_stale_topics,
+                            cam,
+                            &self.topics,
+                        ));
+                    }
+                    if activated {
+                        // Keep the total event count up to date
+                        messages.push(self.message_global_stats());
+                    }
+                }
+                CameraEventType::Snapshot { identifier, image } => {
+                    if let Some(trigger) = cam
+                        .triggers
+                        .iter()
+                        .find(|t| t.trigger.identifier == identifier)
+                    {
+                        messages.push(trigger.message_snapshot(&self.topics, cam, image));
+                    }
+                }
+            }
+        } else {
             // This should not be possible, but is checked to prevent a complete crash in the event of programmer error.
             error!("Invalid camera event: {:?}", event);
         }
@@ -199,17 +1523,212 @@ impl Manager {
     }
 }
 
+/// Whether a camera is connected, published to the camera's log topic
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+    /// Disconnected by a command until it's resumed
+    Paused,
+    /// Not connected to at all, as set in the config
+    Disabled,
+}
+
+/// What's kept of a camera in the state file, so its triggers don't start over as off after a restart
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PersistedCamera {
+    triggers: Vec<TriggerDetails>,
+    #[serde(default)]
+    occupancy: BTreeMap<String, u32>,
+    #[serde(default)]
+    errors: VecDeque<CameraErrorEntry>,
+    #[serde(default)]
+    error_counts: BTreeMap<DisconnectKind, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    muted_until: Option<DateTime<Utc>>,
+}
+
+/// A connection error of a camera, kept in its error history
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct CameraErrorEntry {
+    pub time: DateTime<Utc>,
+    pub kind: DisconnectKind,
+    pub error: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct CameraLog {
+    pub state: ConnectionState,
+    /// A human readable description, e.g. the connection error
+    pub detail: String,
+    /// When the camera entered its current state
+    pub since: DateTime<Utc>,
+    pub retry_in_secs: Option<u64>,
+    /// Why the camera disconnected, while it's disconnected by an error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<DisconnectKind>,
+}
+
+impl CameraLog {
+    pub fn new(state: ConnectionState, detail: String, retry_in_secs: Option<u64>) -> Self {
+        Self {
+            state,
+            detail,
+            since: Utc::now(),
+            retry_in_secs,
+            kind: None,
+        }
+    }
+    /// Replaces the log, keeping `since` unless the state has changed
+    pub fn update(&mut self, state: ConnectionState, detail: String, retry_in_secs: Option<u64>) {
+        if state != self.state {
+            self.since = Utc::now();
+        }
+        self.state = state;
+        self.detail = detail;
+        self.retry_in_secs = retry_in_secs;
+        self.kind = None;
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 struct CameraDetails {
     pub config: ConfigCamera,
     pub info: Option<DeviceInfo>,
     pub triggers: Vec<TriggerDetails>,
     pub connected: bool,
+    /// When the camera last sent an alert
+    #[serde(skip)]
+    pub last_event: Option<DateTime<Utc>>,
+    /// When the camera last connected or sent any alert, including ones that aren't published
+    #[serde(skip)]
+    pub last_activity: Option<DateTime<Utc>>,
+    /// How long without alerts before the camera's events are stale. `None` if not watched.
+    #[serde(skip)]
+    pub stale_after: Option<Duration>,
+    /// Whether the camera is connected but hasn't sent alerts for `stale_after`
+    #[serde(skip)]
+    pub events_stale: bool,
+    /// When the camera disconnected, while it's still shown as available for the availability grace period
+    #[serde(skip)]
+    pub offline_pending: Option<DateTime<Utc>>,
+    /// Number of times the camera connected again since HikSink started
+    #[serde(default)]
+    pub reconnect_count: u64,
     /// Stores either connection info or a connection error
-    pub log: String,
+    pub log: CameraLog,
+    /// The settings published as switches, with `expose_controls`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub controls: Vec<ControlState>,
+    /// The presets of PTZ cameras with `expose_controls`, published as a select
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub presets: Vec<PtzPreset>,
+    /// How many are in each channel's regions, with `derive_occupancy`. Kept while reconnecting, only
+    /// reset by command or restarting.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub occupancy: BTreeMap<String, u32>,
+    /// The root URL the camera was last connected at, which is its fallback address if the first failed
+    #[serde(skip)]
+    pub address: Option<String>,
+    /// How far the camera's clock is ahead of HikSink's, in seconds. `None` until it's been read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_drift: Option<i64>,
+    /// Whether the clock drift is over `clock_drift_threshold_secs`
+    #[serde(default)]
+    pub clock_wrong: bool,
+    /// The latest connection errors, oldest first, up to `ERROR_HISTORY_LEN`
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    pub errors: VecDeque<CameraErrorEntry>,
+    /// Number of connection errors of each kind since HikSink started
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub error_counts: BTreeMap<DisconnectKind, u64>,
+    /// Trigger states aren't published until then, set by the mute command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub muted_until: Option<DateTime<Utc>>,
+    /// The camera's topics, rendered by `refresh_topics`
+    #[serde(skip)]
+    pub topics: CameraTopics,
+    #[serde(skip)]
+    pub publishing: MqttPublishing,
 }
 
 impl CameraDetails {
+    pub fn new(
+        config: ConfigCamera,
+        publishing: MqttPublishing,
+        stale_after: Option<Duration>,
+    ) -> Self {
+        let log = if config.enabled {
+            CameraLog::new(
+                ConnectionState::Connecting,
+                "Initial connection in progress...".to_string(),
+                None,
+            )
+        } else {
+            CameraLog::new(
+                ConnectionState::Disabled,
+                "Disabled in configuration".to_string(),
+                None,
+            )
+        };
+        Self {
+            config,
+            info: None,
+            triggers: Vec::new(),
+            connected: false,
+            last_event: None,
+            last_activity: None,
+            stale_after,
+            events_stale: false,
+            offline_pending: None,
+            reconnect_count: 0,
+            log,
+            controls: Vec::new(),
+            presets: Vec::new(),
+            occupancy: BTreeMap::new(),
+            address: None,
+            clock_drift: None,
+            clock_wrong: false,
+            errors: VecDeque::new(),
+            error_counts: BTreeMap::new(),
+            muted_until: None,
+            topics: CameraTopics::default(),
+            publishing,
+        }
+    }
+    /// Renders the topics of the camera and its triggers again. Call this when they're created, or their config,
+    /// device info or triggers change.
+    fn refresh_topics(&mut self, topics: &MqttTopics) {
+        self.topics = topics.camera_topics(self);
+        let mut triggers = std::mem::take(&mut self.triggers);
+        for trigger in &mut triggers {
+            trigger.topics = topics.trigger_topics(self, &trigger.trigger.identifier);
+        }
+        self.triggers = triggers;
+    }
+    /// Disables or enables the camera after the config has been reloaded, returning the messages to publish
+    fn set_enabled(&mut self, enabled: bool, topics: &MqttTopics) -> Vec<MqttMessage> {
+        if enabled {
+            info!(camera = self.config.identifier(), "Camera enabled");
+            self.log.update(
+                ConnectionState::Connecting,
+                "Enabled in configuration, connecting...".into(),
+                None,
+            );
+            return vec![self.message_log(topics)];
+        }
+        info!(camera = self.config.identifier(), "Camera disabled");
+        self.connected = false;
+        self.offline_pending = None;
+        self.log.update(
+            ConnectionState::Disabled,
+            "Disabled in configuration".into(),
+            None,
+        );
+        vec![self.message_log(topics), self.message_availability(topics)]
+    }
     /// Publishes a complete refresh of camera availability and all trigger states
     pub fn message_complete_refresh(&self, topics: &MqttTopics) -> Vec<MqttMessage> {
         let mut messages = Vec::with_capacity(self.triggers.len() + 1);
@@ -218,386 +1737,5687 @@ impl CameraDetails {
         // Ensure the camera's availability is up to date
         messages.push(self.message_log(topics));
         messages.push(self.message_availability(topics));
+        messages.push(self.message_reconnect_count(topics));
+        messages.append(&mut self.message_control_states(topics));
+        messages.append(&mut self.message_occupancy_states(topics));
+        if self.stale_after.is_some() {
+            messages.push(self.message_events_stale(topics));
+        }
+        if self.clock_drift.is_some() {
+            messages.append(&mut self.message_clock_states(topics));
+        }
+        if let Some(message) = self.message_info(topics) {
+            messages.push(message);
+        }
+        if !self.errors.is_empty() {
+            messages.push(self.message_errors(topics));
+        }
         messages
     }
     /// Publishes all discovery topics for home assistant
     pub fn message_complete_discovery(&self, topics: &MqttTopics) -> Vec<MqttMessage> {
         if let Some(info) = self.info.as_ref() {
-            self.triggers
-                .iter()
-                .map(|trigger| trigger.message_discovery(topics, self, info))
-                .collect()
+            let mut messages = Vec::with_capacity(self.triggers.len());
+            for trigger in &self.triggers {
+                messages.append(&mut trigger.message_discovery(topics, self, info));
+                messages.append(&mut trigger.message_target_discovery(topics, self, info));
+            }
+            messages.push(self.message_log_discovery(topics, info));
+            messages.push(self.message_connectivity_discovery(topics, info));
+            messages.push(self.message_reconnect_discovery(topics, info));
+            messages.push(self.message_reconnect_count_discovery(topics, info));
+            messages.push(self.message_info_discovery(topics, info));
+            if self.stale_after.is_some() {
+                messages.push(self.message_events_stale_discovery(topics, info));
+            }
+            if self.clock_drift.is_some() {
+                messages.append(&mut self.message_clock_discovery(topics, info));
+            }
+            if self.config.expose_controls {
+                messages.push(self.message_reboot_discovery(topics, info));
+            }
+            messages.extend(
+                self.controls
+                    .iter()
+                    .map(|control| self.message_control_discovery(topics, control, info)),
+            );
+            if !self.presets.is_empty() {
+                messages.push(self.message_preset_discovery(topics, info));
+            }
+            for channel in self.occupancy_channels() {
+                messages.push(self.message_occupancy_discovery(topics, &channel, info));
+                messages.push(self.message_occupancy_reset_discovery(topics, &channel, info));
+            }
+            if self.config.snapshot_on_alert {
+                messages.extend(
+                    self.triggers
+                        .iter()
+                        .map(|trigger| trigger.message_snapshot_discovery(topics, self, info)),
+                );
+            }
+            messages
         } else {
             Vec::new()
         }
     }
+    /// All retained topics currently published for this camera
+    pub fn retained_topics(&self, topics: &MqttTopics) -> BTreeSet<String> {
+        let mut retained: BTreeSet<String> = self
+            .message_complete_refresh(topics)
+            .into_iter()
+            .chain(self.message_complete_discovery(topics))
+            .filter(|m| m.retain)
+            .map(|m| m.topic)
+            .collect();
+        if self.config.snapshot_on_alert {
+            retained.extend(
+                self.triggers
+                    .iter()
+                    .map(|trigger| topics.get_trigger_snapshot(trigger)),
+            );
+        }
+        retained
+    }
     /// Publishes whether the camera is available (online)
     pub fn message_availability(&self, topics: &MqttTopics) -> MqttMessage {
         MqttMessage::new(
             topics.get_camera_availability(self),
-            MqttQoS::AtLeastOnce,
+            self.publishing.qos.availability.clone(),
             true,
-            match self.connected {
-                true => "online",
-                false => "offline",
-            },
+            topics.availability_payload(self.connected || self.offline_pending.is_some()),
         )
     }
     /// Publishes the connection details
     pub fn message_log(&self, topics: &MqttTopics) -> MqttMessage {
         MqttMessage::new(
             topics.get_camera_log(self),
-            MqttQoS::AtLeastOnce,
+            self.publishing.qos.availability.clone(),
             true,
-            self.log.as_ref(),
+            match self.publishing.log_format {
+                LogFormat::Text => MqttPayload::from(self.log.detail.as_str()),
+                LogFormat::Json => MqttPayload::from(serde_json::json!(self.log)),
+            },
         )
     }
-    /// Publishes the state of all triggers
-    pub fn message_trigger_states(&self, topics: &MqttTopics) -> Vec<MqttMessage> {
-        self.triggers
-            .iter()
-            .map(|trigger| trigger.message_state(topics, self))
-            .collect()
-    }
-}
-
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
-struct TriggerDetails {
-    pub trigger: TriggerItem,
-    pub alerting: bool,
-    pub regions: Vec<DetectionRegion>,
-    pub last_alert: DateTime<Utc>,
-}
-impl TriggerDetails {
-    /// Publish the state of the trigger
-    pub fn message_state(&self, topics: &MqttTopics, cam: &CameraDetails) -> MqttMessage {
+    /// Publishes an alert that couldn't be parsed so it can be attached to a bug report
+    pub fn message_parse_failure(
+        &self,
+        topics: &MqttTopics,
+        error: &str,
+        raw: &str,
+    ) -> MqttMessage {
+        let mut end = raw.len().min(self.publishing.parse_failure_max_bytes);
+        while !raw.is_char_boundary(end) {
+            end -= 1;
+        }
         MqttMessage::new(
-            topics.get_trigger_state(cam, self),
-            MqttQoS::AtLeastOnce,
-            true,
+            topics.get_camera_parse_error(self),
+            self.publishing.qos.state.clone(),
+            false,
             serde_json::json!({
-                "alerting": self.alerting,
-                "regions": self.regions,
+                "error": error,
+                "raw": &raw[..end],
+                "truncated": end < raw.len(),
             }),
         )
     }
-    /// Publish discovery info for this trigger
-    pub fn message_discovery(
-        &self,
-        topics: &MqttTopics,
-        cam: &CameraDetails,
-        info: &DeviceInfo,
-    ) -> MqttMessage {
-        let name = format!("{} {}", cam.config.name, self.trigger.identifier);
-        let sw_version = format!(
-            "Camera Firmware {} ({})",
-            info.firmware_version, info.firmware_release_date
-        );
+    /// Publish discovery info for the diagnostic sensor showing the connection log
+    pub fn message_log_discovery(&self, topics: &MqttTopics, info: &DeviceInfo) -> MqttMessage {
         let mut discovery = serde_json::json!({
             "availability": [
-                {
-                    "topic": topics.get_global_availability(),
-                },
-                {
-                    "topic": topics.get_camera_availability(cam),
-                }
+                topics.global_availability_entry(),
             ],
-            "device": {
-                "identifiers": [
-                    format!("{}_hiksink", cam.config.identifier()),
-                    info.serial_number,
-                    info.mac_address,
-                ],
-                "manufacturer": "Hikvision",
-                "name": cam.config.name,
-                "sw_version": sw_version,
-                "model": format!("{} ({})", info.model, info.device_type),
-            },
-            "json_attributes_topic": topics.get_trigger_state(cam, self),
-            "name": name,
-            "payload_off": false,
-            "payload_on": true,
-            "state_topic": topics.get_trigger_state(cam, self),
-            "unique_id": format!("{}_hiksink", topics.get_discovery_identifier_trigger(cam, self)),
-            "value_template": "{{ value_json.alerting }}"
+            "device": self.discovery_device(info),
+            "entity_category": "diagnostic",
+            "icon": "mdi:text-box-outline",
+            "name": format!("{} Connection Log", self.config.name),
+            "state_topic": topics.get_camera_log(self),
+            "unique_id": format!("{}_log_hiksink", topics.get_discovery_device(self)),
         });
-        // Add the fields that are only present if they are custom
-        if let Some(icon) = self.trigger.identifier.event_type.icon() {
-            discovery
-                .as_object_mut()
-                .unwrap()
-                .insert("icon".into(), icon.into());
-        }
-        if let Some(device_class) = self.trigger.identifier.event_type.device_class() {
-            discovery
-                .as_object_mut()
-                .unwrap()
-                .insert("device_class".into(), device_class.into());
+        match self.publishing.log_format {
+            LogFormat::Text => {
+                // Home assistant rejects states longer than 255 characters
+                discovery["value_template"] = "{{ value[:255] }}".into();
+            }
+            LogFormat::Json => {
+                discovery["value_template"] = "{{ value_json.state }}".into();
+                discovery["json_attributes_topic"] = topics.get_camera_log(self).into();
+            }
         }
         MqttMessage::new(
-            topics.get_trigger_discovery(cam, self),
-            MqttQoS::AtLeastOnce,
+            topics.get_camera_log_discovery(self),
+            self.publishing.qos.discovery.clone(),
             true,
             discovery,
         )
     }
-}
-
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
-pub struct MqttTopics {
-    pub base: String,
-    pub home_assistant: String,
-}
-
-impl MqttTopics {
-    pub fn new(base: String, home_assistant: String) -> Self {
-        Self {
-            base,
-            home_assistant,
-        }
+    /// Publish discovery info for the diagnostic sensor showing whether the camera is connected
+    pub fn message_connectivity_discovery(
+        &self,
+        topics: &MqttTopics,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_connectivity_discovery(self),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                ],
+                "device": self.discovery_device(info),
+                "device_class": "connectivity",
+                "entity_category": "diagnostic",
+                "name": format!("{} Connected", self.config.name),
+                "payload_off": topics.payload_not_available,
+                "payload_on": topics.payload_available,
+                "state_topic": topics.get_camera_availability(self),
+                "unique_id": format!("{}_connectivity_hiksink", topics.get_discovery_device(self)),
+            }),
+        )
     }
-
-    pub(self) fn get_global_availability(&self) -> String {
-        format!("{}/availability", self.base)
+    /// Publishes how many times the camera has reconnected
+    pub fn message_reconnect_count(&self, topics: &MqttTopics) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_reconnect_count(self),
+            self.publishing.qos.state.clone(),
+            true,
+            self.reconnect_count.to_string().as_str(),
+        )
     }
-    pub(self) fn get_global_stats(&self) -> String {
-        format!("{}/stats", self.base)
+    /// Publish discovery info for the diagnostic sensor counting reconnects
+    pub fn message_reconnect_count_discovery(
+        &self,
+        topics: &MqttTopics,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_reconnect_count_discovery(self),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                ],
+                "device": self.discovery_device(info),
+                "entity_category": "diagnostic",
+                "icon": "mdi:connection",
+                "name": format!("{} Reconnects", self.config.name),
+                "state_class": "total_increasing",
+                "state_topic": topics.get_camera_reconnect_count(self),
+                "unique_id": format!("{}_reconnect_count_hiksink", topics.get_discovery_device(self)),
+            }),
+        )
     }
-    pub(self) fn get_camera_base(&self, cam: &CameraDetails) -> String {
-        format!("{}/device_{}", self.base, cam.config.identifier())
+    /// Publishes whether the camera has stopped sending alerts
+    pub fn message_events_stale(&self, topics: &MqttTopics) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_events_stale(self),
+            self.publishing.qos.state.clone(),
+            true,
+            if self.events_stale { "ON" } else { "OFF" },
+        )
     }
-    pub(self) fn get_camera_availability(&self, cam: &CameraDetails) -> String {
-        format!("{}/availability", self.get_camera_base(cam))
+    /// Publish discovery info for the sensor showing the camera has stopped sending alerts
+    pub fn message_events_stale_discovery(
+        &self,
+        topics: &MqttTopics,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_events_stale_discovery(self),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                    topics.camera_availability_entry(self)
+                ],
+                "device": self.discovery_device(info),
+                "device_class": "problem",
+                "entity_category": "diagnostic",
+                "name": format!("{} Events Stale", self.config.name),
+                "state_topic": topics.get_camera_events_stale(self),
+                "unique_id": format!("{}_events_stale_hiksink", topics.get_discovery_device(self)),
+            }),
+        )
     }
-    pub(self) fn get_camera_log(&self, cam: &CameraDetails) -> String {
-        format!("{}/log", self.get_camera_base(cam))
+    /// Publishes the camera's device info, for keeping an inventory of cameras. `None` until it has connected.
+    pub fn message_info(&self, topics: &MqttTopics) -> Option<MqttMessage> {
+        let info = self.info.as_ref()?;
+        let ip_address = self
+            .address
+            .as_deref()
+            .and_then(|address| reqwest::Url::parse(address).ok())
+            .and_then(|url| {
+                url.host_str()
+                    .map(|host| host.trim_matches(|c| c == '[' || c == ']').to_string())
+            });
+        Some(MqttMessage::new(
+            topics.get_camera_info(self),
+            self.publishing.qos.state.clone(),
+            true,
+            serde_json::json!({
+                "device_name": info.device_name,
+                "model": info.model,
+                "device_type": info.device_type,
+                "serial_number": info.serial_number,
+                "mac_address": info.mac_address,
+                "firmware_version": info.firmware_version,
+                "firmware_release_date": info.firmware_release_date,
+                "ip_address": ip_address,
+                "muted_until": self.muted_until.map(|t| t.to_rfc3339()),
+            }),
+        ))
     }
-    pub(self) fn get_trigger_base(&self, cam: &CameraDetails, trigger: &TriggerDetails) -> String {
-        let identifier = &trigger.trigger.identifier;
-        if let Some(channel) = identifier.channel.as_ref() {
-            format!(
-                "{}/ch{}/{}",
-                self.get_camera_base(cam),
-                channel,
-                identifier.event_type.to_string()
-            )
-        } else {
-            format!(
-                "{}/{}",
-                self.get_camera_base(cam),
-                identifier.event_type.to_string()
-            )
+    /// Adds a connection error to the history, dropping the oldest past `ERROR_HISTORY_LEN`
+    fn record_error(&mut self, kind: DisconnectKind, error: &str) {
+        if self.errors.len() >= ERROR_HISTORY_LEN {
+            self.errors.pop_front();
         }
+        self.errors.push_back(CameraErrorEntry {
+            time: Utc::now(),
+            kind,
+            error: error.to_string(),
+        });
+        *self.error_counts.entry(kind).or_default() += 1;
     }
-    pub(self) fn get_trigger_state(&self, cam: &CameraDetails, trigger: &TriggerDetails) -> String {
-        self.get_trigger_base(cam, trigger)
+    /// Publishes the latest connection errors as a JSON array, oldest first
+    pub fn message_errors(&self, topics: &MqttTopics) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_errors(self),
+            self.publishing.qos.state.clone(),
+            true,
+            serde_json::json!(self.errors),
+        )
     }
-
-    pub(self) fn get_discovery_identifier_trigger(
-        &self,
-        cam: &CameraDetails,
-        trigger: &TriggerDetails,
-    ) -> String {
-        let channel_identifier = trigger
-            .trigger
-            .identifier
-            .channel
-            .as_ref()
-            .map(|c| format!("_ch{}", c))
-            .unwrap_or_default();
-        let type_identifier = format!("_{}", trigger.trigger.identifier.event_type.to_string());
-        format!(
-            "device_{}{}{}",
-            cam.config.identifier(),
-            channel_identifier,
-            type_identifier
+    /// Publish discovery info for the diagnostic sensor showing the camera's model, with the rest of the
+    /// device info as attributes
+    pub fn message_info_discovery(&self, topics: &MqttTopics, info: &DeviceInfo) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_info_discovery(self),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                ],
+                "device": self.discovery_device(info),
+                "entity_category": "diagnostic",
+                "icon": "mdi:information-outline",
+                "json_attributes_topic": topics.get_camera_info(self),
+                "name": format!("{} Device Info", self.config.name),
+                "state_topic": topics.get_camera_info(self),
+                "unique_id": format!("{}_info_hiksink", topics.get_discovery_device(self)),
+                "value_template": "{{ value_json.model }}",
+            }),
         )
     }
-
-    pub(self) fn get_global_stats_discovery(&self, key: &str) -> String {
-        format!("{}/sensor/hiksink/{}/config", self.home_assistant, key)
+    /// Publishes how far the camera's clock is off, and whether that's too far
+    pub fn message_clock_states(&self, topics: &MqttTopics) -> Vec<MqttMessage> {
+        vec![
+            MqttMessage::new(
+                topics.get_camera_clock_drift(self),
+                self.publishing.qos.state.clone(),
+                true,
+                self.clock_drift.unwrap_or_default().to_string().as_str(),
+            ),
+            MqttMessage::new(
+                topics.get_camera_clock_wrong(self),
+                self.publishing.qos.state.clone(),
+                true,
+                if self.clock_wrong { "ON" } else { "OFF" },
+            ),
+        ]
     }
-
-    pub(self) fn get_trigger_discovery(
+    /// Publish discovery info for the diagnostic sensors of the camera's clock
+    pub fn message_clock_discovery(
         &self,
-        cam: &CameraDetails,
-        trigger: &TriggerDetails,
-    ) -> String {
-        format!(
-            "{}/binary_sensor/hiksink/{}/config",
-            self.home_assistant,
-            self.get_discovery_identifier_trigger(cam, trigger)
+        topics: &MqttTopics,
+        info: &DeviceInfo,
+    ) -> Vec<MqttMessage> {
+        let availability = serde_json::json!([
+            topics.global_availability_entry(),
+            topics.camera_availability_entry(self)
+        ]);
+        vec![
+            MqttMessage::new(
+                topics.get_camera_clock_drift_discovery(self),
+                self.publishing.qos.discovery.clone(),
+                true,
+                serde_json::json!({
+                    "availability": availability,
+                    "device": self.discovery_device(info),
+                    "device_class": "duration",
+                    "entity_category": "diagnostic",
+                    "icon": "mdi:clock-alert-outline",
+                    "name": format!("{} Clock Drift", self.config.name),
+                    "state_class": "measurement",
+                    "state_topic": topics.get_camera_clock_drift(self),
+                    "unique_id": format!("{}_clock_drift_hiksink", topics.get_discovery_device(self)),
+                    "unit_of_measurement": "s",
+                }),
+            ),
+            MqttMessage::new(
+                topics.get_camera_clock_wrong_discovery(self),
+                self.publishing.qos.discovery.clone(),
+                true,
+                serde_json::json!({
+                    "availability": availability,
+                    "device": self.discovery_device(info),
+                    "device_class": "problem",
+                    "entity_category": "diagnostic",
+                    "name": format!("{} Clock Wrong", self.config.name),
+                    "state_topic": topics.get_camera_clock_wrong(self),
+                    "unique_id": format!("{}_clock_wrong_hiksink", topics.get_discovery_device(self)),
+                }),
+            ),
+        ]
+    }
+    /// Publish discovery info for the button that makes the camera reconnect
+    pub fn message_reconnect_discovery(
+        &self,
+        topics: &MqttTopics,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_reconnect_discovery(self),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                ],
+                "command_topic": topics.get_camera_command(self),
+                "device": self.discovery_device(info),
+                "entity_category": "config",
+                "icon": "mdi:restart",
+                "name": format!("{} Reconnect", self.config.name),
+                "payload_press": "reconnect",
+                "unique_id": format!("{}_reconnect_hiksink", topics.get_discovery_device(self)),
+            }),
         )
     }
-}
-impl Default for MqttTopics {
-    fn default() -> Self {
-        Self {
-            base: "hikvision_cameras".into(),
-            home_assistant: "homeassistant".into(),
+    /// Publish discovery info for the button that reboots the camera
+    pub fn message_reboot_discovery(&self, topics: &MqttTopics, info: &DeviceInfo) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_camera_reboot_discovery(self),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                ],
+                "command_topic": topics.get_camera_command(self),
+                "device": self.discovery_device(info),
+                "device_class": "restart",
+                "entity_category": "config",
+                "name": format!("{} Reboot", self.config.name),
+                "payload_press": REBOOT_PAYLOAD,
+                "unique_id": format!("{}_reboot_hiksink", topics.get_discovery_device(self)),
+            }),
+        )
+    }
+    /// Publishes whether each control is switched on
+    pub fn message_control_states(&self, topics: &MqttTopics) -> Vec<MqttMessage> {
+        self.controls
+            .iter()
+            .map(|control| {
+                MqttMessage::new(
+                    topics.get_control_state(self, control),
+                    self.publishing.qos.state.clone(),
+                    true,
+                    if control.enabled {
+                        CONTROL_PAYLOAD_ON
+                    } else {
+                        CONTROL_PAYLOAD_OFF
+                    },
+                )
+            })
+            .collect()
+    }
+    /// Publish discovery info for the switch changing a control
+    pub fn message_control_discovery(
+        &self,
+        topics: &MqttTopics,
+        control: &ControlState,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        let mut discovery = serde_json::json!({
+            "availability": [
+                topics.global_availability_entry(),
+                topics.camera_availability_entry(self)
+            ],
+            "command_topic": topics.get_control_set(self, control),
+            "device": match control.control {
+                ControlType::MotionDetection | ControlType::SupplementLight => {
+                    self.discovery_channel_device(info, Some(&control.channel))
+                }
+                ControlType::AlarmOutput => self.discovery_device(info),
+            },
+            "name": format!(
+                "{} {}",
+                self.config.name,
+                self.control_display_name(control.control, &control.channel)
+            ),
+            "payload_off": CONTROL_PAYLOAD_OFF,
+            "payload_on": CONTROL_PAYLOAD_ON,
+            "state_topic": topics.get_control_state(self, control),
+            "unique_id": format!("{}_hiksink", topics.get_discovery_identifier_control(self, control)),
+        });
+        let discovery_map = discovery.as_object_mut().unwrap();
+        match control.control {
+            ControlType::MotionDetection => {
+                discovery_map.insert("entity_category".into(), "config".into());
+                discovery_map.insert("icon".into(), "mdi:motion-sensor".into());
+            }
+            ControlType::AlarmOutput => {
+                discovery_map.insert("icon".into(), "mdi:alarm-light".into());
+            }
+            ControlType::SupplementLight => {
+                discovery_map.insert("icon".into(), "mdi:lightbulb".into());
+            }
+        }
+        MqttMessage::new(
+            topics.get_control_discovery(self, control),
+            self.publishing.qos.discovery.clone(),
+            true,
+            discovery,
+        )
+    }
+    /// Publish discovery info for the select moving a PTZ camera to its presets
+    pub fn message_preset_discovery(&self, topics: &MqttTopics, info: &DeviceInfo) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_ptz_preset_discovery(self),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                    topics.camera_availability_entry(self)
+                ],
+                "command_topic": topics.get_ptz_preset_set(self),
+                "device": self.discovery_device(info),
+                "icon": "mdi:camera-control",
+                "name": format!("{} PTZ Preset", self.config.name),
+                "options": self.presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+                "unique_id": format!("{}_ptz_preset_hiksink", topics.get_discovery_device(self)),
+            }),
+        )
+    }
+    /// The name of a control shown in home assistant and the log, e.g. `CH1 Motion Detection`
+    pub fn control_display_name(&self, control: ControlType, channel: &str) -> String {
+        match control {
+            ControlType::MotionDetection => {
+                format!("{} Motion Detection", self.channel_display_name(channel))
+            }
+            ControlType::SupplementLight => {
+                format!("{} Supplement Light", self.channel_display_name(channel))
+            }
+            ControlType::AlarmOutput => format!("Alarm Output {}", channel),
+        }
+    }
+    /// The configured name of a channel, or e.g. `CH1`
+    pub fn channel_display_name(&self, channel: &str) -> String {
+        match self.config.channels.get(channel) {
+            Some(name) => name.clone(),
+            None => format!("CH{}", channel),
+        }
+    }
+    /// The channels with a region entrance or exiting trigger, which get an occupancy sensor with
+    /// `derive_occupancy`
+    pub fn occupancy_channels(&self) -> Vec<String> {
+        if !self.config.derive_occupancy {
+            return Vec::new();
+        }
+        let channels: BTreeSet<String> = self
+            .triggers
+            .iter()
+            .filter_map(|t| occupancy_channel(self, &t.trigger.identifier))
+            .collect();
+        channels.into_iter().collect()
+    }
+    /// Counts an entrance or exit that started, returning the new count to publish
+    pub fn count_occupancy(
+        &mut self,
+        topics: &MqttTopics,
+        identifier: &EventIdentifier,
+    ) -> Option<MqttMessage> {
+        let channel = occupancy_channel(self, identifier)?;
+        let count = self.occupancy.entry(channel.clone()).or_insert(0);
+        *count = match identifier.event_type {
+            EventType::RegionEntrance => count.saturating_add(1),
+            // Exits of someone who entered before counting started are ignored
+            _ => count.saturating_sub(1),
+        };
+        Some(self.message_occupancy_state(topics, &channel))
+    }
+    /// Publishes how many are in each channel's regions
+    pub fn message_occupancy_states(&self, topics: &MqttTopics) -> Vec<MqttMessage> {
+        self.occupancy_channels()
+            .iter()
+            .map(|channel| self.message_occupancy_state(topics, channel))
+            .collect()
+    }
+    /// Publishes how many are in a channel's regions
+    pub fn message_occupancy_state(&self, topics: &MqttTopics, channel: &str) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_occupancy_state(self, channel),
+            self.publishing.qos.state.clone(),
+            true,
+            self.occupancy
+                .get(channel)
+                .copied()
+                .unwrap_or_default()
+                .to_string(),
+        )
+    }
+    /// Publish discovery info for the sensor counting how many are in a channel's regions
+    pub fn message_occupancy_discovery(
+        &self,
+        topics: &MqttTopics,
+        channel: &str,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_occupancy_discovery(self, channel),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                // Counted by the bridge, so still known while the camera is offline
+                "availability": [
+                    topics.global_availability_entry(),
+                ],
+                "device": self.discovery_channel_device(info, Some(channel)),
+                "icon": "mdi:account-group",
+                "name": format!(
+                    "{} {} Occupancy",
+                    self.config.name,
+                    self.channel_display_name(channel)
+                ),
+                "state_class": "measurement",
+                "state_topic": topics.get_occupancy_state(self, channel),
+                "unique_id": format!("{}_hiksink", topics.get_discovery_identifier_occupancy(self, channel)),
+            }),
+        )
+    }
+    /// Publish discovery info for the button that resets a channel's occupancy
+    pub fn message_occupancy_reset_discovery(
+        &self,
+        topics: &MqttTopics,
+        channel: &str,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_occupancy_reset_discovery(self, channel),
+            self.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                ],
+                "command_topic": topics.get_occupancy_reset(self, channel),
+                "device": self.discovery_channel_device(info, Some(channel)),
+                "entity_category": "config",
+                "icon": "mdi:account-cancel",
+                "name": format!(
+                    "{} {} Occupancy Reset",
+                    self.config.name,
+                    self.channel_display_name(channel)
+                ),
+                "payload_press": "reset",
+                "unique_id": format!("{}_reset_hiksink", topics.get_discovery_identifier_occupancy(self, channel)),
+            }),
+        )
+    }
+    /// The device details shared by all of this camera's discovery messages
+    pub fn discovery_device(&self, info: &DeviceInfo) -> serde_json::Value {
+        let sw_version = format!(
+            "Camera Firmware {} ({})",
+            info.firmware_version, info.firmware_release_date
+        );
+        let mut device = serde_json::json!({
+            "identifiers": [
+                format!("{}_hiksink", self.config.identifier()),
+                info.serial_number,
+                info.mac_address,
+            ],
+            "manufacturer": "Hikvision",
+            "name": self.config.name,
+            "sw_version": sw_version,
+            "model": format!("{} ({})", info.model, info.device_type),
+            "configuration_url": self.config.base_url(),
+            // Cameras are connected to home assistant through the bridge device
+            "via_device": "hiksink_bridge",
+        });
+        if let Some(area) = &self.config.area {
+            device
+                .as_object_mut()
+                .unwrap()
+                .insert("suggested_area".into(), area.as_str().into());
+        }
+        device
+    }
+    /// Maps the channel of an alert onto the camera's triggers. Some NVR firmwares number IP channels
+    /// differently in alerts, offset by `channel_offset` or, when detected, [`NVR_CHANNEL_OFFSET`].
+    fn normalize_alert_identifier(&self, identifier: &EventIdentifier) -> Option<EventIdentifier> {
+        let known = |identifier: &EventIdentifier| {
+            self.triggers
+                .iter()
+                .any(|t| &t.trigger.identifier == identifier)
+        };
+        let offset = match self.config.channel_offset {
+            Some(offset) => offset,
+            None if self.info.as_ref().is_some_and(|i| i.is_recorder()) && !known(identifier) => {
+                NVR_CHANNEL_OFFSET
+            }
+            None => return None,
+        };
+        let channel = identifier.channel.as_ref()?.parse::<u32>().ok()?;
+        if channel <= offset {
+            return None;
+        }
+        let normalized = EventIdentifier::new(
+            Some((channel - offset).to_string()),
+            identifier.event_type.clone(),
+        );
+        (self.config.channel_offset.is_some() || known(&normalized)).then_some(normalized)
+    }
+    /// The device of a channel's entities. With `device_per_channel` each channel is its own device,
+    /// connected through the camera's. Otherwise, and for entities without a channel, it's the camera's.
+    pub fn discovery_channel_device(
+        &self,
+        info: &DeviceInfo,
+        channel: Option<&str>,
+    ) -> serde_json::Value {
+        let channel = match channel {
+            Some(channel) if self.config.device_per_channel => channel,
+            _ => return self.discovery_device(info),
+        };
+        let mut device = serde_json::json!({
+            "identifiers": [
+                format!("{}_ch{}", info.serial_number, channel),
+            ],
+            "manufacturer": "Hikvision",
+            "name": self.channel_device_name(channel),
+            "via_device": info.serial_number,
+        });
+        if let Some(area) = &self.config.area {
+            device
+                .as_object_mut()
+                .unwrap()
+                .insert("suggested_area".into(), area.as_str().into());
+        }
+        device
+    }
+    /// The name of a channel's own device: the configured name of the channel, the name configured on
+    /// the NVR, or e.g. `Front NVR CH5`
+    fn channel_device_name(&self, channel: &str) -> String {
+        if let Some(name) = self.config.channels.get(channel) {
+            return name.clone();
+        }
+        self.triggers
+            .iter()
+            .filter(|t| t.trigger.identifier.channel.as_deref() == Some(channel))
+            .find_map(|t| t.trigger.channel_name.clone())
+            .unwrap_or_else(|| format!("{} CH{}", self.config.name, channel))
+    }
+    /// Publishes the state of all triggers
+    pub fn message_trigger_states(&self, topics: &MqttTopics) -> Vec<MqttMessage> {
+        let mut messages = Vec::with_capacity(self.triggers.len());
+        for trigger in &self.triggers {
+            messages.append(&mut trigger.message_states(topics, self));
+            if self.publishing.regions_topic {
+                messages.push(trigger.message_regions(topics, self));
+            }
         }
+        messages
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
-pub struct MqttMessage {
-    pub topic: String,
-    pub qos: MqttQoS,
-    pub retain: bool,
-    pub payload: MqttPayload,
+/// When a mute ends, from a payload of seconds or `until=<RFC 3339 time>`. `None` unmutes, as do `0`, `off` and a
+/// time in the past.
+fn parse_mute(payload: &str, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+    let until = if payload.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    } else if let Some(time) = payload.strip_prefix("until=") {
+        DateTime::parse_from_rfc3339(time)
+            .map_err(|e| format!("invalid time {:?}: {}", time, e))?
+            .with_timezone(&Utc)
+    } else {
+        let secs: u32 = payload
+            .parse()
+            .map_err(|_| format!("expected seconds, until=<time> or off, got {:?}", payload))?;
+        now + Duration::seconds(secs.into())
+    };
+    Ok((until > now).then_some(until))
 }
 
-impl MqttMessage {
-    pub fn new(topic: String, qos: MqttQoS, retain: bool, payload: impl Into<MqttPayload>) -> Self {
-        Self {
-            topic,
-            qos,
-            retain,
-            payload: payload.into(),
-        }
+/// Records the retained topics of a camera, returning deletions for the ones it no longer publishes
+fn update_retained_topics(
+    retained_topics: &mut BTreeMap<String, BTreeSet<String>>,
+    changed: &mut bool,
+    cleanup_stale_topics: bool,
+    cam: &CameraDetails,
+    topics: &MqttTopics,
+) -> Vec<MqttMessage> {
+    let current = cam.retained_topics(topics);
+    let previous = retained_topics.insert(cam.config.identifier().to_string(), current.clone());
+    if previous.as_ref() != Some(&current) {
+        *changed = true;
     }
-}
-impl From<MqttMessage> for rumqttc::LastWill {
-    fn from(m: MqttMessage) -> Self {
-        rumqttc::LastWill::new(m.topic, m.payload.render(), m.qos.into(), m.retain)
+    if !cleanup_stale_topics {
+        return Vec::new();
     }
+    previous
+        .unwrap_or_default()
+        .difference(&current)
+        .cloned()
+        .map(MqttMessage::delete)
+        .collect()
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
-#[allow(clippy::enum_variant_names)]
-pub enum MqttQoS {
-    AtMostOnce = 0,
-    AtLeastOnce = 1,
-    ExactlyOnce = 2,
-}
-impl From<MqttQoS> for rumqttc::QoS {
-    fn from(q: MqttQoS) -> Self {
-        use rumqttc::QoS;
-        match q {
-            MqttQoS::AtMostOnce => QoS::AtMostOnce,
-            MqttQoS::AtLeastOnce => QoS::AtLeastOnce,
-            MqttQoS::ExactlyOnce => QoS::ExactlyOnce,
+/// The channel whose occupancy a trigger counts towards, with `derive_occupancy`
+fn occupancy_channel(cam: &CameraDetails, identifier: &EventIdentifier) -> Option<String> {
+    match identifier.event_type {
+        EventType::RegionEntrance | EventType::RegionExiting if cam.config.derive_occupancy => {
+            identifier.channel.clone()
         }
+        _ => None,
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
-pub enum MqttPayload {
-    Constant(String),
-    Json(serde_json::Value),
+/// Whether a trigger gets entities and alerts, or is filtered out by the configuration
+fn is_published(system: &ConfigSystem, cam: &ConfigCamera, identifier: &EventIdentifier) -> bool {
+    let ignored_video_loss = identifier.event_type.is_video_loss()
+        && cam.ignore_videoloss.unwrap_or(system.ignore_videoloss);
+    !ignored_video_loss && cam.includes_trigger(identifier)
 }
 
-impl MqttPayload {
-    pub fn render(self) -> Vec<u8> {
-        match self {
-            MqttPayload::Constant(c) => c.into(),
-            MqttPayload::Json(j) => j.to_string().into(),
-        }
-    }
+/// Whether the camera will send alerts for a trigger, as far as the `only_notifying_triggers` option is concerned
+fn sends_alerts(cam: &ConfigCamera, trigger: &TriggerItem) -> bool {
+    !cam.only_notifying_triggers || trigger.notifies_center
 }
 
-impl From<&str> for MqttPayload {
-    fn from(v: &str) -> Self {
-        Self::Constant(v.into())
-    }
+/// How long an alert can go without being posted before it is cleared, if at all.
+/// Event type overrides take precedence over the camera, which takes precedence over the system default.
+fn auto_clear_after(
+    system: &ConfigSystem,
+    cam: &ConfigCamera,
+    event_type: &EventType,
+) -> Option<Duration> {
+    let event_name = event_type.to_string();
+    system
+        .alert_auto_clear_event_secs
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(&event_name))
+        .map(|(_, secs)| *secs)
+        .or(cam.alert_auto_clear_secs)
+        .or(system.alert_auto_clear_secs)
+        .map(|secs| Duration::seconds(secs as i64))
 }
 
-impl From<String> for MqttPayload {
-    fn from(v: String) -> Self {
-        Self::Constant(v)
-    }
+/// Debounce settings of an event type on a camera
+struct Debounce {
+    off_delay: Option<Duration>,
+    publish_interval: Option<Duration>,
 }
 
-impl From<serde_json::Value> for MqttPayload {
-    fn from(v: serde_json::Value) -> Self {
-        Self::Json(v)
+/// The debounce settings of a camera's event type. Each option set on the camera takes precedence over the system's.
+fn debounce_for(system: &ConfigSystem, cam: &ConfigCamera, event_type: &EventType) -> Debounce {
+    let event_name = event_type.to_string();
+    let find = |entries: &HashMap<String, ConfigDebounce>| {
+        entries
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&event_name))
+            .map(|(_, debounce)| debounce.clone())
+    };
+    let (cam_entry, system_entry) = (find(&cam.debounce), find(&system.debounce));
+    let pick = |option: fn(&ConfigDebounce) -> Option<u64>| {
+        cam_entry
+            .as_ref()
+            .and_then(option)
+            .or_else(|| system_entry.as_ref().and_then(option))
+            .map(|secs| Duration::seconds(secs as i64))
+    };
+    Debounce {
+        off_delay: pick(|d| d.min_off_delay_secs),
+        publish_interval: pick(|d| d.min_publish_interval_secs),
     }
 }
 
-#[cfg(test)]
-mod test {
-    use crate::{
-        config::ConfigCamera,
-        hikapi::{
-            AlertItem, CameraEvent, CameraEventType, DetectionRegion, DeviceInfo, EventIdentifier,
-            EventType, RegionCoordinates, TriggerItem,
-        },
+/// Resolves the camera an alert is about. Alerts on a channel listed in the device's `duplicates_of`,
+/// or carrying the serial of another configured camera (e.g. from an NVR), resolve to that camera's
+/// first channel.
+fn dedup_key(cameras: &[CameraDetails], source: &CameraDetails, alert: &AlertItem) -> DedupKey {
+    let mapped = alert
+        .identifier
+        .channel
+        .as_ref()
+        .and_then(|channel| source.config.duplicates_of.get(channel));
+    let resolved = mapped.map(String::as_str).or_else(|| {
+        let serial = alert.device_serial.as_deref()?;
+        cameras
+            .iter()
+            .find(|c| c.info.as_ref().map(|i| i.serial_number.as_str()) == Some(serial))
+            .map(|c| c.config.identifier())
+    });
+    let (camera, channel) = match resolved {
+        Some(camera) if camera != source.config.identifier() => (
+            camera.to_string(),
+            alert.identifier.channel.as_ref().map(|_| "1".to_string()),
+        ),
+        _ => (
+            source.config.identifier().to_string(),
+            alert.identifier.channel.clone(),
+        ),
     };
+    (camera, channel, alert.identifier.event_type.clone())
+}
 
-    use super::{Manager, MqttPayload, MqttTopics};
+/// How long a camera can go without alerts before its events are stale
+fn stale_after(system: &ConfigSystem, cam: &ConfigCamera) -> Option<Duration> {
+    cam.stale_after_secs
+        .or(system.stale_after_secs)
+        .map(|secs| Duration::seconds(secs as i64))
+}
 
-    fn sample_cameras() -> Vec<ConfigCamera> {
-        vec![ConfigCamera {
-            generated_id: "cam1".into(),
-            name: "Camera 1".into(),
-            address: "192.168.20.2".into(),
-            port: None,
-            username: "admin".into(),
-            password: "password".into(),
-        }]
+/// Targets reported by smart (AcuSense) cameras that can be split into their own entities
+const SMART_TARGETS: &[&str] = &["human", "vehicle"];
+
+/// Friendly name of a smart target for output to home assistant
+fn target_friendly_name(target: &str) -> String {
+    let mut chars = target.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
     }
+}
 
-    fn sample_device_info() -> DeviceInfo {
-        DeviceInfo {
-            device_name: "Cam 1".into(),
-            device_id: "7ccc4404-e05d-4376-8ebf-81127da67c11".into(),
-            model: "DS-2DE4A425IW-DE".into(),
-            serial_number: "DS-2DE4A425IW-DE20180101AAWRC52000000W".into(),
-            mac_address: "ff:ff:ff:ff:ff:ff".into(),
-            firmware_version: "V5.5.71".into(),
-            firmware_release_date: "build 180725".into(),
-            device_type: "IPDome".into(),
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct TriggerDetails {
+    pub trigger: TriggerItem,
+    pub alerting: bool,
+    pub regions: Vec<DetectionRegion>,
+    /// What smart cameras detected in the last alert. e.g. `human` or `vehicle`
+    pub target: Option<String>,
+    /// When the trigger last became active, according to the camera's clock
+    pub last_alert: Option<DateTime<Utc>>,
+    /// The time the trigger last became active in the camera's timezone, as sent by the camera
+    pub camera_time: Option<String>,
+    /// Number of times the trigger has become active since HikSink started
+    pub alert_count: u64,
+    /// When the last active alert was received, used to clear alerts that never become inactive
+    #[serde(skip)]
+    pub last_active_post: Option<DateTime<Utc>>,
+    /// The last license plate read, for ANPR triggers
+    #[serde(default)]
+    pub anpr: Option<AnprDetails>,
+    /// The last counts sent, for people counting triggers
+    #[serde(default)]
+    pub people_counting: Option<PeopleCount>,
+    /// Created from an alert rather than the camera's trigger list, so it must survive re-scans
+    #[serde(default)]
+    pub auto_registered: bool,
+    /// The `activePostCount` of the last alert, which the camera increases each time it re-posts an ongoing alert
+    #[serde(default)]
+    pub post_count: u64,
+    /// Posts of ongoing alerts that never arrived, going by the gaps in their post counts
+    #[serde(default)]
+    pub missed_posts: u64,
+    /// The post counters changed since the state was last published
+    #[serde(skip)]
+    pub counters_pending: bool,
+    /// When the state was last published because of an alert
+    #[serde(skip)]
+    pub state_published: Option<DateTime<Utc>>,
+    /// An inactive alert arrived, but the trigger is held on for its debounce off delay
+    #[serde(skip)]
+    pub off_pending: bool,
+    /// The regions changed since they were last published, held back by the debounce publish interval
+    #[serde(skip)]
+    pub regions_pending: bool,
+    /// When the regions were last published
+    #[serde(skip)]
+    pub regions_published: Option<DateTime<Utc>>,
+    /// The channel name sent in the trigger's alerts, kept from the first alert that had one
+    #[serde(default)]
+    pub channel_name: Option<String>,
+    /// The trigger's topics, rendered with those of its camera
+    #[serde(skip)]
+    pub topics: TriggerTopics,
+}
+impl TriggerDetails {
+    pub fn new(trigger: TriggerItem) -> Self {
+        TriggerDetails {
+            trigger,
+            alerting: false,
+            regions: Vec::new(),
+            target: None,
+            last_alert: None,
+            camera_time: None,
+            last_active_post: None,
+            alert_count: 0,
+            anpr: None,
+            people_counting: None,
+            auto_registered: false,
+            post_count: 0,
+            missed_posts: 0,
+            counters_pending: false,
+            state_published: None,
+            off_pending: false,
+            regions_pending: false,
+            regions_published: None,
+            channel_name: None,
+            topics: TriggerTopics::default(),
         }
     }
-
-    #[test]
-    fn test_initial_state() {
-        let cams = sample_cameras();
-        let manager = Manager::new(cams, MqttTopics::default());
-        insta::assert_yaml_snapshot!(manager);
+    /// The name shown in home assistant and logs. e.g. `Front Porch CH1 Motion`
+    pub fn display_name(&self, cam: &CameraDetails) -> String {
+        if let Some(name) = cam.config.trigger_name(&self.trigger.identifier) {
+            return name.to_string();
+        }
+        let identifier = &self.trigger.identifier;
+        let channel_name = if identifier.event_type == EventType::Io {
+            identifier
+                .channel
+                .as_ref()
+                .and_then(|c| cam.config.io_ports.get(c))
+                .or(self.trigger.port_name.as_ref())
+        } else {
+            identifier
+                .channel
+                .as_ref()
+                .and_then(|c| cam.config.channels.get(c))
+                .or(self.trigger.channel_name.as_ref())
+                .or(self
+                    .channel_name
+                    .as_ref()
+                    .filter(|_| cam.publishing.names_from_alerts))
+        };
+        format!(
+            "{} {}",
+            cam.config.name,
+            self.trigger.identifier.display_with_names(
+                channel_name.map(String::as_str),
+                &cam.publishing
+                    .event_name(&self.trigger.identifier.event_type)
+            )
+        )
     }
-
-    #[test]
-    fn test_lwt() {
-        let cams = sample_cameras();
-        let manager = Manager::new(cams, MqttTopics::default());
-        insta::assert_yaml_snapshot!(manager.mqtt_lwt());
+    /// The device the trigger's entities belong to, which is the channel's own with `device_per_channel`
+    fn discovery_device(&self, cam: &CameraDetails, info: &DeviceInfo) -> serde_json::Value {
+        cam.discovery_channel_device(info, self.trigger.identifier.channel.as_deref())
     }
-
-    #[test]
-    fn test_mqtt_connection_initial() {
-        let cams = sample_cameras();
-        let manager = Manager::new(cams, MqttTopics::default());
-        insta::assert_yaml_snapshot!(manager.mqtt_connection_established(), {
-            "[].**.sw_version" => "[sw_version]"
+    /// Publishes all discovery topics of the trigger for home assistant
+    pub fn message_complete_discovery(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        info: &DeviceInfo,
+    ) -> Vec<MqttMessage> {
+        let mut messages = self.message_discovery(topics, cam, info);
+        messages.append(&mut self.message_target_discovery(topics, cam, info));
+        if cam.config.snapshot_on_alert {
+            messages.push(self.message_snapshot_discovery(topics, cam, info));
+        }
+        messages
+    }
+    /// Publish all state topics of the trigger
+    pub fn message_states(&self, topics: &MqttTopics, cam: &CameraDetails) -> Vec<MqttMessage> {
+        let mut messages = vec![self.message_state(topics, cam)];
+        if cam.publishing.plain_state_topics {
+            messages.push(self.message_plain_state(topics, cam));
+        }
+        messages.append(&mut self.message_target_states(topics, cam));
+        messages
+    }
+    /// Publish the state of the trigger as a plain on/off payload
+    pub fn message_plain_state(&self, topics: &MqttTopics, cam: &CameraDetails) -> MqttMessage {
+        let payload = if self.alerting {
+            &cam.publishing.plain_state_payload_on
+        } else {
+            &cam.publishing.plain_state_payload_off
+        };
+        MqttMessage::new(
+            topics.get_trigger_plain_state(self),
+            cam.publishing.qos.state.clone(),
+            cam.publishing
+                .retain_state(&self.trigger.identifier.event_type),
+            payload.as_str(),
+        )
+        .with_expiry(cam.publishing.message_expiry_secs)
+    }
+    /// Publish the state of the trigger
+    pub fn message_state(&self, topics: &MqttTopics, cam: &CameraDetails) -> MqttMessage {
+        let mut state = serde_json::json!({
+            "alerting": self.alerting,
+            "regions": self.regions,
+            "target": self.target,
+            "last_alert": self.last_alert.map(|t| t.to_rfc3339()),
+            "camera_time": self.camera_time,
+            "alert_count": self.alert_count,
+            "post_count": self.post_count,
+            "missed_posts": self.missed_posts,
+        });
+        if cam.publishing.regions_topic {
+            state.as_object_mut().unwrap().remove("regions");
+        }
+        if let Some(channel_name) = &self.channel_name {
+            state
+                .as_object_mut()
+                .unwrap()
+                .insert("channel_name".into(), serde_json::json!(channel_name));
+        }
+        if self.trigger.identifier.event_type.reports_plates() {
+            let anpr = self.anpr.as_ref();
+            let state = state.as_object_mut().unwrap();
+            state.insert("plate".into(), serde_json::json!(anpr.map(|a| &a.plate)));
+            state.insert(
+                "direction".into(),
+                serde_json::json!(anpr.and_then(|a| a.direction.as_ref())),
+            );
+            state.insert(
+                "lane".into(),
+                serde_json::json!(anpr.and_then(|a| a.lane.as_ref())),
+            );
+        }
+        if self.trigger.identifier.event_type.is_counter() {
+            let counts = self.people_counting.as_ref();
+            let state = state.as_object_mut().unwrap();
+            state.insert(
+                "enter".into(),
+                serde_json::json!(counts.and_then(|c| c.enter)),
+            );
+            state.insert(
+                "exit".into(),
+                serde_json::json!(counts.and_then(|c| c.exit)),
+            );
+            state.insert(
+                "pass".into(),
+                serde_json::json!(counts.and_then(|c| c.pass)),
+            );
+        }
+        MqttMessage::new(
+            topics.get_trigger_state(self),
+            cam.publishing.qos.state.clone(),
+            cam.publishing
+                .retain_state(&self.trigger.identifier.event_type),
+            state,
+        )
+        .with_expiry(cam.publishing.message_expiry_secs)
+    }
+    /// Publish the detection regions of the last alert, when they have their own topic
+    pub fn message_regions(&self, topics: &MqttTopics, cam: &CameraDetails) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_trigger_regions(self),
+            cam.publishing.qos.state.clone(),
+            true,
+            serde_json::json!({
+                "regions": self.regions,
+            }),
+        )
+    }
+    /// Publish the picture taken when this trigger alerted
+    pub fn message_snapshot(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        image: Vec<u8>,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_trigger_snapshot(self),
+            cam.publishing.qos.state.clone(),
+            true,
+            image,
+        )
+    }
+    /// Smart detection targets that get their own entity for this trigger
+    pub fn split_targets(&self, cam: &CameraDetails) -> &'static [&'static str] {
+        if cam.config.split_smart_targets
+            && self.trigger.identifier.event_type.supports_smart_targets()
+        {
+            SMART_TARGETS
+        } else {
+            &[]
+        }
+    }
+    /// Publish the state of each smart target entity of this trigger
+    pub fn message_target_states(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+    ) -> Vec<MqttMessage> {
+        if self.ha_entity(cam) != HaEntity::BinarySensor {
+            return Vec::new();
+        }
+        self.split_targets(cam)
+            .iter()
+            .map(|target| {
+                let regions: Vec<_> = self
+                    .regions
+                    .iter()
+                    .filter(|r| r.target.as_deref() == Some(target))
+                    .collect();
+                MqttMessage::new(
+                    topics.get_trigger_target_state(self, target),
+                    cam.publishing.qos.state.clone(),
+                    cam.publishing
+                        .retain_state(&self.trigger.identifier.event_type),
+                    serde_json::json!({
+                        "alerting": self.alerting && self.target.as_deref() == Some(target),
+                        "regions": regions,
+                    }),
+                )
+                .with_expiry(cam.publishing.message_expiry_secs)
+            })
+            .collect()
+    }
+    /// Publish discovery info for this trigger
+    pub fn message_discovery(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        info: &DeviceInfo,
+    ) -> Vec<MqttMessage> {
+        if self.trigger.identifier.event_type.is_counter() {
+            return vec![
+                self.count_sensor_discovery(topics, cam, info, "enter", "Enter Count"),
+                self.count_sensor_discovery(topics, cam, info, "exit", "Exit Count"),
+            ];
+        }
+        match self.ha_entity(cam) {
+            HaEntity::BinarySensor => vec![self.binary_sensor_discovery(topics, cam, info, None)],
+            HaEntity::Event => vec![self.event_discovery(topics, cam, info)],
+            HaEntity::DeviceTrigger => vec![self.device_trigger_discovery(topics, cam, info)],
+        }
+    }
+    /// The kind of entity the trigger gets. Counting triggers always get sensors.
+    pub fn ha_entity(&self, cam: &CameraDetails) -> HaEntity {
+        cam.publishing
+            .event_ha_entity(&self.trigger.identifier.event_type)
+    }
+    /// Discovery for an event entity, fired by [`TriggerDetails::message_event`]
+    fn event_discovery(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        let mut discovery = serde_json::json!({
+            "availability": [
+                topics.global_availability_entry(),
+                topics.camera_availability_entry(cam)
+            ],
+            "device": self.discovery_device(cam, info),
+            "event_types": [TRIGGER_EVENT_TYPE],
+            "name": self.display_name(cam),
+            "state_topic": topics.get_trigger_event(self),
+            "unique_id": format!(
+                "{}_hiksink",
+                topics.get_discovery_identifier_trigger(self)
+            ),
+        });
+        if let Some(icon) = cam
+            .publishing
+            .event_icon(&self.trigger.identifier.event_type)
+        {
+            discovery
+                .as_object_mut()
+                .unwrap()
+                .insert("icon".into(), icon.into());
+        }
+        // Event entities only have a few device classes of their own
+        if let Some(device_class) = cam
+            .publishing
+            .event_device_class(&self.trigger.identifier.event_type)
+            .filter(|c| EVENT_DEVICE_CLASSES.contains(c))
+        {
+            discovery
+                .as_object_mut()
+                .unwrap()
+                .insert("device_class".into(), device_class.into());
+        }
+        MqttMessage::new(
+            topics.get_trigger_event_discovery(self),
+            cam.publishing.qos.discovery.clone(),
+            true,
+            discovery,
+        )
+    }
+    /// Discovery for a device trigger, fired by [`TriggerDetails::message_event`]
+    fn device_trigger_discovery(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_trigger_device_trigger_discovery(self),
+            cam.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "automation_type": "trigger",
+                "device": self.discovery_device(cam, info),
+                "subtype": self.display_name(cam),
+                "topic": topics.get_trigger_event(self),
+                "type": TRIGGER_EVENT_TYPE,
+            }),
+        )
+    }
+    /// Publish an occurrence of the trigger, for event entities and device triggers. Never retained, as
+    /// it would fire again whenever home assistant reconnects.
+    pub fn message_event(&self, topics: &MqttTopics, cam: &CameraDetails) -> MqttMessage {
+        let mut event = serde_json::json!({
+            "event_type": TRIGGER_EVENT_TYPE,
+            "regions": self.regions,
+            "target": self.target,
+            "timestamp": self.last_alert.map(|t| t.to_rfc3339()),
+            "camera_time": self.camera_time,
+        });
+        if self.trigger.identifier.event_type.reports_plates() {
+            let anpr = self.anpr.as_ref();
+            let event = event.as_object_mut().unwrap();
+            event.insert("plate".into(), serde_json::json!(anpr.map(|a| &a.plate)));
+            event.insert(
+                "direction".into(),
+                serde_json::json!(anpr.and_then(|a| a.direction.as_ref())),
+            );
+            event.insert(
+                "lane".into(),
+                serde_json::json!(anpr.and_then(|a| a.lane.as_ref())),
+            );
+        }
+        MqttMessage::new(
+            topics.get_trigger_event(self),
+            cam.publishing.qos.state.clone(),
+            false,
+            event,
+        )
+    }
+    /// Discovery for a sensor showing one of the totals of a counting trigger
+    fn count_sensor_discovery(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        info: &DeviceInfo,
+        key: &str,
+        name: &str,
+    ) -> MqttMessage {
+        MqttMessage::new(
+            topics.get_trigger_count_discovery(self, key),
+            cam.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                    topics.camera_availability_entry(cam)
+                ],
+                "device": self.discovery_device(cam, info),
+                "icon": cam.publishing.event_icon(&self.trigger.identifier.event_type),
+                "name": format!("{} {}", self.display_name(cam), name),
+                "state_class": "total_increasing",
+                "state_topic": topics.get_trigger_state(self),
+                "unique_id": format!(
+                    "{}_{}_hiksink",
+                    topics.get_discovery_identifier_trigger(self),
+                    key
+                ),
+                "value_template": format!("{{{{ value_json.{} }}}}", key),
+            }),
+        )
+    }
+    /// Publish discovery info for each smart target entity of this trigger
+    pub fn message_target_discovery(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        info: &DeviceInfo,
+    ) -> Vec<MqttMessage> {
+        self.split_targets(cam)
+            .iter()
+            .map(|target| self.binary_sensor_discovery(topics, cam, info, Some(target)))
+            .collect()
+    }
+    /// Discovery for the binary sensor of either the whole trigger, or only one of its smart targets
+    fn binary_sensor_discovery(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        info: &DeviceInfo,
+        target: Option<&str>,
+    ) -> MqttMessage {
+        let (name, state_topic, unique_id, discovery_topic) = match target {
+            Some(target) => (
+                format!(
+                    "{} ({})",
+                    self.display_name(cam),
+                    target_friendly_name(target)
+                ),
+                topics.get_trigger_target_state(self, target),
+                format!(
+                    "{}_{}_hiksink",
+                    topics.get_discovery_identifier_trigger(self),
+                    target
+                ),
+                topics.get_trigger_target_discovery(self, target),
+            ),
+            None => (
+                self.display_name(cam),
+                topics.get_trigger_state(self),
+                format!("{}_hiksink", topics.get_discovery_identifier_trigger(self)),
+                topics.get_trigger_discovery(self),
+            ),
+        };
+        let mut discovery = serde_json::json!({
+            "availability": [
+                topics.global_availability_entry(),
+                topics.camera_availability_entry(cam)
+            ],
+            "device": self.discovery_device(cam, info),
+            "json_attributes_topic": state_topic,
+            "name": name,
+            "payload_off": false,
+            "payload_on": true,
+            "state_topic": state_topic,
+            "unique_id": unique_id,
+            "value_template": "{{ value_json.alerting }}"
+        });
+        // Point the attributes at the regions if they have their own topic
+        if target.is_none() && cam.publishing.regions_topic {
+            discovery.as_object_mut().unwrap().insert(
+                "json_attributes_topic".into(),
+                topics.get_trigger_regions(self).into(),
+            );
+        }
+        // Use the plain on/off topic for the state if available, keeping the JSON topic for attributes
+        if target.is_none() && cam.publishing.plain_state_topics {
+            let discovery = discovery.as_object_mut().unwrap();
+            discovery.remove("value_template");
+            discovery.insert(
+                "state_topic".into(),
+                topics.get_trigger_plain_state(self).into(),
+            );
+            discovery.insert(
+                "payload_on".into(),
+                cam.publishing.plain_state_payload_on.as_str().into(),
+            );
+            discovery.insert(
+                "payload_off".into(),
+                cam.publishing.plain_state_payload_off.as_str().into(),
+            );
+        }
+        // Add the fields that are only present if they are custom
+        if let Some(icon) = cam
+            .publishing
+            .event_icon(&self.trigger.identifier.event_type)
+        {
+            discovery
+                .as_object_mut()
+                .unwrap()
+                .insert("icon".into(), icon.into());
+        }
+        if let Some(device_class) = cam
+            .publishing
+            .event_device_class(&self.trigger.identifier.event_type)
+        {
+            discovery
+                .as_object_mut()
+                .unwrap()
+                .insert("device_class".into(), device_class.into());
+        }
+        MqttMessage::new(
+            discovery_topic,
+            cam.publishing.qos.discovery.clone(),
+            true,
+            discovery,
+        )
+    }
+    /// Publish discovery info for the camera entity showing this trigger's snapshots
+    pub fn message_snapshot_discovery(
+        &self,
+        topics: &MqttTopics,
+        cam: &CameraDetails,
+        info: &DeviceInfo,
+    ) -> MqttMessage {
+        let name = format!("{} Snapshot", self.display_name(cam));
+        MqttMessage::new(
+            topics.get_trigger_snapshot_discovery(self),
+            cam.publishing.qos.discovery.clone(),
+            true,
+            serde_json::json!({
+                "availability": [
+                    topics.global_availability_entry(),
+                    topics.camera_availability_entry(cam)
+                ],
+                "device": self.discovery_device(cam, info),
+                "name": name,
+                "topic": topics.get_trigger_snapshot(self),
+                "unique_id": format!("{}_snapshot_hiksink", topics.get_discovery_identifier_trigger(self)),
+            }),
+        )
+    }
+}
+
+/// Controls how messages are published
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct MqttPublishing {
+    retain_state: bool,
+    retain_state_events: HashMap<String, bool>,
+    qos: MqttClassQoS,
+    plain_state_topics: bool,
+    plain_state_payload_on: String,
+    plain_state_payload_off: String,
+    regions_topic: bool,
+    publish_parse_failures: bool,
+    parse_failure_max_bytes: usize,
+    log_format: LogFormat,
+    names_from_alerts: bool,
+    event_types: HashMap<String, ConfigEventType>,
+    /// Set only with MQTT 5, which is needed to send it
+    message_expiry_secs: Option<u32>,
+}
+
+/// The QoS used for each class of message
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct MqttClassQoS {
+    state: MqttQoS,
+    availability: MqttQoS,
+    discovery: MqttQoS,
+    stats: MqttQoS,
+}
+
+impl From<&ConfigMqttQoS> for MqttClassQoS {
+    fn from(config: &ConfigMqttQoS) -> Self {
+        Self {
+            state: config.state.into(),
+            availability: config.availability.into(),
+            discovery: config.discovery.into(),
+            stats: config.stats.into(),
+        }
+    }
+}
+
+impl MqttPublishing {
+    pub fn new(config: &ConfigMqtt, event_types: &HashMap<String, ConfigEventType>) -> Self {
+        Self {
+            retain_state: config.retain_state,
+            retain_state_events: config.retain_state_events.clone(),
+            qos: (&config.qos).into(),
+            plain_state_topics: config.plain_state_topics,
+            plain_state_payload_on: config.plain_state_payload_on.clone(),
+            plain_state_payload_off: config.plain_state_payload_off.clone(),
+            regions_topic: config.regions_topic,
+            publish_parse_failures: config.publish_parse_failures,
+            parse_failure_max_bytes: config.parse_failure_max_bytes,
+            log_format: config.log_format,
+            names_from_alerts: config.names_from_alerts,
+            event_types: event_types.clone(),
+            message_expiry_secs: match config.protocol {
+                MqttProtocol::V4 => None,
+                MqttProtocol::V5 => config.message_expiry_secs,
+            },
+        }
+    }
+    /// The name of an event type, as configured in `event_types` or the built-in name
+    pub(self) fn event_name(&self, event_type: &EventType) -> String {
+        event_type_override(&self.event_types, event_type)
+            .and_then(|e| e.name.clone())
+            .unwrap_or_else(|| event_type.friendly_name())
+    }
+    /// The device class of an event type, as configured in `event_types` or the built-in device class
+    pub(self) fn event_device_class<'a>(&'a self, event_type: &'a EventType) -> Option<&'a str> {
+        event_type_override(&self.event_types, event_type)
+            .and_then(|e| e.device_class.as_deref())
+            .or_else(|| event_type.device_class())
+    }
+    /// The kind of entity triggers of an event type get, as configured in `event_types`
+    pub(self) fn event_ha_entity(&self, event_type: &EventType) -> HaEntity {
+        event_type_override(&self.event_types, event_type)
+            .and_then(|e| e.ha_entity)
+            .unwrap_or_default()
+    }
+    /// The icon of an event type, as configured in `event_types` or the built-in icon
+    pub(self) fn event_icon<'a>(&'a self, event_type: &'a EventType) -> Option<&'a str> {
+        event_type_override(&self.event_types, event_type)
+            .and_then(|e| e.icon.as_deref())
+            .or_else(|| event_type.icon())
+    }
+    /// Whether trigger states of this event type are retained.
+    /// Event type overrides take precedence, then momentary events are never retained, then the global setting.
+    pub(self) fn retain_state(&self, event_type: &EventType) -> bool {
+        let event_name = event_type.to_string();
+        self.retain_state_events
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&event_name))
+            .map(|(_, retain)| *retain)
+            .unwrap_or_else(|| !event_type.is_momentary() && self.retain_state)
+    }
+}
+
+impl Default for MqttPublishing {
+    fn default() -> Self {
+        Self {
+            retain_state: true,
+            retain_state_events: HashMap::new(),
+            qos: (&ConfigMqttQoS::default()).into(),
+            plain_state_topics: false,
+            plain_state_payload_on: "ON".into(),
+            plain_state_payload_off: "OFF".into(),
+            regions_topic: false,
+            publish_parse_failures: false,
+            parse_failure_max_bytes: 4096,
+            log_format: LogFormat::Json,
+            names_from_alerts: false,
+            event_types: HashMap::new(),
+            message_expiry_secs: None,
+        }
+    }
+}
+
+/// The topics of a camera that most others are built from, rendered once rather than for every message
+#[derive(Debug, PartialEq, Clone, Default)]
+struct CameraTopics {
+    base: Arc<str>,
+    availability: Arc<str>,
+    /// What discovery topics and unique IDs of the camera start with
+    discovery_device: Arc<str>,
+}
+
+/// The topics of a trigger, rendered once rather than for every alert
+#[derive(Debug, PartialEq, Clone, Default)]
+struct TriggerTopics {
+    state: Arc<str>,
+    discovery: Arc<str>,
+    /// What the trigger's other discovery topics and unique IDs start with
+    discovery_identifier: Arc<str>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct MqttTopics {
+    pub base: String,
+    pub home_assistant: String,
+    pub camera: TopicTemplate,
+    pub trigger: TopicTemplate,
+    /// Key discovery on the serial numbers of cameras rather than their IDs
+    pub stable_ids: bool,
+    /// Overrides `<base>/availability`
+    pub availability: Option<String>,
+    pub payload_available: String,
+    pub payload_not_available: String,
+}
+
+impl MqttTopics {
+    pub fn new(
+        base: String,
+        home_assistant: String,
+        camera: TopicTemplate,
+        trigger: TopicTemplate,
+        stable_ids: bool,
+    ) -> Self {
+        Self {
+            base,
+            home_assistant,
+            camera,
+            trigger,
+            stable_ids,
+            ..Self::default()
+        }
+    }
+    /// Sets the availability topic of the bridge and the availability payloads
+    pub fn with_availability(
+        mut self,
+        topic: Option<String>,
+        payload_available: String,
+        payload_not_available: String,
+    ) -> Self {
+        self.availability = topic;
+        self.payload_available = payload_available;
+        self.payload_not_available = payload_not_available;
+        self
+    }
+
+    pub(self) fn get_global_availability(&self) -> String {
+        match &self.availability {
+            Some(topic) => topic.clone(),
+            None => format!("{}/availability", self.base),
+        }
+    }
+    /// The payload of an availability topic
+    pub(self) fn availability_payload(&self, available: bool) -> &str {
+        match available {
+            true => &self.payload_available,
+            false => &self.payload_not_available,
+        }
+    }
+    /// An entry of the `availability` list in discovery, for the bridge
+    pub(self) fn global_availability_entry(&self) -> serde_json::Value {
+        self.availability_entry(self.get_global_availability())
+    }
+    /// An entry of the `availability` list in discovery, for a camera
+    pub(self) fn camera_availability_entry(&self, cam: &CameraDetails) -> serde_json::Value {
+        self.availability_entry(self.get_camera_availability(cam))
+    }
+    fn availability_entry(&self, topic: String) -> serde_json::Value {
+        serde_json::json!({
+            "payload_available": self.payload_available,
+            "payload_not_available": self.payload_not_available,
+            "topic": topic,
+        })
+    }
+    pub(self) fn get_global_stats(&self) -> String {
+        format!("{}/stats", self.base)
+    }
+    /// Renders the topics of a camera, which are kept on it
+    pub(self) fn camera_topics(&self, cam: &CameraDetails) -> CameraTopics {
+        let base = self.camera.render(&[
+            ("base", self.get_base(cam)),
+            ("camera_id", cam.config.identifier()),
+        ]);
+        let discovery_device = match &cam.info {
+            Some(info) if self.stable_ids => {
+                let serial: String = info
+                    .serial_number
+                    .chars()
+                    .map(|c| match c {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                        _ => '_',
+                    })
+                    .collect();
+                format!("serial_{}", serial)
+            }
+            _ => format!("device_{}", cam.config.identifier()),
+        };
+        CameraTopics {
+            availability: format!("{}/availability", base).into(),
+            base: base.into(),
+            discovery_device: discovery_device.into(),
+        }
+    }
+    /// Renders the topics of a trigger, which are kept on it. Needs the camera's topics to be rendered first.
+    pub(self) fn trigger_topics(
+        &self,
+        cam: &CameraDetails,
+        identifier: &EventIdentifier,
+    ) -> TriggerTopics {
+        let channel = identifier
+            .channel
+            .as_ref()
+            .map(|c| format!("ch{}", c))
+            .unwrap_or_default();
+        let state = self.trigger.render(&[
+            ("base", self.get_base(cam)),
+            ("camera_id", cam.config.identifier()),
+            ("camera", &cam.topics.base),
+            ("channel", &channel),
+            ("event_type", &identifier.event_type.to_string()),
+        ]);
+        let channel_identifier = identifier
+            .channel
+            .as_ref()
+            .map(|c| format!("_ch{}", c))
+            .unwrap_or_default();
+        let discovery_identifier = format!(
+            "{}{}_{}",
+            cam.topics.discovery_device, channel_identifier, identifier.event_type
+        );
+        TriggerTopics {
+            state: state.into(),
+            discovery: format!(
+                "{}/binary_sensor/hiksink/{}/config",
+                self.home_assistant, discovery_identifier
+            )
+            .into(),
+            discovery_identifier: discovery_identifier.into(),
+        }
+    }
+    pub(self) fn get_camera_base<'a>(&self, cam: &'a CameraDetails) -> &'a str {
+        &cam.topics.base
+    }
+    /// The base topic of a camera, which can be overridden per camera
+    pub(self) fn get_base<'a>(&'a self, cam: &'a CameraDetails) -> &'a str {
+        cam.config.base_topic.as_deref().unwrap_or(&self.base)
+    }
+    /// The camera topic with `+` for the camera, to subscribe to commands of every camera under `base`
+    pub(self) fn get_camera_wildcard(&self, base: &str) -> String {
+        self.camera.render_wildcard("camera_id", &[("base", base)])
+    }
+    pub(self) fn get_camera_availability(&self, cam: &CameraDetails) -> String {
+        cam.topics.availability.to_string()
+    }
+    pub(self) fn get_camera_events_stale(&self, cam: &CameraDetails) -> String {
+        format!("{}/events_stale", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_reconnect_count(&self, cam: &CameraDetails) -> String {
+        format!("{}/reconnect_count", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_info(&self, cam: &CameraDetails) -> String {
+        format!("{}/info", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_errors(&self, cam: &CameraDetails) -> String {
+        format!("{}/errors", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_clock_drift(&self, cam: &CameraDetails) -> String {
+        format!("{}/clock_drift", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_clock_wrong(&self, cam: &CameraDetails) -> String {
+        format!("{}/clock_wrong", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_log(&self, cam: &CameraDetails) -> String {
+        format!("{}/log", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_parse_error(&self, cam: &CameraDetails) -> String {
+        format!("{}/parse_error", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_command(&self, cam: &CameraDetails) -> String {
+        format!("{}/command", self.get_camera_base(cam))
+    }
+    pub(self) fn get_camera_mute(&self, cam: &CameraDetails) -> String {
+        format!("{}/mute", self.get_camera_base(cam))
+    }
+    pub(self) fn get_mute_subscription(&self, base: &str) -> String {
+        format!("{}/mute", self.get_camera_wildcard(base))
+    }
+    pub(self) fn get_global_mute(&self) -> String {
+        format!("{}/mute", self.base)
+    }
+    pub(self) fn get_command_subscription(&self, base: &str) -> String {
+        format!("{}/command", self.get_camera_wildcard(base))
+    }
+    pub(self) fn get_control_state(&self, cam: &CameraDetails, control: &ControlState) -> String {
+        match control.control {
+            ControlType::MotionDetection | ControlType::SupplementLight => format!(
+                "{}/ch{}/{}",
+                self.get_camera_base(cam),
+                control.channel,
+                control.control
+            ),
+            ControlType::AlarmOutput => format!(
+                "{}/{}/{}",
+                self.get_camera_base(cam),
+                control.control,
+                control.channel
+            ),
+        }
+    }
+    pub(self) fn get_control_set(&self, cam: &CameraDetails, control: &ControlState) -> String {
+        format!("{}/set", self.get_control_state(cam, control))
+    }
+    pub(self) fn get_ptz_preset_set(&self, cam: &CameraDetails) -> String {
+        format!("{}/ptz/preset/set", self.get_camera_base(cam))
+    }
+    pub(self) fn get_ptz_preset_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/select/hiksink/{}_ptz_preset/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_occupancy_state(&self, cam: &CameraDetails, channel: &str) -> String {
+        format!("{}/ch{}/occupancy", self.get_camera_base(cam), channel)
+    }
+    pub(self) fn get_occupancy_reset(&self, cam: &CameraDetails, channel: &str) -> String {
+        format!("{}/reset", self.get_occupancy_state(cam, channel))
+    }
+    pub(self) fn get_occupancy_reset_subscription(&self, base: &str) -> String {
+        format!("{}/+/occupancy/reset", self.get_camera_wildcard(base))
+    }
+    pub(self) fn get_discovery_identifier_occupancy(
+        &self,
+        cam: &CameraDetails,
+        channel: &str,
+    ) -> String {
+        format!("{}_ch{}_occupancy", self.get_discovery_device(cam), channel)
+    }
+    pub(self) fn get_occupancy_discovery(&self, cam: &CameraDetails, channel: &str) -> String {
+        format!(
+            "{}/sensor/hiksink/{}/config",
+            self.home_assistant,
+            self.get_discovery_identifier_occupancy(cam, channel)
+        )
+    }
+    pub(self) fn get_occupancy_reset_discovery(
+        &self,
+        cam: &CameraDetails,
+        channel: &str,
+    ) -> String {
+        format!(
+            "{}/button/hiksink/{}_reset/config",
+            self.home_assistant,
+            self.get_discovery_identifier_occupancy(cam, channel)
+        )
+    }
+    pub(self) fn get_control_set_subscription(&self, base: &str) -> String {
+        format!("{}/+/+/set", self.get_camera_wildcard(base))
+    }
+    pub(self) fn get_trigger_base<'a>(&self, trigger: &'a TriggerDetails) -> &'a str {
+        &trigger.topics.state
+    }
+    pub(self) fn get_trigger_state(&self, trigger: &TriggerDetails) -> String {
+        trigger.topics.state.to_string()
+    }
+    pub(self) fn get_trigger_plain_state(&self, trigger: &TriggerDetails) -> String {
+        format!("{}/state", self.get_trigger_base(trigger))
+    }
+    pub(self) fn get_trigger_regions(&self, trigger: &TriggerDetails) -> String {
+        format!("{}/regions", self.get_trigger_base(trigger))
+    }
+    pub(self) fn get_trigger_target_state(&self, trigger: &TriggerDetails, target: &str) -> String {
+        format!("{}/{}", self.get_trigger_base(trigger), target)
+    }
+    pub(self) fn get_trigger_snapshot(&self, trigger: &TriggerDetails) -> String {
+        format!("{}/snapshot", self.get_trigger_base(trigger))
+    }
+
+    /// What discovery topics and unique IDs of a camera start with. With `stable_ids` this is the
+    /// serial number, so renaming the camera or changing its ID keeps its entities.
+    pub(self) fn get_discovery_device<'a>(&self, cam: &'a CameraDetails) -> &'a str {
+        &cam.topics.discovery_device
+    }
+    pub(self) fn get_discovery_identifier_trigger<'a>(
+        &self,
+        trigger: &'a TriggerDetails,
+    ) -> &'a str {
+        &trigger.topics.discovery_identifier
+    }
+
+    pub(self) fn get_home_assistant_status(&self) -> String {
+        format!("{}/status", self.home_assistant)
+    }
+    pub(self) fn get_global_stats_discovery(&self, key: &str) -> String {
+        format!("{}/sensor/hiksink/{}/config", self.home_assistant, key)
+    }
+
+    pub(self) fn get_camera_log_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/sensor/hiksink/{}_log/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_control_discovery(
+        &self,
+        cam: &CameraDetails,
+        control: &ControlState,
+    ) -> String {
+        format!(
+            "{}/switch/hiksink/{}/config",
+            self.home_assistant,
+            self.get_discovery_identifier_control(cam, control)
+        )
+    }
+    pub(self) fn get_discovery_identifier_control(
+        &self,
+        cam: &CameraDetails,
+        control: &ControlState,
+    ) -> String {
+        match control.control {
+            ControlType::MotionDetection | ControlType::SupplementLight => format!(
+                "{}_ch{}_{}",
+                self.get_discovery_device(cam),
+                control.channel,
+                control.control
+            ),
+            ControlType::AlarmOutput => format!(
+                "{}_{}_{}",
+                self.get_discovery_device(cam),
+                control.control,
+                control.channel
+            ),
+        }
+    }
+    pub(self) fn get_camera_reconnect_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/button/hiksink/{}_reconnect/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_camera_reboot_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/button/hiksink/{}_reboot/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_camera_events_stale_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/binary_sensor/hiksink/{}_events_stale/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_camera_info_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/sensor/hiksink/{}_info/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_camera_clock_drift_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/sensor/hiksink/{}_clock_drift/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_camera_clock_wrong_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/binary_sensor/hiksink/{}_clock_wrong/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_camera_reconnect_count_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/sensor/hiksink/{}_reconnect_count/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+    pub(self) fn get_camera_connectivity_discovery(&self, cam: &CameraDetails) -> String {
+        format!(
+            "{}/binary_sensor/hiksink/{}_connectivity/config",
+            self.home_assistant,
+            self.get_discovery_device(cam)
+        )
+    }
+
+    pub(self) fn get_trigger_event(&self, trigger: &TriggerDetails) -> String {
+        format!("{}/event", self.get_trigger_base(trigger))
+    }
+    pub(self) fn get_trigger_event_discovery(&self, trigger: &TriggerDetails) -> String {
+        format!(
+            "{}/event/hiksink/{}/config",
+            self.home_assistant,
+            self.get_discovery_identifier_trigger(trigger)
+        )
+    }
+    pub(self) fn get_trigger_device_trigger_discovery(&self, trigger: &TriggerDetails) -> String {
+        format!(
+            "{}/device_automation/hiksink/{}/config",
+            self.home_assistant,
+            self.get_discovery_identifier_trigger(trigger)
+        )
+    }
+    pub(self) fn get_trigger_discovery(&self, trigger: &TriggerDetails) -> String {
+        trigger.topics.discovery.to_string()
+    }
+
+    pub(self) fn get_trigger_count_discovery(&self, trigger: &TriggerDetails, key: &str) -> String {
+        format!(
+            "{}/sensor/hiksink/{}_{}/config",
+            self.home_assistant,
+            self.get_discovery_identifier_trigger(trigger),
+            key
+        )
+    }
+
+    pub(self) fn get_trigger_target_discovery(
+        &self,
+        trigger: &TriggerDetails,
+        target: &str,
+    ) -> String {
+        format!(
+            "{}/binary_sensor/hiksink/{}_{}/config",
+            self.home_assistant,
+            self.get_discovery_identifier_trigger(trigger),
+            target
+        )
+    }
+
+    pub(self) fn get_trigger_snapshot_discovery(&self, trigger: &TriggerDetails) -> String {
+        format!(
+            "{}/camera/hiksink/{}/config",
+            self.home_assistant,
+            self.get_discovery_identifier_trigger(trigger)
+        )
+    }
+}
+impl Default for MqttTopics {
+    fn default() -> Self {
+        Self {
+            base: "hikvision_cameras".into(),
+            home_assistant: "homeassistant".into(),
+            camera: TopicTemplate::camera_default(),
+            trigger: TopicTemplate::trigger_default(),
+            stable_ids: false,
+            availability: None,
+            payload_available: "online".into(),
+            payload_not_available: "offline".into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub qos: MqttQoS,
+    pub retain: bool,
+    pub payload: MqttPayload,
+    /// How long the broker keeps the message. Only sent with MQTT 5.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry_secs: Option<u32>,
+}
+
+impl MqttMessage {
+    pub fn new(topic: String, qos: MqttQoS, retain: bool, payload: impl Into<MqttPayload>) -> Self {
+        Self {
+            topic,
+            qos,
+            retain,
+            payload: payload.into(),
+            expiry_secs: None,
+        }
+    }
+    /// Sets how long the broker keeps the message
+    pub fn with_expiry(mut self, expiry_secs: Option<u32>) -> Self {
+        self.expiry_secs = expiry_secs;
+        self
+    }
+    /// Deletes a retained message from the broker (and the entity from home assistant for discovery topics)
+    pub fn delete(topic: String) -> Self {
+        Self::new(topic, MqttQoS::AtLeastOnce, true, "")
+    }
+}
+impl From<MqttMessage> for rumqttc::LastWill {
+    fn from(m: MqttMessage) -> Self {
+        rumqttc::LastWill::new(m.topic, m.payload.render(), m.qos.into(), m.retain)
+    }
+}
+impl From<MqttMessage> for rumqttc::v5::mqttbytes::v5::LastWill {
+    fn from(m: MqttMessage) -> Self {
+        Self::new(m.topic, m.payload.render(), m.qos.into(), m.retain, None)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[allow(clippy::enum_variant_names)]
+pub enum MqttQoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+impl From<QoSLevel> for MqttQoS {
+    fn from(level: QoSLevel) -> Self {
+        match level.0 {
+            0 => MqttQoS::AtMostOnce,
+            1 => MqttQoS::AtLeastOnce,
+            _ => MqttQoS::ExactlyOnce,
+        }
+    }
+}
+
+impl From<MqttQoS> for rumqttc::QoS {
+    fn from(q: MqttQoS) -> Self {
+        use rumqttc::QoS;
+        match q {
+            MqttQoS::AtMostOnce => QoS::AtMostOnce,
+            MqttQoS::AtLeastOnce => QoS::AtLeastOnce,
+            MqttQoS::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+impl From<MqttQoS> for rumqttc::v5::mqttbytes::QoS {
+    fn from(q: MqttQoS) -> Self {
+        use rumqttc::v5::mqttbytes::QoS;
+        match q {
+            MqttQoS::AtMostOnce => QoS::AtMostOnce,
+            MqttQoS::AtLeastOnce => QoS::AtLeastOnce,
+            MqttQoS::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum MqttPayload {
+    Constant(String),
+    Json(serde_json::Value),
+    Binary(Vec<u8>),
+}
+
+impl MqttPayload {
+    pub fn render(self) -> Vec<u8> {
+        match self {
+            MqttPayload::Constant(c) => c.into(),
+            MqttPayload::Json(j) => j.to_string().into(),
+            MqttPayload::Binary(b) => b,
+        }
+    }
+    /// A hash of the rendered payload, to tell whether it changed since it was last published
+    pub fn digest(&self) -> u64 {
+        payload_digest(&self.clone().render())
+    }
+}
+
+/// A hash of a rendered payload
+pub fn payload_digest(payload: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl From<&str> for MqttPayload {
+    fn from(v: &str) -> Self {
+        Self::Constant(v.into())
+    }
+}
+
+impl From<String> for MqttPayload {
+    fn from(v: String) -> Self {
+        Self::Constant(v)
+    }
+}
+
+impl From<serde_json::Value> for MqttPayload {
+    fn from(v: serde_json::Value) -> Self {
+        Self::Json(v)
+    }
+}
+
+impl From<Vec<u8>> for MqttPayload {
+    fn from(v: Vec<u8>) -> Self {
+        Self::Binary(v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::{
+            AlertFormat, CameraMode, ConfigCamera, ConfigDebounce, ConfigEventType, ConfigMqttQoS,
+            ConfigSystem, HaEntity, LogFormat, QoSLevel, SystemLogFormat, TopicTemplate,
+        },
+        hikapi::{
+            AlertItem, AnprDetails, CameraCommand, CameraEvent, CameraEventType, ConnectLimit,
+            ControlState, ControlType, DetectionRegion, DeviceInfo, DisconnectKind,
+            EventIdentifier, EventQueue, EventType, PeopleCount, PtzPreset, RegionCoordinates,
+            TriggerItem,
+        },
+    };
+
+    use super::{
+        debounce_for, ConnectionState, Manager, MqttMessage, MqttPayload, MqttPublishing, MqttQoS,
+        MqttTopics, PersistedState, TriggerDetails, COUNTERS_INTERVAL_SECS, DEDUP_WINDOW_SECS,
+        ERROR_HISTORY_LEN, STATE_SAVE_INTERVAL_SECS, STATS_INTERVAL_SECS,
+    };
+    use chrono::{DateTime, Duration, Utc};
+    use std::collections::HashMap;
+
+    fn sample_cameras() -> Vec<ConfigCamera> {
+        vec![ConfigCamera {
+            generated_id: "cam1".into(),
+            id: None,
+            base_topic: None,
+            name: "Camera 1".into(),
+            address: "192.168.20.2".into(),
+            port: None,
+            scheme: None,
+            fallback_address: None,
+            fallback_port: None,
+            username: "admin".into(),
+            username_file: None,
+            username_env: None,
+            password: "password".into(),
+            password_file: None,
+            password_env: None,
+            retry_on_auth_failure: true,
+            alert_format: AlertFormat::Auto,
+            snapshot_on_alert: false,
+            snapshot_min_interval_secs: 5,
+            split_smart_targets: false,
+            alert_auto_clear_secs: None,
+            stale_after_secs: None,
+            area: None,
+            trigger_names: HashMap::new(),
+            channels: HashMap::new(),
+            io_ports: HashMap::new(),
+            device_per_channel: false,
+            channel_offset: None,
+            events_include: Vec::new(),
+            events_exclude: Vec::new(),
+            ignore_videoloss: None,
+            triggers: Vec::new(),
+            trigger_rescan_secs: None,
+            clock_check_secs: None,
+            info_refresh_secs: None,
+            auto_register_triggers: false,
+            only_notifying_triggers: false,
+            mode: CameraMode::Stream,
+            expose_controls: false,
+            derive_occupancy: false,
+            duplicates_of: HashMap::new(),
+            debounce: HashMap::new(),
+            log_level: None,
+            enabled: true,
+        }]
+    }
+
+    fn sample_system() -> ConfigSystem {
+        ConfigSystem {
+            log_level: "INFO".into(),
+            log_format: SystemLogFormat::Text,
+            log_ansi: true,
+            alert_auto_clear_secs: None,
+            alert_auto_clear_event_secs: HashMap::new(),
+            cleanup_stale_topics: true,
+            state_file: None,
+            ignore_videoloss: false,
+            stale_after_secs: None,
+            dedup: false,
+            debounce: HashMap::new(),
+            availability_grace_secs: 0,
+            capture_dir: None,
+            capture_all: false,
+            capture_max_mb: 100,
+            max_concurrent_connects: None,
+            connect_stagger_ms: 0,
+            clock_drift_threshold_secs: 60,
+            exit_on_mqtt_auth_failure: false,
+        }
+    }
+
+    fn sample_device_info() -> DeviceInfo {
+        DeviceInfo {
+            device_name: "Cam 1".into(),
+            device_id: "7ccc4404-e05d-4376-8ebf-81127da67c11".into(),
+            model: "DS-2DE4A425IW-DE".into(),
+            serial_number: "DS-2DE4A425IW-DE20180101AAWRC52000000W".into(),
+            mac_address: "ff:ff:ff:ff:ff:ff".into(),
+            firmware_version: "V5.5.71".into(),
+            firmware_release_date: "build 180725".into(),
+            device_type: "IPDome".into(),
+        }
+    }
+
+    #[test]
+    fn test_initial_state() {
+        let cams = sample_cameras();
+        let manager = Manager::new(
+            cams,
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        insta::assert_yaml_snapshot!(manager, {
+            ".cameras[].log.since" => "[since]"
+        });
+    }
+
+    #[test]
+    fn test_lwt() {
+        let cams = sample_cameras();
+        let manager = Manager::new(
+            cams,
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        insta::assert_yaml_snapshot!(manager.mqtt_lwt());
+    }
+
+    #[test]
+    fn test_mqtt_connection_initial() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams,
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        insta::assert_yaml_snapshot!(manager.mqtt_connection_established(0, false), {
+            "[].**.sw_version" => "[sw_version]",
+            "[].**.since" => "[since]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+    }
+
+    #[test]
+    fn test_custom_availability() {
+        let cams = sample_cameras();
+        let topics = MqttTopics::default().with_availability(
+            Some("tele/hiksink/LWT".into()),
+            "1".into(),
+            "0".into(),
+        );
+        let mut manager = Manager::new(
+            cams.clone(),
+            topics,
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        insta::assert_yaml_snapshot!(manager.mqtt_lwt());
+        insta::assert_yaml_snapshot!(manager.mqtt_connection_established(0, false), {
+            "[].**.sw_version" => "[sw_version]",
+            "[].**.since" => "[since]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.sw_version" => "[sw_version]",
+            "[].**.since" => "[since]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.time" => "[time]"
+        });
+    }
+
+    #[test]
+    fn test_mqtt_reconnect_unchanged() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let publish = |manager: &mut Manager, messages: &[MqttMessage]| {
+            for m in messages.iter().filter(|m| m.retain) {
+                manager.retained_published(0, &m.topic, m.payload.digest());
+            }
+        };
+        let messages = manager.mqtt_connection_established(0, false);
+        publish(&mut manager, &messages);
+
+        // Only availability is needed again when the broker kept the session
+        let messages = manager.mqtt_connection_established(0, true);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic, "hikvision_cameras/availability");
+        assert_eq!(messages[0].payload, "online".into());
+
+        // Changes that were never handed to the broker go out again
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let messages = manager.mqtt_connection_established(0, true);
+        assert!(messages.len() > 1);
+        assert!(messages
+            .iter()
+            .any(|m| m.topic == "hikvision_cameras/device_cam1/availability"));
+        publish(&mut manager, &messages);
+        assert_eq!(manager.mqtt_connection_established(0, true).len(), 1);
+
+        // Everything is published when the session was lost
+        let all = manager.mqtt_connection_established(0, false);
+        assert!(all.len() > messages.len());
+    }
+
+    #[test]
+    fn test_mqtt_mirror_brokers() {
+        let mut manager = Manager::new(
+            sample_cameras(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        manager.report_brokers(vec!["localhost:1883".into(), "backup.local:1883".into()]);
+        for m in manager
+            .mqtt_connection_established(0, false)
+            .iter()
+            .filter(|m| m.retain)
+        {
+            manager.retained_published(0, &m.topic, m.payload.digest());
+        }
+
+        // What the main broker kept says nothing about the mirror
+        assert_eq!(manager.mqtt_connection_established(0, true).len(), 1);
+        assert!(manager.mqtt_connection_established(1, true).len() > 1);
+
+        let stats = match manager.messages_dropped(0).payload {
+            MqttPayload::Json(json) => json["brokers"].clone(),
+            payload => panic!("Unexpected stats payload {:?}", payload),
+        };
+        assert_eq!(
+            stats,
+            serde_json::json!([
+                {"broker": "localhost:1883", "connected": true},
+                {"broker": "backup.local:1883", "connected": true},
+            ])
+        );
+        manager.mqtt_connection_lost(1);
+        let stats = match manager.messages_dropped(0).payload {
+            MqttPayload::Json(json) => json["brokers"][1]["connected"].clone(),
+            payload => panic!("Unexpected stats payload {:?}", payload),
+        };
+        assert_eq!(stats, serde_json::json!(false));
+    }
+
+    #[test]
+    fn test_home_assistant_online() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+
+        let discovery: Vec<_> = manager
+            .mqtt_connection_established(0, false)
+            .into_iter()
+            .filter(|m| m.topic.starts_with("homeassistant/"))
+            .collect();
+        assert!(!discovery.is_empty());
+        assert_eq!(manager.home_assistant_online(), discovery);
+        assert_eq!(
+            manager.home_assistant_status_topic(),
+            "homeassistant/status"
+        );
+    }
+
+    #[test]
+    fn test_camera_connection() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    EventIdentifier::new(Some("1".into()), EventType::Motion).into(),
+                    EventIdentifier::new(Some("1".into()), EventType::Io).into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        insta::assert_yaml_snapshot!(manager, {
+            ".cameras[].triggers[].last_alert" => "[last_alert]",
+            ".cameras[].log.since" => "[since]"
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.since" => "[since]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+    }
+
+    #[test]
+    fn test_camera_log() {
+        let cams = sample_cameras();
+        let disconnected = |error: &str, kind: DisconnectKind| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Disconnected {
+                error: error.into(),
+                kind,
+                retry_in_secs: Some(3),
+            },
+        };
+        let log = |messages: Vec<MqttMessage>| {
+            messages
+                .into_iter()
+                .find(|m| m.topic == "hikvision_cameras/device_cam1/log")
+                .unwrap()
+                .payload
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let initial = manager.cameras[0].log.since;
+
+        let first = match log(manager.next_event(disconnected("Timed out", DisconnectKind::Timeout)))
+        {
+            MqttPayload::Json(json) => json,
+            _ => panic!("Log should be JSON"),
+        };
+        assert_eq!(first["state"], "disconnected");
+        assert_eq!(first["detail"], "Connection Error: Timed out");
+        assert_eq!(first["retry_in_secs"], 3);
+        assert_eq!(first["kind"], "timeout");
+        assert!(manager.cameras[0].log.since >= initial);
+
+        // Still disconnected, so the time it started is kept
+        let second = match log(manager.next_event(disconnected("Refused", DisconnectKind::Network)))
+        {
+            MqttPayload::Json(json) => json,
+            _ => panic!("Log should be JSON"),
+        };
+        assert_eq!(second["detail"], "Connection Error: Refused");
+        assert_eq!(second["since"], first["since"]);
+        assert_eq!(second["kind"], "network");
+
+        // Connecting again clears the kind
+        let connected = match log(manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        })) {
+            MqttPayload::Json(json) => json,
+            _ => panic!("Log should be JSON"),
+        };
+        assert!(connected.get("kind").is_none());
+
+        let publishing = MqttPublishing {
+            log_format: LogFormat::Text,
+            ..MqttPublishing::default()
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            publishing,
+        );
+        assert_eq!(
+            log(manager.next_event(disconnected("Timed out", DisconnectKind::Timeout))),
+            MqttPayload::from("Connection Error: Timed out")
+        );
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+
+        // Add a camera and change the existing one
+        let mut changed = cams[0].clone();
+        changed.address = "192.168.20.3".into();
+        let added = ConfigCamera {
+            generated_id: "cam2".into(),
+            name: "Camera 2".into(),
+            ..cams[0].clone()
+        };
+        let messages = manager.apply_config(vec![changed.clone(), added.clone()]);
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.since" => "[since]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+        // The existing camera keeps its state
+        assert_eq!(manager.cameras[0].config, changed);
+        assert!(manager.cameras[0].connected);
+        assert_eq!(manager.cameras[0].triggers.len(), 1);
+        assert_eq!(manager.cameras[1].config, added);
+
+        // Removing a camera deletes everything published for it
+        let messages = manager.apply_config(vec![added]);
+        let deleted: Vec<&str> = messages
+            .iter()
+            .filter(|m| m.payload == MqttPayload::from(""))
+            .map(|m| m.topic.as_str())
+            .collect();
+        assert!(deleted.contains(&"hikvision_cameras/device_cam1/availability"));
+        assert!(
+            deleted.contains(&"homeassistant/binary_sensor/hiksink/device_cam1_ch1_Motion/config")
+        );
+        assert_eq!(manager.cameras.len(), 1);
+    }
+
+    #[test]
+    fn test_status() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let status = manager.status(true, None);
+        assert!(status.cameras[0].running);
+        assert_eq!(status.cameras[0].error, None);
+
+        // Retries were disabled after the credentials were rejected
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Disconnected {
+                error: "Bad password".into(),
+                kind: DisconnectKind::Auth,
+                retry_in_secs: None,
+            },
+        });
+        let status = manager.status(true, None);
+        assert!(!status.cameras[0].running);
+        assert_eq!(
+            status.cameras[0].error.as_deref(),
+            Some("Connection Error: Bad password")
+        );
+        assert!(!status.is_healthy());
+    }
+
+    #[test]
+    fn test_commands() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        assert_eq!(
+            manager.command_subscriptions(),
+            vec![
+                "hikvision_cameras/+/command",
+                "hikvision_cameras/+/+/+/set",
+                "hikvision_cameras/+/+/occupancy/reset",
+                "hikvision_cameras/+/mute",
+                "hikvision_cameras/mute"
+            ]
+        );
+        assert_eq!(
+            manager.parse_command("hikvision_cameras/device_cam1/command", b"reconnect"),
+            Some(("cam1".to_string(), CameraCommand::Reconnect))
+        );
+        assert_eq!(
+            manager.parse_command("hikvision_cameras/device_cam1/command", b" PAUSE\n"),
+            Some(("cam1".to_string(), CameraCommand::Pause))
+        );
+        assert_eq!(
+            manager.parse_command("hikvision_cameras/device_cam1/command", b"REBOOT"),
+            None
+        );
+        assert_eq!(
+            manager.parse_command("hikvision_cameras/device_unknown/command", b"resume"),
+            None
+        );
+
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Paused,
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.since" => "[since]",
+        });
+        // Paused on purpose, so still healthy
+        let status = manager.status(true, None);
+        assert!(!status.cameras[0].connected);
+        assert!(status.cameras[0].running);
+        assert_eq!(status.cameras[0].error, None);
+    }
+
+    #[test]
+    fn test_topic_templates() {
+        let cams = sample_cameras();
+        let topics = MqttTopics::new(
+            "site".into(),
+            "homeassistant".into(),
+            TopicTemplate::try_from("{base}/main/cameras/{camera_id}".to_string()).unwrap(),
+            TopicTemplate::try_from("{camera}/{event_type}/{channel}".to_string()).unwrap(),
+            false,
+        );
+        let mut manager = Manager::new(
+            cams.clone(),
+            topics,
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        assert_eq!(
+            manager.command_subscriptions(),
+            vec![
+                "site/main/cameras/+/command",
+                "site/main/cameras/+/+/+/set",
+                "site/main/cameras/+/+/occupancy/reset",
+                "site/main/cameras/+/mute",
+                "site/mute"
+            ]
+        );
+        assert_eq!(
+            manager.parse_command("site/main/cameras/cam1/command", b"reconnect"),
+            Some(("cam1".to_string(), CameraCommand::Reconnect))
+        );
+
+        let motion: TriggerItem = EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    motion.clone(),
+                    EventIdentifier::new(None, EventType::Io).into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let published: Vec<&str> = messages.iter().map(|m| m.topic.as_str()).collect();
+        insta::assert_yaml_snapshot!(published);
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: motion.identifier,
+            }),
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+    }
+
+    #[test]
+    fn test_camera_base_topic() {
+        let mut cams = sample_cameras();
+        let mut shared = cams[0].clone();
+        shared.generated_id = "cam2".into();
+        shared.name = "Camera 2".into();
+        shared.base_topic = Some("housemate/cameras".into());
+        cams.push(shared);
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        assert_eq!(
+            manager.command_subscriptions(),
+            vec![
+                "hikvision_cameras/+/command",
+                "hikvision_cameras/+/+/+/set",
+                "hikvision_cameras/+/+/occupancy/reset",
+                "hikvision_cameras/+/mute",
+                "housemate/cameras/+/command",
+                "housemate/cameras/+/+/+/set",
+                "housemate/cameras/+/+/occupancy/reset",
+                "housemate/cameras/+/mute",
+                "hikvision_cameras/mute"
+            ]
+        );
+        assert_eq!(
+            manager.parse_command("housemate/cameras/device_cam2/command", b"pause"),
+            Some(("cam2".to_string(), CameraCommand::Pause))
+        );
+        assert_eq!(
+            manager.parse_command("hikvision_cameras/device_cam2/command", b"pause"),
+            None
+        );
+
+        let messages = manager.next_event(CameraEvent {
+            id: "cam2".to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let published: Vec<&str> = messages.iter().map(|m| m.topic.as_str()).collect();
+        insta::assert_yaml_snapshot!(published);
+        let discovery = messages
+            .iter()
+            .find(|m| {
+                m.topic == "homeassistant/binary_sensor/hiksink/device_cam2_ch1_Motion/config"
+            })
+            .unwrap();
+        match &discovery.payload {
+            MqttPayload::Json(json) => {
+                assert_eq!(
+                    json["state_topic"],
+                    "housemate/cameras/device_cam2/ch1/Motion"
+                );
+            }
+            other => panic!("Unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        let mut cams = sample_cameras();
+        cams[0].expose_controls = true;
+        let topics = MqttTopics {
+            stable_ids: true,
+            ..MqttTopics::default()
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            topics,
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let connected = CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    EventIdentifier::new(Some("1".into()), EventType::Motion).into(),
+                    EventIdentifier::new(None, EventType::Io).into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        };
+        let discovery = |messages: Vec<MqttMessage>| -> Vec<(String, String)> {
+            messages
+                .into_iter()
+                .filter_map(|m| match m.payload {
+                    MqttPayload::Json(json) if m.topic.ends_with("/config") => {
+                        Some((m.topic, json["unique_id"].as_str()?.to_string()))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        let messages = discovery(manager.next_event(connected.clone()));
+        insta::assert_yaml_snapshot!(messages);
+
+        // Renaming the camera, which changes its ID, keeps the same entities
+        let mut renamed = cams.clone();
+        renamed[0].name = "Driveway".into();
+        renamed[0].generated_id = "driveway".into();
+        manager.apply_config(renamed);
+        let mut connected = connected;
+        connected.id = "driveway".into();
+        assert_eq!(discovery(manager.next_event(connected)), messages);
+    }
+
+    #[test]
+    fn test_device_per_channel() {
+        let mut cams = sample_cameras();
+        cams[0].device_per_channel = true;
+        cams[0].channels.insert("2".into(), "Driveway".into());
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let mut front_door: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        front_door.channel_name = Some("Front Door".into());
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    front_door,
+                    EventIdentifier::new(Some("2".into()), EventType::Motion).into(),
+                    EventIdentifier::new(Some("3".into()), EventType::Motion).into(),
+                    EventIdentifier::new(None, EventType::DiskFull).into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        // The device of each trigger's entity
+        let devices: Vec<(String, serde_json::Value)> = messages
+            .into_iter()
+            .filter(|m| {
+                m.topic
+                    .starts_with("homeassistant/binary_sensor/hiksink/device_cam1_")
+            })
+            .filter(|m| !m.topic.contains("connectivity"))
+            .filter_map(|m| match m.payload {
+                MqttPayload::Json(json) => Some((m.topic, json["device"].clone())),
+                _ => None,
+            })
+            .collect();
+        insta::assert_yaml_snapshot!(devices, {
+            "[].**.sw_version" => "[sw_version]",
+        });
+    }
+
+    #[test]
+    fn test_ha_entity() {
+        let cams = sample_cameras();
+        let mut event_types = HashMap::new();
+        event_types.insert(
+            "FaceSnap".to_string(),
+            ConfigEventType {
+                ha_entity: Some(HaEntity::Event),
+                ..ConfigEventType::default()
+            },
+        );
+        event_types.insert(
+            "ANPR".to_string(),
+            ConfigEventType {
+                ha_entity: Some(HaEntity::DeviceTrigger),
+                ..ConfigEventType::default()
+            },
+        );
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing {
+                event_types,
+                ..MqttPublishing::default()
+            },
+        );
+        let face = EventIdentifier::new(Some("1".into()), EventType::FaceSnap);
+        let anpr = EventIdentifier::new(Some("1".into()), EventType::Anpr);
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![face.clone().into(), anpr.clone().into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let discovery: Vec<MqttMessage> = messages
+            .into_iter()
+            .filter(|m| m.topic.contains("_ch1_"))
+            .collect();
+        insta::assert_yaml_snapshot!(discovery, {
+            "[].**.sw_version" => "[sw_version]",
+        });
+
+        let alert = |identifier: &EventIdentifier, plate: Option<&str>| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "2021-10-02T18:04:02+08:00".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: plate.map(|plate| AnprDetails {
+                    plate: plate.into(),
+                    direction: Some("forward".into()),
+                    lane: None,
+                }),
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: identifier.clone(),
+            }),
+        };
+        let events = |messages: Vec<MqttMessage>| -> Vec<MqttMessage> {
+            messages
+                .into_iter()
+                .filter(|m| m.topic.ends_with("/event"))
+                .collect()
+        };
+        let mut published = events(manager.next_event(alert(&face, None)));
+        published.append(&mut events(
+            manager.next_event(alert(&anpr, Some("AB12CDE"))),
+        ));
+        // Another vehicle while the trigger is still alerting
+        published.append(&mut events(
+            manager.next_event(alert(&anpr, Some("XY34ZZZ"))),
+        ));
+        // Repeated posts of the same alert aren't new occurrences
+        assert!(events(manager.next_event(alert(&anpr, Some("XY34ZZZ")))).is_empty());
+        insta::assert_yaml_snapshot!(published, {
+            "[].**.timestamp" => "[timestamp]",
+        });
+    }
+
+    #[test]
+    fn test_reboot() {
+        let mut cams = sample_cameras();
+        cams[0].expose_controls = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let topic = "hikvision_cameras/device_cam1/command";
+        assert_eq!(
+            manager.parse_command(topic, b"REBOOT\n"),
+            Some(("cam1".to_string(), CameraCommand::Reboot))
+        );
+        assert_eq!(manager.parse_command(topic, b"reboot"), None);
+        // Only cameras with controls exposed can be rebooted
+        assert_eq!(
+            manager.parse_command("hikvision_cameras/device_cam2/command", b"REBOOT"),
+            None
+        );
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let reboot_discovery = "homeassistant/button/hiksink/device_cam1_reboot/config";
+        insta::assert_yaml_snapshot!(messages.iter().find(|m| m.topic == reboot_discovery));
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::RebootFailed {
+                error: "Camera returned 403 Forbidden".into(),
+            },
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.since" => "[since]",
+        });
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Rebooting { retry_in_secs: 30 },
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.since" => "[since]",
+        });
+        let status = manager.status(true, None);
+        assert!(!status.cameras[0].connected);
+        assert!(status.cameras[0].running);
+    }
+
+    #[test]
+    fn test_ptz_presets() {
+        let mut cams = sample_cameras();
+        cams[0].expose_controls = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let topic = "hikvision_cameras/device_cam1/ptz/preset/set";
+        assert_eq!(
+            manager.parse_command(topic, b"Gate"),
+            Some(("cam1".to_string(), CameraCommand::GotoPreset("Gate".into())))
+        );
+        assert_eq!(manager.parse_command(topic, b" "), None);
+        assert_eq!(
+            manager.parse_command("hikvision_cameras/device_cam2/ptz/preset/set", b"1"),
+            None
+        );
+
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let presets = vec![
+            PtzPreset {
+                id: "1".into(),
+                name: "Driveway".into(),
+            },
+            PtzPreset {
+                id: "2".into(),
+                name: "Gate".into(),
+            },
+        ];
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::PtzPresets(presets.clone()),
+        });
+        insta::assert_yaml_snapshot!(messages);
+        // Nothing changed on reconnecting
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::PtzPresets(presets),
+        });
+        assert!(messages.is_empty());
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::PresetFailed {
+                preset: "Garden".into(),
+                error: "Camera has no preset \"Garden\"".into(),
+            },
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.since" => "[since]",
+        });
+    }
+
+    #[test]
+    fn test_controls() {
+        let mut cams = sample_cameras();
+        cams[0].expose_controls = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let connected = || CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        };
+        let control = |enabled| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Control(ControlState {
+                control: ControlType::MotionDetection,
+                channel: "1".into(),
+                enabled,
+            }),
+        };
+        manager.next_event(connected());
+        // A new control is announced along with its state
+        let messages = manager.next_event(control(true));
+        insta::assert_yaml_snapshot!(messages);
+
+        let set_topic = "hikvision_cameras/device_cam1/ch1/motion_detection/set";
+        assert_eq!(
+            manager.parse_command(set_topic, b"OFF"),
+            Some((
+                "cam1".to_string(),
+                CameraCommand::SetControl {
+                    control: ControlType::MotionDetection,
+                    channel: "1".into(),
+                    enabled: false,
+                }
+            ))
+        );
+        assert_eq!(manager.parse_command(set_topic, b"toggle"), None);
+        assert_eq!(
+            manager.parse_command(
+                "hikvision_cameras/device_cam1/ch2/motion_detection/set",
+                b"ON"
+            ),
+            None
+        );
+
+        // Only the state is published for a control that's already known
+        let messages = manager.next_event(control(false));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, MqttPayload::from("OFF"));
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Control(ControlState {
+                control: ControlType::AlarmOutput,
+                channel: "1".into(),
+                enabled: false,
+            }),
+        });
+        insta::assert_yaml_snapshot!(messages);
+        assert_eq!(
+            manager.parse_command("hikvision_cameras/device_cam1/alarm_output/1/set", b"ON"),
+            Some((
+                "cam1".to_string(),
+                CameraCommand::SetControl {
+                    control: ControlType::AlarmOutput,
+                    channel: "1".into(),
+                    enabled: true,
+                }
+            ))
+        );
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Control(ControlState {
+                control: ControlType::SupplementLight,
+                channel: "1".into(),
+                enabled: true,
+            }),
+        });
+        insta::assert_yaml_snapshot!(messages);
+        assert_eq!(
+            manager.parse_command(
+                "hikvision_cameras/device_cam1/ch1/supplement_light/set",
+                b"OFF"
+            ),
+            Some((
+                "cam1".to_string(),
+                CameraCommand::SetControl {
+                    control: ControlType::SupplementLight,
+                    channel: "1".into(),
+                    enabled: false,
+                }
+            ))
+        );
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::ControlFailed {
+                control: ControlType::MotionDetection,
+                channel: "1".into(),
+                error: "Camera returned an unexpected status code: 403 Forbidden".into(),
+            },
+        });
+        assert_eq!(messages[0].topic, "hikvision_cameras/device_cam1/log");
+        assert_eq!(manager.cameras[0].log.state, ConnectionState::Connected);
+        assert_eq!(
+            manager.cameras[0].log.detail,
+            "Unable to change CH1 Motion Detection: Camera returned an unexpected status code: 403 Forbidden"
+        );
+
+        // Turning the option off removes the switches and the reboot button
+        let mut disabled = cams[0].clone();
+        disabled.expose_controls = false;
+        manager.apply_config(vec![disabled]);
+        let deleted: Vec<_> = manager
+            .next_event(connected())
+            .into_iter()
+            .filter(|m| m.payload == MqttPayload::from(""))
+            .map(|m| m.topic)
+            .collect();
+        assert_eq!(
+            deleted,
+            vec![
+                "hikvision_cameras/device_cam1/alarm_output/1",
+                "hikvision_cameras/device_cam1/ch1/motion_detection",
+                "hikvision_cameras/device_cam1/ch1/supplement_light",
+                "homeassistant/button/hiksink/device_cam1_reboot/config",
+                "homeassistant/switch/hiksink/device_cam1_alarm_output_1/config",
+                "homeassistant/switch/hiksink/device_cam1_ch1_motion_detection/config",
+                "homeassistant/switch/hiksink/device_cam1_ch1_supplement_light/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_camera_reconnect_keeps_state() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let trigger2: TriggerItem = EventIdentifier::new(Some("1".into()), EventType::Io).into();
+        let connected = |triggers: Vec<TriggerItem>| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers,
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        };
+        manager.next_event(connected(vec![trigger1.clone()]));
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "2021-07-02T14:25:36+08:00".to_string(),
+                time: Some(DateTime::parse_from_rfc3339("2021-07-02T14:25:36+08:00").unwrap()),
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier.clone(),
+            }),
+        });
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Disconnected {
+                error: "Camera closed connection".into(),
+                kind: DisconnectKind::Closed,
+                retry_in_secs: Some(0),
+            },
+        });
+
+        // Reconnect with an extra trigger
+        let messages = manager.next_event(connected(vec![trigger1, trigger2]));
+        insta::assert_yaml_snapshot!(manager, {
+            ".cameras[].log.since" => "[since]",
+            ".cameras[].errors[].time" => "[time]",
+        });
+        insta::assert_yaml_snapshot!(messages
+            .iter()
+            .filter(|m| m.topic.starts_with("hikvision_cameras/device_cam1/ch1/"))
+            .collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_stale_trigger_cleanup() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let trigger2: TriggerItem = EventIdentifier::new(Some("1".into()), EventType::Io).into();
+        let connected = |triggers: Vec<TriggerItem>| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers,
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        };
+        let messages = manager.next_event(connected(vec![trigger1.clone(), trigger2]));
+        assert!(!messages.iter().any(|m| m.payload == "".into()));
+
+        // The IO trigger disappears after a firmware update
+        let messages = manager.next_event(connected(vec![trigger1]));
+        let deleted: Vec<_> = messages
+            .into_iter()
+            .filter(|m| m.payload == "".into())
+            .collect();
+        assert_eq!(
+            deleted,
+            vec![
+                MqttMessage::delete("hikvision_cameras/device_cam1/ch1/Io".into()),
+                MqttMessage::delete(
+                    "homeassistant/binary_sensor/hiksink/device_cam1_ch1_Io/config".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_triggers_updated() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        let motion: TriggerItem = EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let io: TriggerItem = EventIdentifier::new(Some("1".into()), EventType::Io).into();
+        let line: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::LineDetection).into();
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![motion.clone(), io.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "2021-07-02T14:25:36+08:00".to_string(),
+                time: Some(DateTime::parse_from_rfc3339("2021-07-02T14:25:36+08:00").unwrap()),
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: motion.identifier.clone(),
+            }),
+        });
+
+        // Line detection is enabled and the IO trigger disabled in the camera's web interface
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::TriggersUpdated {
+                added: vec![line],
+                removed: vec![io.identifier],
+            },
+        });
+        let topics: Vec<_> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "hikvision_cameras/device_cam1/ch1/LineDetection",
+                "homeassistant/binary_sensor/hiksink/device_cam1_ch1_LineDetection/config",
+                "hikvision_cameras/device_cam1/ch1/Io",
+                "homeassistant/binary_sensor/hiksink/device_cam1_ch1_Io/config",
+            ]
+        );
+        assert_eq!(messages[2], MqttMessage::delete(topics[2].into()));
+        assert_eq!(messages[3], MqttMessage::delete(topics[3].into()));
+
+        // The motion alert in progress is unaffected
+        let triggers = &manager.cameras[0].triggers;
+        assert_eq!(triggers.len(), 2);
+        assert!(triggers[0].alerting);
+        assert_eq!(
+            triggers[1].trigger.identifier.event_type,
+            EventType::LineDetection
+        );
+    }
+
+    #[test]
+    fn test_auto_register_triggers() {
+        let mut cams = sample_cameras();
+        cams[0].auto_register_triggers = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let motion: TriggerItem = EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let connected = CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![motion],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        };
+        manager.next_event(connected.clone());
+
+        let pir = EventIdentifier::new(Some("1".into()), EventType::Unknown("pir".into()));
+        let alert = |identifier: &EventIdentifier, active: bool| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: identifier.clone(),
+            }),
+        };
+        let topics = |messages: Vec<MqttMessage>| -> Vec<String> {
+            messages
+                .into_iter()
+                .map(|m| m.topic)
+                .filter(|t| t.contains("pir"))
+                .collect()
+        };
+
+        // The first alert creates the trigger
+        let messages = manager.next_event(alert(&pir, true));
+        assert_eq!(
+            topics(messages),
+            vec![
+                "homeassistant/binary_sensor/hiksink/device_cam1_ch1_pir/config",
+                "hikvision_cameras/device_cam1/ch1/pir",
+            ]
+        );
+
+        // Later alerts only update the state
+        let messages = manager.next_event(alert(&pir, false));
+        assert_eq!(
+            topics(messages),
+            vec!["hikvision_cameras/device_cam1/ch1/pir"]
+        );
+
+        // Reconnecting keeps the trigger even though it still isn't in the scan
+        let messages = manager.next_event(connected);
+        assert!(!messages.iter().any(|m| m.payload == "".into()));
+        assert_eq!(manager.cameras[0].triggers.len(), 2);
+
+        // Video loss stays excluded
+        let video_loss = EventIdentifier::new(Some("1".into()), EventType::VideoLoss);
+        assert!(manager.next_event(alert(&video_loss, true)).is_empty());
+        assert_eq!(manager.cameras[0].triggers.len(), 2);
+    }
+
+    #[test]
+    fn test_only_notifying_triggers() {
+        let mut cams = sample_cameras();
+        cams[0].only_notifying_triggers = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let triggers = TriggerItem::parse(include_str!("../../samples/triggers_cam.xml")).unwrap();
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers,
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let published: Vec<_> = manager.cameras[0]
+            .triggers
+            .iter()
+            .map(|t| t.trigger.identifier.to_string())
+            .collect();
+        insta::assert_yaml_snapshot!(published);
+    }
+
+    #[test]
+    fn test_stale_camera_cleanup() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let mut previous = PersistedState::default();
+        previous.retained_topics.insert(
+            "old_camera".into(),
+            [
+                "hikvision_cameras/device_old_camera/availability".to_string(),
+                "homeassistant/binary_sensor/hiksink/device_old_camera_ch1_Motion/config"
+                    .to_string(),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        manager.restore(previous);
+
+        let deleted: Vec<_> = manager
+            .mqtt_connection_established(0, false)
+            .into_iter()
+            .filter(|m| m.payload == "".into())
+            .collect();
+        insta::assert_yaml_snapshot!(deleted);
+        // The removal is remembered
+        let later = Utc::now() + Duration::seconds(STATE_SAVE_INTERVAL_SECS);
+        assert_eq!(
+            manager
+                .persisted_state_if_changed(later)
+                .map(|state| state.retained_topics),
+            Some(Default::default())
+        );
+        assert_eq!(manager.persisted_state_if_changed(later), None);
+    }
+
+    #[test]
+    fn test_retain_state() {
+        let cams = sample_cameras();
+        let publishing = MqttPublishing {
+            retain_state: false,
+            retain_state_events: [("io".to_string(), true)].into_iter().collect(),
+            ..MqttPublishing::default()
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            publishing,
+        );
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    EventIdentifier::new(Some("1".into()), EventType::Motion).into(),
+                    EventIdentifier::new(Some("1".into()), EventType::Io).into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let retained: Vec<_> = manager
+            .mqtt_connection_established(0, false)
+            .into_iter()
+            .map(|m| (m.topic, m.retain))
+            .collect();
+        insta::assert_yaml_snapshot!(retained);
+    }
+
+    #[test]
+    fn test_qos_per_class() {
+        let cams = sample_cameras();
+        let config = ConfigMqttQoS {
+            state: QoSLevel(0),
+            availability: QoSLevel(1),
+            discovery: QoSLevel(2),
+            stats: QoSLevel(0),
+        };
+        let publishing = MqttPublishing {
+            qos: (&config).into(),
+            ..MqttPublishing::default()
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            publishing,
+        );
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let qos: Vec<_> = manager
+            .mqtt_connection_established(0, false)
+            .into_iter()
+            .map(|m| (m.topic, m.qos))
+            .collect();
+        insta::assert_yaml_snapshot!(qos);
+        assert_eq!(manager.mqtt_lwt().qos, MqttQoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn test_message_expiry() {
+        let cams = sample_cameras();
+        let publishing = MqttPublishing {
+            plain_state_topics: true,
+            message_expiry_secs: Some(300),
+            ..MqttPublishing::default()
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            publishing,
+        );
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![EventIdentifier::new(Some("1".into()), EventType::Motion).into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        // Only the trigger states expire
+        let expiring: Vec<_> = messages
+            .iter()
+            .filter(|m| m.expiry_secs.is_some())
+            .map(|m| (m.topic.as_str(), m.expiry_secs))
+            .collect();
+        insta::assert_yaml_snapshot!(expiring);
+    }
+
+    #[test]
+    fn test_plain_state_topics() {
+        let cams = sample_cameras();
+        let publishing = MqttPublishing {
+            plain_state_topics: true,
+            plain_state_payload_on: "detected".into(),
+            ..MqttPublishing::default()
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            publishing,
+        );
+        let trigger: TriggerItem = EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let messages: Vec<_> = messages
+            .into_iter()
+            .filter(|m| m.topic.contains("Motion"))
+            .collect();
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.sw_version" => "[sw_version]"
+        });
+
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                identifier: trigger.identifier,
+                active: true,
+                regions: Vec::new(),
+                post_count: 1,
+                description: "Motion alarm".into(),
+                date: "2021-08-30T21:19:00+10:00".into(),
+                time: None,
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+            }),
+        });
+        let plain: Vec<_> = messages
+            .into_iter()
+            .filter(|m| m.topic.ends_with("/state"))
+            .collect();
+        assert_eq!(
+            plain,
+            vec![MqttMessage::new(
+                "hikvision_cameras/device_cam1/ch1/Motion/state".into(),
+                MqttQoS::AtLeastOnce,
+                true,
+                "detected",
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_failure() {
+        let cams = sample_cameras();
+        let failure = || CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::ParseFailure {
+                error: "Missing field: eventType".into(),
+                raw: "<EventNotificationAlert>ü</EventNotificationAlert>".into(),
+            },
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        assert!(manager.next_event(failure()).is_empty());
+
+        let publishing = MqttPublishing {
+            publish_parse_failures: true,
+            parse_failure_max_bytes: 25,
+            ..MqttPublishing::default()
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            publishing,
+        );
+        let messages = manager.next_event(failure());
+        // Truncated before the multi-byte character rather than through it
+        insta::assert_yaml_snapshot!(messages);
+    }
+
+    #[test]
+    fn test_regions_topic() {
+        let cams = sample_cameras();
+        let publishing = MqttPublishing {
+            regions_topic: true,
+            ..MqttPublishing::default()
+        };
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            publishing,
+        );
+        let trigger: TriggerItem = EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let discovery = messages
+            .iter()
+            .find(|m| m.topic.ends_with("/config"))
+            .unwrap();
+        match &discovery.payload {
+            MqttPayload::Json(json) => assert_eq!(
+                json["json_attributes_topic"],
+                "hikvision_cameras/device_cam1/ch1/Motion/regions"
+            ),
+            _ => panic!("Discovery should be JSON"),
+        }
+
+        let alert = |x: u32| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![DetectionRegion {
+                    id: "0".into(),
+                    sensitivity: 50,
+                    coordinates: vec![RegionCoordinates { x, y: 600 }],
+                    target: None,
+                }],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger.identifier.clone(),
+            }),
+        };
+        // Becoming active publishes both
+        let messages = manager.next_event(alert(425));
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+        // Only the regions changed, so the state isn't republished
+        let messages: Vec<_> = manager
+            .next_event(alert(100))
+            .into_iter()
+            .map(|m| m.topic)
+            .collect();
+        assert_eq!(
+            messages,
+            vec!["hikvision_cameras/device_cam1/ch1/Motion/regions"]
+        );
+        // Nothing changed
+        assert!(manager.next_event(alert(100)).is_empty());
+    }
+
+    #[test]
+    fn test_diagnostic_discovery() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let discovery: Vec<_> = manager
+            .home_assistant_online()
+            .into_iter()
+            .filter(|m| m.topic.contains("_log/") || m.topic.contains("_connectivity/"))
+            .collect();
+        insta::assert_yaml_snapshot!(discovery, {
+            "[].**.sw_version" => "[sw_version]"
+        });
+    }
+
+    #[test]
+    fn test_fire_detection_discovery() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    EventIdentifier::new(Some("2".into()), EventType::FireDetection).into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let discovery: Vec<_> = messages
+            .into_iter()
+            .filter(|m| m.topic.ends_with("_ch2_FireDetection/config"))
+            .collect();
+        insta::assert_yaml_snapshot!(discovery, {
+            "[].**.sw_version" => "[sw_version]"
+        });
+    }
+
+    #[test]
+    fn test_anpr_plates() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let anpr = EventIdentifier::new(Some("1".into()), EventType::Anpr);
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![anpr.clone().into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let alert = |plate: &str| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: Some(AnprDetails {
+                    plate: plate.into(),
+                    direction: Some("forward".into()),
+                    lane: None,
+                }),
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: anpr.clone(),
+            }),
+        };
+        let plates = |messages: Vec<MqttMessage>| -> Vec<serde_json::Value> {
+            messages
+                .into_iter()
+                .filter(|m| m.topic == "hikvision_cameras/device_cam1/ch1/Anpr")
+                .map(|m| match m.payload {
+                    MqttPayload::Json(json) => json["plate"].clone(),
+                    _ => panic!("State should be JSON"),
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            plates(manager.next_event(alert("AB12CDE"))),
+            vec!["AB12CDE"]
+        );
+        // Another vehicle while the trigger is still alerting
+        assert_eq!(
+            plates(manager.next_event(alert("XY34ZZZ"))),
+            vec!["XY34ZZZ"]
+        );
+        // The same plate again is not a change
+        assert!(plates(manager.next_event(alert("XY34ZZZ"))).is_empty());
+    }
+
+    #[test]
+    fn test_smart_and_health_event_discovery() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: [
+                    EventType::Loitering,
+                    EventType::Parking,
+                    EventType::FallDetection,
+                    EventType::HddSmart,
+                    EventType::CrowdDensity,
+                    EventType::Intelligent,
+                    EventType::Unknown("diskUnformatted".into()),
+                ]
+                .into_iter()
+                .map(|event_type| EventIdentifier::new(Some("1".into()), event_type).into())
+                .collect(),
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let discovery: Vec<_> = messages
+            .into_iter()
+            .filter(|m| {
+                m.topic
+                    .starts_with("homeassistant/binary_sensor/hiksink/device_cam1_ch1_")
+            })
+            .map(|m| match m.payload {
+                MqttPayload::Json(json) => {
+                    serde_json::json!([json["name"], json["device_class"], json["icon"]])
+                }
+                _ => panic!("Discovery should be JSON"),
+            })
+            .collect();
+        insta::assert_yaml_snapshot!(discovery);
+    }
+
+    #[test]
+    fn test_event_type_overrides() {
+        let cams = sample_cameras();
+        let mut event_types = HashMap::new();
+        event_types.insert(
+            "IO".to_string(),
+            ConfigEventType {
+                name: Some("Doorbell".into()),
+                device_class: Some("sound".into()),
+                icon: Some("mdi:doorbell".into()),
+                ha_entity: None,
+            },
+        );
+        event_types.insert(
+            "mixedTargetDetection".to_string(),
+            ConfigEventType {
+                name: Some("Smart Detection".into()),
+                ..ConfigEventType::default()
+            },
+        );
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing {
+                event_types,
+                ..MqttPublishing::default()
+            },
+        );
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    EventIdentifier::new(Some("1".into()), EventType::Io).into(),
+                    EventIdentifier::new(
+                        Some("1".into()),
+                        EventType::Unknown("mixedtargetdetection".into()),
+                    )
+                    .into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let discovery: Vec<_> = messages
+            .into_iter()
+            .filter(|m| {
+                m.topic
+                    .starts_with("homeassistant/binary_sensor/hiksink/device_cam1_ch1_")
+            })
+            .map(|m| match m.payload {
+                MqttPayload::Json(json) => {
+                    serde_json::json!([json["name"], json["device_class"], json["icon"]])
+                }
+                _ => panic!("Discovery should be JSON"),
+            })
+            .collect();
+        assert_eq!(
+            discovery,
+            vec![
+                serde_json::json!(["Camera 1 CH1 Doorbell", "sound", "mdi:doorbell"]),
+                // Built-in metadata is kept for anything not overridden
+                serde_json::json!(["Camera 1 CH1 Smart Detection", "motion", null]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_people_counting() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let counting = EventIdentifier::new(Some("1".into()), EventType::PeopleCounting);
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![counting.clone().into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        // Counting triggers are published as sensors instead of a binary sensor
+        let discovery: Vec<_> = messages
+            .into_iter()
+            .filter(|m| m.topic.contains("PeopleCounting") && m.topic.ends_with("/config"))
+            .collect();
+        insta::assert_yaml_snapshot!(discovery, {
+            "[].**.sw_version" => "[sw_version]"
+        });
+
+        let alert = |enter: u64| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: Some(PeopleCount {
+                    enter: Some(enter),
+                    exit: Some(3),
+                    pass: None,
+                }),
+                device_serial: None,
+                channel_name: None,
+                identifier: counting.clone(),
+            }),
+        };
+        let states = |messages: Vec<MqttMessage>| -> Vec<serde_json::Value> {
+            messages
+                .into_iter()
+                .filter(|m| m.topic == "hikvision_cameras/device_cam1/ch1/PeopleCounting")
+                .map(|m| match m.payload {
+                    MqttPayload::Json(json) => {
+                        serde_json::json!([json["enter"], json["exit"], json["pass"]])
+                    }
+                    _ => panic!("State should be JSON"),
+                })
+                .collect()
+        };
+        assert_eq!(
+            states(manager.next_event(alert(5))),
+            vec![serde_json::json!([5, 3, null])]
+        );
+        // New counts are published while the trigger stays active
+        assert_eq!(
+            states(manager.next_event(alert(6))),
+            vec![serde_json::json!([6, 3, null])]
+        );
+        assert!(states(manager.next_event(alert(6))).is_empty());
+    }
+
+    #[test]
+    fn test_events_stale() {
+        let mut cams = sample_cameras();
+        cams[0].stale_after_secs = Some(600);
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let stale: Vec<_> = messages
+            .into_iter()
+            .filter(|m| m.topic.contains("events_stale"))
+            .collect();
+        insta::assert_yaml_snapshot!(stale, {
+            "[].**.sw_version" => "[sw_version]"
+        });
+
+        let topic = "hikvision_cameras/device_cam1/events_stale";
+        let stale = |messages: Vec<MqttMessage>| -> Vec<MqttPayload> {
+            messages
+                .into_iter()
+                .filter(|m| m.topic == topic)
+                .map(|m| m.payload)
+                .collect()
+        };
+        let now = Utc::now();
+        assert!(stale(manager.tick(now + Duration::seconds(300))).is_empty());
+        assert_eq!(
+            stale(manager.tick(now + Duration::seconds(601))),
+            vec![MqttPayload::from("ON")]
+        );
+        assert!(stale(manager.tick(now + Duration::seconds(700))).is_empty());
+
+        // Video loss isn't published, but still shows the camera is alive
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: false,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: EventIdentifier::new(Some("1".into()), EventType::VideoLoss),
+            }),
+        });
+        assert_eq!(stale(messages), vec![MqttPayload::from("OFF")]);
+
+        // Cameras without the setting aren't watched
+        assert!(!manager
+            .tick(now + Duration::seconds(10_000))
+            .iter()
+            .any(|m| m.topic.contains("device_cam2") && m.topic.contains("events_stale")));
+    }
+
+    #[test]
+    fn test_availability_grace() {
+        let cams = sample_cameras();
+        let mut system = sample_system();
+        system.availability_grace_secs = 30;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            system,
+            MqttPublishing::default(),
+        );
+        let connected = || CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        };
+        let disconnected = || CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Disconnected {
+                error: "Connection reset".to_string(),
+                kind: DisconnectKind::Network,
+                retry_in_secs: Some(1),
+            },
+        };
+        let payloads = |messages: &[MqttMessage], topic: &str| -> Vec<MqttPayload> {
+            messages
+                .iter()
+                .filter(|m| m.topic == topic)
+                .map(|m| m.payload.clone())
+                .collect()
+        };
+        let availability = "hikvision_cameras/device_cam1/availability";
+        let reconnects = "hikvision_cameras/device_cam1/reconnect_count";
+        let log = "hikvision_cameras/device_cam1/log";
+
+        let messages = manager.next_event(connected());
+        assert_eq!(
+            payloads(&messages, availability),
+            vec![MqttPayload::from("online")]
+        );
+        assert_eq!(
+            payloads(&messages, reconnects),
+            vec![MqttPayload::from("0")]
+        );
+
+        // Reconnecting within the grace period only shows in the log
+        let now = Utc::now();
+        let messages = manager.next_event(disconnected());
+        assert!(payloads(&messages, availability).is_empty());
+        assert_eq!(payloads(&messages, log).len(), 1);
+        assert!(payloads(&manager.tick(now + Duration::seconds(10)), availability).is_empty());
+        let messages = manager.next_event(connected());
+        assert_eq!(
+            payloads(&messages, availability),
+            vec![MqttPayload::from("online")]
+        );
+        assert_eq!(
+            payloads(&messages, reconnects),
+            vec![MqttPayload::from("1")]
+        );
+        assert!(payloads(&manager.tick(now + Duration::seconds(40)), availability).is_empty());
+
+        // Retries failing during the grace period don't extend it
+        let now = Utc::now();
+        manager.next_event(disconnected());
+        let messages = manager.next_event(disconnected());
+        assert!(payloads(&messages, availability).is_empty());
+        assert!(payloads(&manager.tick(now + Duration::seconds(29)), availability).is_empty());
+        assert_eq!(
+            payloads(&manager.tick(now + Duration::seconds(31)), availability),
+            vec![MqttPayload::from("offline")]
+        );
+        assert!(payloads(&manager.tick(now + Duration::seconds(60)), availability).is_empty());
+
+        // Once offline, further errors are published straight away
+        let messages = manager.next_event(disconnected());
+        assert_eq!(
+            payloads(&messages, availability),
+            vec![MqttPayload::from("offline")]
+        );
+        let messages = manager.next_event(connected());
+        assert_eq!(
+            payloads(&messages, reconnects),
+            vec![MqttPayload::from("2")]
+        );
+    }
+
+    #[test]
+    fn test_disabled_camera() {
+        let mut cams = sample_cameras();
+        cams[0].enabled = false;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let payload = |messages: &[MqttMessage], topic: &str| -> Option<MqttPayload> {
+            messages
+                .iter()
+                .find(|m| m.topic == format!("hikvision_cameras/device_cam1/{}", topic))
+                .map(|m| m.payload.clone())
+        };
+        let log = |messages: &[MqttMessage]| match payload(messages, "log") {
+            Some(MqttPayload::Json(log)) => (log["state"].clone(), log["detail"].clone()),
+            other => panic!("Unexpected log {:?}", other),
+        };
+        let offline = Some(MqttPayload::from("offline"));
+        let connected = CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        };
+
+        let messages = manager.mqtt_connection_established(0, false);
+        assert_eq!(
+            log(&messages),
+            ("disabled".into(), "Disabled in configuration".into())
+        );
+        assert_eq!(payload(&messages, "availability"), offline);
+        let status = manager.status(true, None);
+        assert!(!status.cameras[0].running);
+        assert_eq!(status.cameras[0].error, None);
+        // Events still queued from before it was disabled are ignored
+        assert!(manager.next_event(connected.clone()).is_empty());
+
+        cams[0].enabled = true;
+        let messages = manager.apply_config(cams.clone());
+        assert_eq!(log(&messages).0, "connecting");
+        assert!(manager.status(true, None).cameras[0].running);
+        manager.next_event(connected);
+
+        cams[0].enabled = false;
+        let messages = manager.apply_config(cams.clone());
+        assert_eq!(log(&messages).0, "disabled");
+        assert_eq!(payload(&messages, "availability"), offline);
+        // The camera's entities are kept
+        assert!(!messages.iter().any(|m| m.payload == MqttPayload::from("")));
+    }
+
+    #[test]
+    fn test_fallback_address_log() {
+        let mut cams = sample_cameras();
+        cams[0].fallback_address = Some("10.0.0.2".to_string());
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://10.0.0.2".into(),
+            },
+        });
+        let log = messages
+            .iter()
+            .find(|m| m.topic == "hikvision_cameras/device_cam1/log")
+            .unwrap();
+        match &log.payload {
+            MqttPayload::Json(log) => assert_eq!(log["detail"], "Connected to http://10.0.0.2"),
+            other => panic!("Unexpected log payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_debounce() {
+        let cams = sample_cameras();
+        let mut system = sample_system();
+        system.debounce.insert(
+            "FieldDetection".to_string(),
+            ConfigDebounce {
+                min_off_delay_secs: Some(10),
+                min_publish_interval_secs: Some(60),
+            },
+        );
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            system.clone(),
+            MqttPublishing::default(),
+        );
+        let field = EventIdentifier::new(Some("1".into()), EventType::FieldDetection);
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![field.clone().into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let alert = |active, region: Option<&str>| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: region
+                    .map(|id| DetectionRegion {
+                        id: id.to_string(),
+                        sensitivity: 50,
+                        coordinates: vec![],
+                        target: None,
+                    })
+                    .into_iter()
+                    .collect(),
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: field.clone(),
+            }),
+        };
+        let topic = "hikvision_cameras/device_cam1/ch1/FieldDetection";
+        let states = |messages: Vec<MqttMessage>| -> Vec<serde_json::Value> {
+            messages
+                .into_iter()
+                .filter(|m| m.topic == topic)
+                .map(|m| match m.payload {
+                    MqttPayload::Json(json) => json,
+                    _ => panic!("State should be JSON"),
+                })
+                .collect()
+        };
+        let regions = |state: &serde_json::Value| -> Vec<String> {
+            state["regions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // Flapping is only published once it settles
+        let now = Utc::now();
+        let published = states(manager.next_event(alert(true, Some("1"))));
+        assert_eq!(published[0]["alerting"], true);
+        assert!(states(manager.next_event(alert(false, None))).is_empty());
+        assert!(states(manager.next_event(alert(true, Some("1")))).is_empty());
+        assert!(states(manager.next_event(alert(false, None))).is_empty());
+        assert!(states(manager.tick(now + Duration::seconds(5))).is_empty());
+        let published = states(manager.tick(now + Duration::seconds(11)));
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0]["alerting"], false);
+        assert!(regions(&published[0]).is_empty());
+        assert!(states(manager.tick(now + Duration::seconds(20))).is_empty());
+        assert!(states(manager.next_event(alert(false, None))).is_empty());
+
+        // Region changes while alerting are coalesced, and the last ones published
+        let now = Utc::now();
+        let published = states(manager.next_event(alert(true, Some("1"))));
+        assert_eq!(regions(&published[0]), vec!["1"]);
+        assert!(states(manager.next_event(alert(true, Some("2")))).is_empty());
+        assert!(states(manager.next_event(alert(true, Some("3")))).is_empty());
+        assert!(states(manager.tick(now + Duration::seconds(1))).is_empty());
+        let published = states(manager.tick(now + Duration::seconds(61)));
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0]["alerting"], true);
+        assert_eq!(regions(&published[0]), vec!["3"]);
+        assert!(states(manager.tick(now + Duration::seconds(120))).is_empty());
+
+        // The camera's settings take precedence, one option at a time
+        let mut cam = cams[0].clone();
+        cam.debounce.insert(
+            "fielddetection".to_string(),
+            ConfigDebounce {
+                min_off_delay_secs: Some(2),
+                min_publish_interval_secs: None,
+            },
+        );
+        let debounce = debounce_for(&system, &cam, &EventType::FieldDetection);
+        assert_eq!(debounce.off_delay, Some(Duration::seconds(2)));
+        assert_eq!(debounce.publish_interval, Some(Duration::seconds(60)));
+        let debounce = debounce_for(&system, &cam, &EventType::Motion);
+        assert_eq!(debounce.off_delay, None);
+        assert_eq!(debounce.publish_interval, None);
+    }
+
+    #[test]
+    fn test_missed_posts() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let motion = EventIdentifier::new(Some("1".into()), EventType::Motion);
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![motion.clone().into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let alert = |active, post_count| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: motion.clone(),
+            }),
+        };
+        let topic = "hikvision_cameras/device_cam1/ch1/Motion";
+        let states = |messages: Vec<MqttMessage>| -> Vec<serde_json::Value> {
+            messages
+                .into_iter()
+                .filter(|m| m.topic == topic)
+                .map(|m| match m.payload {
+                    MqttPayload::Json(json) => json,
+                    _ => panic!("State should be JSON"),
+                })
+                .collect()
+        };
+
+        let published = states(manager.next_event(alert(true, 1)));
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0]["post_count"], 1);
+        assert_eq!(published[0]["missed_posts"], 0);
+        // Re-posts only change the counters, which aren't published straight away
+        assert!(states(manager.next_event(alert(true, 2))).is_empty());
+        assert!(states(manager.next_event(alert(true, 5))).is_empty());
+        assert_eq!(manager.cameras[0].triggers[0].missed_posts, 2);
+
+        let now = Utc::now();
+        assert!(states(manager.tick(now)).is_empty());
+        let published = states(manager.tick(now + Duration::seconds(COUNTERS_INTERVAL_SECS)));
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0]["alerting"], true);
+        assert_eq!(published[0]["post_count"], 5);
+        assert_eq!(published[0]["missed_posts"], 2);
+        assert!(
+            states(manager.tick(now + Duration::seconds(COUNTERS_INTERVAL_SECS * 2))).is_empty()
+        );
+
+        // A new alert starts counting again
+        let published = states(manager.next_event(alert(false, 0)));
+        assert_eq!(published[0]["alerting"], false);
+        manager.next_event(alert(true, 1));
+        assert_eq!(manager.cameras[0].triggers[0].missed_posts, 2);
+
+        let stats = match manager.message_global_stats().payload {
+            MqttPayload::Json(json) => json,
+            _ => panic!("Stats should be JSON"),
+        };
+        assert_eq!(stats["missed_posts_total"], 2);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut cams = sample_cameras();
+        let mut nvr = cams[0].clone();
+        nvr.generated_id = "nvr".into();
+        nvr.name = "NVR".into();
+        cams.push(nvr);
+        let mut system = sample_system();
+        system.dedup = true;
+        let camera_motion = EventIdentifier::new(Some("1".into()), EventType::Motion);
+        let nvr_motion = EventIdentifier::new(Some("3".into()), EventType::Motion);
+        let camera_serial = sample_device_info().serial_number;
+
+        let setup = |cams: &[ConfigCamera], system: &ConfigSystem| {
+            let mut manager = Manager::new(
+                cams.to_vec(),
+                MqttTopics::default(),
+                system.clone(),
+                MqttPublishing::default(),
+            );
+            let mut nvr_info = sample_device_info();
+            nvr_info.serial_number = "DS-7608NI-K220180101CCWR000000000".into();
+            for (cam, info, trigger) in [
+                (&cams[0], sample_device_info(), &camera_motion),
+                (&cams[1], nvr_info, &nvr_motion),
+            ] {
+                manager.next_event(CameraEvent {
+                    id: cam.identifier().to_string(),
+                    event: CameraEventType::Connected {
+                        triggers: vec![trigger.clone().into()],
+                        info,
+                        address: "http://192.168.20.2".into(),
+                    },
+                });
+            }
+            manager
+        };
+        let alert = |id: &str, identifier: &EventIdentifier, serial: Option<&str>| CameraEvent {
+            id: id.to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: serial.map(|s| s.to_string()),
+                channel_name: None,
+                identifier: identifier.clone(),
+            }),
+        };
+
+        // Camera first, the NVR sends the camera's serial
+        let mut manager = setup(&cams, &system);
+        assert!(!manager
+            .next_event(alert("cam1", &camera_motion, Some(&camera_serial)))
+            .is_empty());
+        assert!(manager
+            .next_event(alert("nvr", &nvr_motion, Some(&camera_serial)))
+            .is_empty());
+        assert_eq!(manager.deduplicated, 1);
+        // The camera's repeats aren't duplicates of itself
+        manager.next_event(alert("cam1", &camera_motion, Some(&camera_serial)));
+        assert_eq!(manager.deduplicated, 1);
+        // Other channels of the NVR aren't affected
+        let other = EventIdentifier::new(Some("4".into()), EventType::Motion);
+        manager.next_event(alert(
+            "nvr",
+            &other,
+            Some("DS-2CD2185FWD-I20180101AAWR111111111"),
+        ));
+        assert_eq!(manager.deduplicated, 1);
+
+        // The event ends through the NVR first, the camera's own end isn't dropped
+        let mut manager = setup(&cams, &system);
+        manager.next_event(alert("cam1", &camera_motion, Some(&camera_serial)));
+        manager.next_event(alert("nvr", &nvr_motion, Some(&camera_serial)));
+        let inactive = |id: &str, identifier: &EventIdentifier| {
+            let mut event = alert(id, identifier, Some(&camera_serial));
+            if let CameraEventType::Alert(alert) = &mut event.event {
+                alert.active = false;
+            }
+            event
+        };
+        assert!(manager.next_event(inactive("nvr", &nvr_motion)).is_empty());
+        assert!(!manager
+            .next_event(inactive("cam1", &camera_motion))
+            .is_empty());
+        assert_eq!(manager.deduplicated, 2);
+
+        // NVR first, matched by config
+        cams[1].duplicates_of = [("3".to_string(), "cam1".to_string())].into();
+        let mut manager = setup(&cams, &system);
+        assert!(!manager
+            .next_event(alert("nvr", &nvr_motion, None))
+            .is_empty());
+        assert!(manager
+            .next_event(alert("cam1", &camera_motion, None))
+            .is_empty());
+        assert_eq!(manager.deduplicated, 1);
+        let stats = match manager.message_global_stats().payload {
+            MqttPayload::Json(json) => json,
+            _ => panic!("Stats should be JSON"),
+        };
+        assert_eq!(stats["deduplicated_total"], 1);
+
+        // Once the window has passed the other device's alert is used
+        manager.tick(Utc::now() + Duration::seconds(DEDUP_WINDOW_SECS));
+        assert!(!manager
+            .next_event(alert("cam1", &camera_motion, None))
+            .is_empty());
+
+        // Only the configured channel of the NVR is the camera
+        let mut manager = setup(&cams, &system);
+        manager.next_event(alert("cam1", &camera_motion, None));
+        manager.next_event(alert("nvr", &other, None));
+        assert_eq!(manager.deduplicated, 0);
+        manager.next_event(alert("nvr", &nvr_motion, None));
+        assert_eq!(manager.deduplicated, 1);
+
+        // Nothing is dropped unless enabled
+        system.dedup = false;
+        let mut manager = setup(&cams, &system);
+        manager.next_event(alert("nvr", &nvr_motion, None));
+        assert!(!manager
+            .next_event(alert("cam1", &camera_motion, None))
+            .is_empty());
+        assert_eq!(manager.deduplicated, 0);
+    }
+
+    #[test]
+    fn test_occupancy() {
+        let mut cams = sample_cameras();
+        cams[0].derive_occupancy = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let entrance = EventIdentifier::new(Some("1".into()), EventType::RegionEntrance);
+        let exiting = EventIdentifier::new(Some("1".into()), EventType::RegionExiting);
+        let connected = || CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![entrance.clone().into(), exiting.clone().into()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        };
+        let messages = manager.next_event(connected());
+        let occupancy: Vec<_> = messages
+            .into_iter()
+            .filter(|m| m.topic.contains("occupancy"))
+            .collect();
+        insta::assert_yaml_snapshot!(occupancy, {
+            "[].**.sw_version" => "[sw_version]"
+        });
+
+        let alert = |identifier: &EventIdentifier, active| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: identifier.clone(),
+            }),
+        };
+        let count = |messages: Vec<MqttMessage>| -> Vec<MqttPayload> {
+            messages
+                .into_iter()
+                .filter(|m| m.topic == "hikvision_cameras/device_cam1/ch1/occupancy")
+                .map(|m| m.payload)
+                .collect()
+        };
+        assert_eq!(
+            count(manager.next_event(alert(&entrance, true))),
+            vec![MqttPayload::from("1")]
+        );
+        // Only counted when the alert starts
+        assert!(count(manager.next_event(alert(&entrance, true))).is_empty());
+        manager.next_event(alert(&entrance, false));
+        assert_eq!(
+            count(manager.next_event(alert(&entrance, true))),
+            vec![MqttPayload::from("2")]
+        );
+        assert_eq!(
+            count(manager.next_event(alert(&exiting, true))),
+            vec![MqttPayload::from("1")]
+        );
+
+        // Kept while reconnecting
+        assert_eq!(
+            count(manager.next_event(connected())),
+            vec![MqttPayload::from("1")]
+        );
+
+        assert_eq!(
+            manager.reset_occupancy("hikvision_cameras/device_cam1/ch1/occupancy/reset"),
+            Some(vec![MqttMessage::new(
+                "hikvision_cameras/device_cam1/ch1/occupancy".into(),
+                MqttQoS::AtLeastOnce,
+                true,
+                "0"
+            )])
+        );
+        assert_eq!(
+            manager.reset_occupancy("hikvision_cameras/device_cam1/ch2/occupancy/reset"),
+            None
+        );
+        // Never counts below zero
+        manager.next_event(alert(&exiting, false));
+        assert_eq!(
+            count(manager.next_event(alert(&exiting, true))),
+            vec![MqttPayload::from("0")]
+        );
+    }
+
+    #[test]
+    fn test_display_name_overrides() {
+        let mut cams = sample_cameras();
+        cams[0].area = Some("Garden".into());
+        cams[0].trigger_names = [
+            ("1/linedetection".to_string(), "Driveway Line".to_string()),
+            ("io".to_string(), "Doorbell".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    EventIdentifier::new(Some("1".into()), EventType::LineDetection).into(),
+                    EventIdentifier::new(Some("1".into()), EventType::Motion).into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let discovery: Vec<_> = messages
+            .iter()
+            .filter(|m| {
+                m.topic
+                    .starts_with("homeassistant/binary_sensor/hiksink/device_cam1_ch1")
+            })
+            .filter_map(|m| match &m.payload {
+                MqttPayload::Json(json) => Some((
+                    json["name"].clone(),
+                    json["unique_id"].clone(),
+                    json["device"]["suggested_area"].clone(),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            discovery,
+            vec![
+                (
+                    "Driveway Line".into(),
+                    "device_cam1_ch1_LineDetection_hiksink".into(),
+                    "Garden".into()
+                ),
+                (
+                    "Camera 1 CH1 Motion".into(),
+                    "device_cam1_ch1_Motion_hiksink".into(),
+                    "Garden".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_channel_names() {
+        let mut cams = sample_cameras();
+        cams[0].name = "NVR".into();
+        cams[0].channels = [("5".to_string(), "Driveway".to_string())]
+            .into_iter()
+            .collect();
+        let manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let cam = &manager.cameras[0];
+        let trigger = |channel: &str, channel_name: Option<&str>| {
+            TriggerDetails::new(TriggerItem {
+                channel_name: channel_name.map(String::from),
+                ..EventIdentifier::new(Some(channel.into()), EventType::Motion).into()
+            })
+        };
+
+        // Configured names take precedence over the names from the NVR
+        assert_eq!(
+            trigger("5", Some("IPCamera 05")).display_name(cam),
+            "NVR Driveway Motion"
+        );
+        assert_eq!(
+            trigger("6", Some("Back Yard")).display_name(cam),
+            "NVR Back Yard Motion"
+        );
+        assert_eq!(trigger("7", None).display_name(cam), "NVR CH7 Motion");
+        // Topics stay on the channel number
+        assert_eq!(
+            &*MqttTopics::default()
+                .trigger_topics(
+                    cam,
+                    &EventIdentifier::new(Some("5".into()), EventType::Motion)
+                )
+                .state,
+            "hikvision_cameras/device_cam1/ch5/Motion"
+        );
+    }
+
+    #[test]
+    fn test_channel_name_from_alerts() {
+        let cams = sample_cameras();
+        let motion = EventIdentifier::new(Some("1".into()), EventType::Motion);
+        let alert = |active: bool, channel_name: Option<&str>| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active,
+                date: "2021-10-02T18:04:02+08:00".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: channel_name.map(String::from),
+                identifier: motion.clone(),
+            }),
+        };
+        let connect = |names_from_alerts: bool| {
+            let mut manager = Manager::new(
+                cams.clone(),
+                MqttTopics::default(),
+                sample_system(),
+                MqttPublishing {
+                    names_from_alerts,
+                    ..MqttPublishing::default()
+                },
+            );
+            manager.next_event(CameraEvent {
+                id: cams[0].identifier().to_string(),
+                event: CameraEventType::Connected {
+                    triggers: vec![motion.clone().into()],
+                    info: sample_device_info(),
+                    address: "http://192.168.20.2".into(),
+                },
+            });
+            manager
+        };
+        let discovery = |messages: &[MqttMessage]| -> Vec<MqttMessage> {
+            messages
+                .iter()
+                .filter(|m| m.topic.starts_with("homeassistant/"))
+                .cloned()
+                .collect()
+        };
+
+        // The name is only an attribute by default
+        let mut manager = connect(false);
+        let messages = manager.next_event(alert(true, Some("Driveway")));
+        assert!(discovery(&messages).is_empty());
+        let state = messages
+            .iter()
+            .find(|m| m.topic == "hikvision_cameras/device_cam1/ch1/Motion")
+            .unwrap();
+        insta::assert_yaml_snapshot!(state, {
+            ".**.last_alert" => "[last_alert]",
+        });
+        // Learning the name isn't a change of state
+        assert!(manager.next_event(alert(true, Some("Driveway"))).is_empty());
+        let cam = &manager.cameras[0];
+        assert_eq!(cam.triggers[0].display_name(cam), "Camera 1 CH1 Motion");
+
+        // Unnamed channels are renamed once, without waiting for the state to change
+        let mut manager = connect(true);
+        let messages = manager.next_event(alert(false, Some("Driveway")));
+        let renamed = discovery(&messages);
+        assert!(!renamed.is_empty());
+        assert!(renamed.iter().all(|m| match &m.payload {
+            MqttPayload::Json(json) => json["name"]
+                .as_str()
+                .is_none_or(|name| name.starts_with("Camera 1 Driveway")),
+            _ => true,
+        }));
+        assert!(discovery(&manager.next_event(alert(true, Some("Porch")))).is_empty());
+        let cam = &manager.cameras[0];
+        assert_eq!(
+            cam.triggers[0].display_name(cam),
+            "Camera 1 Driveway Motion"
+        );
+    }
+
+    #[test]
+    fn test_info_updated() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let motion = EventIdentifier::new(Some("1".into()), EventType::Motion);
+        let event = |event: CameraEventType| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event,
+        };
+        let connected = manager.next_event(event(CameraEventType::Connected {
+            triggers: vec![motion.clone().into()],
+            info: sample_device_info(),
+            address: "http://192.168.20.2".into(),
+        }));
+        manager.next_event(event(CameraEventType::Alert(AlertItem {
+            active: true,
+            date: "2021-10-02T18:04:02+08:00".to_string(),
+            time: None,
+            description: "".to_string(),
+            post_count: 1,
+            regions: vec![],
+            target: None,
+            anpr: None,
+            people_counting: None,
+            device_serial: None,
+            channel_name: None,
+            identifier: motion,
+        })));
+
+        let upgraded = DeviceInfo {
+            firmware_version: "V5.7.3".into(),
+            firmware_release_date: "build 220112".into(),
+            ..sample_device_info()
+        };
+        let messages = manager.next_event(event(CameraEventType::InfoUpdated(upgraded)));
+        // The same discovery topics are published again, without touching any state
+        let discovery_topics = |messages: &[MqttMessage]| -> Vec<String> {
+            messages
+                .iter()
+                .filter(|m| m.topic.starts_with("homeassistant/"))
+                .map(|m| m.topic.clone())
+                .collect()
+        };
+        assert_eq!(discovery_topics(&messages), discovery_topics(&connected));
+        let (discovery, rest): (Vec<_>, Vec<_>) = messages
+            .iter()
+            .partition(|m| m.topic.starts_with("homeassistant/"));
+        assert!(discovery.iter().all(|m| match &m.payload {
+            MqttPayload::Json(json) => {
+                json["device"]["sw_version"] == "Camera Firmware V5.7.3 (build 220112)"
+            }
+            _ => false,
+        }));
+        assert_eq!(
+            rest.iter().map(|m| m.topic.as_str()).collect::<Vec<_>>(),
+            vec!["hikvision_cameras/device_cam1/info"]
+        );
+        let cam = &manager.cameras[0];
+        assert!(cam.triggers[0].alerting);
+        assert_eq!(cam.triggers[0].alert_count, 1);
+        assert_eq!(cam.reconnect_count, 0);
+    }
+
+    #[test]
+    fn test_device_info_topic() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        // Nothing to publish before the camera has connected
+        assert!(manager.cameras[0].message_info(&manager.topics).is_none());
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let info: Vec<MqttMessage> = messages
+            .into_iter()
+            .filter(|m| m.topic.ends_with("/info") || m.topic.contains("_info/"))
+            .collect();
+        insta::assert_yaml_snapshot!(info, {
+            "[].**.sw_version" => "[sw_version]",
+        });
+    }
+
+    #[test]
+    fn test_clock_drift() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let event = |event: CameraEventType| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event,
+        };
+        let connected = manager.next_event(event(CameraEventType::Connected {
+            triggers: vec![],
+            info: sample_device_info(),
+            address: "http://192.168.20.2".into(),
+        }));
+        // Nothing is known about the clock until it's been read
+        assert!(!connected.iter().any(|m| m.topic.contains("clock")));
+
+        let messages = manager.next_event(event(CameraEventType::ClockDrift { drift_secs: -2400 }));
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.sw_version" => "[sw_version]",
+        });
+        let messages = manager.next_event(event(CameraEventType::ClockDrift { drift_secs: 2 }));
+        assert_eq!(
+            messages
+                .iter()
+                .map(|m| (m.topic.as_str(), &m.payload))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "hikvision_cameras/device_cam1/clock_drift",
+                    &MqttPayload::Constant("2".into())
+                ),
+                (
+                    "hikvision_cameras/device_cam1/clock_wrong",
+                    &MqttPayload::Constant("OFF".into())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_channel_offset() {
+        let mut cams = sample_cameras();
+        let id = cams[0].identifier().to_string();
+        let alert = || CameraEvent {
+            id: id.clone(),
+            event: CameraEventType::Alert(
+                AlertItem::parse(include_str!("../../samples/alert_nvr_offset.xml")).unwrap(),
+            ),
+        };
+        let triggers = TriggerItem::parse(include_str!("../../samples/triggers_nvr.xml")).unwrap();
+        let connect = |cams: &[ConfigCamera], device_type: &str| {
+            let mut manager = Manager::new(
+                cams.to_vec(),
+                MqttTopics::default(),
+                sample_system(),
+                MqttPublishing::default(),
+            );
+            manager.next_event(CameraEvent {
+                id: cams[0].identifier().to_string(),
+                event: CameraEventType::Connected {
+                    triggers: triggers.clone(),
+                    info: DeviceInfo {
+                        device_type: device_type.into(),
+                        ..sample_device_info()
+                    },
+                    address: "http://192.168.20.2".into(),
+                },
+            });
+            manager
+        };
+        let alerting = |manager: &Manager| -> Vec<String> {
+            manager.cameras[0]
+                .triggers
+                .iter()
+                .filter(|t| t.alerting)
+                .map(|t| t.trigger.identifier.to_string())
+                .collect()
+        };
+
+        // Detected on recorders, since channel 33 has no trigger
+        let mut manager = connect(&cams, "NVR");
+        manager.next_event(alert());
+        assert_eq!(alerting(&manager), vec!["CH1 Motion"]);
+
+        // Cameras don't number their channels that way
+        let mut manager = connect(&cams, "IPCamera");
+        manager.next_event(alert());
+        assert!(alerting(&manager).is_empty());
+
+        // Configured offsets apply to any device
+        cams[0].channel_offset = Some(32);
+        let mut manager = connect(&cams, "IPCamera");
+        manager.next_event(alert());
+        assert_eq!(alerting(&manager), vec!["CH1 Motion"]);
+    }
+
+    #[test]
+    fn test_io_port_names() {
+        let mut cams = sample_cameras();
+        cams[0].io_ports = [("2".to_string(), "Side Gate".to_string())]
+            .into_iter()
+            .collect();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let input = |port: &str, port_name: Option<&str>| TriggerItem {
+            port_name: port_name.map(String::from),
+            ..EventIdentifier::new(Some(port.into()), EventType::Io).into()
+        };
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    input("1", Some("Doorbell")),
+                    // Configured names take precedence over the names from the camera
+                    input("2", Some("Gate")),
+                    input("3", None),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let cam = &manager.cameras[0];
+        let names: Vec<String> = cam.triggers.iter().map(|t| t.display_name(cam)).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Camera 1 Doorbell (Input 1)",
+                "Camera 1 Side Gate (Input 2)",
+                "Camera 1 CH3 I/O Port"
+            ]
+        );
+        let discovery: Vec<MqttMessage> = messages
+            .into_iter()
+            .filter(|m| m.topic.contains("binary_sensor") && m.topic.contains("_ch1_"))
+            .collect();
+        insta::assert_yaml_snapshot!(discovery, {
+            "[].**.sw_version" => "[sw_version]",
+        });
+    }
+
+    #[test]
+    fn test_event_filters() {
+        let mut cams = sample_cameras();
+        cams[0].events_include = vec!["Motion".into(), "LineDetection".into()];
+        cams[0].events_exclude = vec!["2/Motion".into()];
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![
+                    EventIdentifier::new(Some("1".into()), EventType::Motion).into(),
+                    EventIdentifier::new(Some("2".into()), EventType::Motion).into(),
+                    EventIdentifier::new(Some("1".into()), EventType::LineDetection).into(),
+                    EventIdentifier::new(None, EventType::DiskFull).into(),
+                ],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let triggers: Vec<_> = manager.cameras[0]
+            .triggers
+            .iter()
+            .map(|t| t.trigger.identifier.to_string())
+            .collect();
+        assert_eq!(triggers, vec!["CH1 Motion", "CH1 Line Crossing"]);
+
+        // Alerts of filtered triggers are ignored
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: EventIdentifier::new(Some("2".into()), EventType::Motion),
+            }),
+        });
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_ignore_videoloss() {
+        let connect_and_alert = |system: ConfigSystem, cams: Vec<ConfigCamera>| {
+            let mut manager = Manager::new(
+                cams.clone(),
+                MqttTopics::default(),
+                system,
+                MqttPublishing::default(),
+            );
+            manager.next_event(CameraEvent {
+                id: cams[0].identifier().to_string(),
+                event: CameraEventType::Connected {
+                    triggers: vec![
+                        EventIdentifier::new(Some("1".into()), EventType::Motion).into(),
+                        EventIdentifier::new(Some("1".into()), EventType::VideoLoss).into(),
+                    ],
+                    info: sample_device_info(),
+                    address: "http://192.168.20.2".into(),
+                },
+            });
+            let messages = manager.next_event(CameraEvent {
+                id: cams[0].identifier().to_string(),
+                event: CameraEventType::Alert(AlertItem {
+                    active: true,
+                    date: "".to_string(),
+                    time: None,
+                    description: "".to_string(),
+                    post_count: 1,
+                    regions: vec![],
+                    target: None,
+                    anpr: None,
+                    people_counting: None,
+                    device_serial: None,
+                    channel_name: None,
+                    identifier: EventIdentifier::new(Some("1".into()), EventType::VideoLoss),
+                }),
+            });
+            (manager.cameras[0].triggers.len(), messages.len())
+        };
+
+        // Kept by default
+        let (triggers, messages) = connect_and_alert(sample_system(), sample_cameras());
+        assert_eq!(triggers, 2);
+        assert!(messages > 0);
+
+        let system = ConfigSystem {
+            ignore_videoloss: true,
+            ..sample_system()
+        };
+        assert_eq!(connect_and_alert(system.clone(), sample_cameras()), (1, 0));
+
+        // Cameras can override the system setting
+        let mut cams = sample_cameras();
+        cams[0].ignore_videoloss = Some(false);
+        assert_eq!(connect_and_alert(system, cams).0, 2);
+    }
+
+    #[test]
+    fn test_retain_state_defaults() {
+        let publishing = MqttPublishing::default();
+        assert!(publishing.retain_state(&EventType::Motion));
+        // Momentary events would replay stale states into automations
+        assert!(!publishing.retain_state(&EventType::FaceSnap));
+
+        let publishing = MqttPublishing {
+            retain_state: true,
+            retain_state_events: [
+                ("FaceSnap".to_string(), true),
+                ("Motion".to_string(), false),
+            ]
+            .into_iter()
+            .collect(),
+            ..MqttPublishing::default()
+        };
+        assert!(publishing.retain_state(&EventType::FaceSnap));
+        assert!(!publishing.retain_state(&EventType::Motion));
+        assert!(publishing.retain_state(&EventType::Io));
+    }
+
+    #[test]
+    fn test_camera_alert_invalid() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        // Setup trigger
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger1],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+
+        // Test nothing changes with invalid trigger
+        let old_manager = manager.clone();
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: EventIdentifier::new(Some("2".into()), EventType::Motion),
+            }),
+        });
+
+        assert_eq!(manager, old_manager);
+        assert_eq!(messages.len(), 0);
+    }
+
+    #[test]
+    fn test_camera_alert_basic() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        // Setup trigger
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger1.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+
+        // Send alert
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier,
+            }),
+        });
+
+        insta::assert_yaml_snapshot!(manager, {
+            ".cameras[].triggers[].last_alert" => "[last_alert]",
+            ".cameras[].log.since" => "[since]"
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+    }
+
+    #[test]
+    fn test_camera_alert_regions() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        // Setup trigger
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger1.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+
+        // Send alert with regions
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![DetectionRegion {
+                    id: "0".into(),
+                    sensitivity: 50,
+                    coordinates: vec![
+                        RegionCoordinates { x: 425, y: 600 },
+                        RegionCoordinates { x: 160, y: 400 },
+                    ],
+                    target: None,
+                }],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier,
+            }),
+        });
+
+        insta::assert_yaml_snapshot!(manager, {
+            ".cameras[].triggers[].last_alert" => "[last_alert]",
+            ".cameras[].log.since" => "[since]"
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
         });
     }
 
     #[test]
-    fn test_camera_connection() {
+    fn test_camera_alert_regions_restored() {
         let cams = sample_cameras();
-        let mut manager = Manager::new(cams.clone(), MqttTopics::default());
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
 
-        let messages = manager.next_event(CameraEvent {
+        // Setup trigger
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        manager.next_event(CameraEvent {
             id: cams[0].identifier().to_string(),
             event: CameraEventType::Connected {
-                triggers: vec![
-                    EventIdentifier::new(Some("1".into()), EventType::Motion).into(),
-                    EventIdentifier::new(Some("1".into()), EventType::Io).into(),
-                ],
+                triggers: vec![trigger1.clone()],
                 info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
             },
         });
+
+        // Send alert with regions
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: true,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![DetectionRegion {
+                    id: "0".into(),
+                    sensitivity: 50,
+                    coordinates: vec![
+                        RegionCoordinates { x: 425, y: 600 },
+                        RegionCoordinates { x: 160, y: 400 },
+                    ],
+                    target: None,
+                }],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier.clone(),
+            }),
+        });
+        // Disable alert and remove regions
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active: false,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier,
+            }),
+        });
+
         insta::assert_yaml_snapshot!(manager, {
-            ".cameras[].triggers[].last_alert" => "[last_alert]"
+            ".cameras[].triggers[].last_alert" => "[last_alert]",
+            ".cameras[].log.since" => "[since]"
+        });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]"
         });
-        insta::assert_yaml_snapshot!(messages);
     }
 
     #[test]
-    fn test_camera_alert_invalid() {
+    fn test_camera_alert_time() {
         let cams = sample_cameras();
-        let mut manager = Manager::new(cams.clone(), MqttTopics::default());
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
 
         // Setup trigger
         let trigger1: TriggerItem =
@@ -605,35 +7425,115 @@ mod test {
         manager.next_event(CameraEvent {
             id: cams[0].identifier().to_string(),
             event: CameraEventType::Connected {
-                triggers: vec![trigger1],
+                triggers: vec![trigger1.clone()],
                 info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
             },
         });
 
-        // Test nothing changes with invalid trigger
-        let old_manager = manager.clone();
+        // The last alert time comes from the camera, not the time the alert was received
         let messages = manager.next_event(CameraEvent {
             id: cams[0].identifier().to_string(),
             event: CameraEventType::Alert(AlertItem {
                 active: true,
-                date: "".to_string(),
+                date: "2021-07-02T14:25:36+08:00".to_string(),
+                time: Some(DateTime::parse_from_rfc3339("2021-07-02T14:25:36+08:00").unwrap()),
                 description: "".to_string(),
                 post_count: 1,
                 regions: vec![],
-                identifier: EventIdentifier::new(Some("2".into()), EventType::Motion),
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier,
             }),
         });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+    }
 
-        assert_eq!(manager, old_manager);
-        assert_eq!(messages.len(), 0);
+    #[test]
+    fn test_camera_alert_auto_clear() {
+        let cams = sample_cameras();
+        let mut system = sample_system();
+        system.alert_auto_clear_secs = Some(30);
+        system
+            .alert_auto_clear_event_secs
+            .insert("facesnap".into(), 5);
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            system,
+            MqttPublishing::default(),
+        );
+
+        // Setup triggers
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let trigger2: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::FaceSnap).into();
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger1.clone(), trigger2.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let start = Utc::now();
+        for trigger in [&trigger1, &trigger2] {
+            manager.next_event(CameraEvent {
+                id: cams[0].identifier().to_string(),
+                event: CameraEventType::Alert(AlertItem {
+                    active: true,
+                    date: "".to_string(),
+                    time: None,
+                    description: "".to_string(),
+                    post_count: 1,
+                    regions: vec![],
+                    target: None,
+                    anpr: None,
+                    people_counting: None,
+                    device_serial: None,
+                    channel_name: None,
+                    identifier: trigger.identifier.clone(),
+                }),
+            });
+        }
+
+        // Nothing expires straight away
+        assert_eq!(manager.tick(start).len(), 0);
+        // The event type override expires first
+        let messages = manager.tick(start + Duration::seconds(10));
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]"
+        });
+        // Then the system default, exactly once
+        let messages = manager.tick(start + Duration::seconds(31));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].topic,
+            "hikvision_cameras/device_cam1/ch1/Motion"
+        );
+        assert_eq!(manager.tick(start + Duration::seconds(60)).len(), 0);
     }
 
     #[test]
-    fn test_camera_alert_basic() {
+    fn test_persist_trigger_states() {
         let cams = sample_cameras();
-        let mut manager = Manager::new(cams.clone(), MqttTopics::default());
+        let mut system = sample_system();
+        system.alert_auto_clear_secs = Some(30);
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            system.clone(),
+            MqttPublishing::default(),
+        );
 
-        // Setup trigger
         let trigger1: TriggerItem =
             EventIdentifier::new(Some("1".into()), EventType::Motion).into();
         manager.next_event(CameraEvent {
@@ -641,32 +7541,222 @@ mod test {
             event: CameraEventType::Connected {
                 triggers: vec![trigger1.clone()],
                 info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
             },
         });
-
-        // Send alert
-        let messages = manager.next_event(CameraEvent {
+        manager.next_event(CameraEvent {
             id: cams[0].identifier().to_string(),
             event: CameraEventType::Alert(AlertItem {
                 active: true,
                 date: "".to_string(),
+                time: None,
                 description: "".to_string(),
                 post_count: 1,
                 regions: vec![],
-                identifier: trigger1.identifier,
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier.clone(),
             }),
         });
 
-        insta::assert_yaml_snapshot!(manager, {
-            ".cameras[].triggers[].last_alert" => "[last_alert]"
+        // Saving is held back until the interval has passed
+        let start = Utc::now();
+        assert_eq!(manager.persisted_state_if_changed(start), None);
+        let later = start + Duration::seconds(STATE_SAVE_INTERVAL_SECS);
+        let state = manager.persisted_state_if_changed(later).unwrap();
+        assert_eq!(manager.persisted_state_if_changed(later), None);
+
+        // Round trip through the file format
+        let state: PersistedState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        let mut restored = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            system,
+            MqttPublishing::default(),
+        );
+        restored.restore(state);
+        assert_eq!(restored.cameras[0].triggers.len(), 1);
+        assert!(restored.cameras[0].triggers[0].alerting);
+        assert_eq!(
+            restored.cameras[0].triggers[0].trigger,
+            manager.cameras[0].triggers[0].trigger
+        );
+
+        // The restored alert isn't cleared before the camera had the chance to post it again
+        assert_eq!(restored.tick(Utc::now()).len(), 0);
+        assert_eq!(restored.tick(Utc::now() + Duration::seconds(31)).len(), 1);
+    }
+
+    #[test]
+    fn test_mute() {
+        let mut cams = sample_cameras();
+        cams[0].derive_occupancy = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let entrance: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::RegionEntrance).into();
+        let alert_of = |trigger: &TriggerItem, active| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger.identifier.clone(),
+            }),
+        };
+        let alert = |active| alert_of(&trigger1, active);
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger1.clone(), entrance.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
         });
-        insta::assert_yaml_snapshot!(messages);
+        let state_topic = "hikvision_cameras/device_cam1/ch1/Motion";
+        let publishes_state =
+            |messages: &[MqttMessage]| messages.iter().any(|m| m.topic.starts_with(state_topic));
+        let occupancy_topic = "hikvision_cameras/device_cam1/ch1/occupancy";
+        let occupancy = |messages: &[MqttMessage]| -> Vec<MqttPayload> {
+            messages
+                .iter()
+                .filter(|m| m.topic == occupancy_topic)
+                .map(|m| m.payload.clone())
+                .collect()
+        };
+        assert!(manager
+            .command_subscriptions()
+            .contains(&"hikvision_cameras/+/mute".to_string()));
+        assert!(manager
+            .command_subscriptions()
+            .contains(&"hikvision_cameras/mute".to_string()));
+
+        // Muting is shown on the info topic, and alerts are only tracked
+        let now = Utc::now();
+        let messages = manager
+            .mute("hikvision_cameras/device_cam1/mute", b"3600", now)
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic, "hikvision_cameras/device_cam1/info");
+        match &messages[0].payload {
+            MqttPayload::Json(json) => assert_eq!(
+                json["muted_until"],
+                (now + Duration::seconds(3600)).to_rfc3339()
+            ),
+            payload => panic!("Unexpected info payload {:?}", payload),
+        }
+        assert!(!publishes_state(&manager.next_event(alert(true))));
+        assert!(!publishes_state(&manager.tick(now)));
+        // Occupancy is still counted, but not published
+        assert!(occupancy(&manager.next_event(alert_of(&entrance, true))).is_empty());
+        assert_eq!(
+            manager.reset_occupancy("hikvision_cameras/device_cam1/ch1/occupancy/reset"),
+            Some(Vec::new())
+        );
+        manager.next_event(alert_of(&entrance, false));
+        assert!(occupancy(&manager.next_event(alert_of(&entrance, true))).is_empty());
+        assert_eq!(
+            manager.mute("hikvision_cameras/device_cam1/mute", b"soon", now),
+            Some(Vec::new())
+        );
+        assert_eq!(
+            manager.mute("hikvision_cameras/device_cam1/command", b"off", now),
+            None
+        );
+        // Nor are they published when reconnecting, reloading the config or Home Assistant coming online
+        let reconnect = manager.mqtt_connection_established(0, false);
+        assert!(!publishes_state(&reconnect));
+        assert!(occupancy(&reconnect).is_empty());
+        let reload = manager.apply_config(cams.clone());
+        assert!(!publishes_state(&reload));
+        assert!(occupancy(&reload).is_empty());
+        assert!(!publishes_state(&manager.home_assistant_online()));
+
+        // Unmuting catches up on the real state
+        let messages = manager
+            .mute("hikvision_cameras/device_cam1/mute", b"off", now)
+            .unwrap();
+        let state = messages.iter().find(|m| m.topic == state_topic).unwrap();
+        match &state.payload {
+            MqttPayload::Json(json) => assert_eq!(json["alerting"], true),
+            payload => panic!("Unexpected state payload {:?}", payload),
+        }
+        assert_eq!(occupancy(&messages), vec![MqttPayload::from("1")]);
+        assert!(publishes_state(&manager.next_event(alert(false))));
+
+        // Muting all cameras survives a restart, and ends by itself
+        let until = now + Duration::seconds(60);
+        manager
+            .mute(
+                "hikvision_cameras/mute",
+                format!("until={}", until.to_rfc3339()).as_bytes(),
+                now,
+            )
+            .unwrap();
+        let state: PersistedState =
+            serde_json::from_str(&serde_json::to_string(&manager.persisted_state(now)).unwrap())
+                .unwrap();
+        let mut restored = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        restored.restore(state);
+        assert_eq!(restored.cameras[0].muted_until, Some(until));
+        assert!(!publishes_state(&restored.next_event(alert(true))));
+        assert!(publishes_state(&restored.tick(until)));
+        assert_eq!(restored.cameras[0].muted_until, None);
     }
 
     #[test]
-    fn test_camera_alert_regions() {
+    fn test_parse_mute() {
+        let now = Utc::now();
+        assert_eq!(
+            super::parse_mute("90", now),
+            Ok(Some(now + Duration::seconds(90)))
+        );
+        assert_eq!(super::parse_mute("0", now), Ok(None));
+        assert_eq!(super::parse_mute("OFF", now), Ok(None));
+        assert_eq!(
+            super::parse_mute("until=2021-10-02T18:04:02+08:00", now),
+            Ok(None)
+        );
+        assert_eq!(
+            super::parse_mute("until=2999-01-01T00:00:00Z", now),
+            Ok("2999-01-01T00:00:00Z".parse().ok())
+        );
+        assert!(super::parse_mute("until=tomorrow", now).is_err());
+        assert!(super::parse_mute("-5", now).is_err());
+    }
+
+    #[test]
+    fn test_camera_alert_count() {
         let cams = sample_cameras();
-        let mut manager = Manager::new(cams.clone(), MqttTopics::default());
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
 
         // Setup trigger
         let trigger1: TriggerItem =
@@ -676,85 +7766,262 @@ mod test {
             event: CameraEventType::Connected {
                 triggers: vec![trigger1.clone()],
                 info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
             },
         });
 
-        // Send alert with regions
-        let messages = manager.next_event(CameraEvent {
+        let alert = |active: bool, post_count: u64| CameraEvent {
             id: cams[0].identifier().to_string(),
             event: CameraEventType::Alert(AlertItem {
-                active: true,
+                active,
                 date: "".to_string(),
+                time: None,
                 description: "".to_string(),
-                post_count: 1,
-                regions: vec![DetectionRegion {
-                    id: "0".into(),
-                    sensitivity: 50,
-                    coordinates: vec![
-                        RegionCoordinates { x: 425, y: 600 },
-                        RegionCoordinates { x: 160, y: 400 },
-                    ],
-                }],
-                identifier: trigger1.identifier,
+                post_count,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier.clone(),
             }),
+        };
+        // Only transitions to active are counted, not repeated posts
+        manager.next_event(alert(true, 1));
+        manager.next_event(alert(true, 2));
+        manager.next_event(alert(false, 0));
+        let messages = manager.next_event(alert(true, 1));
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
         });
+    }
 
-        insta::assert_yaml_snapshot!(manager, {
-            ".cameras[].triggers[].last_alert" => "[last_alert]"
+    #[tokio::test]
+    async fn test_global_stats() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger1.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
         });
-        insta::assert_yaml_snapshot!(messages);
+        let alert = |active: bool| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Alert(AlertItem {
+                active,
+                date: "".to_string(),
+                time: None,
+                description: "".to_string(),
+                post_count: 1,
+                regions: vec![],
+                target: None,
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
+                identifier: trigger1.identifier.clone(),
+            }),
+        };
+        manager.next_event(alert(true));
+        manager.next_event(alert(true));
+        manager.next_event(alert(false));
+        let connects = ConnectLimit::new(Some(2));
+        manager.report_connects(connects.clone());
+        let _connecting = connects.acquire().await;
+        // A full queue drops the next alert
+        let (events, _rx) = EventQueue::channel(1);
+        manager.report_queue(events.clone());
+        events.send(alert(true)).await.unwrap();
+        events.send(alert(false)).await.unwrap();
+
+        // Stats are only refreshed once they are old enough
+        let stats_topic = "hikvision_cameras/stats";
+        let now = Utc::now();
+        assert!(!manager.tick(now).iter().any(|m| m.topic == stats_topic));
+        let messages = manager.tick(now + Duration::seconds(STATS_INTERVAL_SECS));
+        let stats = match &messages
+            .iter()
+            .find(|m| m.topic == stats_topic)
+            .unwrap()
+            .payload
+        {
+            MqttPayload::Json(json) => json.clone(),
+            _ => panic!("Stats should be JSON"),
+        };
+        assert_eq!(stats["events_total"], 1);
+        assert_eq!(stats["events_processed_total"], 3);
+        assert_eq!(stats["connects_in_flight"], 1);
+        assert_eq!(stats["events_dropped"], 1);
+        assert_eq!(stats["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(stats["cameras"][0]["id"], "cam1");
+        assert_eq!(stats["cameras"][0]["connected"], true);
+        assert_eq!(stats["cameras"][0]["triggers"], 1);
+        assert!(stats["cameras"][0]["last_event"].is_string());
     }
 
     #[test]
-    fn test_camera_alert_regions_restored() {
+    fn test_camera_alert_target_change() {
         let cams = sample_cameras();
-        let mut manager = Manager::new(cams.clone(), MqttTopics::default());
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
 
         // Setup trigger
         let trigger1: TriggerItem =
-            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+            EventIdentifier::new(Some("1".into()), EventType::LineDetection).into();
         manager.next_event(CameraEvent {
             id: cams[0].identifier().to_string(),
             event: CameraEventType::Connected {
                 triggers: vec![trigger1.clone()],
                 info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
             },
         });
 
-        // Send alert with regions
-        manager.next_event(CameraEvent {
+        let alert = |target: &str| CameraEvent {
             id: cams[0].identifier().to_string(),
             event: CameraEventType::Alert(AlertItem {
                 active: true,
                 date: "".to_string(),
+                time: None,
                 description: "".to_string(),
                 post_count: 1,
-                regions: vec![DetectionRegion {
-                    id: "0".into(),
-                    sensitivity: 50,
-                    coordinates: vec![
-                        RegionCoordinates { x: 425, y: 600 },
-                        RegionCoordinates { x: 160, y: 400 },
-                    ],
-                }],
+                regions: vec![],
+                target: Some(target.into()),
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
                 identifier: trigger1.identifier.clone(),
             }),
+        };
+        manager.next_event(alert("vehicle"));
+        // A different target while still alerting must be published
+        let messages = manager.next_event(alert("human"));
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]"
         });
-        // Disable alert and remove regions
+        // But the same target is not
+        let messages = manager.next_event(alert("human"));
+        assert_eq!(messages.len(), 0);
+    }
+
+    #[test]
+    fn test_camera_split_smart_targets() {
+        let mut cams = sample_cameras();
+        cams[0].split_smart_targets = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        // Only smart events are split
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::LineDetection).into();
+        let trigger2: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger1.clone(), trigger2],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        let discovery: Vec<_> = messages
+            .iter()
+            .filter(|m| m.topic.starts_with("homeassistant/binary_sensor/"))
+            .collect();
+        insta::assert_yaml_snapshot!(discovery, {
+            "[].**.sw_version" => "[sw_version]"
+        });
+
+        // Send alert with a human detected
         let messages = manager.next_event(CameraEvent {
             id: cams[0].identifier().to_string(),
             event: CameraEventType::Alert(AlertItem {
-                active: false,
+                active: true,
                 date: "".to_string(),
+                time: None,
                 description: "".to_string(),
                 post_count: 1,
-                regions: vec![],
+                regions: vec![DetectionRegion {
+                    id: "1".into(),
+                    sensitivity: 50,
+                    coordinates: vec![],
+                    target: Some("human".into()),
+                }],
+                target: Some("human".into()),
+                anpr: None,
+                people_counting: None,
+                device_serial: None,
+                channel_name: None,
                 identifier: trigger1.identifier,
             }),
         });
+        insta::assert_yaml_snapshot!(messages, {
+            "[].**.last_alert" => "[last_alert]",
+            "[].**.last_event" => "[last_event]",
+            "[].**.uptime_secs" => "[uptime_secs]",
+            "[].**.version" => "[version]"
+        });
+    }
 
-        insta::assert_yaml_snapshot!(manager, {
-            ".cameras[].triggers[].last_alert" => "[last_alert]"
+    #[test]
+    fn test_camera_snapshot() {
+        let mut cams = sample_cameras();
+        cams[0].snapshot_on_alert = true;
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+
+        // Setup trigger
+        let trigger1: TriggerItem =
+            EventIdentifier::new(Some("1".into()), EventType::Motion).into();
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Connected {
+                triggers: vec![trigger1.clone()],
+                info: sample_device_info(),
+                address: "http://192.168.20.2".into(),
+            },
+        });
+        // The camera entity is discovered alongside the binary sensor
+        let discovery: Vec<_> = messages
+            .iter()
+            .filter(|m| m.topic.starts_with("homeassistant/camera/"))
+            .collect();
+        insta::assert_yaml_snapshot!(discovery);
+
+        // Send snapshot
+        let messages = manager.next_event(CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Snapshot {
+                identifier: trigger1.identifier,
+                image: vec![0xff, 0xd8, 0xff],
+            },
         });
         insta::assert_yaml_snapshot!(messages);
     }
@@ -771,5 +8038,72 @@ mod test {
         ---
         "{\"nested\":{\"test\":\"output\"},\"test\":\"output\"}"
         "###);
+        let mq: MqttPayload = vec![0xff, 0xd8, 0xff].into();
+        assert_eq!(mq.render(), vec![0xff, 0xd8, 0xff]);
+    }
+
+    #[test]
+    fn test_error_history() {
+        let cams = sample_cameras();
+        let mut manager = Manager::new(
+            cams.clone(),
+            MqttTopics::default(),
+            sample_system(),
+            MqttPublishing::default(),
+        );
+        let disconnected = |error: &str, kind: DisconnectKind| CameraEvent {
+            id: cams[0].identifier().to_string(),
+            event: CameraEventType::Disconnected {
+                error: error.to_string(),
+                kind,
+                retry_in_secs: Some(1),
+            },
+        };
+        let errors_topic = "hikvision_cameras/device_cam1/errors";
+        // Nothing to publish until the camera had an error
+        assert!(!manager.cameras[0]
+            .message_complete_refresh(&manager.topics)
+            .iter()
+            .any(|m| m.topic == errors_topic));
+
+        let messages = manager.next_event(disconnected("Bad password", DisconnectKind::Auth));
+        let errors: Vec<&MqttMessage> = messages
+            .iter()
+            .filter(|m| m.topic == errors_topic)
+            .collect();
+        insta::assert_yaml_snapshot!(errors, {
+            "[].payload.*.time" => "[time]",
+        });
+
+        // A requested reconnect isn't recorded
+        let messages = manager.next_event(disconnected(
+            "Reconnect requested",
+            DisconnectKind::Requested,
+        ));
+        assert!(!messages.iter().any(|m| m.topic == errors_topic));
+
+        // Only the latest errors are kept, but all are counted
+        for _ in 0..ERROR_HISTORY_LEN {
+            manager.next_event(disconnected("Connection reset", DisconnectKind::Network));
+        }
+        let cam = &manager.cameras[0];
+        assert_eq!(cam.errors.len(), ERROR_HISTORY_LEN);
+        assert!(cam.errors.iter().all(|e| e.kind == DisconnectKind::Network));
+        let stats = manager.message_global_stats();
+        match stats.payload {
+            MqttPayload::Json(j) => {
+                assert_eq!(
+                    j["cameras"][0]["errors"],
+                    serde_json::json!({ "auth": 1, "network": ERROR_HISTORY_LEN })
+                );
+                assert_eq!(j["disconnects"], j["cameras"][0]["errors"]);
+            }
+            _ => panic!("stats should be JSON"),
+        }
+        // The history is republished with the rest of the camera's state
+        assert!(manager.cameras[0]
+            .message_complete_refresh(&manager.topics)
+            .iter()
+            .any(|m| m.topic == errors_topic));
     }
 }
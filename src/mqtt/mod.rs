@@ -1,4 +1,7 @@
+mod buffer;
+mod client;
 mod connection;
 mod manager;
+mod state;
 
-pub use connection::initiate_connection;
+pub use connection::MqttSink;
@@ -0,0 +1,106 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::manager::PersistedCamera;
+
+/// Version of the state file's layout. Files of other versions are ignored.
+pub const STATE_VERSION: u32 = 1;
+
+/// State that is kept between restarts of HikSink
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct PersistedState {
+    /// `0` for files written before the state was versioned, which only held the retained topics
+    #[serde(default)]
+    pub version: u32,
+    /// The retained topics published for each camera, keyed by the camera's identifier
+    #[serde(default)]
+    pub retained_topics: BTreeMap<String, BTreeSet<String>>,
+    /// The trigger states of each camera, keyed by the camera's identifier
+    #[serde(default)]
+    pub cameras: BTreeMap<String, PersistedCamera>,
+}
+
+impl PersistedState {
+    /// Loads the state from a file. A missing file is treated as an empty state.
+    pub fn load(path: impl AsRef<Path>) -> Result<PersistedState, String> {
+        let state: PersistedState = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| e.to_string())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.to_string()),
+        };
+        if state.version != 0 && state.version != STATE_VERSION {
+            return Err(format!(
+                "State file is version {}, expected {}",
+                state.version, STATE_VERSION
+            ));
+        }
+        Ok(state)
+    }
+
+    /// Writes the state to a file, replacing it only once it's complete
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        let partial = path.with_extension("tmp");
+        std::fs::write(&partial, contents).map_err(|e| e.to_string())?;
+        std::fs::rename(&partial, path).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PersistedState, STATE_VERSION};
+
+    fn state_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "hiksink_state_{}_{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_load_and_save() {
+        let path = state_path("round_trip");
+        // Nothing saved yet
+        assert_eq!(PersistedState::load(&path), Ok(PersistedState::default()));
+
+        let mut state = PersistedState {
+            version: STATE_VERSION,
+            ..PersistedState::default()
+        };
+        state.retained_topics.insert(
+            "cam1".into(),
+            ["hikvision_cameras/device_cam1/log".to_string()].into(),
+        );
+        state.save(&path).unwrap();
+        assert_eq!(PersistedState::load(&path), Ok(state));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let path = state_path("invalid");
+        std::fs::write(&path, "{\"retained_topics\": ").unwrap();
+        assert!(PersistedState::load(&path).is_err());
+
+        std::fs::write(&path, "{\"version\": 99}").unwrap();
+        assert_eq!(
+            PersistedState::load(&path),
+            Err(format!(
+                "State file is version 99, expected {}",
+                STATE_VERSION
+            ))
+        );
+
+        // Files from before the state was versioned only held the retained topics
+        std::fs::write(&path, "{\"retained_topics\": {\"cam1\": [\"topic\"]}}").unwrap();
+        let state = PersistedState::load(&path).unwrap();
+        assert_eq!(state.retained_topics["cam1"].len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt::Write;
+
+use crate::{
+    config::ConfigCamera,
+    hikapi::{Camera, DeviceInfo, TriggerItem},
+};
+
+/// Pages fetched while probing, printed as is with `--raw`
+const RAW_PATHS: [&str; 2] = ["/ISAPI/System/deviceInfo", "/ISAPI/Event/triggers"];
+
+/// Loads a camera the same way as when running, without subscribing to its alerts or touching MQTT,
+/// and prints what was found
+pub async fn probe(camera: ConfigCamera, raw: bool) -> Result<(), String> {
+    println!("Probing {}", camera.base_url());
+    if raw {
+        // Printed before parsing, so they're available even if the camera sends something unparseable
+        for path in RAW_PATHS {
+            println!("--- {} ---", path);
+            match Camera::get_text(&camera, path).await {
+                Ok(text) => println!("{}", text.trim_end()),
+                Err(e) => println!("Unable to fetch: {}", e),
+            }
+        }
+        println!("---");
+    }
+    let camera = Camera::probe(camera).await.map_err(|e| e.to_string())?;
+    print!("{}", describe(&camera.info, &camera.triggers));
+    Ok(())
+}
+
+/// A readable summary of the device info and triggers of a camera
+fn describe(info: &DeviceInfo, triggers: &[TriggerItem]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Device name: {}", info.device_name);
+    let _ = writeln!(out, "Device ID: {}", info.device_id);
+    let _ = writeln!(out, "Model: {}", info.model);
+    let _ = writeln!(out, "Device type: {}", info.device_type);
+    let _ = writeln!(out, "Serial number: {}", info.serial_number);
+    let _ = writeln!(out, "MAC address: {}", info.mac_address);
+    let _ = writeln!(
+        out,
+        "Firmware: {} ({})",
+        info.firmware_version, info.firmware_release_date
+    );
+    let _ = writeln!(out, "Triggers: {}", triggers.len());
+    for trigger in triggers {
+        // The key used for the trigger in the config, e.g. in `events_exclude`
+        let key = match &trigger.identifier.channel {
+            Some(channel) => format!("{}/{}", channel, trigger.identifier.event_type),
+            None => trigger.identifier.event_type.to_string(),
+        };
+        let _ = write!(out, "  {}: {}", key, trigger.identifier);
+        if let Some(name) = &trigger.channel_name {
+            let _ = write!(out, " on {}", name);
+        }
+        let _ = write!(out, " [{}]", trigger.hik_id);
+        if !trigger.description.is_empty() {
+            let _ = write!(out, " - {}", trigger.description);
+        }
+        if !trigger.notifies_center {
+            let _ = write!(out, " (not set to notify the surveillance center)");
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::describe;
+    use crate::hikapi::{DeviceInfo, TriggerItem};
+
+    const TRIGGERS_CAM: &str = include_str!("../samples/triggers_cam.xml");
+
+    #[test]
+    fn test_describe() {
+        let info = DeviceInfo {
+            device_name: "Front Porch".to_string(),
+            device_id: "7ccc4404-e05d-4376-8ebf-81127da67c11".to_string(),
+            model: "DS-2CD2385G1-I".to_string(),
+            serial_number: "DS-2CD2385G1-I20190101AAWRD00000000".to_string(),
+            mac_address: "ff:ff:ff:ff:ff:ff".to_string(),
+            firmware_version: "V5.6.3".to_string(),
+            firmware_release_date: "build 190923".to_string(),
+            device_type: "IPCamera".to_string(),
+        };
+        let triggers = TriggerItem::parse(TRIGGERS_CAM).unwrap();
+        insta::assert_snapshot!(describe(&info, &triggers));
+    }
+}
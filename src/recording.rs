@@ -0,0 +1,229 @@
+use std::{
+    fs::File,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use bytes::Bytes;
+use futures::StreamExt;
+use multipart_stream::Part;
+
+use crate::{
+    config::{AlertFormat, ConfigCamera},
+    hikapi::{parse_part, stream_boundary, Camera, CameraError},
+};
+
+/// Separates the content type header from the stream in a recording
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+/// Records the raw alert stream of a camera to a file until Ctrl-C, or until the stream fails
+pub async fn record(camera: ConfigCamera, out: &Path) -> Result<(), String> {
+    let file =
+        File::create(out).map_err(|e| format!("Unable to create {}: {}", out.display(), e))?;
+    let mut camera = Camera::probe(camera).await.map_err(|e| e.to_string())?;
+    camera
+        .subscribe_recording(Some(file))
+        .await
+        .map_err(|e| e.to_string())?;
+    eprintln!(
+        "Recording the alert stream to {}, press Ctrl-C to stop",
+        out.display()
+    );
+    let mut alerts = 0;
+    loop {
+        let next = tokio::select! {
+            next = camera.next_event() => next,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        match next {
+            Ok((_, parsed)) => {
+                alerts += parsed.len();
+                eprintln!("Recorded {} alerts", alerts);
+            }
+            Err(CameraError::AlertUnparseable(error, _)) => {
+                eprintln!("Recorded an alert that couldn't be parsed: {}", error)
+            }
+            // This is what the recording is for, so keep it
+            Err(e) => {
+                eprintln!("Stopped recording: {}", e);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Feeds a recording through the alert stream parsers, printing the outcome of every part.
+/// Fails if anything in the recording couldn't be parsed.
+pub async fn replay(path: &Path, format: AlertFormat) -> Result<(), String> {
+    let data =
+        std::fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let report = replay_data(Bytes::from(data), format).await?;
+    for line in &report.lines {
+        println!("{}", line);
+    }
+    println!(
+        "{} parts, {} alerts, {} unparseable alerts, {} stream errors",
+        report.parts, report.alerts, report.alert_failures, report.stream_failures
+    );
+    if report.alert_failures > 0 || report.stream_failures > 0 {
+        return Err("The recording contains errors".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct ReplayReport {
+    lines: Vec<String>,
+    parts: usize,
+    alerts: usize,
+    alert_failures: usize,
+    stream_failures: usize,
+}
+
+/// Replays a recording. Offsets are in bytes from the end of the content type header.
+/// Stream errors end a live connection, but here parsing carries on from the next boundary.
+async fn replay_data(data: Bytes, format: AlertFormat) -> Result<ReplayReport, String> {
+    let header_len = data
+        .windows(HEADER_END.len())
+        .position(|w| w == HEADER_END)
+        .ok_or("Recording has no content type header")?;
+    let header = String::from_utf8_lossy(&data[..header_len]);
+    let content_type = header
+        .strip_prefix("Content-Type:")
+        .ok_or("Recording doesn't start with a content type header")?
+        .trim();
+    let boundary = stream_boundary(content_type).map_err(|e| e.to_string())?;
+    let delimiter = format!("--{}", boundary);
+    let stream = data.slice(header_len + HEADER_END.len()..);
+
+    let mut report = ReplayReport::default();
+    let mut start = 0;
+    while start < stream.len() {
+        // Fed a byte at a time, so the bytes taken by the parser give the exact offset of each part
+        let consumed = Arc::new(AtomicUsize::new(start));
+        let input = {
+            let consumed = consumed.clone();
+            let stream = stream.clone();
+            futures::stream::iter(start..stream.len()).map(move |i| {
+                consumed.store(i + 1, Ordering::Relaxed);
+                Ok::<_, std::convert::Infallible>(stream.slice(i..i + 1))
+            })
+        };
+        let mut parts = Box::pin(multipart_stream::parse(input, &boundary));
+        let mut part_start = start;
+        start = stream.len();
+        while let Some(part) = parts.next().await {
+            let offset = consumed.load(Ordering::Relaxed);
+            match part {
+                Ok(part) => {
+                    report.parts += 1;
+                    let outcome = describe_part(&part, format, &mut report);
+                    report
+                        .lines
+                        .push(format!("{}..{}: {}", part_start, offset, outcome));
+                    part_start = offset;
+                }
+                Err(e) => {
+                    report.stream_failures += 1;
+                    report
+                        .lines
+                        .push(format!("{}: stream error: {}", offset, e));
+                    if let Some(next) = find(&stream[offset..], delimiter.as_bytes()) {
+                        start = offset + next;
+                        report
+                            .lines
+                            .push(format!("{}: resuming at the next boundary", start));
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// What became of a part of the stream, counting it in the report
+fn describe_part(part: &Part, format: AlertFormat, report: &mut ReplayReport) -> String {
+    match parse_part(&part.headers, &part.body, format) {
+        None => format!(
+            "skipped {:?} part",
+            part.headers
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|c| c.to_str().ok())
+                .unwrap_or("unknown")
+        ),
+        Some(Ok((_, alerts))) => {
+            report.alerts += alerts.len();
+            let alerts: Vec<String> = alerts
+                .iter()
+                .map(|alert| {
+                    let state = if alert.active { "active" } else { "inactive" };
+                    format!("{} {}", alert.identifier, state)
+                })
+                .collect();
+            format!("parsed {}", alerts.join(", "))
+        }
+        Some(Err(e)) => {
+            report.alert_failures += 1;
+            e.to_string()
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::replay_data;
+    use crate::config::AlertFormat;
+
+    fn part(headers: &str, body: &str) -> String {
+        format!(
+            "--boundary\r\n{}Content-Length: {}\r\n\r\n{}\r\n",
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let alert = |state: &str| {
+            format!(
+                "<EventNotificationAlert xmlns=\"http://www.hikvision.com/ver20/XMLSchema\"><channelID>1</channelID><dateTime>2021-10-02T18:04:02+08:00</dateTime>\
+                <activePostCount>1</activePostCount><eventType>VMD</eventType><eventState>{}</eventState>\
+                <eventDescription>Motion alarm</eventDescription></EventNotificationAlert>",
+                state
+            )
+        };
+        let recording = [
+            "Content-Type: multipart/mixed; boundary=boundary\r\n\r\n".to_string(),
+            part("Content-Type: application/xml\r\n", &alert("active")),
+            part(
+                "Content-Type: application/xml\r\n",
+                "<EventNotificationAlert>",
+            ),
+            part("Content-Type: image/jpeg\r\n", "jpeg"),
+            // Broke the stream on some NVRs
+            part("Content Type: application/xml\r\n", &alert("active")),
+            part("Content-Type: application/xml\r\n", &alert("inactive")),
+        ]
+        .concat();
+        let report = replay_data(Bytes::from(recording), AlertFormat::Auto)
+            .await
+            .unwrap();
+        insta::assert_yaml_snapshot!(report.lines);
+        assert_eq!(report.parts, 4);
+        assert_eq!(report.alerts, 2);
+        assert_eq!(report.alert_failures, 1);
+        assert_eq!(report.stream_failures, 1);
+    }
+}
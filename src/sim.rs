@@ -0,0 +1,334 @@
+//! A fake Hikvision camera for testing against. Serves the parts of ISAPI that HikSink uses,
+//! behind digest auth, and an alert stream that follows a script.
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use digest_auth::{AuthContext, AuthorizationHeader};
+use hyper::{
+    body::Bytes,
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde::Deserialize;
+
+const DEVICE_INFO: &str = include_str!("../samples/device_info_cam.xml");
+const TRIGGERS: &str = include_str!("../samples/triggers_cam.xml");
+const MOTION_DETECTION: &str = include_str!("../samples/motion_detection.xml");
+const MOTION_DETECTION_PATH: &str = "/ISAPI/System/Video/inputs/channels/1/motionDetection";
+const SUPPLEMENT_LIGHT: &str = include_str!("../samples/supplement_light.xml");
+const SUPPLEMENT_LIGHT_PATH: &str = "/ISAPI/Image/channels/1/supplementLight";
+const PTZ_PRESETS: &str = include_str!("../samples/ptz_presets.xml");
+const RESPONSE_OK: &str = r#"<ResponseStatus version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema"><statusCode>1</statusCode><statusString>OK</statusString></ResponseStatus>"#;
+const INPUTS: &str = include_str!("../samples/io_inputs.xml");
+const OUTPUTS: &str = include_str!("../samples/io_outputs.xml");
+const OUTPUT_STATUS: &str = include_str!("../samples/io_output_status.xml");
+const REALM: &str = "hik_sim";
+const NONCE: &str = "4e6a51784d7a4132";
+const BOUNDARY: &str = "boundary";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Script {
+    pub username: String,
+    pub password: String,
+    /// Close the alert stream after the last step instead of keeping it open
+    #[serde(default)]
+    pub close_after_script: bool,
+    /// Replaces the built in device info
+    #[serde(default)]
+    pub device_info_file: Option<PathBuf>,
+    /// Replaces the built in trigger list
+    #[serde(default)]
+    pub triggers_file: Option<PathBuf>,
+    #[serde(default, rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+/// A part of the alert stream. Sends `raw` if set, otherwise `body`, otherwise an alert made from `event`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Step {
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// The camera's name for the event type, e.g. `VMD`
+    #[serde(default)]
+    pub event: Option<String>,
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    #[serde(default = "default_true")]
+    pub active: bool,
+    #[serde(default = "default_post_count")]
+    pub post_count: u64,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// Bytes written to the stream as is, e.g. to send a malformed part
+    #[serde(default)]
+    pub raw: Option<String>,
+}
+
+fn default_channel() -> String {
+    "1".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_post_count() -> u64 {
+    1
+}
+
+fn default_content_type() -> String {
+    "application/xml; charset=\"UTF-8\"".to_string()
+}
+
+impl Script {
+    pub fn parse(s: &str) -> Result<Script, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+}
+
+impl Step {
+    /// The bytes sent for this step, including the boundary
+    fn render(&self) -> String {
+        if let Some(raw) = &self.raw {
+            return raw.clone();
+        }
+        let body = match (&self.body, &self.event) {
+            (Some(body), _) => body.clone(),
+            (None, Some(event)) => alert_xml(
+                event,
+                &self.channel,
+                self.active,
+                self.post_count,
+                &chrono::Local::now()
+                    .format("%Y-%m-%dT%H:%M:%S%:z")
+                    .to_string(),
+            ),
+            (None, None) => String::new(),
+        };
+        format!(
+            "--{}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}\r\n",
+            BOUNDARY,
+            self.content_type,
+            body.len(),
+            body
+        )
+    }
+}
+
+fn alert_xml(event: &str, channel: &str, active: bool, post_count: u64, time: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+<ipAddress>127.0.0.1</ipAddress>
+<portNo>80</portNo>
+<protocol>HTTP</protocol>
+<macAddress>ff:ff:ff:ff:ff:ff</macAddress>
+<channelID>{}</channelID>
+<dateTime>{}</dateTime>
+<activePostCount>{}</activePostCount>
+<eventType>{}</eventType>
+<eventState>{}</eventState>
+<eventDescription>{} alarm</eventDescription>
+</EventNotificationAlert>
+"#,
+        channel,
+        time,
+        post_count,
+        event,
+        if active { "active" } else { "inactive" },
+        event
+    )
+}
+
+struct Simulator {
+    script: Script,
+    device_info: String,
+    triggers: String,
+    /// Whether motion detection is enabled on channel 1, changed by PUTs like on a real camera
+    motion_detection: Mutex<bool>,
+    /// Whether alarm output 1 is high
+    alarm_output: Mutex<bool>,
+    /// The supplement light settings of channel 1, replaced by PUTs
+    supplement_light: Mutex<String>,
+}
+
+/// Starts the simulator in the background, returning the address it's listening on.
+/// Listen on port 0 to pick a free port.
+pub fn serve(script: Script, listen: SocketAddr) -> Result<SocketAddr, String> {
+    let read = |file: &Option<PathBuf>, default: &str| match file {
+        Some(file) => std::fs::read_to_string(file)
+            .map_err(|e| format!("Unable to read {}: {}", file.display(), e)),
+        None => Ok(default.to_string()),
+    };
+    let sim = Arc::new(Simulator {
+        device_info: read(&script.device_info_file, DEVICE_INFO)?,
+        triggers: read(&script.triggers_file, TRIGGERS)?,
+        motion_detection: Mutex::new(true),
+        alarm_output: Mutex::new(false),
+        supplement_light: Mutex::new(SUPPLEMENT_LIGHT.to_string()),
+        script,
+    });
+    let server = Server::try_bind(&listen).map_err(|e| e.to_string())?;
+    let make_service = make_service_fn(move |_| {
+        let sim = sim.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let sim = sim.clone();
+                async move { Ok::<_, Infallible>(respond(&sim, req).await) }
+            }))
+        }
+    });
+    let server = server.serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    Ok(addr)
+}
+
+async fn respond(sim: &Simulator, req: Request<Body>) -> Response<Body> {
+    if !authorized(&sim.script, &req) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(
+                header::WWW_AUTHENTICATE,
+                format!(
+                    r#"Digest qop="auth", realm="{}", nonce="{}", stale="FALSE""#,
+                    REALM, NONCE
+                ),
+            )
+            .body(Body::empty())
+            .unwrap();
+    }
+    let xml = |body: &str| {
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/xml; charset=\"UTF-8\"")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    match req.uri().path() {
+        MOTION_DETECTION_PATH if req.method() == hyper::Method::PUT => {
+            let body = hyper::body::to_bytes(req.into_body())
+                .await
+                .unwrap_or_default();
+            let enabled = !String::from_utf8_lossy(&body).contains("<enabled>false</enabled>");
+            *sim.motion_detection.lock().unwrap() = enabled;
+            xml(RESPONSE_OK)
+        }
+        SUPPLEMENT_LIGHT_PATH if req.method() == hyper::Method::PUT => {
+            let body = hyper::body::to_bytes(req.into_body())
+                .await
+                .unwrap_or_default();
+            *sim.supplement_light.lock().unwrap() = String::from_utf8_lossy(&body).into_owned();
+            xml(RESPONSE_OK)
+        }
+        SUPPLEMENT_LIGHT_PATH => xml(&sim.supplement_light.lock().unwrap()),
+        MOTION_DETECTION_PATH => {
+            let enabled = *sim.motion_detection.lock().unwrap();
+            xml(&MOTION_DETECTION.replace(
+                "<enabled>true</enabled>",
+                &format!("<enabled>{}</enabled>", enabled),
+            ))
+        }
+        "/ISAPI/System/IO/inputs" => xml(INPUTS),
+        "/ISAPI/System/IO/outputs" => xml(OUTPUTS),
+        "/ISAPI/System/IO/outputs/1/status" => {
+            let high = *sim.alarm_output.lock().unwrap();
+            xml(&OUTPUT_STATUS.replace(
+                "<ioState>inactive</ioState>",
+                if high {
+                    "<ioState>active</ioState>"
+                } else {
+                    "<ioState>inactive</ioState>"
+                },
+            ))
+        }
+        "/ISAPI/System/IO/outputs/1/trigger" if req.method() == hyper::Method::PUT => {
+            let body = hyper::body::to_bytes(req.into_body())
+                .await
+                .unwrap_or_default();
+            let high = String::from_utf8_lossy(&body).contains("<outputState>high</outputState>");
+            *sim.alarm_output.lock().unwrap() = high;
+            xml(RESPONSE_OK)
+        }
+        "/ISAPI/PTZCtrl/channels/1/presets" => xml(PTZ_PRESETS),
+        path if path.starts_with("/ISAPI/PTZCtrl/channels/1/presets/")
+            && path.ends_with("/goto")
+            && req.method() == hyper::Method::PUT =>
+        {
+            xml(RESPONSE_OK)
+        }
+        // Accepted, but the simulator carries on as if it had already come back up
+        "/ISAPI/System/reboot" if req.method() == hyper::Method::PUT => xml(RESPONSE_OK),
+        "/ISAPI/System/deviceInfo" => xml(&sim.device_info),
+        "/ISAPI/System/time" => xml(&format!(
+            "<Time xmlns=\"http://www.hikvision.com/ver20/XMLSchema\"><timeMode>NTP</timeMode><localTime>{}</localTime></Time>",
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%:z")
+        )),
+        "/ISAPI/Event/triggers" => xml(&sim.triggers),
+        "/ISAPI/Event/notification/alertStream" => Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/mixed; boundary={}", BOUNDARY),
+            )
+            .body(alert_stream(&sim.script))
+            .unwrap(),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+/// Checks the digest the client sent with the request, the same way a camera would
+fn authorized(script: &Script, req: &Request<Body>) -> bool {
+    let auth = match req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| AuthorizationHeader::parse(h).ok())
+    {
+        Some(auth) => auth,
+        None => return false,
+    };
+    let mut expected = auth.clone();
+    let mut context = AuthContext::new(
+        script.username.as_str(),
+        script.password.as_str(),
+        auth.uri.as_str(),
+    );
+    context.method = req.method().as_str().into();
+    expected.digest(&context);
+    auth.username == script.username
+        && auth.nonce == NONCE
+        && auth.uri == req.uri().path()
+        && auth.response == expected.response
+}
+
+fn alert_stream(script: &Script) -> Body {
+    let (mut sender, body) = Body::channel();
+    let script = script.clone();
+    tokio::spawn(async move {
+        for step in &script.steps {
+            tokio::time::sleep(Duration::from_millis(step.delay_ms)).await;
+            if sender.send_data(Bytes::from(step.render())).await.is_err() {
+                return;
+            }
+        }
+        if script.close_after_script {
+            return;
+        }
+        // Cameras keep the stream open until the client goes away
+        while sender.send_data(Bytes::new()).await.is_ok() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+    body
+}
@@ -0,0 +1,73 @@
+pub mod status;
+mod stdout;
+
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+
+use crate::{
+    config::{Config, ConfigCamera, SinkType},
+    hikapi::{CameraCommand, CameraEvent, ConnectLimit, EventQueue},
+    influx::InfluxStats,
+    mqtt::MqttSink,
+};
+use status::BridgeStatus;
+pub use stdout::StdoutSink;
+
+/// Somewhere camera events are delivered to. Each sink runs as its own task, so it can deal with
+/// its transport, e.g. reconnecting or buffering, without holding up the cameras.
+pub trait EventSink {
+    /// Starts delivering events in the background
+    fn start(self) -> SinkHandle;
+}
+
+/// The ways to communicate with a running sink
+pub struct SinkHandle {
+    /// The queue for camera events, given to each camera
+    pub events: EventQueue,
+    /// Replaces the configured cameras after the config has been reloaded
+    pub cameras: mpsc::UnboundedSender<Vec<ConfigCamera>>,
+    /// The state of the sink and cameras, for health checks
+    pub status: watch::Receiver<BridgeStatus>,
+    /// Commands for cameras received by the sink, with the id of the camera. Closed by sinks that
+    /// don't take commands.
+    pub commands: mpsc::UnboundedReceiver<(String, CameraCommand)>,
+    /// Asks the sink to wrap up before HikSink exits, e.g. save its state, and to say when it's done. Closed by
+    /// sinks with nothing to wrap up.
+    pub shutdown: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    /// Errors the sink can't recover from, after which HikSink shuts down and exits. Closed by sinks
+    /// that can't fail this way.
+    pub fatal: mpsc::UnboundedReceiver<String>,
+}
+
+impl SinkHandle {
+    /// Lets the sink wrap up, returning once it has
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.shutdown.send(done_tx).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+}
+
+/// Starts the sink selected in the config. `connects` is shared with the cameras, for reporting how many are connecting,
+/// and `influx` with the InfluxDB writer, for reporting its outcomes.
+pub fn start(
+    config: &Config,
+    connects: &ConnectLimit,
+    influx: Option<&InfluxStats>,
+) -> Result<SinkHandle, String> {
+    Ok(match config.sink.kind {
+        SinkType::Mqtt => MqttSink::new(config, connects.clone())?
+            .with_influx(influx.cloned())
+            .start(),
+        SinkType::Stdout => StdoutSink::new(config).start(),
+    })
+}
+
+/// Copies every camera event to a broadcast of `capacity` events, for an output running alongside the
+/// sink, e.g. the webhook. Each output gets its own, so a slow one only loses its own events. The sink's
+/// queue is kept as configured, and is never held up by the outputs.
+pub fn broadcast(handle: &mut SinkHandle, capacity: usize) -> broadcast::Receiver<CameraEvent> {
+    let (broadcast_tx, broadcast_rx) = broadcast::channel(capacity);
+    handle.events.copy_to(broadcast_tx);
+    broadcast_rx
+}
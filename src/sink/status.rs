@@ -0,0 +1,134 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    config::ConfigCamera,
+    hikapi::{CameraEvent, CameraEventType},
+};
+
+/// A snapshot of the bridge's state, for health checks
+#[derive(Debug, PartialEq, Serialize, Clone, Default)]
+pub struct BridgeStatus {
+    /// Whether the sink can deliver events. For MQTT, whether the broker has acknowledged our
+    /// connection and it hasn't errored since. Named for MQTT as it was the only sink at first.
+    pub mqtt_connected: bool,
+    /// The last broker connection error, while disconnected
+    pub mqtt_error: Option<String>,
+    pub cameras: Vec<CameraStatus>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct CameraStatus {
+    pub id: String,
+    pub name: String,
+    pub connected: bool,
+    /// `false` once the camera has given up reconnecting, e.g. after its credentials were rejected
+    pub running: bool,
+    /// The last connection error, while disconnected
+    pub error: Option<String>,
+    /// When the camera was last connected or disconnected
+    pub since: DateTime<Utc>,
+    pub last_event: Option<DateTime<Utc>>,
+    pub triggers: usize,
+}
+
+impl BridgeStatus {
+    /// Events can be delivered: the sink is connected and at least one camera is still trying to connect
+    pub fn is_healthy(&self) -> bool {
+        self.mqtt_connected && self.cameras.iter().any(|c| c.running)
+    }
+    /// Events are being delivered: the sink and at least one camera are connected
+    pub fn is_ready(&self) -> bool {
+        self.mqtt_connected && self.cameras.iter().any(|c| c.connected)
+    }
+    /// Tracks the configured cameras, for sinks that don't keep their own state of each camera.
+    /// Cameras that are still configured keep their state.
+    pub fn apply_config(&mut self, cameras: &[ConfigCamera], now: DateTime<Utc>) {
+        let mut previous = std::mem::take(&mut self.cameras);
+        self.cameras = cameras
+            .iter()
+            .map(|cam| {
+                let status = match previous.iter().position(|c| c.id == cam.identifier()) {
+                    Some(index) => CameraStatus {
+                        name: cam.name.clone(),
+                        ..previous.swap_remove(index)
+                    },
+                    None => CameraStatus {
+                        id: cam.identifier().to_string(),
+                        name: cam.name.clone(),
+                        connected: false,
+                        running: cam.enabled,
+                        error: None,
+                        since: now,
+                        last_event: None,
+                        triggers: 0,
+                    },
+                };
+                match (cam.enabled, status.running || status.connected) {
+                    // Stopped when it was disabled, so it won't send any more events
+                    (false, true) => CameraStatus {
+                        connected: false,
+                        running: false,
+                        error: None,
+                        since: now,
+                        ..status
+                    },
+                    // Started again after being disabled
+                    (true, false) if status.error.is_none() => CameraStatus {
+                        running: true,
+                        since: now,
+                        ..status
+                    },
+                    _ => status,
+                }
+            })
+            .collect();
+    }
+    /// Updates the state of a camera from one of its events
+    pub fn apply_event(&mut self, event: &CameraEvent, now: DateTime<Utc>) {
+        let camera = match self.cameras.iter_mut().find(|c| c.id == event.id) {
+            Some(camera) => camera,
+            None => return,
+        };
+        match &event.event {
+            CameraEventType::Connected { triggers, .. } => {
+                camera.connected = true;
+                camera.running = true;
+                camera.error = None;
+                camera.since = now;
+                camera.triggers = triggers.len();
+            }
+            CameraEventType::Disconnected {
+                error,
+                retry_in_secs,
+                ..
+            } => {
+                camera.connected = false;
+                camera.running = retry_in_secs.is_some();
+                camera.error = Some(error.clone());
+                camera.since = now;
+            }
+            CameraEventType::Paused | CameraEventType::Rebooting { .. } => {
+                // Deliberately disconnected, so it's neither failing nor given up
+                camera.connected = false;
+                camera.running = true;
+                camera.error = None;
+                camera.since = now;
+            }
+            CameraEventType::TriggersUpdated { added, removed } => {
+                camera.triggers = (camera.triggers + added.len()).saturating_sub(removed.len());
+            }
+            CameraEventType::Alert(_) => camera.last_event = Some(now),
+            CameraEventType::ParseFailure { .. }
+            | CameraEventType::Control(_)
+            | CameraEventType::ControlFailed { .. }
+            | CameraEventType::RebootFailed { .. }
+            | CameraEventType::PtzPresets(_)
+            | CameraEventType::PresetFailed { .. }
+            | CameraEventType::InfoUpdated(_)
+            | CameraEventType::ClockDrift { .. }
+            | CameraEventType::Snapshot { .. }
+            | CameraEventType::RawPart(_) => {}
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::io::Write;
+
+use tokio::sync::{mpsc, watch};
+use tracing::warn;
+
+use super::{status::BridgeStatus, EventSink, SinkHandle};
+use crate::{
+    config::{Config, ConfigCamera},
+    hikapi::{CameraEvent, CameraEventType, EventQueue},
+};
+
+/// Enough for bursts of alerts while stdout is slow
+const QUEUE_CAPACITY: usize = 100;
+
+/// Prints each camera event to stdout as a line of JSON, to be piped into other tools
+pub struct StdoutSink {
+    cameras: Vec<ConfigCamera>,
+}
+
+impl StdoutSink {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            cameras: config.camera.clone(),
+        }
+    }
+}
+
+impl EventSink for StdoutSink {
+    fn start(self) -> SinkHandle {
+        let (events_tx, mut events_rx) = EventQueue::channel(QUEUE_CAPACITY);
+        let (cameras_tx, mut cameras_rx) = mpsc::unbounded_channel::<Vec<ConfigCamera>>();
+        // Nothing to connect to, so the sink is always available
+        let mut status = BridgeStatus {
+            mqtt_connected: true,
+            ..Default::default()
+        };
+        status.apply_config(&self.cameras, chrono::Utc::now());
+        let (status_tx, status_rx) = watch::channel(status.clone());
+
+        tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(event) = events_rx.recv() => {
+                        status.apply_event(&event, chrono::Utc::now());
+                        if let Some(line) = render_line(&event) {
+                            let mut stdout = std::io::stdout().lock();
+                            if let Err(e) = writeln!(stdout, "{}", line).and_then(|_| stdout.flush()) {
+                                warn!("Unable to write event to stdout: {}", e);
+                            }
+                        }
+                    }
+                    Some(cameras) = cameras_rx.recv() => {
+                        status.apply_config(&cameras, chrono::Utc::now());
+                    }
+                    else => break,
+                }
+                let _ = status_tx.send(status.clone());
+            }
+        });
+
+        // Nothing to receive commands from, to wrap up, or to fail
+        let (_, commands_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, _) = mpsc::unbounded_channel();
+        let (_, fatal_rx) = mpsc::unbounded_channel();
+        SinkHandle {
+            events: events_tx,
+            cameras: cameras_tx,
+            status: status_rx,
+            commands: commands_rx,
+            shutdown: shutdown_tx,
+            fatal: fatal_rx,
+        }
+    }
+}
+
+/// The line printed for an event, if it's printed at all
+fn render_line(event: &CameraEvent) -> Option<String> {
+    match &event.event {
+        // Binary, and too large for a line
+        CameraEventType::Snapshot { .. } => None,
+        _ => match serde_json::to_string(event) {
+            Ok(line) => Some(line),
+            Err(e) => {
+                warn!(id=?event.id, "Unable to serialize event: {}", e);
+                None
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use figment::providers::{Format, Toml};
+
+    use super::render_line;
+    use crate::{
+        hikapi::{
+            AlertItem, CameraEvent, CameraEventType, DisconnectKind, EventIdentifier, EventType,
+        },
+        sink::status::BridgeStatus,
+    };
+
+    fn alert() -> AlertItem {
+        AlertItem::parse(indoc::indoc! {r#"
+            <EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <ipAddress>192.168.1.200</ipAddress>
+            <portNo>80</portNo>
+            <protocol>HTTP</protocol>
+            <macAddress>ff:ff:ff:ff:ff:ff</macAddress>
+            <channelID>1</channelID>
+            <dateTime>2021-10-02T18:04:02+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>VMD</eventType>
+            <eventState>active</eventState>
+            <eventDescription>Motion alarm</eventDescription>
+            </EventNotificationAlert>
+        "#})
+        .unwrap()
+    }
+
+    #[test]
+    fn test_render_line() {
+        let event = CameraEvent {
+            id: "front".into(),
+            event: CameraEventType::Alert(alert()),
+        };
+        insta::assert_snapshot!(render_line(&event).unwrap());
+
+        let snapshot = CameraEvent {
+            id: "front".into(),
+            event: CameraEventType::Snapshot {
+                identifier: EventIdentifier::new(Some("1".into()), EventType::Motion),
+                image: vec![0xff, 0xd8],
+            },
+        };
+        assert_eq!(render_line(&snapshot), None);
+    }
+
+    #[test]
+    fn test_status() {
+        let cfg =
+            crate::config::load_config(Toml::string(include_str!("../../sample_config.toml")))
+                .unwrap();
+        let now = chrono::Utc::now();
+        let mut status = BridgeStatus {
+            mqtt_connected: true,
+            ..Default::default()
+        };
+        status.apply_config(&cfg.camera, now);
+        let id = cfg.camera[0].identifier().to_string();
+        assert!(!status.is_ready());
+        assert!(status.is_healthy());
+
+        status.apply_event(
+            &CameraEvent {
+                id: id.clone(),
+                event: CameraEventType::Alert(alert()),
+            },
+            now,
+        );
+        assert_eq!(status.cameras[0].last_event, Some(now));
+
+        status.apply_event(
+            &CameraEvent {
+                id: id.clone(),
+                event: CameraEventType::Disconnected {
+                    error: "Camera closed connection".into(),
+                    kind: DisconnectKind::Closed,
+                    retry_in_secs: None,
+                },
+            },
+            now,
+        );
+        assert!(!status.cameras[0].running);
+        assert_eq!(
+            status.cameras[0].error.as_deref(),
+            Some("Camera closed connection")
+        );
+
+        // Cameras that are still configured keep their state
+        status.apply_config(&cfg.camera, now);
+        assert!(!status.cameras[0].running);
+        status.apply_config(&[], now);
+        assert!(status.cameras.is_empty());
+
+        // Disabled cameras are neither running nor failing, until they are enabled again
+        let mut cameras = cfg.camera.clone();
+        cameras[0].enabled = false;
+        status.apply_config(&cameras, now);
+        assert!(!status.cameras[0].running);
+        assert_eq!(status.cameras[0].error, None);
+        status.apply_config(&cfg.camera, now);
+        assert!(status.cameras[0].running);
+    }
+}
@@ -0,0 +1,292 @@
+use std::{convert::TryFrom, time::Duration};
+
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client,
+};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, info, warn};
+
+use crate::{
+    backoff::Backoff,
+    config::ConfigWebhook,
+    hikapi::{CameraEvent, CameraEventType, DetectionRegion},
+};
+
+/// The JSON body POSTed for each alert
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    camera: &'a str,
+    event_type: String,
+    channel: Option<&'a str>,
+    state: &'static str,
+    regions: &'a [DetectionRegion],
+    /// The alert time as sent by the camera
+    timestamp: &'a str,
+}
+
+/// Why a request failed
+enum PostError {
+    /// Worth trying again, e.g. a timeout or a server error
+    Transient(String),
+    /// Will fail the same way every time, e.g. the request was rejected
+    Permanent(String),
+}
+
+/// Outcomes of the alerts received so far, logged with each failure
+#[derive(Debug, Default)]
+struct Stats {
+    delivered: u64,
+    failed: u64,
+    /// Alerts that were dropped because the endpoint couldn't keep up
+    dropped: u64,
+}
+
+/// Starts POSTing the alerts from the broadcast to the webhook. Runs until the broadcast is closed.
+pub fn start(
+    config: ConfigWebhook,
+    mut events: broadcast::Receiver<CameraEvent>,
+) -> Result<(), String> {
+    let client = client(&config)?;
+    info!(url = config.url.as_str(), "Sending alerts to webhook");
+    tokio::task::spawn(async move {
+        let mut stats = Stats::default();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(count)) => {
+                    stats.dropped += count;
+                    warn!(
+                        dropped = stats.dropped,
+                        "Webhook is too slow, dropped {} alerts", count
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let body = match payload(&config, &event) {
+                Some(payload) => match serde_json::to_vec(&payload) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("Unable to serialize webhook payload: {}", e);
+                        continue;
+                    }
+                },
+                None => continue,
+            };
+            match deliver(&client, &config, body).await {
+                Ok(()) => stats.delivered += 1,
+                Err(e) => {
+                    stats.failed += 1;
+                    warn!(
+                        id = event.id.as_str(),
+                        delivered = stats.delivered,
+                        failed = stats.failed,
+                        "Unable to send alert to webhook: {}",
+                        e
+                    );
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn client(config: &ConfigWebhook) -> Result<Client, String> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.headers {
+        headers.insert(
+            HeaderName::try_from(name.as_str()).map_err(|e| e.to_string())?,
+            HeaderValue::try_from(value.as_str()).map_err(|e| e.to_string())?,
+        );
+    }
+    Client::builder()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// The body to send for an event, if it's an alert that passes the filters
+fn payload<'a>(config: &ConfigWebhook, event: &'a CameraEvent) -> Option<Payload<'a>> {
+    let alert = match &event.event {
+        CameraEventType::Alert(alert) if config.includes_trigger(&alert.identifier) => alert,
+        _ => return None,
+    };
+    Some(Payload {
+        camera: &event.id,
+        event_type: alert.identifier.event_type.to_string(),
+        channel: alert.identifier.channel.as_deref(),
+        state: if alert.active { "active" } else { "inactive" },
+        regions: &alert.regions,
+        timestamp: &alert.date,
+    })
+}
+
+/// POSTs the body, retrying transient failures
+async fn deliver(client: &Client, config: &ConfigWebhook, body: Vec<u8>) -> Result<(), String> {
+    let mut backoff = Backoff::new(config.retry_backoff.clone());
+    let mut attempt = 0;
+    loop {
+        match post(client, &config.url, body.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(PostError::Transient(e)) if attempt < config.retries => {
+                attempt += 1;
+                let delay = backoff.next_delay();
+                debug!("Webhook request failed, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+            }
+            Err(PostError::Transient(e)) | Err(PostError::Permanent(e)) => return Err(e),
+        }
+    }
+}
+
+async fn post(client: &Client, url: &str, body: Vec<u8>) -> Result<(), PostError> {
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| PostError::Transient(e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(PostError::Transient(format!(
+            "Endpoint returned {}",
+            status
+        )))
+    } else {
+        Err(PostError::Permanent(format!(
+            "Endpoint returned {}",
+            status
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::{payload, start};
+    use crate::{
+        config::{ConfigBackoff, ConfigWebhook},
+        hikapi::{AlertItem, CameraEvent, CameraEventType, DisconnectKind},
+    };
+
+    fn config(url: String) -> ConfigWebhook {
+        let mut headers = BTreeMap::new();
+        headers.insert("Authorization".to_string(), "Bearer token".to_string());
+        ConfigWebhook {
+            url,
+            headers,
+            events_include: vec![],
+            events_exclude: vec!["VideoLoss".to_string()],
+            timeout_secs: 5,
+            retries: 2,
+            retry_backoff: ConfigBackoff {
+                min_secs: 0,
+                max_secs: 0,
+            },
+            queue_size: 10,
+        }
+    }
+
+    fn alert(event_type: &str) -> CameraEvent {
+        let alert = AlertItem::parse(&format!(
+            r#"<EventNotificationAlert version="2.0" xmlns="http://www.hikvision.com/ver20/XMLSchema">
+            <channelID>1</channelID>
+            <dateTime>2021-10-02T18:04:02+08:00</dateTime>
+            <activePostCount>1</activePostCount>
+            <eventType>{}</eventType>
+            <eventState>active</eventState>
+            <eventDescription>alarm</eventDescription>
+            </EventNotificationAlert>"#,
+            event_type
+        ))
+        .unwrap();
+        CameraEvent {
+            id: "front".into(),
+            event: CameraEventType::Alert(alert),
+        }
+    }
+
+    #[test]
+    fn test_payload() {
+        let config = config("http://localhost".into());
+        insta::assert_yaml_snapshot!(payload(&config, &alert("VMD")));
+        assert!(payload(&config, &alert("videoloss")).is_none());
+        let disconnected = CameraEvent {
+            id: "front".into(),
+            event: CameraEventType::Disconnected {
+                error: "Camera closed connection".into(),
+                kind: DisconnectKind::Closed,
+                retry_in_secs: Some(1),
+            },
+        };
+        assert!(payload(&config, &disconnected).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delivery_retries() {
+        // Fails the first request of each alert, then accepts the retry
+        let retrying = Arc::new(AtomicBool::new(false));
+        let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let retrying = retrying.clone();
+            let received_tx = received_tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let retry = retrying.fetch_xor(true, Ordering::SeqCst);
+                    let received_tx = received_tx.clone();
+                    async move {
+                        let auth = req.headers()["authorization"].to_str().unwrap().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let status = if !retry {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        } else {
+                            let _ = received_tx.send((auth, body));
+                            StatusCode::OK
+                        };
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let (events, _) = broadcast::channel(10);
+        start(config(format!("http://{}/hook", addr)), events.subscribe()).unwrap();
+        events.send(alert("videoloss")).unwrap();
+        events.send(alert("VMD")).unwrap();
+
+        let (auth, body) = received_rx.recv().await.unwrap();
+        assert_eq!(auth, "Bearer token");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["event_type"], "Motion");
+        assert_eq!(body["state"], "active");
+        assert!(received_rx.try_recv().is_err());
+    }
+}